@@ -0,0 +1,92 @@
+//! Decoding subscription stream messages into label records, with the strings that repeat across
+//! labels shared through one interner as when streaming, and with a fresh interner for every
+//! message so that nothing is shared between labels. Run with `cargo bench`.
+
+// labelview is only a binary, so the modules that decode labels are built into the benchmark
+#![allow(dead_code)]
+#[path = "../src/atproto.rs"]
+mod atproto;
+#[path = "../src/db.rs"]
+mod db;
+
+use ciborium::Value;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use db::{DecodeAnomalies, Interner, LabelRecord};
+use std::hint::black_box;
+
+const MESSAGES: usize = 10_000;
+const VALUES: [&str; 4] = ["spam", "porn", "rude", "misleading"];
+
+/// messages of one label each, as most labelers send them, from the same src with a handful of
+/// values, as the header and body of each
+fn messages() -> Vec<(Vec<u8>, Vec<u8>)> {
+    let text = |text: &str| Value::Text(text.to_owned());
+    (0..MESSAGES)
+        .map(|i| {
+            let label = Value::Map(vec![
+                (text("ver"), 1.into()),
+                (text("src"), text("did:plc:ar7c4by46qjdydhdevvrndac")),
+                (
+                    text("uri"),
+                    text(&format!(
+                        "at://did:plc:target{i:016}/app.bsky.feed.post/3l{i:011}"
+                    )),
+                ),
+                (text("val"), text(VALUES[i % VALUES.len()])),
+                (text("cts"), text("2024-11-05T08:57:32.123Z")),
+                (text("sig"), Value::Bytes(vec![7; 64])),
+            ]);
+            let header = Value::Map(vec![(text("op"), 1.into()), (text("t"), text("#labels"))]);
+            let body = Value::Map(vec![
+                (text("seq"), (i as i64 + 1).into()),
+                (text("labels"), Value::Array(vec![label])),
+            ]);
+            let (mut header_bytes, mut body_bytes) = (Vec::new(), Vec::new());
+            ciborium::into_writer(&header, &mut header_bytes).unwrap();
+            ciborium::into_writer(&body, &mut body_bytes).unwrap();
+            (header_bytes, body_bytes)
+        })
+        .collect()
+}
+
+fn decode(
+    (header, body): &(Vec<u8>, Vec<u8>),
+    interner: &mut Interner,
+    anomalies: &mut DecodeAnomalies,
+) {
+    let decoded =
+        LabelRecord::from_subscription_record(header, body, interner, anomalies, usize::MAX, false)
+            .unwrap();
+    black_box(decoded);
+}
+
+fn decoding(c: &mut Criterion) {
+    let messages = messages();
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(MESSAGES as u64));
+    group.bench_function("interned", |b| {
+        b.iter_batched(
+            Interner::default,
+            |mut interner| {
+                let mut anomalies = DecodeAnomalies::default();
+                for message in &messages {
+                    decode(message, &mut interner, &mut anomalies);
+                }
+                interner
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("not interned", |b| {
+        b.iter(|| {
+            let mut anomalies = DecodeAnomalies::default();
+            for message in &messages {
+                decode(message, &mut Interner::default(), &mut anomalies);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decoding);
+criterion_main!(benches);
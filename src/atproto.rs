@@ -0,0 +1,743 @@
+use eyre::{bail, eyre as err, Result};
+use std::{
+    borrow::{Borrow, Cow},
+    fmt,
+    rc::Rc,
+    str::FromStr,
+};
+
+/// A decentralized identifier naming an account, like `did:plc:ewvi7nxzyoun6zhxrhs64oiz`
+///
+/// Dids are checked to be `did:`, a method name of lowercase letters, a colon, and a
+/// method-specific identifier of the characters dids allow, not ending in `:` or `%`. The
+/// identifier isn't checked against the rules of its method, so dids of methods other than
+/// `did:plc` and `did:web` are accepted too; `check_method_rules` checks that strictly, for dids
+/// that must name an atproto account. The string is shared rather than copied when a did is
+/// cloned, since the same few dids are repeated across every label record.
+///
+/// https://atproto.com/specs/did
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Did(Rc<str>);
+
+/// length of the identifier of every did:plc
+const PLC_IDENTIFIER_LENGTH: usize = 24;
+
+impl Did {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// the method name, like `plc` or `web`
+    pub fn method(&self) -> &str {
+        self.split().0
+    }
+
+    /// the method-specific identifier, everything after the method name
+    pub fn identifier(&self) -> &str {
+        self.split().1
+    }
+
+    /// checks the method-specific identifier against the rules of its method, for dids naming an
+    /// atproto account. Atproto only supports `did:plc` and `did:web`, so other methods fail
+    pub fn check_method_rules(&self) -> Result<()> {
+        match self.method() {
+            "plc" => self.check_plc_identifier(),
+            "web" => self.check_web_identifier(),
+            method => bail!(
+                "did {self} uses the did:{method} method, but atproto only supports did:plc and \
+                did:web"
+            ),
+        }
+    }
+
+    /// plc identifiers are 24 characters of lowercase base32
+    fn check_plc_identifier(&self) -> Result<()> {
+        let identifier = self.identifier();
+        if let Some(c) = identifier
+            .chars()
+            .find(|c| !matches!(c, 'a'..='z' | '2'..='7'))
+        {
+            bail!(
+                "did {self} contains {c:?}, but did:plc identifiers only use the base32 \
+                characters a-z and 2-7"
+            );
+        }
+        if identifier.len() != PLC_IDENTIFIER_LENGTH {
+            bail!(
+                "did {self} has a plc identifier of {len} character(s), but they are always \
+                {PLC_IDENTIFIER_LENGTH}",
+                len = identifier.len()
+            );
+        }
+        Ok(())
+    }
+
+    /// web identifiers are a hostname, then optionally a port after an encoded colon (`%3A`), then
+    /// optionally path segments separated by colons
+    fn check_web_identifier(&self) -> Result<()> {
+        let mut segments = self.identifier().split(':');
+        let host_and_port = segments.next().unwrap_or_default();
+        let (host, port) = match host_and_port
+            .find("%3A")
+            .or_else(|| host_and_port.find("%3a"))
+        {
+            Some(at) => (&host_and_port[..at], Some(&host_and_port[at + 3..])),
+            None => (host_and_port, None),
+        };
+        if host.is_empty() || host.len() > 253 {
+            bail!("did {self} has a did:web hostname of {len} character(s), but hostnames are 1 to 253", len = host.len());
+        }
+        for label in host.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                bail!("did {self} has a hostname with a part {label:?} that isn't 1 to 63 characters long");
+            }
+            if !label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            {
+                bail!("did {self} has a hostname with a part {label:?} that isn't only letters, digits, and hyphens");
+            }
+            if label.starts_with('-') || label.ends_with('-') {
+                bail!("did {self} has a hostname with a part {label:?} that starts or ends with a hyphen");
+            }
+        }
+        if let Some(port) = port {
+            if !port.bytes().all(|b| b.is_ascii_digit())
+                || !port.parse::<u16>().is_ok_and(|port| port > 0)
+            {
+                bail!("did {self} has a port {port:?} that isn't a number from 1 to 65535");
+            }
+        }
+        if segments.any(str::is_empty) {
+            bail!("did {self} has an empty path segment");
+        }
+        Ok(())
+    }
+
+    fn split(&self) -> (&str, &str) {
+        self.0["did:".len()..]
+            .split_once(':')
+            .expect("dids are validated to have a method and identifier")
+    }
+}
+
+impl TryFrom<Rc<str>> for Did {
+    type Error = eyre::Report;
+
+    fn try_from(s: Rc<str>) -> Result<Did> {
+        let Some(rest) = s.strip_prefix("did:") else {
+            bail!("did {s:?} does not start with did:");
+        };
+        let Some((method, identifier)) = rest.split_once(':') else {
+            bail!("did {s:?} has no method-specific identifier");
+        };
+        if method.is_empty() || !method.bytes().all(|b| b.is_ascii_lowercase()) {
+            bail!("did {s:?} has a method that isn't lowercase letters");
+        }
+        if identifier.is_empty() {
+            bail!("did {s:?} has an empty method-specific identifier");
+        }
+        if !identifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b".:_%-".contains(&b))
+        {
+            bail!("did {s:?} contains characters that aren't allowed in a did");
+        }
+        if identifier.ends_with([':', '%']) {
+            bail!("did {s:?} ends with {last:?}", last = &s[s.len() - 1..]);
+        }
+        Ok(Did(s))
+    }
+}
+
+impl FromStr for Did {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Did> {
+        Did::try_from(Rc::from(s))
+    }
+}
+
+impl Borrow<str> for Did {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Did {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Puts a did in the form it is compared and saved in, so that one identity isn't split in two by
+/// differences in case between the places it was read from. The `did:` scheme and the method name
+/// are lowercased; the method-specific identifier is left as it is, since methods are free to treat
+/// it as case-sensitive. Anything that doesn't look like a did is returned unchanged
+///
+/// https://atproto.com/specs/did
+pub fn normalize_did(did: &str) -> Cow<'_, str> {
+    let Some((scheme, rest)) = did.split_once(':') else {
+        return Cow::Borrowed(did);
+    };
+    let Some((method, identifier)) = rest.split_once(':') else {
+        return Cow::Borrowed(did);
+    };
+    if !scheme.eq_ignore_ascii_case("did")
+        || (scheme == "did" && !method.bytes().any(|b| b.is_ascii_uppercase()))
+    {
+        return Cow::Borrowed(did);
+    }
+    Cow::Owned(format!(
+        "did:{method}:{identifier}",
+        method = method.to_ascii_lowercase()
+    ))
+}
+
+/// multicodec code of dag-cbor, the encoding of atproto records
+const DAG_CBOR: u64 = 0x71;
+/// multihash code of sha2-256, the hash atproto records are addressed by
+const SHA2_256: u64 = 0x12;
+
+/// Checks that a cid is in the form atproto uses to pin a version of a record: a CIDv1 of dag-cbor
+/// content hashed with sha2-256. Whether it matches the record's content isn't checked, since that
+/// would mean fetching the record from its pds
+///
+/// https://atproto.com/specs/data-model#link-and-cid-formats
+pub fn verify_cid_format(cid_str: &str) -> Result<()> {
+    let cid: cid::Cid = cid_str
+        .parse()
+        .map_err(|e| err!("cid {cid_str:?} doesn't parse: {e}"))?;
+    if cid.version() != cid::Version::V1 {
+        bail!("cid {cid_str} is a CIDv0 rather than a CIDv1");
+    }
+    if cid.codec() != DAG_CBOR {
+        bail!(
+            "cid {cid_str} has codec {codec:#x} rather than dag-cbor ({DAG_CBOR:#x})",
+            codec = cid.codec()
+        );
+    }
+    if cid.hash().code() != SHA2_256 {
+        bail!(
+            "cid {cid_str} has hash {hash:#x} rather than sha2-256 ({SHA2_256:#x})",
+            hash = cid.hash().code()
+        );
+    }
+    Ok(())
+}
+
+/// A namespaced identifier naming a type of record or an API endpoint, like `app.bsky.feed.post`
+///
+/// https://atproto.com/specs/nsid
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Nsid(String);
+
+impl Nsid {
+    /// the longest an nsid may be, in bytes
+    const MAX_LENGTH: usize = 317;
+
+    pub fn parse(s: &str) -> Result<Nsid> {
+        if s.len() > Self::MAX_LENGTH {
+            bail!("nsid is longer than {max} bytes", max = Self::MAX_LENGTH);
+        }
+        if s.starts_with('.') || s.ends_with('.') {
+            bail!("nsid {s:?} has a leading or trailing dot");
+        }
+        let segments: Vec<&str> = s.split('.').collect();
+        let [authority @ .., name] = segments.as_slice() else {
+            unreachable!("split always yields at least one segment");
+        };
+        if authority.is_empty() {
+            bail!("nsid {s:?} must have at least two segments");
+        }
+        for (i, segment) in authority.iter().enumerate() {
+            if segment.is_empty() {
+                bail!("nsid {s:?} has an empty segment");
+            }
+            if segment.len() > 63 {
+                bail!("nsid {s:?} has a segment longer than 63 bytes");
+            }
+            if !segment
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+            {
+                bail!("nsid {s:?} has a segment that isn't lowercase letters, digits, and hyphens");
+            }
+            if segment.starts_with('-') || segment.ends_with('-') {
+                bail!("nsid {s:?} has a segment with a leading or trailing hyphen");
+            }
+            // the first segment is the top level domain of the authority, reversed
+            if i == 0 && !segment.starts_with(|c: char| c.is_ascii_lowercase()) {
+                bail!("nsid {s:?} must start with a letter");
+            }
+        }
+        // the final segment is a name rather than part of a domain, and may be camelCase
+        if name.is_empty() {
+            bail!("nsid {s:?} has an empty segment");
+        }
+        if name.len() > 63 {
+            bail!("nsid {s:?} has a segment longer than 63 bytes");
+        }
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic())
+            || !name.bytes().all(|b| b.is_ascii_alphanumeric())
+        {
+            bail!("nsid {s:?} has a name that isn't letters and digits starting with a letter");
+        }
+        Ok(Nsid(s.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Nsid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An `at://` uri naming an account's repository, or a collection or record within it
+///
+/// https://atproto.com/specs/at-uri-scheme
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AtUri {
+    /// the did or handle of the account
+    pub authority: String,
+    pub collection: Option<Nsid>,
+    pub rkey: Option<String>,
+}
+
+impl AtUri {
+    pub fn parse(s: &str) -> Result<AtUri> {
+        let Some(rest) = s.strip_prefix("at://") else {
+            bail!("at-uri {s:?} does not start with at://");
+        };
+        // the query and fragment aren't part of what the uri names
+        let rest = rest.split(['?', '#']).next().unwrap_or_default();
+        let mut split = rest.split('/');
+        let authority = split.next().unwrap_or_default();
+        if authority.is_empty() {
+            bail!("at-uri {s:?} has no authority");
+        }
+        let collection = split
+            .next()
+            .filter(|c| !c.is_empty())
+            .map(Nsid::parse)
+            .transpose()?;
+        let rkey = split.next().filter(|r| !r.is_empty()).map(str::to_owned);
+        if split.any(|extra| !extra.is_empty()) {
+            bail!("at-uri {s:?} has too many path segments");
+        }
+        Ok(AtUri {
+            authority: authority.to_owned(),
+            collection,
+            rkey,
+        })
+    }
+}
+
+impl fmt::Display for AtUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "at://{authority}", authority = self.authority)?;
+        if let Some(collection) = &self.collection {
+            write!(f, "/{collection}")?;
+            if let Some(rkey) = &self.rkey {
+                write!(f, "/{rkey}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A label target that names its account by handle where a did belongs, either a bare handle or
+/// an `at://` uri with a handle as its authority. Such targets don't match the did-based targets
+/// of the same account
+pub struct HandleTarget<'a> {
+    /// `at://`, or nothing for a bare handle
+    prefix: &'a str,
+    pub handle: &'a str,
+    /// the collection and record key, if any
+    rest: &'a str,
+}
+
+impl<'a> HandleTarget<'a> {
+    pub fn parse(target_uri: &'a str) -> Option<Self> {
+        let (prefix, uri) = match target_uri.strip_prefix("at://") {
+            Some(uri) => ("at://", uri),
+            None => ("", target_uri),
+        };
+        let end = uri.find(['/', '?', '#']).unwrap_or(uri.len());
+        let (handle, rest) = uri.split_at(end);
+        // handles are domain names, which dids can never be since they contain a colon
+        let looks_like_handle = handle.contains('.')
+            && !handle.starts_with('.')
+            && !handle.ends_with('.')
+            && handle
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+        if !looks_like_handle || (prefix.is_empty() && !rest.is_empty()) {
+            return None;
+        }
+        Some(Self {
+            prefix,
+            handle,
+            rest,
+        })
+    }
+
+    /// the same target with the account named by its did instead
+    pub fn with_did(&self, did: &str) -> String {
+        format!(
+            "{prefix}{did}{rest}",
+            prefix = self.prefix,
+            rest = self.rest
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// checks a did the way dids naming atproto accounts are checked, returning the error if any
+    fn check(did: &str) -> Result<(), String> {
+        did.parse::<Did>()
+            .and_then(|did| did.check_method_rules())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn valid_dids_pass() {
+        for did in [
+            "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+            "did:web:example.com",
+            "did:web:sub.example-host.com",
+            "did:web:localhost%3A8080",
+            "did:web:example.com%3a443",
+            "did:web:example.com:users:alice",
+        ] {
+            assert_eq!(check(did), Ok(()), "{did}");
+        }
+    }
+
+    #[test]
+    fn malformed_dids_say_which_rule_they_break() {
+        let cases = [
+            // general did syntax
+            ("plc:ewvi7nxzyoun6zhxrhs64oiz", "does not start with did:"),
+            ("did:plc", "no method-specific identifier"),
+            (
+                "did::ewvi7nxzyoun6zhxrhs64oiz",
+                "method that isn't lowercase",
+            ),
+            (
+                "did:PLC:ewvi7nxzyoun6zhxrhs64oiz",
+                "method that isn't lowercase",
+            ),
+            ("did:plc:", "empty method-specific identifier"),
+            (
+                "did:plc:ewvi7nxzyoun6 zhxrhs64oiz",
+                "characters that aren't allowed",
+            ),
+            ("did:web:example.com:", "ends with \":\""),
+            ("did:web:example.com%", "ends with \"%\""),
+            // plc identifiers
+            ("did:plc:abc", "plc identifier of 3 character(s)"),
+            (
+                "did:plc:ewvi7nxzyoun6zhxrhs64oizz",
+                "plc identifier of 25 character(s)",
+            ),
+            ("did:plc:EWVI7NXZYOUN6ZHXRHS64OIZ", "only use the base32"),
+            ("did:plc:ewvi7nxzyoun6zhxrhs64oi1", "only use the base32"),
+            ("did:plc:ewvi7nxzyoun6zhxrhs64oi8", "only use the base32"),
+            ("did:plc:ewvi7nxzyoun6zhxrhs64o-z", "only use the base32"),
+            // web hostnames, ports, and paths
+            (
+                "did:web:exa_mple.com",
+                "isn't only letters, digits, and hyphens",
+            ),
+            (
+                "did:web:exa%41mple.com",
+                "isn't only letters, digits, and hyphens",
+            ),
+            ("did:web:example..com", "isn't 1 to 63 characters long"),
+            ("did:web:-example.com", "starts or ends with a hyphen"),
+            ("did:web:%3A8080", "hostname of 0 character(s)"),
+            (
+                "did:web:example.com%3Ahttp",
+                "isn't a number from 1 to 65535",
+            ),
+            ("did:web:example.com%3A0", "isn't a number from 1 to 65535"),
+            (
+                "did:web:example.com%3A65536",
+                "isn't a number from 1 to 65535",
+            ),
+            ("did:web:example.com::alice", "empty path segment"),
+            // methods atproto doesn't support
+            (
+                "did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK",
+                "only supports",
+            ),
+        ];
+        for (did, rule) in cases {
+            match check(did) {
+                Ok(()) => panic!("{did} was accepted"),
+                Err(e) => assert!(e.contains(rule), "{did}: {e:?} should mention {rule:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn nsids_follow_the_syntax_rules() {
+        let segment = "a".repeat(63);
+        // four full segments and a name, 317 bytes altogether
+        let longest = format!(
+            "{segment}.{segment}.{segment}.{segment}.{name}",
+            name = "b".repeat(61)
+        );
+        assert_eq!(longest.len(), 317);
+        let valid = [
+            "app.bsky.feed.post".to_owned(),
+            "com.example.fooBar".to_owned(),
+            "app.bsky.feed.getPostThread".to_owned(),
+            "com.example.post2".to_owned(),
+            "com.1example.post".to_owned(),
+            "com.exa-mple.post".to_owned(),
+            "a.b".to_owned(),
+            format!("com.{segment}.post"),
+            format!("com.example.{segment}"),
+            longest.clone(),
+        ];
+        for nsid in valid {
+            match Nsid::parse(&nsid) {
+                Ok(parsed) => assert_eq!(parsed.as_str(), nsid),
+                Err(e) => panic!("{nsid} was rejected: {e}"),
+            }
+        }
+
+        let cases = [
+            (format!("{longest}b"), "longer than 317 bytes"),
+            ("1com.example.post".to_owned(), "must start with a letter"),
+            (
+                "com.example.1post".to_owned(),
+                "name that isn't letters and digits",
+            ),
+            (
+                "com.example.my-post".to_owned(),
+                "name that isn't letters and digits",
+            ),
+            (
+                "com.example.my_post".to_owned(),
+                "name that isn't letters and digits",
+            ),
+            // only the name may be camelCase
+            (
+                "com.Example.post".to_owned(),
+                "isn't lowercase letters, digits, and hyphens",
+            ),
+            (
+                "com.exa_mple.post".to_owned(),
+                "isn't lowercase letters, digits, and hyphens",
+            ),
+            ("com.-example.post".to_owned(), "leading or trailing hyphen"),
+            ("com.example-.post".to_owned(), "leading or trailing hyphen"),
+            (format!("com.a{segment}.post"), "longer than 63 bytes"),
+            (format!("com.example.a{segment}"), "longer than 63 bytes"),
+            ("com..post".to_owned(), "empty segment"),
+            ("com.example..post".to_owned(), "empty segment"),
+            (".com.example.post".to_owned(), "leading or trailing dot"),
+            ("com.example.post.".to_owned(), "leading or trailing dot"),
+            ("post".to_owned(), "at least two segments"),
+            ("".to_owned(), "at least two segments"),
+        ];
+        for (nsid, rule) in cases {
+            match Nsid::parse(&nsid) {
+                Ok(_) => panic!("{nsid} was accepted"),
+                Err(e) => {
+                    let e = e.to_string();
+                    assert!(e.contains(rule), "{nsid}: {e:?} should mention {rule:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn at_uris_name_an_account_collection_or_record() {
+        let valid = [
+            ("at://did:plc:x", "did:plc:x", None, None),
+            ("at://alice.test", "alice.test", None, None),
+            ("at://did:plc:x/", "did:plc:x", None, None),
+            (
+                "at://did:plc:x/app.bsky.feed.post",
+                "did:plc:x",
+                Some("app.bsky.feed.post"),
+                None,
+            ),
+            (
+                "at://alice.test/app.bsky.feed.post/3kabc",
+                "alice.test",
+                Some("app.bsky.feed.post"),
+                Some("3kabc"),
+            ),
+            (
+                "at://did:plc:x/app.bsky.feed.post/3kabc/",
+                "did:plc:x",
+                Some("app.bsky.feed.post"),
+                Some("3kabc"),
+            ),
+            // the query and fragment aren't part of what is named
+            (
+                "at://did:plc:x/app.bsky.feed.post/3kabc?a=b#c",
+                "did:plc:x",
+                Some("app.bsky.feed.post"),
+                Some("3kabc"),
+            ),
+            ("at://did:plc:x#c", "did:plc:x", None, None),
+        ];
+        for (uri, authority, collection, rkey) in valid {
+            let parsed = AtUri::parse(uri).unwrap_or_else(|e| panic!("{uri} was rejected: {e}"));
+            assert_eq!(parsed.authority, authority, "{uri}");
+            assert_eq!(
+                parsed.collection.as_ref().map(Nsid::as_str),
+                collection,
+                "{uri}"
+            );
+            assert_eq!(parsed.rkey.as_deref(), rkey, "{uri}");
+            // parsing what is displayed gives the same uri back
+            assert_eq!(AtUri::parse(&parsed.to_string()).unwrap(), parsed, "{uri}");
+        }
+
+        let cases = [
+            ("did:plc:x", "does not start with at://"),
+            ("AT://did:plc:x", "does not start with at://"),
+            ("at://", "no authority"),
+            ("at:///app.bsky.feed.post", "no authority"),
+            ("at://?a=b", "no authority"),
+            (
+                "at://did:plc:x/app.bsky.feed.post/3kabc/extra",
+                "too many path segments",
+            ),
+            (
+                "at://did:plc:x/app.bsky.feed.post/3kabc//extra",
+                "too many path segments",
+            ),
+            ("at://did:plc:x/post/3kabc", "at least two segments"),
+            (
+                "at://did:plc:x/1app.bsky.feed.post",
+                "must start with a letter",
+            ),
+        ];
+        for (uri, rule) in cases {
+            match AtUri::parse(uri) {
+                Ok(_) => panic!("{uri} was accepted"),
+                Err(e) => {
+                    let e = e.to_string();
+                    assert!(e.contains(rule), "{uri}: {e:?} should mention {rule:?}");
+                }
+            }
+        }
+    }
+
+    /// the text of a cid with the given version, codec, and hash function, over a made up digest
+    fn cid_text(version: cid::Version, codec: u64, hash: u64) -> String {
+        let hash = cid::multihash::Multihash::wrap(hash, &[7; 32]).unwrap();
+        cid::Cid::new(version, codec, hash).unwrap().to_string()
+    }
+
+    #[test]
+    fn record_cids_must_be_dag_cbor_and_sha2_256() {
+        const RAW: u64 = 0x55;
+        const BLAKE2B_256: u64 = 0xb220;
+        let valid = [
+            "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm".to_owned(),
+            cid_text(cid::Version::V1, DAG_CBOR, SHA2_256),
+        ];
+        for cid in valid {
+            assert!(verify_cid_format(&cid).is_ok(), "{cid} was rejected");
+        }
+
+        let cases = [
+            (
+                // v0 cids are always dag-pb and sha2-256
+                cid_text(cid::Version::V0, 0x70, SHA2_256),
+                "is a CIDv0 rather than a CIDv1",
+            ),
+            (
+                cid_text(cid::Version::V1, RAW, SHA2_256),
+                "has codec 0x55 rather than dag-cbor (0x71)",
+            ),
+            (
+                cid_text(cid::Version::V1, DAG_CBOR, BLAKE2B_256),
+                "has hash 0xb220 rather than sha2-256 (0x12)",
+            ),
+            ("".to_owned(), "doesn't parse"),
+            ("not a cid".to_owned(), "doesn't parse"),
+            (
+                "bafyreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3h".to_owned(),
+                "doesn't parse",
+            ),
+        ];
+        for (cid, rule) in cases {
+            match verify_cid_format(&cid) {
+                Ok(()) => panic!("{cid} was accepted"),
+                Err(e) => {
+                    let e = e.to_string();
+                    assert!(e.contains(rule), "{cid}: {e:?} should mention {rule:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn dids_normalize_scheme_and_method_case_only() {
+        let cases = [
+            // already normalized
+            (
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+            ),
+            ("did:web:example.com", "did:web:example.com"),
+            // the scheme and method are case-insensitive
+            (
+                "DID:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+            ),
+            (
+                "did:PLC:ewvi7nxzyoun6zhxrhs64oiz",
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+            ),
+            ("Did:Web:example.com", "did:web:example.com"),
+            // the method-specific identifier is left alone, since methods may treat its case as
+            // meaningful
+            (
+                "did:PLC:EWVI7NXZYOUN6ZHXRHS64OIZ",
+                "did:plc:EWVI7NXZYOUN6ZHXRHS64OIZ",
+            ),
+            ("did:web:Example.COM", "did:web:Example.COM"),
+            (
+                "did:key:z6MkhaXgBZDvotDkL5257",
+                "did:key:z6MkhaXgBZDvotDkL5257",
+            ),
+            // anything that isn't a did is returned unchanged
+            ("alice.bsky.social", "alice.bsky.social"),
+            ("DID:plc", "DID:plc"),
+            (
+                "at://did:PLC:abc/app.bsky.feed.post/1",
+                "at://did:PLC:abc/app.bsky.feed.post/1",
+            ),
+            ("", ""),
+        ];
+        for (given, expected) in cases {
+            let normalized = normalize_did(given);
+            assert_eq!(normalized, expected, "{given}");
+            // normalizing is idempotent, and only allocates when something changed
+            assert_eq!(normalize_did(&normalized), normalized);
+            assert_eq!(
+                matches!(normalized, Cow::Borrowed(_)),
+                given == expected,
+                "{given}"
+            );
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    atproto::normalize_did,
+    db::{self, now, DateTime},
+};
+use clap::Args;
+use comfy_table::{presets, CellAlignment, ContentArrangement, Table};
+use eyre::{bail, eyre as err, Result};
+use serde::Serialize;
+use std::path::PathBuf;
+
+#[derive(Debug, Args)]
+pub struct CompareCmd {
+    /// Sqlite file previously written with --save-to-db, holding label records from both
+    /// labelers
+    #[arg(long)]
+    db: PathBuf,
+    /// Name of the table of label records to read
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = crate::parse_table_name)]
+    table: String,
+    /// DID of a labeler to compare. Give it twice, once for each labeler
+    #[arg(long = "src", required = true)]
+    srcs: Vec<String>,
+    #[command(flatten)]
+    filter: crate::RecordFilterArgs,
+    /// Compare the labels in effect at this time instead of now, considering only the records
+    /// created before it. Given as an RFC 3339 timestamp or a date like 2024-01-31
+    #[arg(long, value_parser = crate::parse_time)]
+    as_of: Option<DateTime>,
+    /// Also write the comparison to this file as JSON
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+/// The comparison, as written by --json
+#[derive(Serialize)]
+struct CompareOutput<'a> {
+    a: &'a str,
+    b: &'a str,
+    /// the time the labels were in effect at
+    as_of: String,
+    #[serde(flatten)]
+    overlap: &'a db::SrcOverlap,
+}
+
+impl CompareCmd {
+    pub fn go(self) -> Result<()> {
+        let [a, b] = self.srcs.as_slice() else {
+            bail!("expected --src twice, once for each labeler to compare");
+        };
+        let (a, b) = (normalize_did(a), normalize_did(b));
+        if a == b {
+            bail!("can't compare {a} with itself");
+        }
+        if !self.db.is_file() {
+            bail!("no database found at {path}", path = self.db.display());
+        }
+        let db = db::open_for_reading(&self.db, &self.table)?;
+        let (filter, as_of) = match self.as_of {
+            Some(as_of) => (self.filter.to_filter().created_before(as_of), as_of),
+            None => (self.filter.to_filter(), now()),
+        };
+        let overlap = db::compare_srcs(&db, &self.table, &filter, &as_of, &a, &b)?;
+
+        match self.as_of {
+            Some(as_of) => println!(
+                "comparing the labels in effect at {as_of} from",
+                as_of = as_of.to_rfc3339()
+            ),
+            None => println!("comparing the labels currently in effect from"),
+        }
+        println!("   A: {a}");
+        println!("   B: {b}");
+        println!();
+        println!(
+            "targets labeled by both:   {both}",
+            both = overlap.targets_both
+        );
+        println!(
+            "   with the same values:   {agreeing}",
+            agreeing = overlap.targets_agreeing
+        );
+        println!(
+            "   with different values:  {differing}",
+            differing = overlap.targets_both - overlap.targets_agreeing
+        );
+        println!(
+            "targets only A labels:     {only_a}",
+            only_a = overlap.targets_only_a
+        );
+        println!(
+            "targets only B labels:     {only_b}",
+            only_b = overlap.targets_only_b
+        );
+        println!(
+            "on targets labeled by both, {matching} label(s) are applied by both and {mismatching} \
+            by only one",
+            matching = overlap.matching_labels,
+            mismatching = overlap.mismatching_labels,
+        );
+
+        if !overlap.values.is_empty() {
+            println!();
+            println!("label values, by targets each applies them to:");
+            let mut table = Table::new();
+            table
+                .load_preset(presets::ASCII_BORDERS_ONLY_CONDENSED)
+                .set_content_arrangement(ContentArrangement::Dynamic)
+                .set_header(["value", "A", "B", "both", "agreement"]);
+            for value in &overlap.values {
+                table.add_row([
+                    format!("{val:?}", val = value.val),
+                    value.a.to_string(),
+                    value.b.to_string(),
+                    value.both.to_string(),
+                    format!("{agreement:.1}%", agreement = value.agreement),
+                ]);
+            }
+            for column in 1..5 {
+                if let Some(column) = table.column_mut(column) {
+                    column.set_cell_alignment(CellAlignment::Right);
+                }
+            }
+            println!("{table}");
+        }
+        if overlap.targets_both + overlap.targets_only_a + overlap.targets_only_b == 0 {
+            println!(
+                "XX --> neither labeler has labels in effect in {table}",
+                table = self.table
+            );
+        }
+
+        if let Some(path) = &self.json {
+            let output = CompareOutput {
+                a: &a,
+                b: &b,
+                as_of: as_of.to_rfc3339(),
+                overlap: &overlap,
+            };
+            let json = serde_json::to_string_pretty(&output)?;
+            std::fs::write(path, json + "\n").map_err(|e| {
+                err!(
+                    "error writing comparison to {path}: {e}",
+                    path = path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
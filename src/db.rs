@@ -1,6 +1,24 @@
+use crate::atproto::{normalize_did, AtUri, Did};
+use atrium_api::com::atproto::label::defs::LabelData;
+use base64::{prelude::BASE64_STANDARD_NO_PAD, Engine};
+use clap::ValueEnum;
 use eyre::{bail, eyre as err, Result};
-use rusqlite::named_params;
-use std::{borrow::Borrow, path::Path, rc::Rc};
+use rusqlite::{
+    named_params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, Value, ValueRef},
+    OpenFlags, OptionalExtension, ToSql, Transaction, TransactionBehavior,
+};
+use serde::{
+    de::{IgnoredAny, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{
+    borrow::{Borrow, Cow},
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::Path,
+    rc::Rc,
+};
 
 pub use rusqlite::Connection;
 
@@ -16,8 +34,320 @@ pub fn parse_datetime(s: &str) -> Option<DateTime> {
         .map(|d| d.to_utc())
 }
 
-/// Connects to the application's database
-pub fn connect(path: &Path) -> Result<Connection> {
+/// A change to the database schema
+pub struct Migration {
+    pub description: &'static str,
+    sql: &'static str,
+}
+
+/// Every migration of the tables shared by the whole database, in order. A database at schema
+/// version N (its sqlite `user_version`) has had the first N of these applied. Only ever add to the
+/// end of this list.
+const DATABASE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the run_stats table",
+        // databases written before schema versioning already have this table
+        sql: r#"
+            CREATE TABLE IF NOT EXISTS run_stats(
+                run_id INTEGER PRIMARY KEY,
+                labeler_domain TEXT NOT NULL,
+                started_at_timestamp TEXT NOT NULL,
+                finished_at_timestamp TEXT,
+                start_cursor INTEGER NOT NULL,
+                end_cursor INTEGER,
+                labels_received INTEGER,
+                connections INTEGER,
+                retries INTEGER,
+                ended_ok INTEGER,
+                ended_stepped INTEGER,
+                ended_closed INTEGER,
+                ended_websocket_error INTEGER,
+                ended_atproto_error INTEGER
+            );
+        "#,
+    },
+    Migration {
+        description: "record which table of label records each run wrote to",
+        // runs from before this column was added all wrote to label_records
+        sql: r#"
+            ALTER TABLE run_stats ADD COLUMN label_table TEXT NOT NULL DEFAULT 'label_records';
+        "#,
+    },
+    Migration {
+        description: "create the infos table",
+        sql: r#"
+            CREATE TABLE infos(
+                run_id INTEGER NOT NULL REFERENCES run_stats(run_id),
+                received_at_timestamp TEXT NOT NULL,
+                cursor INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                message TEXT
+            );
+        "#,
+    },
+    Migration {
+        description: "record the oldest seq the labeler had available in each run",
+        sql: r#"
+            ALTER TABLE run_stats ADD COLUMN first_available_seq INTEGER;
+        "#,
+    },
+    Migration {
+        description: "remember the dids and endpoints of labelers that have been looked up",
+        sql: r#"
+            CREATE TABLE labeler_identities(
+                handle_or_did TEXT PRIMARY KEY,
+                did TEXT NOT NULL,
+                labeler_domain TEXT NOT NULL,
+                verified_at_timestamp TEXT NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        description: "create the ingest_errors table",
+        sql: r#"
+            CREATE TABLE ingest_errors(
+                run_id INTEGER REFERENCES run_stats(run_id),
+                label_table TEXT NOT NULL,
+                recorded_at_timestamp TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                src TEXT NOT NULL,
+                target_uri TEXT NOT NULL,
+                val TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                detail TEXT NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        description: "record the last error the labeler sent in each run",
+        sql: r#"
+            ALTER TABLE run_stats ADD COLUMN last_atproto_error TEXT;
+            ALTER TABLE run_stats ADD COLUMN last_atproto_error_message TEXT;
+            ALTER TABLE run_stats ADD COLUMN last_atproto_error_cursor INTEGER;
+        "#,
+    },
+    Migration {
+        description: "create the label_definitions table",
+        sql: r#"
+            CREATE TABLE label_definitions(
+                src TEXT NOT NULL,
+                val TEXT NOT NULL,
+                name TEXT,
+                description TEXT,
+                severity TEXT NOT NULL,
+                blurs TEXT NOT NULL,
+                locale TEXT,
+                fetched_at_timestamp TEXT NOT NULL
+            );
+            CREATE INDEX label_definitions_by_value ON label_definitions(src, val);
+        "#,
+    },
+    Migration {
+        description: "record where polling with queryLabels left off in each run",
+        // the queryLabels cursor is opaque, so it is kept apart from the subscription seq cursor
+        sql: r#"
+            ALTER TABLE run_stats ADD COLUMN end_query_cursor TEXT;
+        "#,
+    },
+];
+
+/// Every migration of a table of label records, in order, with `{table}` standing in for the
+/// table's name. A table at schema version N (recorded in the schema_version table) has had the
+/// first N of these applied. Only ever add to the end of this list.
+const LABEL_TABLE_MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the table",
+        // databases written before schema versioning already have the label_records table
+        sql: r#"
+        CREATE TABLE IF NOT EXISTS "{table}"(
+            src TEXT NOT NULL,
+            target_uri TEXT NOT NULL,
+            val TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            create_timestamp TEXT NOT NULL,
+            expiry_timestamp TEXT,
+            neg BOOL NOT NULL,
+            target_cid TEXT,
+            sig BLOB,
+            seen_at_timestamp TEXT NOT NULL
+        );
+    "#,
+    },
+    Migration {
+        description: "record the labeler host each record was streamed from",
+        // records from before this column was added have no record of where they came from
+        sql: r#"
+            ALTER TABLE "{table}" ADD COLUMN fetched_from TEXT;
+        "#,
+    },
+    Migration {
+        description: "index records by label key and seq, to find ones already saved",
+        sql: r#"
+            CREATE INDEX IF NOT EXISTS "{table}_by_key"
+            ON "{table}"(src, target_uri, val, seq);
+        "#,
+    },
+    Migration {
+        description: "record the run and label message each record was received in",
+        // records from before these columns were added only have their seen_at time
+        sql: r#"
+            ALTER TABLE "{table}" ADD COLUMN run_id INTEGER;
+            ALTER TABLE "{table}" ADD COLUMN frame_index INTEGER;
+        "#,
+    },
+    Migration {
+        description: "keep the target as the labeler sent it when it is rewritten",
+        // only set on records whose handle target was resolved to a did with --normalize-targets
+        sql: r#"
+            ALTER TABLE "{table}" ADD COLUMN original_target_uri TEXT;
+        "#,
+    },
+    Migration {
+        description: "index records by create time and by when they were seen, for time ranges",
+        // the create timestamp is indexed as the time it stands for rather than as text, because
+        // that is how queries compare it: records saved with --preserve-timestamps can write the
+        // same time differently. seen_at timestamps are always written the same way
+        sql: r#"
+            CREATE INDEX IF NOT EXISTS "{table}_by_create_time"
+            ON "{table}"(unixepoch(create_timestamp, 'subsec'));
+            CREATE INDEX IF NOT EXISTS "{table}_by_seen_at"
+            ON "{table}"(seen_at_timestamp);
+        "#,
+    },
+    Migration {
+        description: "save records fetched with queryLabels without a seq",
+        // queryLabels doesn't say which seq each label had in the subscription stream. sqlite can't
+        // drop a NOT NULL constraint, so the table is rebuilt with the same columns and indexes
+        sql: r#"
+            ALTER TABLE "{table}" RENAME TO "{table}_rebuilding";
+            DROP INDEX IF EXISTS "{table}_by_key";
+            DROP INDEX IF EXISTS "{table}_by_create_time";
+            DROP INDEX IF EXISTS "{table}_by_seen_at";
+            CREATE TABLE "{table}"(
+                src TEXT NOT NULL,
+                target_uri TEXT NOT NULL,
+                val TEXT NOT NULL,
+                seq INTEGER,
+                create_timestamp TEXT NOT NULL,
+                expiry_timestamp TEXT,
+                neg BOOL NOT NULL,
+                target_cid TEXT,
+                sig BLOB,
+                seen_at_timestamp TEXT NOT NULL,
+                fetched_from TEXT,
+                run_id INTEGER,
+                frame_index INTEGER,
+                original_target_uri TEXT
+            );
+            INSERT INTO "{table}"(
+                rowid, src, target_uri, val, seq,
+                create_timestamp, expiry_timestamp, neg,
+                target_cid, sig, seen_at_timestamp, fetched_from,
+                run_id, frame_index, original_target_uri
+            )
+            SELECT
+                rowid, src, target_uri, val, seq,
+                create_timestamp, expiry_timestamp, neg,
+                target_cid, sig, seen_at_timestamp, fetched_from,
+                run_id, frame_index, original_target_uri
+            FROM "{table}_rebuilding";
+            DROP TABLE "{table}_rebuilding";
+            CREATE INDEX "{table}_by_key"
+            ON "{table}"(src, target_uri, val, seq);
+            CREATE INDEX "{table}_by_create_time"
+            ON "{table}"(unixepoch(create_timestamp, 'subsec'));
+            CREATE INDEX "{table}_by_seen_at"
+            ON "{table}"(seen_at_timestamp);
+        "#,
+    },
+    Migration {
+        description: "record the latest run each record was received in",
+        // a record received again in a later run isn't saved again, but it is still part of what
+        // that run received. records saved before this were only known to their own run
+        sql: r#"
+            ALTER TABLE "{table}" ADD COLUMN last_run_id INTEGER;
+            UPDATE "{table}" SET last_run_id = run_id;
+        "#,
+    },
+    Migration {
+        description: "keep one record for each label key and seq",
+        // records used to be saved without looking for ones already saved, so a key and seq can
+        // have been saved several times. the first saved is kept, as it would be now, and counts as
+        // received in the latest run any of them was
+        sql: r#"
+            UPDATE "{table}" AS kept
+            SET last_run_id = (
+                SELECT MAX(last_run_id) FROM "{table}" AS saved
+                WHERE
+                    saved.src = kept.src AND
+                    saved.target_uri = kept.target_uri AND
+                    saved.val = kept.val AND
+                    saved.seq = kept.seq
+            )
+            WHERE seq IS NOT NULL;
+            DELETE FROM "{table}"
+            WHERE seq IS NOT NULL AND rowid NOT IN (
+                SELECT MIN(rowid) FROM "{table}"
+                WHERE seq IS NOT NULL
+                GROUP BY src, target_uri, val, seq
+            );
+            DROP INDEX IF EXISTS "{table}_by_key";
+            CREATE UNIQUE INDEX "{table}_by_key"
+            ON "{table}"(src, target_uri, val, seq);
+        "#,
+    },
+];
+
+/// The schema versions of the database and of label tables that this binary reads and writes
+pub const DATABASE_SCHEMA_VERSION: usize = DATABASE_MIGRATIONS.len();
+pub const LABEL_TABLE_SCHEMA_VERSION: usize = LABEL_TABLE_MIGRATIONS.len();
+
+/// The tables shared by the whole database, which label tables can't be named after. Every table
+/// the database migrations create must be listed here
+const SHARED_TABLES: &[&str] = &[
+    "schema_version",
+    "run_stats",
+    "infos",
+    "labeler_identities",
+    "ingest_errors",
+    "label_definitions",
+];
+
+/// The table label records are saved in unless another is chosen
+pub const DEFAULT_LABEL_TABLE: &str = "label_records";
+
+/// Checks that a name is usable for a table of label records. Names are limited to ascii letters,
+/// digits, and underscores so they can be safely interpolated into queries.
+pub fn validate_table_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let Some(first) = chars.next() else {
+        bail!("table name must not be empty");
+    };
+    if !(first.is_ascii_alphabetic() || first == '_')
+        || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        bail!(
+            "table name {name:?} must start with a letter or underscore and contain only ascii \
+            letters, digits, and underscores"
+        );
+    }
+    let lowercase = name.to_ascii_lowercase();
+    if lowercase.starts_with("sqlite_") || SHARED_TABLES.contains(&lowercase.as_str()) {
+        bail!("table name {name:?} is reserved");
+    }
+    Ok(())
+}
+
+/// Connects to the application's database, bringing its schema and that of the given table of label
+/// records up to date
+pub fn connect(path: &Path, table: &str) -> Result<Connection> {
+    let db = open(path)?;
+    migrate(&db, table)?;
+    Ok(db)
+}
+
+/// Opens the application's database without changing its schema
+pub fn open(path: &Path) -> Result<Connection> {
     let db = Connection::open(path)?;
     db.set_db_config(
         rusqlite::config::DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY,
@@ -29,17 +359,9 @@ pub fn connect(path: &Path) -> Result<Connection> {
         .map_err(|e| err!("error setting up db connection: {e}"))?;
     db.execute(
         r#"
-        CREATE TABLE IF NOT EXISTS label_records(
-            src TEXT NOT NULL,
-            target_uri TEXT NOT NULL,
-            val TEXT NOT NULL,
-            seq INTEGER NOT NULL,
-            create_timestamp TEXT NOT NULL,
-            expiry_timestamp TEXT,
-            neg BOOL NOT NULL,
-            target_cid TEXT,
-            sig BLOB,
-            seen_at_timestamp TEXT NOT NULL
+        CREATE TABLE IF NOT EXISTS schema_version(
+            table_name TEXT PRIMARY KEY,
+            version INTEGER NOT NULL
         );
         "#,
         [],
@@ -47,9 +369,195 @@ pub fn connect(path: &Path) -> Result<Connection> {
     Ok(db)
 }
 
+/// Opens the application's database without writing anything to it, not even to set it up
+pub fn open_read_only(path: &Path) -> Result<Connection> {
+    Ok(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?)
+}
+
+/// Opens the application's database read-only for commands that only read a table of label records
+/// from it. Its schema is never changed, so a database or table that isn't up to date is refused
+/// with a pointer to `labelview migrate` instead of being upgraded as a side effect of reading it
+pub fn open_for_reading(path: &Path, table: &str) -> Result<Connection> {
+    let db = open_read_only(path)?;
+    let exists: bool = db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = :table);",
+        named_params!(":table": table),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        bail!(
+            "{path} has no table of label records named {table}",
+            path = path.display()
+        );
+    }
+    let pending = pending_migrations(&db, table)?;
+    if pending > 0 {
+        bail!(
+            "{path} was written by an older version of labelview and needs {pending} schema \
+            migration(s) before it can be read; run `labelview migrate --db {path} --table {table}` \
+            to bring it up to date",
+            path = path.display()
+        );
+    }
+    Ok(db)
+}
+
+/// Reads the schema version of the tables shared by the whole database; zero if it is new
+pub fn database_schema_version(db: &Connection) -> Result<usize> {
+    Ok(db.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Reads the schema version of a table of label records; zero if it predates schema versioning or
+/// doesn't exist yet
+pub fn label_table_schema_version(db: &Connection, table: &str) -> Result<usize> {
+    // a database that was never opened for writing doesn't have the schema_version table yet
+    let versioned: bool = db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version');",
+        [],
+        |row| row.get(0),
+    )?;
+    if !versioned {
+        return Ok(0);
+    }
+    let version: Option<usize> = db
+        .query_row(
+            "SELECT version FROM schema_version WHERE table_name = :table;",
+            named_params!(":table": table),
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(version.unwrap_or(0))
+}
+
+/// A migration that was applied to the database
+pub struct AppliedMigration {
+    /// the label table that was migrated, or None for the tables shared by the whole database
+    pub table: Option<String>,
+    pub version: usize,
+    pub description: &'static str,
+}
+
+/// Applies any migrations the database and the given table of label records haven't had yet,
+/// returning the ones that were applied. Fails if either was written by a newer version of
+/// labelview than this one.
+pub fn migrate(db: &Connection, table: &str) -> Result<Vec<AppliedMigration>> {
+    validate_table_name(table)?;
+    let database_version = database_schema_version(db)?;
+    let table_version = label_table_schema_version(db, table)?;
+    check_schema_versions(database_version, table, table_version)?;
+
+    let mut applied = Vec::new();
+    for (migration, version) in DATABASE_MIGRATIONS[database_version..]
+        .iter()
+        .zip(database_version + 1..)
+    {
+        let tx = db.unchecked_transaction()?;
+        tx.execute_batch(migration.sql).map_err(|e| {
+            err!(
+                "error migrating database to schema version {version} ({description}): {e}",
+                description = migration.description,
+            )
+        })?;
+        tx.pragma_update(None, "user_version", version)?;
+        tx.commit()?;
+        applied.push(AppliedMigration {
+            table: None,
+            version,
+            description: migration.description,
+        });
+    }
+    for (migration, version) in LABEL_TABLE_MIGRATIONS[table_version..]
+        .iter()
+        .zip(table_version + 1..)
+    {
+        let tx = db.unchecked_transaction()?;
+        tx.execute_batch(&migration.sql.replace("{table}", table))
+            .map_err(|e| {
+                err!(
+                    "error migrating table {table} to schema version {version} ({description}): \
+                    {e}",
+                    description = migration.description,
+                )
+            })?;
+        tx.execute(
+            r#"
+            INSERT INTO schema_version(table_name, version)
+            VALUES (:table, :version)
+            ON CONFLICT (table_name) DO UPDATE SET version = excluded.version;
+            "#,
+            named_params!(":table": table, ":version": version),
+        )?;
+        tx.commit()?;
+        applied.push(AppliedMigration {
+            table: Some(table.to_owned()),
+            version,
+            description: migration.description,
+        });
+    }
+    Ok(applied)
+}
+
+/// Counts the migrations the database and the given table of label records haven't had yet,
+/// without applying any. Fails like `migrate` if either was written by a newer version of
+/// labelview than this one.
+pub fn pending_migrations(db: &Connection, table: &str) -> Result<usize> {
+    validate_table_name(table)?;
+    let database_version = database_schema_version(db)?;
+    let table_version = label_table_schema_version(db, table)?;
+    check_schema_versions(database_version, table, table_version)?;
+    Ok(DATABASE_SCHEMA_VERSION - database_version + LABEL_TABLE_SCHEMA_VERSION - table_version)
+}
+
+/// fails if the database or table is at a schema version newer than this binary understands
+fn check_schema_versions(database_version: usize, table: &str, table_version: usize) -> Result<()> {
+    if database_version > DATABASE_SCHEMA_VERSION || table_version > LABEL_TABLE_SCHEMA_VERSION {
+        bail!(
+            "this database was written by a newer version of labelview (schema version \
+            {database_version}, table {table} at version {table_version}, but this version only \
+            understands up to {DATABASE_SCHEMA_VERSION} and {LABEL_TABLE_SCHEMA_VERSION}); please \
+            upgrade labelview to use it"
+        );
+    }
+    Ok(())
+}
+
+/// Shares one allocation between copies of strings that repeat across many labels, like the src
+/// did and the label values
+#[derive(Debug, Default)]
+pub struct Interner(HashSet<Rc<str>>);
+
+impl Interner {
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(existing) = self.0.get(s) {
+            return existing.clone();
+        }
+        let interned: Rc<str> = s.into();
+        self.0.insert(interned.clone());
+        interned
+    }
+}
+
+impl ToSql for Did {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.as_str().to_sql()
+    }
+}
+
+impl FromSql for Did {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: eyre::Report| FromSqlError::Other(e.into()))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LabelKey {
-    pub src: Rc<str>,
+    pub src: Did,
     pub target_uri: Rc<str>,
     pub val: Rc<str>,
 }
@@ -57,17 +565,72 @@ pub struct LabelKey {
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LabelDbKey {
     pub key: LabelKey,
-    pub seq: i64,
+    /// the seq of the subscription stream message the record came in, or None for records fetched
+    /// with queryLabels, which doesn't say
+    pub seq: Option<i64>,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// Records order by their effective weight first, so when several records share a key the
+/// greatest one is the one in effect.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LabelRecord {
+    /// the create timestamp and seq of the record; newer records supercede older ones, and the seq
+    /// breaks ties, with records that have no seq losing them. records with unparseable create
+    /// timestamps are treated as the oldest
+    pub effective_weight: (DateTime, Option<i64>),
     pub dbkey: LabelDbKey,
     pub create_timestamp: Rc<str>,
     pub expiry_timestamp: Option<String>,
     pub neg: bool,
     pub target_cid: Option<String>,
     pub sig: Option<Vec<u8>>,
+    /// the target as the labeler sent it, when it named an account by handle and was rewritten to
+    /// name it by did
+    pub original_target_uri: Option<String>,
+}
+
+/// Whether a label with this expiry timestamp has expired at the given time. A label expires at its
+/// expiry time exactly; one without an expiry timestamp, or with one that can't be parsed, never
+/// does. Queries use the same rule through `UNEXPIRED_AT`.
+pub fn is_expired_at(expiry_timestamp: Option<&str>, at: &DateTime) -> bool {
+    expiry_timestamp
+        .and_then(parse_datetime)
+        .is_some_and(|exp| exp <= *at)
+}
+
+/// Condition selecting records whose label hasn't expired at the time bound to `:as_of` (see
+/// `epoch_seconds`), by the same rule as `is_expired_at`
+const UNEXPIRED_AT: &str = "(
+    expiry_timestamp IS NULL OR
+    unixepoch(expiry_timestamp, 'subsec') IS NULL OR
+    unixepoch(expiry_timestamp, 'subsec') > :as_of
+)";
+
+/// A time in the form queries compare with `unixepoch(.., 'subsec')`
+fn epoch_seconds(time: &DateTime) -> f64 {
+    time.timestamp_micros() as f64 / 1e6
+}
+
+/// The form timestamps are normalized to: UTC RFC 3339 to the second, like `2024-01-01T00:00:00Z`.
+/// Fractional seconds are dropped. The same format string works for chrono and sqlite's strftime
+const CANONICAL_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+
+/// A timestamp rewritten in the canonical form, or None if it can't be parsed
+fn canonical_timestamp(time: &str) -> Option<String> {
+    parse_datetime(time).map(|time| time.format(CANONICAL_TIMESTAMP_FORMAT).to_string())
+}
+
+/// Whether two timestamps are the same time once normalized, even if one was normalized and the
+/// other wasn't
+fn same_time(a: &str, b: &str) -> bool {
+    a == b || canonical_timestamp(a).is_some_and(|a| canonical_timestamp(b) == Some(a))
+}
+
+fn effective_weight(create_timestamp: &str, seq: Option<i64>) -> (DateTime, Option<i64>) {
+    (
+        parse_datetime(create_timestamp).unwrap_or(DateTime::MIN_UTC),
+        seq,
+    )
 }
 
 impl Borrow<LabelDbKey> for LabelRecord {
@@ -82,14 +645,305 @@ impl Borrow<LabelKey> for LabelRecord {
     }
 }
 
+/// Formats a cid in its canonical form, as a CIDv1 in lowercase base32, so that different
+/// representations of the same cid compare equal
+pub fn normalize_cid(cid: &cid::Cid) -> String {
+    match cid.into_v1() {
+        Ok(cid) => cid.to_string(),
+        Err(_) => cid.to_string(),
+    }
+}
+
+/// Describes a stream frame that couldn't be decoded: its size, its header, and the start of its body
+fn frame_context(header: &[u8], body: &[u8]) -> String {
+    const BODY_BYTES: usize = 128;
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!(
+        "frame of {size} byte(s), header {header}, body begins {body}{more}",
+        size = header.len() + body.len(),
+        header = hex(header),
+        body = hex(&body[..body.len().min(BODY_BYTES)]),
+        more = if body.len() > BODY_BYTES { "…" } else { "" },
+    )
+}
+
+/// Counts the labels in the body of a subscription stream message without keeping them, so that
+/// huge messages can be turned away before they are decoded. Returns None if the body doesn't look
+/// like a label update
+fn count_labels(body: &[u8]) -> Option<usize> {
+    struct Count(usize);
+
+    impl<'de> Deserialize<'de> for Count {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            struct SeqVisitor;
+            impl<'de> Visitor<'de> for SeqVisitor {
+                type Value = Count;
+
+                fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                    f.write_str("an array")
+                }
+
+                fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Count, A::Error> {
+                    let mut count = 0;
+                    while seq.next_element::<IgnoredAny>()?.is_some() {
+                        count += 1;
+                    }
+                    Ok(Count(count))
+                }
+            }
+            deserializer.deserialize_seq(SeqVisitor)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Body {
+        labels: Count,
+    }
+
+    ciborium::from_reader::<Body, _>(body)
+        .ok()
+        .map(|body| body.labels.0)
+}
+
+/// Counts of the problems in label records that were worked around while decoding them
+#[derive(Debug, Default)]
+pub struct DecodeAnomalies {
+    /// target cids that didn't parse and were dropped from their records
+    pub invalid_cids: usize,
+    /// records whose src isn't a valid did, which were skipped
+    pub invalid_srcs: usize,
+    /// records whose src did had to be normalized, such as by lowercasing its method
+    pub normalized_srcs: usize,
+    /// records whose signature isn't [`SIGNATURE_LENGTH`] bytes long, which were kept as they are
+    pub bad_signatures: usize,
+}
+
+/// Length in bytes of the signatures on label records, for both of the curves atproto supports
+pub const SIGNATURE_LENGTH: usize = 64;
+
+/// A page of labels from com.atproto.label.queryLabels
+///
+/// https://github.com/bluesky-social/atproto/blob/main/lexicons/com/atproto/label/queryLabels.json
+pub struct QueriedLabels {
+    /// cursor to fetch the next page with, if there may be more
+    pub cursor: Option<String>,
+    /// the labels as JSON without their signatures, with each signature decoded beside it
+    labels: Vec<(serde_json::Value, Option<Vec<u8>>)>,
+}
+
+impl QueriedLabels {
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Output {
+            cursor: Option<String>,
+            labels: Vec<serde_json::Map<String, serde_json::Value>>,
+        }
+        let output: Output =
+            serde_json::from_slice(body).map_err(|e| err!("error parsing queried labels: {e}"))?;
+        let labels = output
+            .labels
+            .into_iter()
+            .map(|mut label| {
+                // signatures come as {"$bytes": "<base64>"} in JSON, which doesn't deserialize as
+                // bytes, so they are decoded here and put back after the rest of the label
+                let sig = match label.remove("sig") {
+                    None | Some(serde_json::Value::Null) => None,
+                    Some(sig) => {
+                        let Some(encoded) = sig.get("$bytes").and_then(|bytes| bytes.as_str())
+                        else {
+                            bail!("queried label has a malformed signature: {sig}");
+                        };
+                        Some(
+                            BASE64_STANDARD_NO_PAD
+                                .decode(encoded.trim_end_matches('='))
+                                .map_err(|e| {
+                                    err!("queried label has a malformed signature: {e}")
+                                })?,
+                        )
+                    }
+                };
+                Ok((serde_json::Value::Object(label), sig))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            cursor: output.cursor,
+            labels,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Decodes the labels, which have no seq since queried labels don't say which seq they had in
+    /// the subscription stream. Labels are repaired or skipped the same way as when streaming, and
+    /// counted in `anomalies`
+    pub fn into_records(
+        self,
+        interner: &mut Interner,
+        anomalies: &mut DecodeAnomalies,
+        preserve_timestamps: bool,
+    ) -> Result<Vec<LabelRecord>> {
+        let mut records = Vec::with_capacity(self.labels.len());
+        for (label, sig) in self.labels {
+            let Some(mut label) = decode_queried_label(&label, anomalies)? else {
+                continue;
+            };
+            label.sig = sig;
+            let record = LabelRecord::from_label(label, None, interner, anomalies)?;
+            records.push(if preserve_timestamps {
+                record
+            } else {
+                record.normalize_timestamps()
+            });
+        }
+        Ok(records)
+    }
+}
+
+/// Decodes one label from a page of [`QueriedLabels`]. A label that doesn't decode is repaired the
+/// same way as labels in the subscription stream; if its src isn't a did it is counted in
+/// `anomalies` and skipped
+fn decode_queried_label(
+    label: &serde_json::Value,
+    anomalies: &mut DecodeAnomalies,
+) -> Result<Option<LabelData>> {
+    let e = match LabelData::deserialize(label) {
+        Ok(label) => return Ok(Some(label)),
+        Err(e) => e,
+    };
+    let mut value = ciborium::Value::serialized(label)
+        .map_err(|_| err!("error decoding queried label: {e}"))?;
+    match repair_label(&mut value, anomalies) {
+        None => Ok(None),
+        Some(false) => bail!("error decoding queried label: {e}"),
+        Some(true) => value
+            .deserialized()
+            .map(Some)
+            .map_err(|e| err!("error decoding queried label: {e}")),
+    }
+}
+
+/// Returns a field of a decoded CBOR map
+fn field<'a>(map: &'a mut ciborium::Value, name: &str) -> Option<&'a mut ciborium::Value> {
+    map.as_map_mut()?
+        .iter_mut()
+        .find(|(key, _)| key.as_text() == Some(name))
+        .map(|(_, value)| value)
+}
+
+/// Fixes up the labels in a decoded subscription stream message so that it can be deserialized:
+/// src dids are normalized, labels whose src still isn't a did are removed, and cids that don't
+/// parse are dropped. Returns whether anything was changed
+fn repair_labels(message: &mut ciborium::Value, anomalies: &mut DecodeAnomalies) -> bool {
+    let Some(labels) = field(message, "labels").and_then(|labels| labels.as_array_mut()) else {
+        return false;
+    };
+    let mut changed = false;
+    labels.retain_mut(|label| match repair_label(label, anomalies) {
+        Some(repaired) => {
+            changed |= repaired;
+            true
+        }
+        None => {
+            changed = true;
+            false
+        }
+    });
+    changed
+}
+
+/// Fixes up one decoded label the way [`repair_labels`] does. Returns None if its src isn't a did
+/// and it should be removed, and otherwise whether anything was changed
+fn repair_label(label: &mut ciborium::Value, anomalies: &mut DecodeAnomalies) -> Option<bool> {
+    let mut changed = false;
+    if let Some(src) = field(label, "src") {
+        let normalized = src.as_text().and_then(|text| match normalize_did(text) {
+            Cow::Owned(normalized) => Some(normalized),
+            Cow::Borrowed(_) => None,
+        });
+        if let Some(normalized) = normalized {
+            *src = ciborium::Value::Text(normalized);
+            anomalies.normalized_srcs += 1;
+            changed = true;
+        }
+    }
+    let valid_src = field(label, "src")
+        .and_then(|src| src.as_text())
+        .is_some_and(|src| src.parse::<Did>().is_ok());
+    if !valid_src {
+        anomalies.invalid_srcs += 1;
+        return None;
+    }
+    if let Some(cid) = field(label, "cid") {
+        let valid = cid
+            .as_text()
+            .is_some_and(|text| text.parse::<cid::Cid>().is_ok());
+        if !valid && !cid.is_null() {
+            *cid = ciborium::Value::Null;
+            anomalies.invalid_cids += 1;
+            changed = true;
+        }
+    }
+    Some(changed)
+}
+
 impl LabelRecord {
-    /// Returns the seq and labels from a subscription stream message.
+    /// Returns the seq and labels from the body of a subscription stream frame whose header has
+    /// already been read, along with the number of bytes left over after the body. Target cids that
+    /// aren't valid are dropped from their records, and records whose src isn't a did are skipped;
+    /// both are counted in `anomalies`. Frames with more than `max_labels` labels are refused before
+    /// their labels are decoded. Errors describe the frame they came from, which is why the header's
+    /// bytes are passed too.
     ///
     /// https://atproto.com/specs/label#schema-and-data-model
-    pub fn from_subscription_record(bin: &mut &[u8]) -> Result<(i64, Vec<Self>)> {
-        let labels: atrium_api::com::atproto::label::subscribe_labels::Labels =
-            ciborium::from_reader(bin)
-                .map_err(|e| err!("error decoding label record event stream body: {e}"))?;
+    pub fn from_subscription_record(
+        header: &[u8],
+        body: &[u8],
+        interner: &mut Interner,
+        anomalies: &mut DecodeAnomalies,
+        max_labels: usize,
+        preserve_timestamps: bool,
+    ) -> Result<(i64, Vec<Self>, usize)> {
+        if let Some(count) = count_labels(body).filter(|&count| count > max_labels) {
+            bail!(
+                "refusing a label update of {count} labels, more than the limit of {max_labels}; \
+                {context}",
+                context = frame_context(header, body),
+            );
+        }
+        let mut rest = body;
+        let (seq, mut labels) = Self::decode_labels(&mut rest, interner, anomalies)
+            .map_err(|e| err!("{e}; {context}", context = frame_context(header, body)))?;
+        if !preserve_timestamps {
+            labels = labels.into_iter().map(Self::normalize_timestamps).collect();
+        }
+        Ok((seq, labels, rest.len()))
+    }
+
+    /// Decodes the body of a subscription stream message
+    fn decode_labels(
+        bin: &mut &[u8],
+        interner: &mut Interner,
+        anomalies: &mut DecodeAnomalies,
+    ) -> Result<(i64, Vec<Self>)> {
+        type Labels = atrium_api::com::atproto::label::subscribe_labels::Labels;
+        let original = *bin;
+        let labels: Labels = match ciborium::from_reader(&mut *bin) {
+            Ok(labels) => labels,
+            Err(e) => {
+                // see whether it's only invalid cids or srcs that kept the message from decoding
+                *bin = original;
+                let mut value: ciborium::Value = ciborium::from_reader(&mut *bin)
+                    .map_err(|_| err!("error decoding label record event stream body: {e}"))?;
+                if !repair_labels(&mut value, anomalies) {
+                    bail!("error decoding label record event stream body: {e}");
+                }
+                value
+                    .deserialized()
+                    .map_err(|e| err!("error decoding label record event stream body: {e}"))?
+            }
+        };
         let seq = labels.seq;
         if !(1..i64::MAX).contains(&seq) {
             bail!("non-positive sequence number in label update: {seq}");
@@ -98,61 +952,291 @@ impl LabelRecord {
             .data
             .labels
             .into_iter()
-            .map(|label| {
-                let label = label.data;
-                if label.ver != Some(1) {
-                    let ver = label.ver;
-                    bail!("unsupported or missing label record version {ver:?}");
-                }
-                // TODO(widders): can we check the signature? do we know how
-                Ok(Self {
-                    dbkey: LabelDbKey {
-                        key: LabelKey {
-                            src: label.src.to_string().into(),
-                            target_uri: label.uri.into(),
-                            val: label.val.into(),
-                        },
-                        seq,
-                    },
-                    target_cid: label.cid.map(|cid| cid.as_ref().to_string()),
-                    create_timestamp: label.cts.as_str().into(),
-                    expiry_timestamp: label.exp.map(|exp| exp.as_str().to_owned()),
-                    neg: label.neg.unwrap_or(false),
-                    sig: label.sig,
-                })
-            })
+            .map(|label| Self::from_label(label.data, Some(seq), interner, anomalies))
             .collect::<Result<_>>()
             .map(|labels| (seq, labels))
     }
 
-    pub fn is_expired(&self, now: &DateTime) -> bool {
-        let Some(exp) = &self.expiry_timestamp else {
-            return false;
+    /// Makes a record from a decoded label, which has a seq if it came from the subscription stream
+    fn from_label(
+        label: LabelData,
+        seq: Option<i64>,
+        interner: &mut Interner,
+        anomalies: &mut DecodeAnomalies,
+    ) -> Result<Self> {
+        if label.ver != Some(1) {
+            let ver = label.ver;
+            bail!("unsupported or missing label record version {ver:?}");
+        }
+        let src = Did::try_from(interner.intern(label.src.as_str()))
+            .map_err(|e| err!("invalid src: {e}"))?;
+        if label
+            .sig
+            .as_ref()
+            .is_some_and(|sig| sig.len() != SIGNATURE_LENGTH)
+        {
+            anomalies.bad_signatures += 1;
+        }
+        // TODO(widders): can we check the signature? do we know how
+        Ok(Self {
+            effective_weight: effective_weight(label.cts.as_str(), seq),
+            dbkey: LabelDbKey {
+                key: LabelKey {
+                    src,
+                    target_uri: label.uri.into(),
+                    val: interner.intern(&label.val),
+                },
+                seq,
+            },
+            target_cid: label.cid.map(|cid| normalize_cid(cid.as_ref())),
+            create_timestamp: label.cts.as_str().into(),
+            expiry_timestamp: label.exp.map(|exp| exp.as_str().to_owned()),
+            neg: label.neg.unwrap_or(false),
+            sig: label.sig,
+            original_target_uri: None,
+        })
+    }
+
+    /// Reads a record from a row selecting all the label_records columns except seen_at_timestamp,
+    /// in table order
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        let seq = row.get(3)?;
+        let create_timestamp: String = row.get(4)?;
+        Ok(Self {
+            effective_weight: effective_weight(&create_timestamp, seq),
+            dbkey: LabelDbKey {
+                key: LabelKey {
+                    src: row.get(0)?,
+                    target_uri: row.get::<_, String>(1)?.into(),
+                    val: row.get::<_, String>(2)?.into(),
+                },
+                seq,
+            },
+            create_timestamp: create_timestamp.into(),
+            expiry_timestamp: row.get(5)?,
+            neg: row.get(6)?,
+            target_cid: row.get(7)?,
+            sig: row.get(8)?,
+            original_target_uri: None,
+        })
+    }
+
+    /// Rewrites the create and expiry timestamps in the canonical form, UTC to the second, so that
+    /// timestamps from labelers that write them differently still compare correctly as text.
+    /// Timestamps that can't be parsed are kept as they are. The record no longer carries the exact
+    /// timestamps the labeler signed.
+    pub fn normalize_timestamps(mut self) -> Self {
+        if let Some(create_timestamp) = canonical_timestamp(&self.create_timestamp) {
+            if *create_timestamp != *self.create_timestamp {
+                self.create_timestamp = create_timestamp.into();
+            }
+        }
+        if let Some(expiry_timestamp) = self
+            .expiry_timestamp
+            .as_deref()
+            .and_then(canonical_timestamp)
+        {
+            self.expiry_timestamp = Some(expiry_timestamp);
+        }
+        self
+    }
+
+    /// whether the label has expired at the given time
+    pub fn is_expired(&self, at: &DateTime) -> bool {
+        is_expired_at(self.expiry_timestamp.as_deref(), at)
+    }
+
+    /// whether the label's src is labeling its own account
+    pub fn is_self_label(&self) -> bool {
+        *self.dbkey.key.target_uri == *self.dbkey.key.src.as_str()
+    }
+
+    /// whether the label targets a specific record, named by an at-uri with a collection and rkey,
+    /// without including the cid that pins it to one version of that record
+    pub fn is_unpinned_record_label(&self) -> bool {
+        self.target_cid.is_none()
+            && AtUri::parse(&self.dbkey.key.target_uri)
+                .is_ok_and(|uri| uri.collection.is_some() && uri.rkey.is_some())
+    }
+
+    /// tries to insert the record, as streamed from the given labeler host. A record with the same
+    /// key and seq as one already in the table is not inserted again; if it differs from the
+    /// record already there, it replaces it when `replace_conflicting` is set. A record without a
+    /// seq is only left out if an identical record with the same key was already saved, with or
+    /// without a seq, and otherwise never conflicts with one
+    pub fn insert(
+        &self,
+        db: &Connection,
+        table: &str,
+        fetched_from: &str,
+        receipt: &Receipt,
+        replace_conflicting: bool,
+    ) -> Result<InsertOutcome> {
+        let Some(seq) = self.dbkey.seq else {
+            return self.insert_without_seq(db, table, fetched_from, receipt);
         };
-        let Some(exp) = parse_datetime(exp) else {
-            return false;
+        // the table's unique index on the key and seq turns away a record that was already saved,
+        // so the saved one is only read when that happens
+        if self.insert_row(db, table, fetched_from, receipt)? {
+            return Ok(InsertOutcome::Inserted);
+        }
+        let (rowid, differing) = db
+            .prepare_cached(&format!(
+                r#"
+                SELECT rowid, create_timestamp, expiry_timestamp, neg, target_cid, sig
+                FROM "{table}"
+                WHERE src = :src AND target_uri = :uri AND val = :val AND seq = :seq;
+                "#,
+            ))?
+            .query_row(
+                named_params!(
+                    ":src": &self.dbkey.key.src,
+                    ":uri": &self.dbkey.key.target_uri,
+                    ":val": &self.dbkey.key.val,
+                    ":seq": seq,
+                ),
+                |row| Ok((row.get::<_, i64>(0)?, self.differences(row)?)),
+            )?;
+        if differing.is_empty() {
+            mark_received(db, table, rowid, receipt)?;
+            return Ok(InsertOutcome::AlreadyPresent);
+        }
+        let conflict = InsertConflict {
+            key: self.dbkey.key.clone(),
+            seq,
+            differing,
         };
-        exp > *now
+        if !replace_conflicting {
+            // the saved record stands for this one in what the run received
+            mark_received(db, table, rowid, receipt)?;
+            return Ok(InsertOutcome::Conflict(conflict));
+        }
+        self.replace_row(db, table, rowid, fetched_from, receipt)?;
+        Ok(InsertOutcome::Replaced(conflict))
     }
 
-    /// tries to insert the record, returning true if it was inserted and false if there was a key
-    /// conflict
-    pub fn insert(&self, db: &Connection, now: &DateTime) -> Result<()> {
-        let mut stmt = db.prepare_cached(
+    /// inserts a record without a seq unless an identical one with the same key is already saved.
+    /// that can't be left to an index, since the saved record may have a seq, so the key's saved
+    /// records are read first
+    fn insert_without_seq(
+        &self,
+        db: &Connection,
+        table: &str,
+        fetched_from: &str,
+        receipt: &Receipt,
+    ) -> Result<InsertOutcome> {
+        let mut existing = db.prepare_cached(&format!(
             r#"
-            INSERT INTO label_records(
+            SELECT rowid, create_timestamp, expiry_timestamp, neg, target_cid, sig
+            FROM "{table}"
+            WHERE src = :src AND target_uri = :uri AND val = :val;
+            "#,
+        ))?;
+        let mut rows = existing.query(named_params!(
+            ":src": &self.dbkey.key.src,
+            ":uri": &self.dbkey.key.target_uri,
+            ":val": &self.dbkey.key.val,
+        ))?;
+        while let Some(row) = rows.next()? {
+            if self.differences(row)?.is_empty() {
+                mark_received(db, table, row.get(0)?, receipt)?;
+                return Ok(InsertOutcome::AlreadyPresent);
+            }
+        }
+        self.insert_row(db, table, fetched_from, receipt)?;
+        Ok(InsertOutcome::Inserted)
+    }
+
+    /// names of the fields in which a saved record differs from this one, as the labeler sends
+    /// them. the row holds the saved record's create_timestamp, expiry_timestamp, neg, target_cid
+    /// and sig, after its rowid
+    fn differences(&self, row: &rusqlite::Row) -> rusqlite::Result<Vec<&'static str>> {
+        let cts: String = row.get(1)?;
+        let exp: Option<String> = row.get(2)?;
+        let neg: bool = row.get(3)?;
+        let cid: Option<String> = row.get(4)?;
+        let sig: Option<Vec<u8>> = row.get(5)?;
+        Ok([
+            ("cts", !same_time(&cts, &self.create_timestamp)),
+            (
+                "exp",
+                match (&exp, &self.expiry_timestamp) {
+                    (Some(saved), Some(exp)) => !same_time(saved, exp),
+                    (saved, exp) => saved != exp,
+                },
+            ),
+            ("neg", neg != self.neg),
+            ("cid", cid != self.target_cid),
+            ("sig", sig != self.sig),
+        ]
+        .into_iter()
+        .filter_map(|(field, differs)| differs.then_some(field))
+        .collect())
+    }
+
+    /// overwrites the saved record in the given row, which has the same key and seq, with this one
+    fn replace_row(
+        &self,
+        db: &Connection,
+        table: &str,
+        rowid: i64,
+        fetched_from: &str,
+        receipt: &Receipt,
+    ) -> Result<()> {
+        db.prepare_cached(&format!(
+            r#"
+            UPDATE "{table}"
+            SET
+                create_timestamp = :cts, expiry_timestamp = :exp, neg = :neg,
+                target_cid = :cid, sig = :sig, seen_at_timestamp = :last_seen,
+                fetched_from = :fetched_from, run_id = :run_id, frame_index = :frame_index,
+                original_target_uri = :original_uri, last_run_id = :run_id
+            WHERE rowid = :rowid;
+            "#,
+        ))?
+        .execute(named_params!(
+            ":rowid": rowid,
+            ":cts": &self.create_timestamp,
+            ":exp": &self.expiry_timestamp,
+            ":neg": &self.neg,
+            ":cid": &self.target_cid,
+            ":sig": &self.sig,
+            ":last_seen": &receipt.seen_at,
+            ":fetched_from": fetched_from,
+            ":run_id": &receipt.run_id,
+            ":frame_index": &receipt.frame_index,
+            ":original_uri": &self.original_target_uri,
+        ))?;
+        Ok(())
+    }
+
+    /// inserts the record as a new row, returning whether it was inserted or turned away because
+    /// a record with the same key and seq is already saved
+    fn insert_row(
+        &self,
+        db: &Connection,
+        table: &str,
+        fetched_from: &str,
+        receipt: &Receipt,
+    ) -> Result<bool> {
+        let mut stmt = db.prepare_cached(&format!(
+            r#"
+            INSERT INTO "{table}"(
                 src, target_uri, val, seq,
                 create_timestamp, expiry_timestamp, neg,
-                target_cid, sig, seen_at_timestamp
+                target_cid, sig, seen_at_timestamp, fetched_from,
+                run_id, frame_index, original_target_uri, last_run_id
             )
             VALUES (
                 :src, :uri, :val, :seq,
                 :cts, :exp, :neg,
-                :cid, :sig, :last_seen
-            );
+                :cid, :sig, :last_seen, :fetched_from,
+                :run_id, :frame_index, :original_uri, :run_id
+            )
+            ON CONFLICT DO NOTHING;
             "#,
-        )?;
-        stmt.execute(named_params!(
+        ))?;
+        let inserted = stmt.execute(named_params!(
             ":src": &self.dbkey.key.src,
             ":uri": &self.dbkey.key.target_uri,
             ":val": &self.dbkey.key.val,
@@ -162,8 +1246,2351 @@ impl LabelRecord {
             ":neg": &self.neg,
             ":cid": &self.target_cid,
             ":sig": &self.sig,
-            ":last_seen": now,
+            ":last_seen": &receipt.seen_at,
+            ":fetched_from": fetched_from,
+            ":run_id": &receipt.run_id,
+            ":frame_index": &receipt.frame_index,
+            ":original_uri": &self.original_target_uri,
         ))?;
-        Ok(())
+        Ok(inserted > 0)
+    }
+}
+
+/// Notes that the saved record in the given row was received again in the receipt's run, so that it
+/// counts toward what that run received even though it isn't saved again
+fn mark_received(db: &Connection, table: &str, rowid: i64, receipt: &Receipt) -> Result<()> {
+    if receipt.run_id.is_some() {
+        db.prepare_cached(&format!(
+            r#"UPDATE "{table}" SET last_run_id = :run_id WHERE rowid = :rowid;"#
+        ))?
+        .execute(named_params!(":run_id": receipt.run_id, ":rowid": rowid))?;
+    }
+    Ok(())
+}
+
+/// When a label record was received. The seen_at time comes from the wall clock, so it can jump
+/// around; the index of the label message (or page of queried labels) it came in within its run
+/// only ever increases, so it is what orders records received in the same run.
+pub struct Receipt {
+    pub seen_at: DateTime,
+    pub run_id: Option<i64>,
+    pub frame_index: Option<i64>,
+}
+
+/// What happened when inserting a label record
+pub enum InsertOutcome {
+    Inserted,
+    /// the table already has an identical record
+    AlreadyPresent,
+    /// the table has a different record with the same key and seq, which was kept
+    Conflict(InsertConflict),
+    /// the table had a different record with the same key and seq, which was replaced
+    Replaced(InsertConflict),
+}
+
+/// A label record received with the same key and seq as one already saved, but different contents
+#[derive(Debug)]
+pub struct InsertConflict {
+    pub key: LabelKey,
+    pub seq: i64,
+    /// names of the fields that differ from the saved record, as the labeler sends them
+    pub differing: Vec<&'static str>,
+}
+
+impl fmt::Display for InsertConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "label {val:?} from {src} on {uri} at seq {seq} differs from the saved record in: \
+            {fields}",
+            val = self.key.val,
+            src = self.key.src,
+            uri = self.key.target_uri,
+            seq = self.seq,
+            fields = self.differing.join(", "),
+        )
+    }
+}
+
+/// How to handle a label record that conflicts with one already saved
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the record already saved and note the conflict
+    KeepOld,
+    /// Replace the saved record with the new one and note the conflict
+    KeepNew,
+    /// Stop with an error
+    Error,
+}
+
+/// Begins inserting many records, streamed from the given labeler host during the given run, into a
+/// table of label records in a single write transaction, which is taken immediately so that it
+/// can't fail partway through for want of the write lock
+pub fn insert_batch_tx<'conn>(
+    conn: &'conn mut Connection,
+    table: &'conn str,
+    fetched_from: &'conn str,
+    run_id: Option<i64>,
+    conflict_policy: ConflictPolicy,
+) -> Result<BatchInserter<'conn>> {
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    Ok(BatchInserter {
+        tx,
+        table,
+        fetched_from,
+        run_id,
+        conflict_policy,
+        inserted: 0,
+    })
+}
+
+/// An open transaction inserting records into a table of label records. Dropping it without
+/// committing rolls back everything it inserted.
+pub struct BatchInserter<'conn> {
+    tx: Transaction<'conn>,
+    table: &'conn str,
+    fetched_from: &'conn str,
+    run_id: Option<i64>,
+    conflict_policy: ConflictPolicy,
+    /// number of records inserted so far, not counting those already present or conflicting
+    inserted: usize,
+}
+
+impl BatchInserter<'_> {
+    /// inserts the record, seen at the given time in the given label message of the run, unless
+    /// the table already has it. Returns the conflict if the table has a different record with the
+    /// same key and seq; with `ConflictPolicy::Error` the new record is left out and it's up to the
+    /// caller to stop
+    pub fn insert(
+        &mut self,
+        label: &LabelRecord,
+        seen_at: &DateTime,
+        frame_index: Option<i64>,
+    ) -> Result<Option<InsertConflict>> {
+        let replace = matches!(self.conflict_policy, ConflictPolicy::KeepNew);
+        let receipt = Receipt {
+            seen_at: *seen_at,
+            run_id: self.run_id,
+            frame_index,
+        };
+        Ok(
+            match label.insert(&self.tx, self.table, self.fetched_from, &receipt, replace)? {
+                InsertOutcome::Inserted => {
+                    self.inserted += 1;
+                    None
+                }
+                InsertOutcome::AlreadyPresent => None,
+                InsertOutcome::Conflict(conflict) | InsertOutcome::Replaced(conflict) => {
+                    Some(conflict)
+                }
+            },
+        )
+    }
+
+    /// commits the inserted records, returning how many there were
+    pub fn commit(self) -> Result<usize> {
+        self.tx.commit()?;
+        Ok(self.inserted)
+    }
+
+    pub fn rollback(self) -> Result<()> {
+        Ok(self.tx.rollback()?)
+    }
+}
+
+/// Condition selecting the records saved in run `:run_id`. Records saved before runs were recorded
+/// on them are in it if they were seen at or after `:since`, when the run started.
+const IN_RUN: &str = "(run_id = :run_id OR (run_id IS NULL AND seen_at_timestamp >= :since))";
+
+/// Condition selecting the records received in run `:run_id`, whether they were saved in it or had
+/// already been saved before, with the same allowance as [`IN_RUN`] for records without runs
+const RECEIVED_IN_RUN: &str =
+    "(last_run_id = :run_id OR (last_run_id IS NULL AND seen_at_timestamp >= :since))";
+
+/// Whether a latest record is effective and the first effective one passed on for its target and
+/// for its src, so the distinct targets and srcs of effective labels can be counted in the same pass
+#[derive(Debug, Clone, Copy)]
+pub struct FirstOf {
+    pub target: bool,
+    pub src: bool,
+}
+
+/// Calls `f` with the most recent saved record for each label key that the given run received a
+/// record for, or, for records saved before runs were recorded on them, that was seen at or after
+/// `since`. Records saved in earlier runs count toward which is most recent, so a label negated
+/// before is still negated if the run receives it again. Records are streamed from the database
+/// rather than collected, in a single query, along with whether each is the first effective one at
+/// `as_of` for its target and its src.
+pub fn for_each_latest_label(
+    db: &Connection,
+    table: &str,
+    run_id: Option<i64>,
+    since: &DateTime,
+    as_of: &DateTime,
+    mut f: impl FnMut(LabelRecord, FirstOf),
+) -> Result<()> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT
+            src, target_uri, val, seq,
+            create_timestamp, expiry_timestamp, neg,
+            target_cid, sig,
+            effective AND row_number() OVER (PARTITION BY target_uri, effective) = 1,
+            effective AND row_number() OVER (PARTITION BY src, effective) = 1
+        FROM (
+            SELECT
+                *,
+                NOT neg AND {UNEXPIRED_AT} AS effective,
+                row_number() OVER (
+                    PARTITION BY src, target_uri, val
+                    ORDER BY unixepoch(create_timestamp, 'subsec') DESC NULLS LAST, seq DESC
+                ) AS recency
+            FROM "{table}"
+            WHERE (src, target_uri, val) IN (
+                SELECT src, target_uri, val FROM "{table}" WHERE {RECEIVED_IN_RUN}
+            )
+        )
+        WHERE recency = 1;
+        "#,
+    ))?;
+    let mut rows = stmt.query(named_params!(
+        ":run_id": run_id,
+        ":since": since,
+        ":as_of": epoch_seconds(as_of),
+    ))?;
+    while let Some(row) = rows.next()? {
+        let first = FirstOf {
+            target: row.get(9)?,
+            src: row.get(10)?,
+        };
+        f(LabelRecord::from_row(row)?, first);
+    }
+    Ok(())
+}
+
+/// Restricts which label records are read from a table
+#[derive(Debug, Default, Clone)]
+pub struct RecordFilter {
+    /// only records created at or after this time
+    pub since: Option<DateTime>,
+    /// only records created before this time
+    pub until: Option<DateTime>,
+    /// only records with at least this seq
+    pub first_seq: Option<i64>,
+    /// only records with at most this seq
+    pub last_seq: Option<i64>,
+    /// only records whose src labels its own account
+    pub self_labels: bool,
+    /// only records labeling this account or one of its records
+    pub target_did: Option<String>,
+    /// only records from this src
+    pub src: Option<String>,
+}
+
+impl RecordFilter {
+    /// the records of the table that pass the filter, as something to select from. queries using
+    /// it must bind `params()`
+    fn source(&self, table: &str) -> String {
+        let mut conditions = Vec::new();
+        if self.since.is_some() {
+            conditions.push("unixepoch(create_timestamp, 'subsec') >= :since");
+        }
+        if self.until.is_some() {
+            conditions.push("unixepoch(create_timestamp, 'subsec') < :until");
+        }
+        if self.first_seq.is_some() {
+            conditions.push("seq >= :first_seq");
+        }
+        if self.last_seq.is_some() {
+            conditions.push("seq <= :last_seq");
+        }
+        if self.self_labels {
+            conditions.push("target_uri = src");
+        }
+        if self.target_did.is_some() {
+            conditions.push(
+                "(target_uri = :target_did OR \
+                substr(target_uri, 1, length(:target_records)) = :target_records)",
+            );
+        }
+        if self.src.is_some() {
+            conditions.push("src = :src");
+        }
+        if conditions.is_empty() {
+            format!(r#""{table}""#)
+        } else {
+            format!(
+                r#"(SELECT rowid, * FROM "{table}" WHERE {conditions})"#,
+                conditions = conditions.join(" AND ")
+            )
+        }
+    }
+
+    /// narrows the filter to records created before `time`, to find the labels in effect then
+    pub fn created_before(mut self, time: DateTime) -> Self {
+        self.until = Some(self.until.map_or(time, |until| until.min(time)));
+        self
+    }
+
+    /// the parameters used by `source()`
+    fn params(&self) -> Vec<(&'static str, Value)> {
+        let epoch = |time: &DateTime| Value::Real(epoch_seconds(time));
+        let mut params = Vec::new();
+        if let Some(since) = &self.since {
+            params.push((":since", epoch(since)));
+        }
+        if let Some(until) = &self.until {
+            params.push((":until", epoch(until)));
+        }
+        if let Some(first_seq) = self.first_seq {
+            params.push((":first_seq", Value::Integer(first_seq)));
+        }
+        if let Some(last_seq) = self.last_seq {
+            params.push((":last_seq", Value::Integer(last_seq)));
+        }
+        if let Some(did) = &self.target_did {
+            params.push((":target_did", Value::Text(did.clone())));
+            params.push((":target_records", Value::Text(format!("at://{did}/"))));
+        }
+        if let Some(src) = &self.src {
+            params.push((":src", Value::Text(src.clone())));
+        }
+        params
+    }
+}
+
+/// borrows named parameters in the form rusqlite binds them from
+fn bind<'a>(params: &'a [(&'static str, Value)]) -> Vec<(&'static str, &'a dyn ToSql)> {
+    params
+        .iter()
+        .map(|(name, value)| (*name, value as &dyn ToSql))
+        .collect()
+}
+
+/// A label record as saved in the database, along with where and when it was received
+pub struct SavedRecord {
+    pub record: LabelRecord,
+    pub seen_at_timestamp: String,
+    pub fetched_from: Option<String>,
+    pub run_id: Option<i64>,
+    pub frame_index: Option<i64>,
+}
+
+/// Calls `f` with every record in a table of label records, in seq order, stopping at the first
+/// error it returns
+pub fn for_each_record(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+    mut f: impl FnMut(SavedRecord) -> Result<()>,
+) -> Result<()> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT
+            src, target_uri, val, seq,
+            create_timestamp, expiry_timestamp, neg,
+            target_cid, sig, seen_at_timestamp, fetched_from,
+            run_id, frame_index
+        FROM {source}
+        ORDER BY seq, rowid;
+        "#,
+        source = filter.source(table),
+    ))?;
+    let params = filter.params();
+    let mut rows = stmt.query(bind(&params).as_slice())?;
+    while let Some(row) = rows.next()? {
+        f(SavedRecord {
+            record: LabelRecord::from_row(row)?,
+            seen_at_timestamp: row.get(9)?,
+            fetched_from: row.get(10)?,
+            run_id: row.get(11)?,
+            frame_index: row.get(12)?,
+        })?;
+    }
+    Ok(())
+}
+
+/// Finds the greatest seq in a table of label records, if it has any
+pub fn max_seq(db: &Connection, table: &str, filter: &RecordFilter) -> Result<Option<i64>> {
+    Ok(db.query_row(
+        &format!(
+            "SELECT MAX(seq) FROM {source};",
+            source = filter.source(table)
+        ),
+        bind(&filter.params()).as_slice(),
+        |row| row.get(0),
+    )?)
+}
+
+/// Returns the least and greatest seq of the label records, if there are any
+pub fn get_seq_range(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+) -> Result<Option<(i64, i64)>> {
+    let range: (Option<i64>, Option<i64>) = db.query_row(
+        &format!(
+            "SELECT MIN(seq), MAX(seq) FROM {source};",
+            source = filter.source(table)
+        ),
+        bind(&filter.params()).as_slice(),
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(range.0.zip(range.1))
+}
+
+/// Counts the distinct seqs of the label records. When this is less than the size of the range
+/// from [`get_seq_range`], some seqs in between are missing. Seqs only run contiguously within one
+/// src's records, and only among all of them, so gaps should be looked for with a filter that
+/// selects just a src
+pub fn count_distinct_seqs(db: &Connection, table: &str, filter: &RecordFilter) -> Result<u64> {
+    Ok(db.query_row(
+        &format!(
+            "SELECT COUNT(DISTINCT seq) FROM {source};",
+            source = filter.source(table)
+        ),
+        bind(&filter.params()).as_slice(),
+        |row| row.get(0),
+    )?)
+}
+
+/// Lists every src did that has label records in the database
+pub fn get_distinct_src_dids(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+) -> Result<Vec<String>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT DISTINCT src FROM {source} ORDER BY src;",
+        source = filter.source(table),
+    ))?;
+    let dids = stmt
+        .query_map(bind(&filter.params()).as_slice(), |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(dids)
+}
+
+/// Counts the distinct values of one of the columns of a table of label records
+pub fn count_distinct(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+    column: &str,
+) -> Result<usize> {
+    const COLUMNS: &[&str] = &[
+        "src",
+        "target_uri",
+        "val",
+        "seq",
+        "create_timestamp",
+        "expiry_timestamp",
+        "neg",
+        "target_cid",
+        "sig",
+        "seen_at_timestamp",
+        "fetched_from",
+        "run_id",
+        "frame_index",
+    ];
+    if !COLUMNS.contains(&column) {
+        bail!("{column:?} is not a column of label records");
+    }
+    Ok(db.query_row(
+        &format!(
+            r#"SELECT COUNT(DISTINCT "{column}") FROM {source};"#,
+            source = filter.source(table),
+        ),
+        bind(&filter.params()).as_slice(),
+        |row| row.get(0),
+    )?)
+}
+
+/// Counts the label records from one src for each label value
+pub fn count_vals_from_src(
+    db: &Connection,
+    table: &str,
+    src: &str,
+) -> Result<BTreeMap<String, usize>> {
+    let mut stmt = db.prepare(&format!(
+        r#"SELECT val, COUNT(*) FROM "{table}" WHERE src = :src GROUP BY val;"#
+    ))?;
+    let counts = stmt
+        .query_map(named_params!(":src": src), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(counts)
+}
+
+/// Rewrites the create timestamps of a table's label records that aren't in the canonical form, UTC
+/// to the second, such as ones with fractional seconds or a timezone offset, so they all sort
+/// correctly as text. This is the same form `LabelRecord::normalize_timestamps` gives new records.
+/// Timestamps that can't be parsed are left alone. Returns how many records were changed.
+///
+/// The rewritten timestamps no longer match the ones the labeler signed, so the record's original
+/// bytes can't be reconstructed from them to check its signature.
+pub fn compact_timestamps(db: &Connection, table: &str) -> Result<usize> {
+    let changed = db.execute(
+        &format!(
+            r#"
+            UPDATE "{table}"
+            SET create_timestamp = strftime(:format, create_timestamp)
+            WHERE
+                strftime(:format, create_timestamp) IS NOT NULL AND
+                strftime(:format, create_timestamp) != create_timestamp;
+            "#
+        ),
+        named_params!(":format": CANONICAL_TIMESTAMP_FORMAT),
+    )?;
+    Ok(changed)
+}
+
+/// Runs sqlite's own check of the database file, returning the problems it finds
+pub fn integrity_check(db: &Connection) -> Result<Vec<String>> {
+    let mut stmt = db.prepare("PRAGMA integrity_check;")?;
+    let problems = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .filter(|message| !matches!(message.as_deref(), Ok("ok")))
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(problems)
+}
+
+/// A seq that more than one record from the same src was saved with
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateSeq {
+    pub seq: i64,
+    pub src: String,
+    /// how many records from the src have this seq
+    pub count: usize,
+}
+
+/// Finds seqs that several records from the same src were saved with, most duplicated first.
+/// These shouldn't happen, but can come from bugs or from importing records by hand, and make
+/// queries that assume each src's seqs are unique give wrong results
+pub fn find_duplicate_seqs(db: &Connection, table: &str) -> Result<Vec<DuplicateSeq>> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT seq, src, COUNT(*) AS c
+        FROM "{table}"
+        WHERE seq IS NOT NULL
+        GROUP BY seq, src
+        HAVING c > 1
+        ORDER BY c DESC, seq, src;
+        "#
+    ))?;
+    let duplicates = stmt
+        .query_map([], |row| {
+            Ok(DuplicateSeq {
+                seq: row.get(0)?,
+                src: row.get(1)?,
+                count: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(duplicates)
+}
+
+/// A label key that has more than one record saved with the same seq
+#[derive(Debug, PartialEq, Eq)]
+pub struct DuplicateKeySeq {
+    pub seq: i64,
+    pub src: String,
+    pub target_uri: String,
+    pub val: String,
+    /// how many records have this key and seq
+    pub count: usize,
+}
+
+/// Finds label keys with several records saved at the same seq, most duplicated first. Records
+/// are only saved once per key and seq, so these are the same record saved more than once, which
+/// counts it twice wherever records are counted
+pub fn find_duplicate_key_seqs(db: &Connection, table: &str) -> Result<Vec<DuplicateKeySeq>> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT seq, src, target_uri, val, COUNT(*) AS c
+        FROM "{table}"
+        WHERE seq IS NOT NULL
+        GROUP BY src, target_uri, val, seq
+        HAVING c > 1
+        ORDER BY c DESC, seq, src, target_uri, val;
+        "#
+    ))?;
+    let duplicates = stmt
+        .query_map([], |row| {
+            Ok(DuplicateKeySeq {
+                seq: row.get(0)?,
+                src: row.get(1)?,
+                target_uri: row.get(2)?,
+                val: row.get(3)?,
+                count: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(duplicates)
+}
+
+/// Counts the records whose create or expiry timestamp sqlite can't read as a time, which leaves
+/// them out of the ordering used to tell which labels are in effect
+pub fn count_unreadable_timestamps(db: &Connection, table: &str) -> Result<usize> {
+    Ok(db.query_row(
+        &format!(
+            r#"
+            SELECT COUNT(*) FROM "{table}"
+            WHERE
+                unixepoch(create_timestamp) IS NULL OR
+                (expiry_timestamp IS NOT NULL AND unixepoch(expiry_timestamp) IS NULL);
+            "#
+        ),
+        [],
+        |row| row.get(0),
+    )?)
+}
+
+/// What was removed by pruning expired records
+pub struct PrunedExpired {
+    /// expired records that a later record with the same key had already replaced
+    pub superseded: usize,
+    /// label keys whose latest record had expired, all of whose records were removed
+    pub expired_labels: usize,
+    /// records removed along with those label keys
+    pub expired_label_records: usize,
+}
+
+/// Removes label records that had expired by the cutoff, but only where that can't change which
+/// labels are in effect: records that a later one with the same key replaced, and every record of
+/// a label whose latest record expired. Removing only the latest record of a label would bring an
+/// earlier one, perhaps one that never expires, back into effect.
+///
+/// Records the labeler sent still go missing, so seq ranges in the table get gaps and counts of how
+/// often values were applied and negated shrink.
+pub fn prune_expired(db: &mut Connection, table: &str, cutoff: &DateTime) -> Result<PrunedExpired> {
+    let ranked = format!(
+        r#"
+        WITH ranked AS (
+            SELECT
+                rowid AS id, src, target_uri, val,
+                NOT {UNEXPIRED_AT} AS expired,
+                row_number() OVER (
+                    PARTITION BY src, target_uri, val
+                    ORDER BY unixepoch(create_timestamp, 'subsec') DESC NULLS LAST, seq DESC
+                ) AS recency
+            FROM "{table}"
+        )
+        "#
+    );
+    let params = named_params!(":as_of": epoch_seconds(cutoff));
+    let tx = db.transaction()?;
+    let superseded = tx.execute(
+        &format!(
+            r#"
+            {ranked}
+            DELETE FROM "{table}"
+            WHERE rowid IN (SELECT id FROM ranked WHERE recency > 1 AND expired);
+            "#
+        ),
+        params,
+    )?;
+    let expired_labels = tx.query_row(
+        &format!("{ranked} SELECT COUNT(*) FROM ranked WHERE recency = 1 AND expired;"),
+        params,
+        |row| row.get(0),
+    )?;
+    let expired_label_records = tx.execute(
+        &format!(
+            r#"
+            {ranked}
+            DELETE FROM "{table}"
+            WHERE (src, target_uri, val) IN (
+                SELECT src, target_uri, val FROM ranked WHERE recency = 1 AND expired
+            );
+            "#
+        ),
+        params,
+    )?;
+    tx.commit()?;
+    Ok(PrunedExpired {
+        superseded,
+        expired_labels,
+        expired_label_records,
+    })
+}
+
+/// Rebuilds the database file to give back the space freed by removing records
+pub fn vacuum(db: &Connection) -> Result<()> {
+    db.execute_batch("VACUUM;")?;
+    Ok(())
+}
+
+/// How much a label value has been used by one src
+#[derive(Debug)]
+pub struct ValueUsage {
+    pub src: String,
+    pub val: String,
+    /// records applying the label
+    pub applied: usize,
+    /// records negating the label
+    pub negated: usize,
+    /// targets the label currently applies to
+    pub effective: usize,
+}
+
+/// Counts the records applying and negating each label value from each src, and how many targets
+/// each is in effect for at `as_of`. Records saved more than once by different runs are only
+/// counted once.
+pub fn get_value_usage(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+    as_of: &DateTime,
+) -> Result<Vec<ValueUsage>> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        WITH records AS (
+            SELECT DISTINCT src, target_uri, val, seq, create_timestamp, expiry_timestamp, neg
+            FROM {source}
+        ),
+        latest AS (
+            SELECT
+                *,
+                row_number() OVER (
+                    PARTITION BY src, target_uri, val
+                    ORDER BY unixepoch(create_timestamp, 'subsec') DESC NULLS LAST, seq DESC
+                ) AS recency
+            FROM records
+        ),
+        effective AS (
+            SELECT src, val, COUNT(*) AS effective
+            FROM latest
+            WHERE
+                recency = 1 AND
+                NOT neg AND
+                {UNEXPIRED_AT}
+            GROUP BY src, val
+        )
+        SELECT r.src, r.val, SUM(NOT r.neg), SUM(r.neg), COALESCE(e.effective, 0)
+        FROM records r
+        LEFT JOIN effective e ON e.src = r.src AND e.val = r.val
+        GROUP BY r.src, r.val
+        ORDER BY r.src, r.val;
+        "#,
+        source = filter.source(table),
+    ))?;
+    let mut params = filter.params();
+    params.push((":as_of", Value::Real(epoch_seconds(as_of))));
+    let usage = stmt
+        .query_map(bind(&params).as_slice(), |row| {
+            Ok(ValueUsage {
+                src: row.get(0)?,
+                val: row.get(1)?,
+                applied: row.get(2)?,
+                negated: row.get(3)?,
+                effective: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(usage)
+}
+
+/// How the effective labels of two srcs overlap, over the targets either of them labels
+#[derive(Debug, Default, Serialize)]
+pub struct SrcOverlap {
+    /// targets both srcs label
+    pub targets_both: usize,
+    /// targets both srcs label with exactly the same values
+    pub targets_agreeing: usize,
+    /// targets only the first src labels
+    pub targets_only_a: usize,
+    /// targets only the second src labels
+    pub targets_only_b: usize,
+    /// labels on targets both srcs label that both of them apply
+    pub matching_labels: usize,
+    /// labels on targets both srcs label that only one of them applies
+    pub mismatching_labels: usize,
+    pub values: Vec<ValueOverlap>,
+}
+
+/// How two srcs' use of one label value overlaps
+#[derive(Debug, Serialize)]
+pub struct ValueOverlap {
+    pub val: String,
+    /// targets the first src applies the value to
+    pub a: usize,
+    /// targets the second src applies the value to
+    pub b: usize,
+    /// targets both srcs apply the value to
+    pub both: usize,
+    /// percentage of the targets either src applies the value to that both apply it to
+    pub agreement: f64,
+}
+
+/// Compares the labels in effect at `as_of` from two srcs, counting the targets and values they
+/// agree and disagree on. The counting is done by the database, without reading the labels out.
+pub fn compare_srcs(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+    as_of: &DateTime,
+    a: &str,
+    b: &str,
+) -> Result<SrcOverlap> {
+    // which of the two srcs currently applies each value to each target
+    let labels = format!(
+        r#"
+        WITH records AS (
+            SELECT DISTINCT src, target_uri, val, seq, create_timestamp, expiry_timestamp, neg
+            FROM {source}
+            WHERE src IN (:a, :b)
+        ),
+        latest AS (
+            SELECT
+                *,
+                row_number() OVER (
+                    PARTITION BY src, target_uri, val
+                    ORDER BY unixepoch(create_timestamp, 'subsec') DESC NULLS LAST, seq DESC
+                ) AS recency
+            FROM records
+        ),
+        labels AS (
+            SELECT target_uri, val, MAX(src = :a) AS in_a, MAX(src = :b) AS in_b
+            FROM latest
+            WHERE
+                recency = 1 AND
+                NOT neg AND
+                {UNEXPIRED_AT}
+            GROUP BY target_uri, val
+        )
+        "#,
+        source = filter.source(table),
+    );
+    let mut params = filter.params();
+    params.push((":as_of", Value::Real(epoch_seconds(as_of))));
+    params.push((":a", Value::Text(a.to_owned())));
+    params.push((":b", Value::Text(b.to_owned())));
+    let params = bind(&params);
+
+    let mut overlap = db.query_row(
+        &format!(
+            r#"
+            {labels},
+            targets AS (
+                SELECT
+                    MAX(in_a) AS a,
+                    MAX(in_b) AS b,
+                    SUM(in_a AND in_b) AS matching,
+                    SUM(in_a != in_b) AS mismatching
+                FROM labels
+                GROUP BY target_uri
+            )
+            SELECT
+                COALESCE(SUM(a AND b), 0),
+                COALESCE(SUM(a AND b AND mismatching = 0), 0),
+                COALESCE(SUM(a AND NOT b), 0),
+                COALESCE(SUM(b AND NOT a), 0),
+                COALESCE(SUM(CASE WHEN a AND b THEN matching END), 0),
+                COALESCE(SUM(CASE WHEN a AND b THEN mismatching END), 0)
+            FROM targets;
+            "#
+        ),
+        params.as_slice(),
+        |row| {
+            Ok(SrcOverlap {
+                targets_both: row.get(0)?,
+                targets_agreeing: row.get(1)?,
+                targets_only_a: row.get(2)?,
+                targets_only_b: row.get(3)?,
+                matching_labels: row.get(4)?,
+                mismatching_labels: row.get(5)?,
+                values: vec![],
+            })
+        },
+    )?;
+    let mut stmt = db.prepare(&format!(
+        r#"
+        {labels}
+        SELECT val, SUM(in_a), SUM(in_b), SUM(in_a AND in_b)
+        FROM labels
+        GROUP BY val
+        ORDER BY val;
+        "#
+    ))?;
+    overlap.values = stmt
+        .query_map(params.as_slice(), |row| {
+            let (a, b, both): (usize, usize, usize) = (row.get(1)?, row.get(2)?, row.get(3)?);
+            Ok(ValueOverlap {
+                val: row.get(0)?,
+                a,
+                b,
+                both,
+                agreement: both as f64 * 100.0 / (a + b - both) as f64,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(overlap)
+}
+
+/// Counts of how each connection to the labeler went over the course of a run
+#[derive(Debug, Default, Serialize)]
+pub struct RunStats {
+    /// number of times we connected, or tried to connect, to the labeler
+    pub connections: usize,
+    /// number of connections that ended without the cursor advancing, or advancing by less than
+    /// --min-seq-progress
+    pub retries: usize,
+    pub ended_ok: usize,
+    pub ended_stepped: usize,
+    pub ended_closed: usize,
+    pub ended_websocket_error: usize,
+    pub ended_atproto_error: usize,
+    /// the last error the labeler sent, even if a later connection went on to catch up
+    pub last_atproto_error: Option<AtprotoError>,
+}
+
+/// An error the labeler sent on the stream, which ends the connection
+#[derive(Debug, Clone, Serialize)]
+pub struct AtprotoError {
+    /// the error's name, like `ConsumerTooSlow` or `FutureCursor`
+    pub error: String,
+    pub message: Option<String>,
+    /// our cursor when the error arrived
+    pub cursor: i64,
+}
+
+/// Records the start of a run, returning its id
+pub fn insert_run(
+    db: &Connection,
+    label_table: &str,
+    labeler_domain: &str,
+    started_at: &DateTime,
+    start_cursor: i64,
+) -> Result<i64> {
+    db.execute(
+        r#"
+        INSERT INTO run_stats(label_table, labeler_domain, started_at_timestamp, start_cursor)
+        VALUES (:table, :domain, :started_at, :cursor);
+        "#,
+        named_params!(
+            ":table": label_table,
+            ":domain": labeler_domain,
+            ":started_at": started_at,
+            ":cursor": start_cursor,
+        ),
+    )?;
+    Ok(db.last_insert_rowid())
+}
+
+/// Records the outcome of a finished run, including the oldest seq the labeler had available if we
+/// learned it
+pub fn finish_run(
+    db: &Connection,
+    run_id: i64,
+    finished_at: &DateTime,
+    end_cursor: i64,
+    labels_received: usize,
+    first_available_seq: Option<i64>,
+    stats: &RunStats,
+) -> Result<()> {
+    let last_error = stats.last_atproto_error.as_ref();
+    db.execute(
+        r#"
+        UPDATE run_stats SET
+            finished_at_timestamp = :finished_at,
+            end_cursor = :cursor,
+            labels_received = :labels,
+            first_available_seq = :first_available_seq,
+            connections = :connections,
+            retries = :retries,
+            ended_ok = :ok,
+            ended_stepped = :stepped,
+            ended_closed = :closed,
+            ended_websocket_error = :websocket_error,
+            ended_atproto_error = :atproto_error,
+            last_atproto_error = :last_error,
+            last_atproto_error_message = :last_error_message,
+            last_atproto_error_cursor = :last_error_cursor
+        WHERE run_id = :run_id;
+        "#,
+        named_params!(
+            ":run_id": run_id,
+            ":finished_at": finished_at,
+            ":cursor": end_cursor,
+            ":labels": labels_received,
+            ":first_available_seq": first_available_seq,
+            ":connections": stats.connections,
+            ":retries": stats.retries,
+            ":ok": stats.ended_ok,
+            ":stepped": stats.ended_stepped,
+            ":closed": stats.ended_closed,
+            ":websocket_error": stats.ended_websocket_error,
+            ":atproto_error": stats.ended_atproto_error,
+            ":last_error": last_error.map(|e| &e.error),
+            ":last_error_message": last_error.and_then(|e| e.message.as_ref()),
+            ":last_error_cursor": last_error.map(|e| e.cursor),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Finds the oldest seq the labeler had available as of the latest earlier run that learned it
+pub fn previous_first_available_seq(
+    db: &Connection,
+    labeler_domain: &str,
+    run_id: i64,
+) -> Result<Option<i64>> {
+    Ok(db
+        .query_row(
+            r#"
+            SELECT first_available_seq FROM run_stats
+            WHERE labeler_domain = :domain
+                AND run_id < :run_id
+                AND first_available_seq IS NOT NULL
+            ORDER BY run_id DESC
+            LIMIT 1;
+            "#,
+            named_params!(":domain": labeler_domain, ":run_id": run_id),
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// Where a labeler was found the last time it was looked up
+#[derive(Debug)]
+pub struct LabelerIdentity {
+    pub did: String,
+    pub labeler_domain: String,
+    pub verified_at: DateTime,
+}
+
+/// Reads what a handle or did resolved to the last time it was looked up, if it has been
+pub fn get_labeler_identity(
+    db: &Connection,
+    handle_or_did: &str,
+) -> Result<Option<LabelerIdentity>> {
+    Ok(db
+        .query_row(
+            r#"
+            SELECT did, labeler_domain, verified_at_timestamp FROM labeler_identities
+            WHERE handle_or_did = :handle_or_did;
+            "#,
+            named_params!(":handle_or_did": handle_or_did),
+            |row| {
+                Ok(LabelerIdentity {
+                    did: row.get(0)?,
+                    labeler_domain: row.get(1)?,
+                    verified_at: row.get(2)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+/// Remembers what a handle or did resolved to, replacing what it resolved to before
+pub fn save_labeler_identity(
+    db: &Connection,
+    handle_or_did: &str,
+    identity: &LabelerIdentity,
+) -> Result<()> {
+    db.execute(
+        r#"
+        INSERT INTO labeler_identities(handle_or_did, did, labeler_domain, verified_at_timestamp)
+        VALUES (:handle_or_did, :did, :domain, :verified_at)
+        ON CONFLICT (handle_or_did) DO UPDATE SET
+            did = excluded.did,
+            labeler_domain = excluded.labeler_domain,
+            verified_at_timestamp = excluded.verified_at_timestamp;
+        "#,
+        named_params!(
+            ":handle_or_did": handle_or_did,
+            ":did": identity.did,
+            ":domain": identity.labeler_domain,
+            ":verified_at": identity.verified_at,
+        ),
+    )?;
+    Ok(())
+}
+
+/// How a labeler defines one of its label values in one language, as saved in the
+/// label_definitions table. Definitions without any locales are saved with no name, description,
+/// or locale
+pub struct LabelDefinition {
+    pub val: String,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub severity: String,
+    pub blurs: String,
+    pub locale: Option<String>,
+}
+
+/// Replaces the label value definitions saved for a labeler with the ones it declares now
+pub fn save_label_definitions(
+    db: &Connection,
+    src: &str,
+    definitions: &[LabelDefinition],
+    fetched_at: &DateTime,
+) -> Result<()> {
+    let tx = db.unchecked_transaction()?;
+    tx.execute(
+        "DELETE FROM label_definitions WHERE src = :src;",
+        named_params!(":src": src),
+    )?;
+    {
+        let mut stmt = tx.prepare(
+            r#"
+            INSERT INTO label_definitions(
+                src, val, name, description, severity, blurs, locale, fetched_at_timestamp
+            )
+            VALUES (:src, :val, :name, :description, :severity, :blurs, :locale, :fetched_at);
+            "#,
+        )?;
+        for definition in definitions {
+            stmt.execute(named_params!(
+                ":src": src,
+                ":val": definition.val,
+                ":name": definition.name,
+                ":description": definition.description,
+                ":severity": definition.severity,
+                ":blurs": definition.blurs,
+                ":locale": definition.locale,
+                ":fetched_at": fetched_at,
+            ))?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Changes which labeler a run is recorded as streaming from, for when it had to look the labeler
+/// up again partway through
+pub fn set_run_labeler_domain(db: &Connection, run_id: i64, labeler_domain: &str) -> Result<()> {
+    db.execute(
+        "UPDATE run_stats SET labeler_domain = :domain WHERE run_id = :run_id;",
+        named_params!(":run_id": run_id, ":domain": labeler_domain),
+    )?;
+    Ok(())
+}
+
+/// A run that was started but never recorded as finished, most likely because the process was
+/// killed partway through
+#[derive(Debug)]
+pub struct UnfinishedRun {
+    pub run_id: i64,
+    pub started_at: DateTime,
+    pub start_cursor: i64,
+    /// where polling had got to, if the run was polling with queryLabels
+    pub query_cursor: Option<String>,
+}
+
+/// Finds the latest run from a labeler into a table that never finished
+pub fn find_unfinished_run(
+    db: &Connection,
+    label_table: &str,
+    labeler_domain: &str,
+) -> Result<Option<UnfinishedRun>> {
+    Ok(db
+        .query_row(
+            r#"
+            SELECT run_id, started_at_timestamp, start_cursor, end_query_cursor FROM run_stats
+            WHERE label_table = :table
+                AND labeler_domain = :domain
+                AND finished_at_timestamp IS NULL
+            ORDER BY run_id DESC
+            LIMIT 1;
+            "#,
+            named_params!(":table": label_table, ":domain": labeler_domain),
+            |row| {
+                Ok(UnfinishedRun {
+                    run_id: row.get(0)?,
+                    started_at: row.get(1)?,
+                    start_cursor: row.get(2)?,
+                    query_cursor: row.get(3)?,
+                })
+            },
+        )
+        .optional()?)
+}
+
+/// Records the queryLabels cursor of the next page a run would poll, as it goes, so that the run
+/// or a later one can pick up from there
+pub fn set_run_query_cursor(db: &Connection, run_id: i64, cursor: &str) -> Result<()> {
+    db.execute(
+        "UPDATE run_stats SET end_query_cursor = :cursor WHERE run_id = :run_id;",
+        named_params!(":cursor": cursor, ":run_id": run_id),
+    )?;
+    Ok(())
+}
+
+/// Finds where the latest run that polled a labeler into a table left off, if any did
+pub fn latest_query_cursor(
+    db: &Connection,
+    label_table: &str,
+    labeler_domain: &str,
+) -> Result<Option<String>> {
+    Ok(db
+        .query_row(
+            r#"
+            SELECT end_query_cursor FROM run_stats
+            WHERE label_table = :table
+                AND labeler_domain = :domain
+                AND end_query_cursor IS NOT NULL
+            ORDER BY run_id DESC
+            LIMIT 1;
+            "#,
+            named_params!(":table": label_table, ":domain": labeler_domain),
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+/// What a run already saved to the database, for picking up where it left off
+#[derive(Debug)]
+pub struct RunProgress {
+    pub records: usize,
+    pub first_seq: Option<i64>,
+    pub last_seq: Option<i64>,
+    pub latest_create_timestamp: Option<String>,
+    /// index of the last label message the run saved records from
+    pub last_frame_index: Option<i64>,
+    /// each src did, with the greatest seq (if any of its records have one) and latest create
+    /// timestamp among its records
+    pub srcs: Vec<(String, Option<i64>, String)>,
+    /// records applying and negating each label value from each src
+    pub value_counts: Vec<(String, String, usize, usize)>,
+}
+
+/// Reads back what a run has saved from a labeler, counting records saved before runs were recorded
+/// on them if they were seen since the run started
+pub fn run_progress(
+    db: &Connection,
+    table: &str,
+    labeler_domain: &str,
+    run_id: i64,
+    since: &DateTime,
+) -> Result<RunProgress> {
+    let params = named_params!(":domain": labeler_domain, ":run_id": run_id, ":since": since);
+    let (records, first_seq, last_seq, latest_create_timestamp, last_frame_index) = db.query_row(
+        &format!(
+            r#"
+            SELECT COUNT(*), MIN(seq), MAX(seq), MAX(create_timestamp), MAX(frame_index)
+            FROM "{table}"
+            WHERE fetched_from = :domain AND {IN_RUN};
+            "#
+        ),
+        params,
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        },
+    )?;
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT src, MAX(seq), MAX(create_timestamp) FROM "{table}"
+        WHERE fetched_from = :domain AND {IN_RUN}
+        GROUP BY src;
+        "#
+    ))?;
+    let srcs = stmt
+        .query_map(params, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let mut stmt = db.prepare(&format!(
+        r#"
+        SELECT src, val, SUM(NOT neg), SUM(neg) FROM "{table}"
+        WHERE fetched_from = :domain AND {IN_RUN}
+        GROUP BY src, val;
+        "#
+    ))?;
+    let value_counts = stmt
+        .query_map(params, |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(RunProgress {
+        records,
+        first_seq,
+        last_seq,
+        latest_create_timestamp,
+        last_frame_index,
+        srcs,
+        value_counts,
+    })
+}
+
+/// Counts the #info messages recorded for a run, by name
+pub fn count_infos(db: &Connection, run_id: i64) -> Result<BTreeMap<String, usize>> {
+    let mut stmt =
+        db.prepare("SELECT name, COUNT(*) FROM infos WHERE run_id = :run_id GROUP BY name;")?;
+    let counts = stmt
+        .query_map(named_params!(":run_id": run_id), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(counts)
+}
+
+/// Records an #info message the labeler sent during a run, along with the cursor at the time
+pub fn insert_info(
+    db: &Connection,
+    run_id: i64,
+    received_at: &DateTime,
+    cursor: i64,
+    name: &str,
+    message: Option<&str>,
+) -> Result<()> {
+    db.execute(
+        r#"
+        INSERT INTO infos(run_id, received_at_timestamp, cursor, name, message)
+        VALUES (:run_id, :received_at, :cursor, :name, :message);
+        "#,
+        named_params!(
+            ":run_id": run_id,
+            ":received_at": received_at,
+            ":cursor": cursor,
+            ":name": name,
+            ":message": message,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Records a label record that conflicted with one already saved in the given table, and what was
+/// done about it
+pub fn insert_ingest_conflict(
+    db: &Connection,
+    run_id: Option<i64>,
+    table: &str,
+    recorded_at: &DateTime,
+    conflict: &InsertConflict,
+    resolution: &str,
+) -> Result<()> {
+    db.execute(
+        r#"
+        INSERT INTO ingest_errors(
+            run_id, label_table, recorded_at_timestamp, kind,
+            src, target_uri, val, seq, detail
+        )
+        VALUES (
+            :run_id, :table, :recorded_at, 'conflict',
+            :src, :uri, :val, :seq, :detail
+        );
+        "#,
+        named_params!(
+            ":run_id": run_id,
+            ":table": table,
+            ":recorded_at": recorded_at,
+            ":src": &conflict.key.src,
+            ":uri": &conflict.key.target_uri,
+            ":val": &conflict.key.val,
+            ":seq": conflict.seq,
+            ":detail": format!(
+                "differs in {fields}; {resolution}",
+                fields = conflict.differing.join(", ")
+            ),
+        ),
+    )?;
+    Ok(())
+}
+
+/// How active a labeler has been, judging by the create timestamps of its label records
+#[derive(Debug)]
+pub struct Velocity {
+    pub src: String,
+    pub count: i64,
+    /// unix timestamps of the earliest and latest parseable create timestamps
+    pub first: Option<f64>,
+    pub last: Option<f64>,
+    /// the day (YYYY-MM-DD) on which the most records were created, with its count
+    pub busiest_day: Option<(String, i64)>,
+}
+
+impl Velocity {
+    /// labels created per day over the span between the first and last create timestamps
+    pub fn per_day(&self) -> Option<f64> {
+        let days = (self.last? - self.first?) / 86400.0;
+        (days > 0.0).then(|| self.count as f64 / days)
+    }
+}
+
+/// Computes the label creation velocity of every src in the database. Records saved more than
+/// once by different runs are only counted once.
+pub fn get_velocities(
+    db: &Connection,
+    table: &str,
+    filter: &RecordFilter,
+) -> Result<Vec<Velocity>> {
+    let mut stmt = db.prepare(&format!(
+        r#"
+        WITH distinct_records AS (
+            SELECT DISTINCT src, target_uri, val, seq, create_timestamp
+            FROM {source}
+        ),
+        busiest AS (
+            SELECT
+                src,
+                date(create_timestamp) AS day,
+                COUNT(*) AS n,
+                row_number() OVER (
+                    PARTITION BY src
+                    ORDER BY COUNT(*) DESC, date(create_timestamp)
+                ) AS rank
+            FROM distinct_records
+            WHERE date(create_timestamp) IS NOT NULL
+            GROUP BY src, day
+        )
+        SELECT
+            r.src,
+            COUNT(*),
+            MIN(unixepoch(r.create_timestamp, 'subsec')),
+            MAX(unixepoch(r.create_timestamp, 'subsec')),
+            b.day,
+            b.n
+        FROM distinct_records r
+        LEFT JOIN busiest b ON b.src = r.src AND b.rank = 1
+        GROUP BY r.src
+        ORDER BY r.src;
+        "#,
+        source = filter.source(table),
+    ))?;
+    let velocities = stmt
+        .query_map(bind(&filter.params()).as_slice(), |row| {
+            let day: Option<String> = row.get(4)?;
+            let day_count: Option<i64> = row.get(5)?;
+            Ok(Velocity {
+                src: row.get(0)?,
+                count: row.get(1)?,
+                first: row.get(2)?,
+                last: row.get(3)?,
+                busiest_day: day.zip(day_count),
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(velocities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a fresh database in memory with an up to date label table
+    fn test_db() -> Connection {
+        connect(Path::new(":memory:"), DEFAULT_LABEL_TABLE).unwrap()
+    }
+
+    fn insert(db: &Connection, src: &str, uri: &str, val: &str, seq: i64) {
+        db.execute(
+            r#"
+            INSERT INTO label_records(
+                src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+            )
+            VALUES (?1, ?2, ?3, ?4, '2024-01-01T00:00:00Z', 0, '2024-01-01T00:00:00Z');
+            "#,
+            rusqlite::params![src, uri, val, seq],
+        )
+        .unwrap();
+    }
+
+    /// a path for a database file that doesn't exist yet
+    fn temp_db_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-{name}.sqlite",
+            pid = std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reading_never_migrates() {
+        let path = temp_db_path("reading");
+        // a label table from before schema versioning, in a database without any migrations
+        {
+            let db = Connection::open(&path).unwrap();
+            let create = LABEL_TABLE_MIGRATIONS[0]
+                .sql
+                .replace("{table}", DEFAULT_LABEL_TABLE);
+            db.execute_batch(&create).unwrap();
+        }
+        let error = open_for_reading(&path, DEFAULT_LABEL_TABLE)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("labelview migrate"), "{error}");
+        let db = open_read_only(&path).unwrap();
+        assert_eq!(database_schema_version(&db).unwrap(), 0);
+        drop(db);
+
+        connect(&path, DEFAULT_LABEL_TABLE).unwrap();
+        open_for_reading(&path, DEFAULT_LABEL_TABLE).unwrap();
+        let error = open_for_reading(&path, "other").err().unwrap().to_string();
+        assert!(error.contains("no table"), "{error}");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// the timestamps of a record with the given create and expiry timestamps, once normalized
+    fn normalized(cts: &str, exp: Option<&str>) -> (String, Option<String>) {
+        let record = LabelRecord {
+            effective_weight: effective_weight(cts, Some(1)),
+            dbkey: LabelDbKey {
+                key: LabelKey {
+                    src: "did:plc:a".parse().unwrap(),
+                    target_uri: "did:plc:x".into(),
+                    val: "spam".into(),
+                },
+                seq: Some(1),
+            },
+            create_timestamp: cts.into(),
+            expiry_timestamp: exp.map(str::to_owned),
+            neg: false,
+            target_cid: None,
+            sig: None,
+            original_target_uri: None,
+        }
+        .normalize_timestamps();
+        (record.create_timestamp.to_string(), record.expiry_timestamp)
+    }
+
+    #[test]
+    fn timestamps_normalize_to_utc_seconds() {
+        let cases = [
+            // already canonical
+            ("2024-01-01T00:00:00Z", "2024-01-01T00:00:00Z"),
+            // milliseconds, and finer than milliseconds, are dropped
+            ("2024-01-01T00:00:00.123Z", "2024-01-01T00:00:00Z"),
+            ("2024-01-01T00:00:00.999999Z", "2024-01-01T00:00:00Z"),
+            ("2024-01-01T00:00:00.123456789Z", "2024-01-01T00:00:00Z"),
+            // offsets are converted to UTC
+            ("2024-01-01T00:00:00+00:00", "2024-01-01T00:00:00Z"),
+            ("2024-01-01T02:30:00+02:30", "2024-01-01T00:00:00Z"),
+            ("2023-12-31T19:00:00.5-05:00", "2024-01-01T00:00:00Z"),
+            // lowercase separators are allowed by RFC 3339
+            ("2024-01-01t00:00:00z", "2024-01-01T00:00:00Z"),
+            // unparseable timestamps are kept as they are
+            ("yesterday", "yesterday"),
+            ("2024-01-01", "2024-01-01"),
+            ("2024-01-01T00:00:00", "2024-01-01T00:00:00"),
+            ("", ""),
+        ];
+        for (given, expected) in cases {
+            let (cts, exp) = normalized(given, Some(given));
+            assert_eq!(cts, expected, "create timestamp {given:?}");
+            assert_eq!(exp.as_deref(), Some(expected), "expiry timestamp {given:?}");
+        }
+        assert_eq!(normalized("2024-01-01T00:00:00Z", None).1, None);
+    }
+
+    #[test]
+    fn compacted_timestamps_match_normalized_ones() {
+        let db = test_db();
+        let timestamps = [
+            "2024-01-01T00:00:00Z",
+            "2024-01-01T00:00:00.123Z",
+            "2024-01-01T00:00:00.123456789Z",
+            "2024-01-01T02:30:00+02:30",
+            "2023-12-31T19:00:00.5-05:00",
+        ];
+        for (seq, timestamp) in timestamps.iter().enumerate() {
+            db.execute(
+                r#"
+                INSERT INTO label_records(
+                    src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+                )
+                VALUES ('did:plc:a', 'did:plc:x', 'spam', ?1, ?2, 0, '2024-01-01T00:00:00Z');
+                "#,
+                rusqlite::params![seq as i64, timestamp],
+            )
+            .unwrap();
+        }
+        // all but the one already canonical are rewritten
+        assert_eq!(
+            compact_timestamps(&db, DEFAULT_LABEL_TABLE).unwrap(),
+            timestamps.len() - 1
+        );
+        let mut stmt = db
+            .prepare("SELECT create_timestamp FROM label_records ORDER BY seq;")
+            .unwrap();
+        let compacted: Vec<String> = stmt
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        for (given, compacted) in timestamps.iter().zip(compacted) {
+            assert_eq!(compacted, normalized(given, None).0, "{given:?}");
+        }
+        assert_eq!(compact_timestamps(&db, DEFAULT_LABEL_TABLE).unwrap(), 0);
+    }
+
+    #[test]
+    fn same_time_compares_normalized_timestamps() {
+        assert!(same_time("2024-01-01T00:00:00Z", "2024-01-01T00:00:00Z"));
+        assert!(same_time(
+            "2024-01-01T00:00:00.123Z",
+            "2024-01-01T00:00:00Z"
+        ));
+        assert!(same_time(
+            "2024-01-01T02:00:00+02:00",
+            "2024-01-01T00:00:00Z"
+        ));
+        assert!(!same_time("2024-01-01T00:00:01Z", "2024-01-01T00:00:00Z"));
+        assert!(same_time("garbage", "garbage"));
+        assert!(!same_time("garbage", "2024-01-01T00:00:00Z"));
+    }
+
+    fn time(s: &str) -> DateTime {
+        parse_datetime(s).unwrap()
+    }
+
+    #[test]
+    fn expiry_rule_is_the_same_in_rust_and_sql() {
+        let db = Connection::open_in_memory().unwrap();
+        let expiries = [
+            None,
+            Some("garbage"),
+            Some("2024-01-01T00:00:00Z"),
+            Some("2024-01-01T00:00:00.500Z"),
+            Some("2024-01-01T01:00:00+01:00"),
+        ];
+        let instants = [
+            "2023-12-31T23:59:59Z",
+            "2024-01-01T00:00:00Z",
+            "2024-01-01T00:00:00.250Z",
+            "2024-01-01T00:00:00.500Z",
+            "2024-01-01T00:00:01Z",
+        ];
+        for expiry in expiries {
+            for instant in instants {
+                let at = time(instant);
+                let expired_in_sql: bool = db
+                    .query_row(
+                        &format!(
+                            "SELECT NOT {UNEXPIRED_AT} FROM (SELECT :exp AS expiry_timestamp);"
+                        ),
+                        named_params!(":exp": expiry, ":as_of": epoch_seconds(&at)),
+                        |row| row.get(0),
+                    )
+                    .unwrap();
+                assert_eq!(
+                    is_expired_at(expiry, &at),
+                    expired_in_sql,
+                    "expiry {expiry:?} at {instant}"
+                );
+            }
+        }
+        // a label expires at its expiry time exactly
+        assert!(!is_expired_at(
+            Some("2024-01-01T00:00:00Z"),
+            &time("2023-12-31T23:59:59Z")
+        ));
+        assert!(is_expired_at(
+            Some("2024-01-01T00:00:00Z"),
+            &time("2024-01-01T00:00:00Z")
+        ));
+        assert!(!is_expired_at(None, &time("9999-01-01T00:00:00Z")));
+        assert!(!is_expired_at(
+            Some("garbage"),
+            &time("9999-01-01T00:00:00Z")
+        ));
+    }
+
+    #[test]
+    fn label_expiring_between_as_of_points() {
+        let db = test_db();
+        db.execute(
+            r#"
+            INSERT INTO label_records(
+                src, target_uri, val, seq, create_timestamp, expiry_timestamp, neg,
+                seen_at_timestamp
+            )
+            VALUES (
+                'did:plc:a', 'did:plc:x', 'spam', 1, '2024-01-01T00:00:00Z',
+                '2024-06-01T00:00:00Z', 0, '2024-01-01T00:00:00Z'
+            );
+            "#,
+            [],
+        )
+        .unwrap();
+        let effective_at = |instant: &str| {
+            let usage = get_value_usage(
+                &db,
+                DEFAULT_LABEL_TABLE,
+                &RecordFilter::default(),
+                &time(instant),
+            )
+            .unwrap();
+            assert_eq!(usage.len(), 1);
+            usage[0].effective
+        };
+        assert_eq!(effective_at("2024-05-01T00:00:00Z"), 1);
+        assert_eq!(effective_at("2024-07-01T00:00:00Z"), 0);
+
+        let mut records = Vec::new();
+        for_each_record(
+            &db,
+            DEFAULT_LABEL_TABLE,
+            &RecordFilter::default(),
+            |saved| {
+                records.push(saved.record);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(!records[0].is_expired(&time("2024-05-01T00:00:00Z")));
+        assert!(records[0].is_expired(&time("2024-07-01T00:00:00Z")));
+    }
+
+    #[test]
+    fn srcs_are_compared_by_their_effective_labels() {
+        const A: &str = "did:plc:a";
+        const B: &str = "did:plc:b";
+        let db = test_db();
+        // src, target, val, seq, create time, expiry, negation, run
+        let records = [
+            // t1: both apply spam
+            (A, "t1", "spam", 1, "2024-01-01T00:00:00Z", None, false, 1),
+            (B, "t1", "spam", 1, "2024-01-01T00:00:00Z", None, false, 1),
+            // t2: both apply spam, only a applies rude
+            (A, "t2", "spam", 2, "2024-01-01T00:00:00Z", None, false, 1),
+            (A, "t2", "rude", 3, "2024-01-01T00:00:00Z", None, false, 1),
+            (B, "t2", "spam", 2, "2024-01-01T00:00:00Z", None, false, 1),
+            // t3: only a, which negated spam and then applied it again
+            (A, "t3", "spam", 4, "2024-01-01T00:00:00Z", None, false, 1),
+            (A, "t3", "spam", 5, "2024-02-01T00:00:00Z", None, true, 1),
+            (A, "t3", "spam", 6, "2024-03-01T00:00:00Z", None, false, 1),
+            // and a third src, which isn't compared
+            (
+                "did:plc:c",
+                "t3",
+                "rude",
+                1,
+                "2024-01-01T00:00:00Z",
+                None,
+                false,
+                1,
+            ),
+            // t4: b negated rude, sent again in a second run, and its spam has expired
+            (B, "t4", "rude", 3, "2024-01-01T00:00:00Z", None, false, 1),
+            (B, "t4", "rude", 4, "2024-02-01T00:00:00Z", None, true, 1),
+            (B, "t4", "rude", 10, "2024-02-01T00:00:00Z", None, true, 2),
+            (
+                B,
+                "t4",
+                "spam",
+                5,
+                "2024-01-01T00:00:00Z",
+                Some("2024-05-01T00:00:00Z"),
+                false,
+                1,
+            ),
+            // t5 and t6: only b, with t5's spam sent again in a later run
+            (B, "t5", "spam", 6, "2024-01-01T00:00:00Z", None, false, 1),
+            (B, "t5", "spam", 9, "2024-01-01T00:00:00Z", None, false, 2),
+            (B, "t6", "spam", 7, "2024-01-01T00:00:00Z", None, false, 2),
+            // t7: both apply rude, a's until after the comparison
+            (
+                A,
+                "t7",
+                "rude",
+                7,
+                "2024-01-01T00:00:00Z",
+                Some("2024-07-01T00:00:00Z"),
+                false,
+                1,
+            ),
+            (B, "t7", "rude", 8, "2024-01-01T00:00:00Z", None, false, 2),
+        ];
+        for (src, uri, val, seq, cts, exp, neg, run_id) in records {
+            db.execute(
+                r#"
+                INSERT INTO label_records(
+                    src, target_uri, val, seq, create_timestamp, expiry_timestamp, neg,
+                    seen_at_timestamp, run_id
+                )
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, '2024-01-01T00:00:00Z', ?8);
+                "#,
+                rusqlite::params![src, uri, val, seq, cts, exp, neg, run_id],
+            )
+            .unwrap();
+        }
+        let overlap = compare_srcs(
+            &db,
+            DEFAULT_LABEL_TABLE,
+            &RecordFilter::default(),
+            &time("2024-06-01T00:00:00Z"),
+            A,
+            B,
+        )
+        .unwrap();
+        assert_eq!(overlap.targets_both, 3);
+        assert_eq!(overlap.targets_agreeing, 2);
+        assert_eq!(overlap.targets_only_a, 1);
+        assert_eq!(overlap.targets_only_b, 2);
+        assert_eq!(overlap.matching_labels, 3);
+        assert_eq!(overlap.mismatching_labels, 1);
+        let values: Vec<_> = overlap
+            .values
+            .iter()
+            .map(|value| {
+                (
+                    value.val.as_str(),
+                    value.a,
+                    value.b,
+                    value.both,
+                    value.agreement,
+                )
+            })
+            .collect();
+        assert_eq!(values, [("rude", 2, 1, 1, 50.0), ("spam", 3, 4, 2, 40.0)]);
+
+        // by the time a's rude on t7 has expired, they no longer agree there
+        let overlap = compare_srcs(
+            &db,
+            DEFAULT_LABEL_TABLE,
+            &RecordFilter::default(),
+            &time("2024-08-01T00:00:00Z"),
+            A,
+            B,
+        )
+        .unwrap();
+        assert_eq!(overlap.targets_both, 2);
+        assert_eq!(overlap.targets_agreeing, 1);
+        assert_eq!(overlap.targets_only_b, 3);
+        assert_eq!(overlap.values[0].agreement, 0.0);
+    }
+
+    #[test]
+    fn shared_tables_are_reserved() {
+        // every table the database migrations create must be in SHARED_TABLES
+        let created = DATABASE_MIGRATIONS.iter().flat_map(|migration| {
+            migration
+                .sql
+                .split("CREATE TABLE")
+                .skip(1)
+                .map(|rest| {
+                    rest.trim_start()
+                        .trim_start_matches("IF NOT EXISTS")
+                        .trim_start()
+                        .split(['(', ' ', '\n'])
+                        .next()
+                        .unwrap()
+                        .to_owned()
+                })
+                .collect::<Vec<_>>()
+        });
+        for table in created {
+            assert!(
+                SHARED_TABLES.contains(&table.as_str()),
+                "{table} isn't reserved"
+            );
+        }
+        for table in SHARED_TABLES {
+            assert!(validate_table_name(table).is_err());
+            assert!(validate_table_name(&table.to_ascii_uppercase()).is_err());
+        }
+        assert!(validate_table_name("sqlite_master").is_err());
+        assert!(validate_table_name(DEFAULT_LABEL_TABLE).is_ok());
+        assert!(validate_table_name("other_labeler").is_ok());
+    }
+
+    #[test]
+    fn duplicate_seqs_are_found_per_src() {
+        let db = test_db();
+        // as in a database from before each key and seq was kept unique
+        db.execute_batch("DROP INDEX label_records_by_key;")
+            .unwrap();
+        // two different labels sharing a seq from one src
+        insert(&db, "did:plc:a", "did:plc:x", "spam", 1);
+        insert(&db, "did:plc:a", "did:plc:y", "rude", 1);
+        // the same seq from another src is fine
+        insert(&db, "did:plc:b", "did:plc:x", "spam", 2);
+        insert(&db, "did:plc:a", "did:plc:x", "spam", 2);
+        // the same record twice
+        insert(&db, "did:plc:a", "did:plc:z", "spam", 3);
+        insert(&db, "did:plc:a", "did:plc:z", "spam", 3);
+        insert(&db, "did:plc:a", "did:plc:z", "spam", 3);
+
+        let duplicates = find_duplicate_seqs(&db, DEFAULT_LABEL_TABLE).unwrap();
+        let expected = [(3, "did:plc:a", 3), (1, "did:plc:a", 2)];
+        assert_eq!(duplicates.len(), expected.len());
+        for (duplicate, (seq, src, count)) in duplicates.iter().zip(expected) {
+            assert_eq!(
+                (duplicate.seq, duplicate.src.as_str(), duplicate.count),
+                (seq, src, count)
+            );
+        }
+
+        let duplicates = find_duplicate_key_seqs(&db, DEFAULT_LABEL_TABLE).unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].seq, 3);
+        assert_eq!(duplicates[0].target_uri, "did:plc:z");
+        assert_eq!(duplicates[0].count, 3);
+    }
+
+    #[test]
+    fn latest_labels_count_targets_and_srcs_once() {
+        let db = test_db();
+        for (src, uri, val, seq) in [
+            ("did:plc:a", "did:plc:x", "spam", 1),
+            ("did:plc:a", "did:plc:x", "spam", 2),
+            ("did:plc:a", "did:plc:x", "rude", 3),
+            ("did:plc:a", "did:plc:y", "spam", 4),
+            ("did:plc:b", "did:plc:x", "spam", 5),
+            ("did:plc:c", "did:plc:w", "spam", 6),
+            ("did:plc:b", "did:plc:v", "spam", 7),
+            ("did:plc:b", "did:plc:u", "spam", 8),
+        ] {
+            insert(&db, src, uri, val, seq);
+        }
+        db.execute_batch(
+            r#"
+            UPDATE label_records SET neg = 1 WHERE seq = 6;
+            UPDATE label_records SET expiry_timestamp = '2024-06-01T00:00:00Z' WHERE seq = 7;
+            UPDATE label_records SET expiry_timestamp = '2025-06-01T00:00:00Z' WHERE seq = 8;
+            "#,
+        )
+        .unwrap();
+        let (mut latest, mut targets, mut srcs) = (Vec::new(), 0, 0);
+        let since = time("2024-01-01T00:00:00Z");
+        let as_of = time("2025-01-01T00:00:00Z");
+        for_each_latest_label(
+            &db,
+            DEFAULT_LABEL_TABLE,
+            None,
+            &since,
+            &as_of,
+            |label, first| {
+                latest.push(label.dbkey.seq);
+                targets += usize::from(first.target);
+                srcs += usize::from(first.src);
+            },
+        )
+        .unwrap();
+        latest.sort();
+        assert_eq!(
+            latest,
+            [2, 3, 4, 5, 6, 7, 8].map(Some),
+            "negated and expired records are still the latest of their keys"
+        );
+        // the negated label is the only one from did:plc:c and on did:plc:w, and the expired one
+        // the only one on did:plc:v, so neither counts toward the targets and srcs of effective
+        // labels
+        assert_eq!((targets, srcs), (3, 2));
+    }
+
+    /// a record labeling did:plc:x as spam, with or without a seq
+    fn spam_record(seq: Option<i64>, neg: bool) -> LabelRecord {
+        LabelRecord {
+            effective_weight: effective_weight("2024-01-01T00:00:00Z", seq),
+            dbkey: LabelDbKey {
+                key: LabelKey {
+                    src: "did:plc:a".parse().unwrap(),
+                    target_uri: "did:plc:x".into(),
+                    val: "spam".into(),
+                },
+                seq,
+            },
+            create_timestamp: "2024-01-01T00:00:00Z".into(),
+            expiry_timestamp: None,
+            neg,
+            target_cid: None,
+            sig: None,
+            original_target_uri: None,
+        }
+    }
+
+    fn insert_record(db: &Connection, record: &LabelRecord) -> InsertOutcome {
+        let receipt = Receipt {
+            seen_at: time("2024-01-01T00:00:00Z"),
+            run_id: None,
+            frame_index: None,
+        };
+        record
+            .insert(db, DEFAULT_LABEL_TABLE, "labeler.example", &receipt, false)
+            .unwrap()
+    }
+
+    fn count_records(db: &Connection) -> usize {
+        db.query_row("SELECT COUNT(*) FROM label_records;", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn seq_gaps_are_counted_per_src() {
+        let db = test_db();
+        for (src, uri, seq) in [
+            ("did:plc:a", "did:plc:x", 1),
+            ("did:plc:a", "did:plc:y", 2),
+            ("did:plc:a", "did:plc:x", 4),
+            ("did:plc:b", "did:plc:x", 10),
+            ("did:plc:b", "did:plc:y", 11),
+            ("did:plc:b", "did:plc:z", 11),
+        ] {
+            insert(&db, src, uri, "spam", seq);
+        }
+        let gaps = |filter: &RecordFilter| {
+            let (first, last) = get_seq_range(&db, DEFAULT_LABEL_TABLE, filter)
+                .unwrap()
+                .unwrap();
+            let distinct = count_distinct_seqs(&db, DEFAULT_LABEL_TABLE, filter).unwrap();
+            (first, last, (last - first + 1) as u64 - distinct)
+        };
+        let from = |src: &str| RecordFilter {
+            src: Some(src.to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(gaps(&from("did:plc:a")), (1, 4, 1));
+        assert_eq!(gaps(&from("did:plc:b")), (10, 11, 0));
+        // across both srcs, the seqs between their streams would look missing
+        assert_eq!(gaps(&RecordFilter::default()), (1, 11, 6));
+    }
+
+    #[test]
+    fn conflicts_are_resolved_by_policy() {
+        let saved = spam_record(Some(5), false);
+        let mut received = spam_record(Some(5), true);
+        received.sig = Some(vec![0; SIGNATURE_LENGTH]);
+        for (policy, saved_neg) in [
+            (ConflictPolicy::KeepOld, false),
+            (ConflictPolicy::KeepNew, true),
+            (ConflictPolicy::Error, false),
+        ] {
+            let mut db = test_db();
+            let seen_at = time("2024-01-01T00:00:00Z");
+            let mut batch = insert_batch_tx(
+                &mut db,
+                DEFAULT_LABEL_TABLE,
+                "labeler.example",
+                Some(1),
+                policy,
+            )
+            .unwrap();
+            assert!(batch.insert(&saved, &seen_at, Some(0)).unwrap().is_none());
+            // the same record again is no conflict
+            assert!(batch.insert(&saved, &seen_at, Some(1)).unwrap().is_none());
+            let conflict = batch
+                .insert(&received, &seen_at, Some(2))
+                .unwrap()
+                .expect("the record conflicts with the saved one");
+            assert_eq!((conflict.seq, conflict.differing), (5, vec!["neg", "sig"]));
+            assert_eq!(batch.commit().unwrap(), 1, "{policy:?}");
+
+            let (count, neg, frame_index): (usize, bool, i64) = db
+                .query_row(
+                    "SELECT COUNT(*), neg, frame_index FROM label_records;",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )
+                .unwrap();
+            let replaced = matches!(policy, ConflictPolicy::KeepNew);
+            assert_eq!(
+                (count, neg, frame_index),
+                (1, saved_neg, if replaced { 2 } else { 0 }),
+                "{policy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn records_without_a_seq_never_conflict() {
+        let db = test_db();
+        assert!(matches!(
+            insert_record(&db, &spam_record(None, false)),
+            InsertOutcome::Inserted
+        ));
+        // the same record polled again, or streamed with a seq afterwards, is already there
+        assert!(matches!(
+            insert_record(&db, &spam_record(None, false)),
+            InsertOutcome::AlreadyPresent
+        ));
+        assert!(matches!(
+            insert_record(&db, &spam_record(Some(5), false)),
+            InsertOutcome::Inserted
+        ));
+        assert!(matches!(
+            insert_record(&db, &spam_record(None, false)),
+            InsertOutcome::AlreadyPresent
+        ));
+        // a different record without a seq is saved alongside, not counted as a conflict
+        assert!(matches!(
+            insert_record(&db, &spam_record(None, true)),
+            InsertOutcome::Inserted
+        ));
+        assert!(matches!(
+            insert_record(&db, &spam_record(Some(5), true)),
+            InsertOutcome::Conflict(_)
+        ));
+        assert_eq!(count_records(&db), 3);
+        let seqs: Vec<Option<i64>> = db
+            .prepare("SELECT seq FROM label_records ORDER BY rowid;")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(seqs, [None, Some(5), None]);
+        assert!(find_duplicate_seqs(&db, DEFAULT_LABEL_TABLE)
+            .unwrap()
+            .is_empty());
+        assert!(find_duplicate_key_seqs(&db, DEFAULT_LABEL_TABLE)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn label_tables_migrate_to_one_record_per_key_and_seq() {
+        let path = temp_db_path("unique-seqs");
+        // a database from before records were checked for ones already saved
+        {
+            let db = open(&path).unwrap();
+            for (version, migration) in (1..).zip(DATABASE_MIGRATIONS) {
+                db.execute_batch(migration.sql).unwrap();
+                db.pragma_update(None, "user_version", version).unwrap();
+            }
+            let table_version = LABEL_TABLE_MIGRATIONS
+                .iter()
+                .position(|migration| migration.description.contains("one record for each"))
+                .unwrap();
+            for migration in &LABEL_TABLE_MIGRATIONS[..table_version] {
+                db.execute_batch(&migration.sql.replace("{table}", DEFAULT_LABEL_TABLE))
+                    .unwrap();
+            }
+            db.execute(
+                "INSERT INTO schema_version(table_name, version) VALUES (?1, ?2);",
+                rusqlite::params![DEFAULT_LABEL_TABLE, table_version],
+            )
+            .unwrap();
+            for (uri, seq, neg, run_id) in [
+                ("did:plc:x", Some(1), false, 1),
+                ("did:plc:x", Some(1), true, 3),
+                ("did:plc:x", Some(1), false, 2),
+                ("did:plc:y", Some(2), false, 1),
+                ("did:plc:y", None, false, 1),
+                ("did:plc:y", None, false, 2),
+            ] {
+                db.execute(
+                    r#"
+                    INSERT INTO label_records(
+                        src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp,
+                        run_id, last_run_id
+                    )
+                    VALUES ('did:plc:a', ?1, 'spam', ?2, '2024-01-01T00:00:00Z', ?3,
+                        '2024-01-01T00:00:00Z', ?4, ?4);
+                    "#,
+                    rusqlite::params![uri, seq, neg, run_id],
+                )
+                .unwrap();
+            }
+        }
+        let db = connect(&path, DEFAULT_LABEL_TABLE).unwrap();
+        assert_eq!(pending_migrations(&db, DEFAULT_LABEL_TABLE).unwrap(), 0);
+        let mut stmt = db
+            .prepare(
+                "SELECT target_uri, seq, neg, run_id, last_run_id FROM label_records ORDER BY rowid;",
+            )
+            .unwrap();
+        let saved: Vec<(String, Option<i64>, bool, i64, i64)> = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        // the first record saved for a key and seq is kept, as received in the latest of their
+        // runs; records without a seq are left alone
+        assert_eq!(
+            saved,
+            [
+                ("did:plc:x".to_owned(), Some(1), false, 1, 3),
+                ("did:plc:y".to_owned(), Some(2), false, 1, 1),
+                ("did:plc:y".to_owned(), None, false, 1, 1),
+                ("did:plc:y".to_owned(), None, false, 2, 2),
+            ]
+        );
+        assert!(find_duplicate_key_seqs(&db, DEFAULT_LABEL_TABLE)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            insert_record(&db, &spam_record(Some(1), true)),
+            InsertOutcome::Conflict(_)
+        ));
+        assert_eq!(count_records(&db), 4);
+        drop(stmt);
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn label_tables_migrate_to_nullable_seqs() {
+        let path = temp_db_path("nullable-seqs");
+        // a database from before polled records were saved without a seq
+        {
+            let db = open(&path).unwrap();
+            for (version, migration) in
+                (1..).zip(&DATABASE_MIGRATIONS[..DATABASE_MIGRATIONS.len() - 1])
+            {
+                db.execute_batch(migration.sql).unwrap();
+                db.pragma_update(None, "user_version", version).unwrap();
+            }
+            let table_version = LABEL_TABLE_MIGRATIONS
+                .iter()
+                .position(|migration| migration.description.contains("without a seq"))
+                .unwrap();
+            for migration in &LABEL_TABLE_MIGRATIONS[..table_version] {
+                db.execute_batch(&migration.sql.replace("{table}", DEFAULT_LABEL_TABLE))
+                    .unwrap();
+            }
+            db.execute(
+                "INSERT INTO schema_version(table_name, version) VALUES (?1, ?2);",
+                rusqlite::params![DEFAULT_LABEL_TABLE, table_version],
+            )
+            .unwrap();
+            insert(&db, "did:plc:a", "did:plc:x", "spam", 7);
+            assert!(db
+                .execute(
+                    r#"
+                    INSERT INTO label_records(
+                        src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+                    )
+                    VALUES ('did:plc:a', 'did:plc:y', 'spam', NULL, '2024-01-01T00:00:00Z', 0,
+                        '2024-01-01T00:00:00Z');
+                    "#,
+                    [],
+                )
+                .is_err());
+        }
+        let db = connect(&path, DEFAULT_LABEL_TABLE).unwrap();
+        assert_eq!(pending_migrations(&db, DEFAULT_LABEL_TABLE).unwrap(), 0);
+        let saved: (String, i64) = db
+            .query_row("SELECT target_uri, seq FROM label_records;", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(saved, ("did:plc:x".to_owned(), 7));
+        assert!(matches!(
+            insert_record(&db, &spam_record(None, true)),
+            InsertOutcome::Inserted
+        ));
+        assert_eq!(count_records(&db), 2);
+        let indexes: usize = db
+            .query_row(
+                r#"
+                SELECT COUNT(*) FROM sqlite_master
+                WHERE type = 'index' AND tbl_name = 'label_records' AND sql IS NOT NULL;
+                "#,
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(indexes, 3);
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn query_cursors_are_saved_per_run() {
+        let db = test_db();
+        let started_at = time("2024-01-01T00:00:00Z");
+        assert_eq!(
+            latest_query_cursor(&db, DEFAULT_LABEL_TABLE, "labeler.example").unwrap(),
+            None
+        );
+        let first =
+            insert_run(&db, DEFAULT_LABEL_TABLE, "labeler.example", &started_at, 0).unwrap();
+        set_run_query_cursor(&db, first, "page-2").unwrap();
+        finish_run(&db, first, &started_at, 0, 2, None, &RunStats::default()).unwrap();
+        // a run that was killed partway through polling
+        let second =
+            insert_run(&db, DEFAULT_LABEL_TABLE, "labeler.example", &started_at, 0).unwrap();
+        set_run_query_cursor(&db, second, "page-3").unwrap();
+        // runs that haven't polled anything, or polled another labeler or table, don't count
+        insert_run(&db, DEFAULT_LABEL_TABLE, "labeler.example", &started_at, 0).unwrap();
+        let other = insert_run(&db, DEFAULT_LABEL_TABLE, "other.example", &started_at, 0).unwrap();
+        set_run_query_cursor(&db, other, "elsewhere").unwrap();
+        let other = insert_run(&db, "other_records", "labeler.example", &started_at, 0).unwrap();
+        set_run_query_cursor(&db, other, "elsewhere").unwrap();
+
+        assert_eq!(
+            latest_query_cursor(&db, DEFAULT_LABEL_TABLE, "labeler.example")
+                .unwrap()
+                .as_deref(),
+            Some("page-3")
+        );
+        let unfinished = find_unfinished_run(&db, DEFAULT_LABEL_TABLE, "labeler.example")
+            .unwrap()
+            .unwrap();
+        assert_eq!(unfinished.query_cursor, None);
+        db.execute(
+            "DELETE FROM run_stats WHERE run_id > :run_id;",
+            named_params!(":run_id": second),
+        )
+        .unwrap();
+        let unfinished = find_unfinished_run(&db, DEFAULT_LABEL_TABLE, "labeler.example")
+            .unwrap()
+            .unwrap();
+        assert_eq!(unfinished.run_id, second);
+        assert_eq!(unfinished.query_cursor.as_deref(), Some("page-3"));
+    }
+
+    #[test]
+    fn queried_labels_decode_without_a_seq() {
+        let sig = BASE64_STANDARD_NO_PAD.encode([7u8; SIGNATURE_LENGTH]);
+        let body = serde_json::json!({
+            "cursor": "page-2",
+            "labels": [
+                {
+                    "ver": 1,
+                    "src": "did:plc:a",
+                    "uri": "did:plc:x",
+                    "val": "spam",
+                    "cts": "2024-01-01T00:00:00.000Z",
+                    "sig": {"$bytes": sig},
+                },
+                // repaired the same way as streamed labels
+                {
+                    "ver": 1,
+                    "src": "DID:PLC:B",
+                    "uri": "at://did:plc:y/app.bsky.feed.post/1",
+                    "cid": "not a cid",
+                    "val": "rude",
+                    "cts": "2024-01-02T00:00:00Z",
+                    "neg": true,
+                },
+                // skipped
+                {
+                    "ver": 1,
+                    "src": "not a did",
+                    "uri": "did:plc:z",
+                    "val": "spam",
+                    "cts": "2024-01-03T00:00:00Z",
+                },
+            ],
+        });
+        let page = QueriedLabels::parse(body.to_string().as_bytes()).unwrap();
+        assert_eq!(page.cursor.as_deref(), Some("page-2"));
+        let mut anomalies = DecodeAnomalies::default();
+        let records = page
+            .into_records(&mut Interner::default(), &mut anomalies, false)
+            .unwrap();
+
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.dbkey.seq.is_none()));
+        assert_eq!(&*records[0].dbkey.key.val, "spam");
+        assert_eq!(&*records[0].create_timestamp, "2024-01-01T00:00:00Z");
+        assert_eq!(
+            records[0].sig.as_deref(),
+            Some(&[7u8; SIGNATURE_LENGTH][..])
+        );
+        assert_eq!(records[1].dbkey.key.src.to_string(), "did:plc:B");
+        assert_eq!(records[1].target_cid, None);
+        assert!(records[1].neg);
+        assert_eq!(anomalies.normalized_srcs, 1);
+        assert_eq!(anomalies.invalid_cids, 1);
+        assert_eq!(anomalies.invalid_srcs, 1);
     }
 }
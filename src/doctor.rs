@@ -0,0 +1,400 @@
+use crate::{db, lookup};
+use clap::Args;
+use eyre::{bail, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio_tungstenite::connect_async;
+use url::Url;
+
+#[derive(Debug, Args)]
+pub struct DoctorCmd {
+    /// Sqlite file to check, as would be passed to --save-to-db
+    #[arg(long)]
+    db: Option<PathBuf>,
+    /// Table of label records to check in the database, as would be passed to --table
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE)]
+    table: String,
+    /// Handle or DID of a labeler to try connecting to
+    #[arg(long)]
+    labeler: Option<String>,
+    /// Handle known to be resolvable, used to check that dns and HTTPS lookups work
+    #[arg(long, default_value = "bsky.app")]
+    probe_handle: String,
+    /// Directory service to use for plc lookups
+    #[arg(long, default_value = lookup::DEFAULT_PLC_DIRECTORY)]
+    plc_directory: String,
+}
+
+/// WAL files bigger than this are probably left over from a run that didn't shut down cleanly
+const LARGE_WAL_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn pass(&mut self, check: &str, detail: impl AsRef<str>) {
+        println!("PASS  {check}: {detail}", detail = detail.as_ref());
+    }
+
+    fn warn(&mut self, check: &str, detail: impl AsRef<str>, hint: &str) {
+        self.warnings += 1;
+        println!("WARN  {check}: {detail}", detail = detail.as_ref());
+        println!("      hint: {hint}");
+    }
+
+    fn fail(&mut self, check: &str, detail: impl AsRef<str>, hint: &str) {
+        self.failures += 1;
+        println!("FAIL  {check}: {detail}", detail = detail.as_ref());
+        println!("      hint: {hint}");
+    }
+}
+
+impl DoctorCmd {
+    pub async fn go(self) -> Result<()> {
+        let mut report = Report::default();
+
+        report.pass(
+            "sqlite",
+            format!("bundled sqlite version {}", rusqlite::version()),
+        );
+        if let Some(db_path) = &self.db {
+            check_db(&mut report, db_path, &self.table);
+        }
+        match lookup::Resolver::new(&self.plc_directory, Some(Duration::from_secs(5))) {
+            Ok(resolver) => self.check_lookups(&mut report, &resolver).await,
+            Err(e) => report.fail(
+                "dns configuration",
+                format!("{e}"),
+                "check the system's dns resolver configuration (e.g. /etc/resolv.conf)",
+            ),
+        }
+
+        println!();
+        println!(
+            "{failures} check(s) failed, {warnings} warning(s)",
+            failures = report.failures,
+            warnings = report.warnings,
+        );
+        if report.failures > 0 {
+            bail!("some checks failed");
+        }
+        Ok(())
+    }
+
+    /// checks that handles, did documents, and the --labeler (if any) can be looked up
+    async fn check_lookups(&self, report: &mut Report, resolver: &lookup::Resolver) {
+        let probe_did = check_resolution(report, resolver, &self.probe_handle).await;
+        if let Some(did) = probe_did {
+            match resolver.fetch_did_doc(&did).await {
+                Ok(_) => report.pass("did document", format!("fetched the document for {did}")),
+                Err(e) => report.fail(
+                    "did document",
+                    format!("could not fetch the document for {did}: {e}"),
+                    "check that the plc directory is reachable, or pass a different \
+                    --plc-directory",
+                ),
+            }
+        }
+        if let Some(labeler) = &self.labeler {
+            check_labeler(report, resolver, labeler).await;
+        }
+    }
+}
+
+fn check_db(report: &mut Report, db_path: &Path, table: &str) {
+    let dir = match db_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !dir.is_dir() {
+        report.fail(
+            "db directory",
+            format!("{dir} does not exist", dir = dir.display()),
+            "create the directory before saving to a database in it",
+        );
+        return;
+    }
+    let probe = dir.join(format!(".labelview-doctor-{}", std::process::id()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            report.pass(
+                "db directory",
+                format!("{dir} is writable", dir = dir.display()),
+            );
+        }
+        Err(e) => {
+            report.fail(
+                "db directory",
+                format!("{dir} is not writable: {e}", dir = dir.display()),
+                "choose a different location or fix the directory's permissions",
+            );
+            return;
+        }
+    }
+
+    if db_path.is_dir() {
+        report.fail(
+            "db file",
+            format!("{path} is a directory", path = db_path.display()),
+            "pass the path of a file inside the directory instead",
+        );
+        return;
+    }
+    if !db_path.exists() {
+        report.pass(
+            "db file",
+            format!(
+                "{path} does not exist yet and will be created",
+                path = db_path.display()
+            ),
+        );
+        return;
+    }
+
+    let mut wal_path = db_path.as_os_str().to_owned();
+    wal_path.push("-wal");
+    let wal_size = fs::metadata(&wal_path).map(|m| m.len()).unwrap_or(0);
+
+    // read-only, so that checking the database doesn't set it up or checkpoint its write-ahead log
+    let db = match db::open_read_only(db_path) {
+        Ok(db) => db,
+        Err(e) => {
+            report.fail(
+                "db file",
+                format!("could not open {path}: {e}", path = db_path.display()),
+                "make sure the file is a sqlite database written by labelview",
+            );
+            return;
+        }
+    };
+    if let Err(e) = db::validate_table_name(table) {
+        report.fail("db table", format!("{e}"), "choose a different --table");
+        return;
+    }
+    let versions = db::database_schema_version(&db)
+        .and_then(|version| Ok((version, db::label_table_schema_version(&db, table)?)));
+    let current = (db::DATABASE_SCHEMA_VERSION, db::LABEL_TABLE_SCHEMA_VERSION);
+    match versions {
+        Ok(versions) if versions == current => {
+            report.pass(
+                "db schema",
+                format!(
+                    "schema version {version} and table {table} at version {table_version} are \
+                    up to date",
+                    version = versions.0,
+                    table_version = versions.1,
+                ),
+            );
+        }
+        Ok(versions) if versions.0 <= current.0 && versions.1 <= current.1 => {
+            report.warn(
+                "db schema",
+                format!(
+                    "schema version {version} and table {table} at version {table_version} are \
+                    older than the current versions {current_version} and {current_table_version}",
+                    version = versions.0,
+                    table_version = versions.1,
+                    current_version = current.0,
+                    current_table_version = current.1,
+                ),
+                "they will be upgraded the next time they are used, or run `labelview migrate \
+                --db <file> --table <table>` to upgrade them now",
+            );
+            return;
+        }
+        Ok(versions) => {
+            report.fail(
+                "db schema",
+                format!(
+                    "schema version {version} or table {table} at version {table_version} is \
+                    newer than this version of labelview understands ({current_version} and \
+                    {current_table_version})",
+                    version = versions.0,
+                    table_version = versions.1,
+                    current_version = current.0,
+                    current_table_version = current.1,
+                ),
+                "upgrade labelview to use this database",
+            );
+            return;
+        }
+        Err(e) => {
+            report.fail(
+                "db schema",
+                format!("could not read the schema version: {e}"),
+                "make sure the file is a sqlite database written by labelview",
+            );
+            return;
+        }
+    }
+    match count_rows(&db, table) {
+        Ok((records, runs)) => report.pass(
+            "db file",
+            format!("{records} label record(s) from {runs} run(s)"),
+        ),
+        Err(e) => report.fail(
+            "db file",
+            format!("could not read the tables: {e}"),
+            "the database may be corrupt; try `sqlite3 <file> 'PRAGMA integrity_check'`",
+        ),
+    }
+    match list_indexes(&db, table) {
+        Ok(indexes) if indexes.is_empty() => {
+            report.pass("db indexes", format!("{table} has no indexes"))
+        }
+        Ok(indexes) => report.pass("db indexes", indexes.join(", ")),
+        Err(e) => report.fail(
+            "db indexes",
+            format!("could not list indexes: {e}"),
+            "the database may be corrupt; try `sqlite3 <file> 'PRAGMA integrity_check'`",
+        ),
+    }
+    if wal_size > LARGE_WAL_BYTES {
+        report.warn(
+            "db wal",
+            format!("write-ahead log is {wal_size} bytes"),
+            "a previous run may have crashed; opening and closing the database with labelview \
+            or sqlite3 checkpoints it",
+        );
+    } else {
+        report.pass("db wal", format!("write-ahead log is {wal_size} bytes"));
+    }
+}
+
+fn count_rows(db: &db::Connection, table: &str) -> rusqlite::Result<(i64, i64)> {
+    let records = db.query_row(&format!(r#"SELECT COUNT(*) FROM "{table}";"#), [], |row| {
+        row.get(0)
+    })?;
+    let runs = db.query_row("SELECT COUNT(*) FROM run_stats;", [], |row| row.get(0))?;
+    Ok((records, runs))
+}
+
+fn list_indexes(db: &db::Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db.prepare("SELECT name FROM pragma_index_list(:table);")?;
+    let indexes = stmt
+        .query_map(rusqlite::named_params!(":table": table), |row| row.get(0))?
+        .collect();
+    indexes
+}
+
+/// checks that the handle resolves over both dns and HTTPS, returning its did if either works
+async fn check_resolution(
+    report: &mut Report,
+    resolver: &lookup::Resolver,
+    handle: &str,
+) -> Option<String> {
+    let from_dns = resolver
+        .find_did_in_dns(&format!("_atproto.{handle}"))
+        .await;
+    match &from_dns {
+        Some(did) => report.pass("dns TXT lookup", format!("{handle} -> {did}")),
+        None => report.fail(
+            "dns TXT lookup",
+            format!("could not resolve {handle}"),
+            "check the system's dns resolver configuration (e.g. /etc/resolv.conf)",
+        ),
+    }
+    let from_https = resolver.find_did_in_well_known(handle).await;
+    match &from_https {
+        Some(did) => report.pass("HTTPS .well-known lookup", format!("{handle} -> {did}")),
+        None => report.fail(
+            "HTTPS .well-known lookup",
+            format!("could not resolve {handle}"),
+            "check that outgoing HTTPS connections are allowed and that the system's root \
+            certificates are installed",
+        ),
+    }
+    from_dns.or(from_https)
+}
+
+async fn check_labeler(report: &mut Report, resolver: &lookup::Resolver, labeler: &str) {
+    let endpoint = async {
+        let did = resolver.resolve_handle(labeler).await?;
+        let doc = resolver.fetch_did_doc(&did).await?;
+        let Some(endpoint) = lookup::service_from_doc(&doc, "#atproto_labeler", "AtprotoLabeler")
+        else {
+            bail!("{did} does not list a labeler endpoint");
+        };
+        let endpoint = Url::parse(endpoint)?;
+        let Some(domain) = endpoint.domain() else {
+            bail!("labeler endpoint {endpoint} does not specify a domain");
+        };
+        Ok(domain.to_owned())
+    }
+    .await;
+    let domain = match endpoint {
+        Ok(domain) => {
+            report.pass(
+                "labeler endpoint",
+                format!("{labeler} is served from {domain}"),
+            );
+            domain
+        }
+        Err(e) => {
+            report.fail(
+                "labeler endpoint",
+                format!("{e}"),
+                "check the handle or did, or use `direct` if you know the labeler's domain",
+            );
+            return;
+        }
+    };
+
+    let address = format!("wss://{domain}/xrpc/com.atproto.label.subscribeLabels");
+    match tokio::time::timeout(Duration::from_secs(10), connect_async(&address)).await {
+        Ok(Ok(_)) => report.pass("labeler websocket", format!("connected to {address}")),
+        Ok(Err(e)) => report.fail(
+            "labeler websocket",
+            format!("could not connect to {address}: {e}"),
+            "the labeler service may be down or may not support label subscriptions",
+        ),
+        Err(_) => report.fail(
+            "labeler websocket",
+            format!("timed out connecting to {address}"),
+            "the labeler service may be down, or a firewall may be dropping the connection",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checking_a_database_leaves_it_unchanged() {
+        let path = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-doctor.sqlite",
+            pid = std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let db = db::connect(&path, db::DEFAULT_LABEL_TABLE).unwrap();
+        // as written by an older labelview, before databases used a write-ahead log or recorded
+        // their schema versions
+        db.execute_batch(
+            "DROP TABLE schema_version; PRAGMA user_version = 0; PRAGMA journal_mode = DELETE;",
+        )
+        .unwrap();
+        drop(db);
+        let before = fs::read(&path).unwrap();
+
+        let mut report = Report::default();
+        check_db(&mut report, &path, db::DEFAULT_LABEL_TABLE);
+        // it is only pointed out as needing an upgrade
+        assert_eq!((report.warnings, report.failures), (1, 0));
+        assert!(
+            fs::read(&path).unwrap() == before,
+            "the database was changed"
+        );
+        let mut wal_path = path.as_os_str().to_owned();
+        wal_path.push("-wal");
+        assert!(!Path::new(&wal_path).exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,834 @@
+use crate::db::{self, now, LabelRecord, Receipt, SavedRecord};
+use clap::{Args, ValueEnum};
+use comfy_table::{presets, CellAlignment, ColumnConstraint, ContentArrangement, Table};
+use eyre::{bail, eyre as err, Result};
+use flate2::write::GzEncoder;
+use itertools::Itertools;
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Args)]
+pub struct ExportCmd {
+    /// Sqlite file previously written with --save-to-db
+    db: PathBuf,
+    /// Name of the table of label records to read
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = crate::parse_table_name)]
+    table: String,
+    #[command(flatten)]
+    filter: crate::RecordFilterArgs,
+    /// Format to write the records in
+    #[arg(long, value_enum, default_value_t = ExportFormat::Ndjson)]
+    format: ExportFormat,
+    /// File to write the records to, instead of stdout. It is compressed if its name ends in .gz
+    /// or .zst
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+    /// Compress the output, whatever the --output file is named
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+    /// Begin with an object describing the export: the source dids, the greatest seq, and when it
+    /// was generated
+    #[arg(long)]
+    with_meta: bool,
+    /// Indent the JSON so it is easier to read by eye. NDJSON stays one record per line
+    #[arg(long)]
+    json_pretty: bool,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ExportFormat {
+    /// One JSON object per line
+    Ndjson,
+    /// A single JSON array of records, or an object with "meta" and "records" when --with-meta is
+    /// given
+    Json,
+    /// A table to read in a terminal, fitted to its width. The whole table is built before any of
+    /// it is written, so this suits a filtered selection better than a large database
+    Table,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Compression {
+    /// gzip, as for .gz files
+    Gzip,
+    /// zstandard, as for .zst files
+    Zstd,
+}
+
+impl Compression {
+    /// the compression a file's extension calls for, if any
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Self::Gzip),
+            "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// Where an export is written, through an encoder if it is compressed
+enum Output {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+}
+
+impl Output {
+    fn new(out: Box<dyn Write>, compression: Option<Compression>) -> Result<Self> {
+        Ok(match compression {
+            None => Self::Plain(out),
+            Some(Compression::Gzip) => {
+                Self::Gzip(GzEncoder::new(out, flate2::Compression::default()))
+            }
+            Some(Compression::Zstd) => Self::Zstd(zstd::Encoder::new(out, 0)?),
+        })
+    }
+
+    /// writes out the end of the compressed stream, if there is one, and flushes everything
+    fn finish(self) -> Result<()> {
+        let mut out = match self {
+            Self::Plain(out) => out,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        out.flush()?;
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(out) => out.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(out) => out.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Describes an export, for --with-meta
+#[derive(Serialize)]
+struct Meta {
+    src: Vec<String>,
+    cursor: Option<i64>,
+    generated_at: String,
+}
+
+/// A label record as written by export
+#[derive(Serialize)]
+struct ExportRecord<'a> {
+    src: &'a str,
+    uri: &'a str,
+    val: &'a str,
+    /// None for records fetched with queryLabels
+    seq: Option<i64>,
+    cts: &'a str,
+    exp: Option<&'a str>,
+    neg: bool,
+    cid: Option<&'a str>,
+    /// name of the multicodec the target cid's content is encoded with
+    cid_codec: Option<String>,
+    /// name of the multihash function the target cid was hashed with
+    cid_hash: Option<String>,
+    /// signature bytes in hex
+    sig: Option<String>,
+    seen_at: &'a str,
+    fetched_from: Option<&'a str>,
+    run_id: Option<i64>,
+    /// index of the label message the record came in within its run
+    frame_index: Option<i64>,
+}
+
+impl ExportCmd {
+    pub fn go(self) -> Result<()> {
+        if !self.db.is_file() {
+            bail!("no database found at {path}", path = self.db.display());
+        }
+        if self.with_meta && matches!(self.format, ExportFormat::Table) {
+            bail!("--with-meta can't be used with --format table");
+        }
+        let db = db::open_for_reading(&self.db, &self.table)?;
+        let filter = self.filter.to_filter();
+
+        let out: Box<dyn Write> = match &self.output {
+            Some(path) => Box::new(
+                File::create(path)
+                    .map_err(|e| err!("error creating {path}: {e}", path = path.display()))?,
+            ),
+            None => Box::new(io::stdout().lock()),
+        };
+        let compression = self
+            .compress
+            .or_else(|| self.output.as_deref().and_then(Compression::from_extension));
+        let mut out = BufWriter::new(Output::new(out, compression)?);
+
+        let meta = if self.with_meta {
+            Some(Meta {
+                src: db::get_distinct_src_dids(&db, &self.table, &filter)?,
+                cursor: db::max_seq(&db, &self.table, &filter)?,
+                generated_at: now().to_rfc3339(),
+            })
+        } else {
+            None
+        };
+
+        match self.format {
+            ExportFormat::Ndjson => {
+                if let Some(meta) = &meta {
+                    #[derive(Serialize)]
+                    struct MetaLine<'a> {
+                        meta: &'a Meta,
+                    }
+                    serde_json::to_writer(&mut out, &MetaLine { meta })?;
+                    writeln!(out)?;
+                }
+                db::for_each_record(&db, &self.table, &filter, |saved| {
+                    serde_json::to_writer(&mut out, &ExportRecord::new(&saved))?;
+                    writeln!(out)?;
+                    Ok(())
+                })?;
+            }
+            ExportFormat::Json => {
+                // records are written as they are read rather than collected into one array value,
+                // so large exports don't have to fit in memory
+                let indent = if self.json_pretty { "  " } else { "" };
+                let newline = if self.json_pretty { "\n" } else { "" };
+                let mut records_indent = indent;
+                if let Some(meta) = &meta {
+                    write!(out, "{{{newline}{indent}\"meta\":")?;
+                    if self.json_pretty {
+                        write!(out, " ")?;
+                    }
+                    self.write_json(&mut out, meta, indent)?;
+                    write!(out, ",{newline}{indent}\"records\":")?;
+                    if self.json_pretty {
+                        write!(out, " ")?;
+                    }
+                    records_indent = "    ";
+                }
+                write!(out, "[")?;
+                let mut first = true;
+                db::for_each_record(&db, &self.table, &filter, |saved| {
+                    if !first {
+                        write!(out, ",")?;
+                    }
+                    first = false;
+                    if self.json_pretty {
+                        write!(out, "\n{records_indent}")?;
+                    }
+                    self.write_json(&mut out, &ExportRecord::new(&saved), records_indent)?;
+                    Ok(())
+                })?;
+                if self.json_pretty && !first {
+                    write!(out, "\n{indent}", indent = &records_indent[2..])?;
+                }
+                write!(out, "]")?;
+                if meta.is_some() {
+                    write!(out, "{newline}}}")?;
+                }
+                writeln!(out)?;
+            }
+            ExportFormat::Table => {
+                // symbols only when they will be seen in a terminal that may show color
+                let symbols = self.output.is_none()
+                    && io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none();
+                let width = match self.output {
+                    None => Some(crate::output_width()),
+                    Some(_) => None,
+                };
+                let table = record_table(&db, &self.table, &filter, width, symbols)?;
+                writeln!(out, "{table}")?;
+            }
+        }
+        out.into_inner()
+            .map_err(|e| err!("error writing export: {e}", e = e.error()))?
+            .finish()?;
+        Ok(())
+    }
+
+    /// writes a value as JSON, pretty printed with every line after the first indented if asked for
+    fn write_json(&self, out: &mut impl Write, value: &impl Serialize, indent: &str) -> Result<()> {
+        if self.json_pretty {
+            let json = serde_json::to_string_pretty(value)?;
+            write!(out, "{}", json.replace('\n', &format!("\n{indent}")))?;
+        } else {
+            serde_json::to_writer(out, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// widest the target column of a table is allowed to be, not counting padding
+const TARGET_WIDTH: usize = 60;
+
+/// Builds a table of the records selected by the filter. It is fitted to `width` if one is given,
+/// and negations are marked with a check mark if `symbols` is set or with Y/N otherwise
+fn record_table(
+    db: &rusqlite::Connection,
+    table_name: &str,
+    filter: &db::RecordFilter,
+    width: Option<usize>,
+    symbols: bool,
+) -> Result<Table> {
+    let mut table = Table::new();
+    table
+        .load_preset(presets::ASCII_BORDERS_ONLY_CONDENSED)
+        .set_header(["SEQ", "VAL", "NEG", "SRC", "TARGET", "CREATED", "SEEN AT"]);
+    if let Some(width) = width {
+        table
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_width(width.try_into().unwrap_or(u16::MAX));
+    }
+    let (negated, applied) = if symbols { ("✓", "✗") } else { ("Y", "N") };
+    db::for_each_record(db, table_name, filter, |saved| {
+        let record = &saved.record;
+        let key = &record.dbkey.key;
+        let target = key
+            .target_uri
+            .strip_prefix("at://")
+            .unwrap_or(&key.target_uri);
+        let target = match target.split_once('/') {
+            Some((did, path)) => format!("{did}/{path}", did = short_did(did)),
+            None => short_did(target).into_owned(),
+        };
+        table.add_row([
+            record
+                .dbkey
+                .seq
+                .map(|seq| seq.to_string())
+                .unwrap_or_default(),
+            key.val.to_string(),
+            if record.neg { negated } else { applied }.to_owned(),
+            short_did(key.src.as_str()).into_owned(),
+            crate::truncate(&target, TARGET_WIDTH).into_owned(),
+            table_time(&record.create_timestamp),
+            table_time(&saved.seen_at_timestamp),
+        ]);
+        Ok(())
+    })?;
+    if let Some(column) = table.column_mut(0) {
+        column.set_cell_alignment(CellAlignment::Right);
+    }
+    // the short columns keep their width, so that only the target and value wrap when space is
+    // tight
+    for column in [0, 2, 3, 5, 6] {
+        if let Some(column) = table.column_mut(column) {
+            column.set_constraint(ColumnConstraint::ContentWidth);
+        }
+    }
+    Ok(table)
+}
+
+/// shows a timestamp to the second in UTC, or as it is if it can't be read
+fn table_time(timestamp: &str) -> String {
+    match db::parse_datetime(timestamp) {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%SZ").to_string(),
+        None => timestamp.to_owned(),
+    }
+}
+
+/// shortens a did to its method and the last 8 characters of its identifier, like
+/// `did:plc:...abcd1234`, so a table column of them stays narrow but tells them apart
+fn short_did(did: &str) -> Cow<'_, str> {
+    let Some((method, id)) = did
+        .strip_prefix("did:")
+        .and_then(|rest| rest.split_once(':'))
+    else {
+        return Cow::Borrowed(did);
+    };
+    let id_chars = id.chars().count();
+    if id_chars <= 11 {
+        return Cow::Borrowed(did);
+    }
+    let end: String = id.chars().skip(id_chars - 8).collect();
+    Cow::Owned(format!("did:{method}:...{end}"))
+}
+
+impl<'a> ExportRecord<'a> {
+    fn new(saved: &'a SavedRecord) -> Self {
+        Self::received(
+            &saved.record,
+            &saved.seen_at_timestamp,
+            saved.fetched_from.as_deref(),
+            saved.run_id,
+            saved.frame_index,
+        )
+    }
+
+    /// describes a record along with where and when it was received
+    fn received(
+        record: &'a LabelRecord,
+        seen_at: &'a str,
+        fetched_from: Option<&'a str>,
+        run_id: Option<i64>,
+        frame_index: Option<i64>,
+    ) -> Self {
+        let key = &record.dbkey.key;
+        let cid = record
+            .target_cid
+            .as_deref()
+            .and_then(|cid| cid.parse::<cid::Cid>().ok());
+        Self {
+            src: key.src.as_str(),
+            uri: &key.target_uri,
+            val: &key.val,
+            seq: record.dbkey.seq,
+            cts: &record.create_timestamp,
+            exp: record.expiry_timestamp.as_deref(),
+            neg: record.neg,
+            cid: record.target_cid.as_deref(),
+            cid_codec: cid.map(|cid| codec_name(cid.codec())),
+            cid_hash: cid.map(|cid| hash_name(cid.hash().code())),
+            sig: record
+                .sig
+                .as_ref()
+                .map(|sig| sig.iter().map(|b| format!("{b:02x}")).collect()),
+            seen_at,
+            fetched_from,
+            run_id,
+            frame_index,
+        }
+    }
+
+    /// the record's fields in the order of CSV_COLUMNS, empty where they are missing
+    fn csv_fields(&self) -> [Cow<'a, str>; CSV_COLUMNS.len()] {
+        let text = |value: Option<&'a str>| Cow::Borrowed(value.unwrap_or_default());
+        let number =
+            |value: Option<i64>| Cow::Owned(value.map(|n| n.to_string()).unwrap_or_default());
+        [
+            Cow::Borrowed(self.src),
+            Cow::Borrowed(self.uri),
+            Cow::Borrowed(self.val),
+            number(self.seq),
+            Cow::Borrowed(self.cts),
+            text(self.exp),
+            Cow::Owned(self.neg.to_string()),
+            text(self.cid),
+            Cow::Owned(self.cid_codec.clone().unwrap_or_default()),
+            Cow::Owned(self.cid_hash.clone().unwrap_or_default()),
+            Cow::Owned(self.sig.clone().unwrap_or_default()),
+            Cow::Borrowed(self.seen_at),
+            text(self.fetched_from),
+            number(self.run_id),
+            number(self.frame_index),
+        ]
+    }
+}
+
+/// The header row of CSV written by --emit, naming the same fields as the JSON records
+const CSV_COLUMNS: [&str; 15] = [
+    "src",
+    "uri",
+    "val",
+    "seq",
+    "cts",
+    "exp",
+    "neg",
+    "cid",
+    "cid_codec",
+    "cid_hash",
+    "sig",
+    "seen_at",
+    "fetched_from",
+    "run_id",
+    "frame_index",
+];
+
+/// quotes a CSV field if it has any commas, quotes, or line breaks in it
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Format to write label records to stdout in as they are received
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum EmitFormat {
+    /// Comma-separated values, beginning with a header row
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+/// How long records written to stdout may sit in the buffer, so that the output is usable up to
+/// about then if the run is cut off
+const EMIT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Writes label records to stdout as they are received, for --emit
+pub struct RecordEmitter {
+    format: EmitFormat,
+    out: BufWriter<io::Stdout>,
+    last_flush: Instant,
+}
+
+impl RecordEmitter {
+    pub fn new(format: EmitFormat) -> Result<Self> {
+        let mut out = BufWriter::new(io::stdout());
+        if matches!(format, EmitFormat::Csv) {
+            writeln!(out, "{header}", header = CSV_COLUMNS.join(","))?;
+        }
+        Ok(Self {
+            format,
+            out,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// writes a record, streamed from the given labeler host
+    pub fn emit(
+        &mut self,
+        record: &LabelRecord,
+        fetched_from: &str,
+        receipt: &Receipt,
+    ) -> Result<()> {
+        let seen_at = receipt.seen_at.to_rfc3339();
+        let record = ExportRecord::received(
+            record,
+            &seen_at,
+            Some(fetched_from),
+            receipt.run_id,
+            receipt.frame_index,
+        );
+        match self.format {
+            EmitFormat::Csv => {
+                let fields = record.csv_fields();
+                writeln!(
+                    self.out,
+                    "{row}",
+                    row = fields.iter().map(|field| csv_field(field)).join(",")
+                )?;
+            }
+            EmitFormat::Ndjson => {
+                serde_json::to_writer(&mut self.out, &record)?;
+                writeln!(self.out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// flushes the records written so far if they have been waiting long enough
+    pub fn flush_if_due(&mut self) -> Result<()> {
+        if self.last_flush.elapsed() >= EMIT_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+}
+
+/// names the multicodecs that atproto uses, falling back to the code in hex
+///
+/// https://github.com/multiformats/multicodec/blob/master/table.csv
+fn codec_name(code: u64) -> String {
+    match code {
+        0x55 => "raw".to_owned(),
+        0x70 => "dag-pb".to_owned(),
+        0x71 => "dag-cbor".to_owned(),
+        code => format!("{code:#x}"),
+    }
+}
+
+/// names the multihash functions that atproto uses, falling back to the code in hex
+fn hash_name(code: u64) -> String {
+    match code {
+        0x12 => "sha2-256".to_owned(),
+        0x1e => "blake3".to_owned(),
+        code => format!("{code:#x}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cmd;
+    use clap::Parser;
+    use serde_json::{json, Value};
+
+    const SRC: &str = "did:plc:labeler";
+
+    /// a path for a test file that doesn't exist yet
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-export-{name}",
+            pid = std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// a database holding `count` records from one labeler, with seqs counting up from 1
+    fn db_with_records(name: &str, count: i64) -> PathBuf {
+        let path = temp_path(&format!("{name}.sqlite"));
+        let db = db::connect(&path, db::DEFAULT_LABEL_TABLE).unwrap();
+        for seq in 1..=count {
+            db.execute(
+                r#"
+                INSERT INTO label_records(
+                    src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+                )
+                VALUES (?1, ?2, 'spam', ?3, '2024-01-01T00:00:00Z', 0, '2024-01-01T00:00:00Z');
+                "#,
+                rusqlite::params![SRC, format!("did:plc:target{seq}"), seq],
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    /// runs `labelview export` on a database, writing to a file with the given name, and returns
+    /// what was written
+    fn export(db_path: &Path, output: &str, args: &[&str]) -> Vec<u8> {
+        let output = temp_path(output);
+        let command = [
+            "labelview",
+            "export",
+            db_path.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+        ]
+        .into_iter()
+        .chain(args.iter().copied());
+        match Cmd::try_parse_from(command).unwrap() {
+            Cmd::Export(cmd) => cmd.go().unwrap(),
+            _ => unreachable!(),
+        }
+        let written = std::fs::read(&output).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        written
+    }
+
+    #[test]
+    fn streamed_json_is_valid_whatever_its_shape() {
+        for count in 0..=2 {
+            let db_path = db_with_records(&format!("json-{count}"), count);
+            let mut compact_records = Vec::new();
+            for with_meta in [false, true] {
+                for pretty in [false, true] {
+                    let case = format!("{count} records, with_meta={with_meta}, pretty={pretty}");
+                    let mut args = vec!["--format=json"];
+                    if with_meta {
+                        args.push("--with-meta");
+                    }
+                    if pretty {
+                        args.push("--json-pretty");
+                    }
+                    let text = String::from_utf8(export(&db_path, "out.json", &args)).unwrap();
+                    let value: Value = serde_json::from_str(&text)
+                        .unwrap_or_else(|e| panic!("{case}: {e} in\n{text}"));
+
+                    let records = if with_meta {
+                        assert_eq!(value.as_object().unwrap().len(), 2, "{case}");
+                        let meta = &value["meta"];
+                        let src: &[&str] = if count > 0 { &[SRC] } else { &[] };
+                        assert_eq!(meta["src"], json!(src), "{case}");
+                        assert_eq!(
+                            meta["cursor"],
+                            json!((count > 0).then_some(count)),
+                            "{case}"
+                        );
+                        let generated_at = meta["generated_at"].as_str().unwrap();
+                        assert!(db::parse_datetime(generated_at).is_some(), "{case}");
+                        &value["records"]
+                    } else {
+                        &value
+                    };
+                    let records = records.as_array().unwrap();
+                    let seqs: Vec<_> = records.iter().map(|r| r["seq"].as_i64()).collect();
+                    let expected: Vec<_> = (1..=count).map(Some).collect();
+                    assert_eq!(seqs, expected, "{case}");
+                    // the same records whether or not they are pretty printed
+                    if pretty {
+                        assert_eq!(records, &compact_records, "{case}");
+                    } else {
+                        compact_records = records.clone();
+                    }
+
+                    // the hand written joins between the records are laid out like serde_json
+                    // lays out the rest
+                    let ending = match (pretty, with_meta, count) {
+                        (false, false, _) => "]\n",
+                        (false, true, _) => "]}\n",
+                        (true, false, 0) => "[]\n",
+                        (true, false, _) => "\n  }\n]\n",
+                        (true, true, 0) => "\n  \"records\": []\n}\n",
+                        (true, true, _) => "\n    }\n  ]\n}\n",
+                    };
+                    assert!(text.ends_with(ending), "{case}:\n{text}");
+                    if pretty {
+                        let start = if with_meta {
+                            "{\n  \"meta\": {\n    \""
+                        } else {
+                            "["
+                        };
+                        assert!(text.starts_with(start), "{case}:\n{text}");
+                        assert!(!text.lines().any(|line| line.ends_with(' ')), "{case}");
+                    } else {
+                        assert_eq!(text.lines().count(), 1, "{case}");
+                    }
+                }
+            }
+            std::fs::remove_file(&db_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn compression_follows_the_output_extension() {
+        let compression = |name: &str| Compression::from_extension(Path::new(name));
+        assert!(matches!(compression("out.gz"), Some(Compression::Gzip)));
+        assert!(matches!(
+            compression("out.ndjson.gz"),
+            Some(Compression::Gzip)
+        ));
+        assert!(matches!(compression("out.zst"), Some(Compression::Zstd)));
+        assert!(matches!(
+            compression("dir/out.json.zst"),
+            Some(Compression::Zstd)
+        ));
+        assert!(compression("out.ndjson").is_none());
+        assert!(compression("out.gz.json").is_none());
+        assert!(compression("gz").is_none());
+        assert!(compression(".zst").is_none());
+    }
+
+    #[test]
+    fn compressed_exports_decompress_to_the_plain_export() {
+        let db_path = db_with_records("compressed", 3);
+        let plain = export(&db_path, "out.ndjson", &[]);
+        assert_eq!(plain.iter().filter(|&&b| b == b'\n').count(), 3);
+
+        let gunzip = |bytes: Vec<u8>| {
+            let mut decompressed = Vec::new();
+            io::Read::read_to_end(
+                &mut flate2::read::GzDecoder::new(&bytes[..]),
+                &mut decompressed,
+            )
+            .unwrap();
+            decompressed
+        };
+        let unzstd = |bytes: Vec<u8>| zstd::decode_all(&bytes[..]).unwrap();
+
+        let gzipped = export(&db_path, "out.ndjson.gz", &[]);
+        assert_ne!(gzipped, plain);
+        assert_eq!(gunzip(gzipped), plain);
+        let zstded = export(&db_path, "out.ndjson.zst", &[]);
+        assert_ne!(zstded, plain);
+        assert_eq!(unzstd(zstded), plain);
+
+        // --compress applies whatever the file is named
+        assert_eq!(gunzip(export(&db_path, "out", &["--compress=gzip"])), plain);
+        assert_eq!(
+            unzstd(export(&db_path, "out.gz", &["--compress=zstd"])),
+            plain
+        );
+        std::fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn long_did_identifiers_are_shortened_to_their_last_8_characters() {
+        let cases = [
+            ("did:plc:abcdefghijklmnopqrstuvwx", "did:plc:...qrstuvwx"),
+            ("did:plc:abcdefghijkl", "did:plc:...efghijkl"),
+            ("did:web:labeler.example.com", "did:web:...mple.com"),
+            // counted in characters, not bytes
+            ("did:web:éééééééééééé", "did:web:...éééééééé"),
+            // too short for "..." to save anything
+            ("did:plc:abcdefghijk", "did:plc:abcdefghijk"),
+            ("did:web:a.example", "did:web:a.example"),
+            // not dids
+            ("labeler.example", "labeler.example"),
+            (
+                "did:plcabcdefghijklmnopqrstuvwx",
+                "did:plcabcdefghijklmnopqrstuvwx",
+            ),
+            (
+                "at://did:plc:abcdefghijklmnopqrstuvwx",
+                "at://did:plc:abcdefghijklmnopqrstuvwx",
+            ),
+        ];
+        for (did, short) in cases {
+            assert_eq!(short_did(did), short, "{did}");
+            assert_eq!(
+                matches!(short_did(did), Cow::Borrowed(_)),
+                did == short,
+                "{did}"
+            );
+        }
+    }
+
+    #[test]
+    fn record_tables_shorten_dids_and_mark_negations() {
+        let db = db::connect(Path::new(":memory:"), db::DEFAULT_LABEL_TABLE).unwrap();
+        let records = [
+            (
+                1,
+                "did:plc:abcdefghijklmnopqrstuvwx",
+                "did:plc:target",
+                "spam",
+                false,
+            ),
+            (
+                2,
+                SRC,
+                "at://did:plc:abcdefghijklmnopqrstuvwx/app.bsky.feed.post/3kabc",
+                "rude",
+                true,
+            ),
+        ];
+        for (seq, src, uri, val, neg) in records {
+            db.execute(
+                r#"
+                INSERT INTO label_records(
+                    src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+                )
+                VALUES (?1, ?2, ?3, ?4, '2024-01-01T00:00:00Z', ?5, '2024-01-02T03:04:05.678Z');
+                "#,
+                rusqlite::params![src, uri, val, seq, neg],
+            )
+            .unwrap();
+        }
+        let render = |symbols| {
+            record_table(
+                &db,
+                db::DEFAULT_LABEL_TABLE,
+                &db::RecordFilter::default(),
+                None,
+                symbols,
+            )
+            .unwrap()
+            .to_string()
+        };
+        let expected = [
+            "+-------------------------------------------------------------------------------------------------------------------------------------+",
+            "| SEQ   VAL    NEG   SRC                   TARGET                                         CREATED                SEEN AT              |",
+            "+=====================================================================================================================================+",
+            "|   1   spam   N     did:plc:...qrstuvwx   did:plc:target                                 2024-01-01 00:00:00Z   2024-01-02 03:04:05Z |",
+            "|   2   rude   Y     did:plc:labeler       did:plc:...qrstuvwx/app.bsky.feed.post/3kabc   2024-01-01 00:00:00Z   2024-01-02 03:04:05Z |",
+            "+-------------------------------------------------------------------------------------------------------------------------------------+",
+        ];
+        assert_eq!(render(false), expected.join("\n"));
+        let with_symbols = render(true);
+        assert!(with_symbols.contains(" ✗ ") && with_symbols.contains(" ✓ "));
+    }
+}
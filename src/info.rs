@@ -0,0 +1,158 @@
+use crate::{atproto::Did, lookup};
+use clap::Args;
+use eyre::{bail, Result};
+
+#[derive(Debug, Args)]
+pub struct InfoCmd {
+    /// Handle or DID of the account
+    handle_or_did: String,
+    /// Directory service to use for plc lookups
+    #[arg(long, default_value = lookup::DEFAULT_PLC_DIRECTORY)]
+    plc_directory: String,
+    /// Timeout for each attempt to resolve a handle to a did (via dns, then via HTTPS), in
+    /// seconds. Non-positive values wait forever
+    #[arg(long, default_value = "5")]
+    dns_timeout: f64,
+    /// Also show the did's history from the plc directory's operation log: when it was created,
+    /// and when its handle, pds, and labeler endpoint changed. Only did:plc identities have one
+    #[arg(long)]
+    plc_log: bool,
+}
+
+/// The parts of a did's document that a plc operation sets which are shown in its history
+struct PlcState {
+    handle: Option<String>,
+    pds: Option<String>,
+    labeler: Option<String>,
+}
+
+impl PlcState {
+    /// reads the state an operation leaves the did in, if it is a kind of operation we know
+    fn from_operation(op: &serde_json::Value) -> Option<Self> {
+        let text = |value: &serde_json::Value| value.as_str().map(str::to_owned);
+        match op["type"].as_str()? {
+            "plc_operation" => Some(Self {
+                handle: op["alsoKnownAs"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .find_map(|aka| aka.as_str()?.strip_prefix("at://"))
+                    .map(str::to_owned),
+                pds: text(&op["services"]["atproto_pds"]["endpoint"]),
+                labeler: text(&op["services"]["atproto_labeler"]["endpoint"]),
+            }),
+            // the original kind of operation, which predates labelers
+            "create" => Some(Self {
+                handle: text(&op["handle"]),
+                pds: text(&op["service"]),
+                labeler: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// describes what changed from the previous state, or everything set if there wasn't one
+    fn changes_from(&self, previous: Option<&PlcState>) -> Vec<String> {
+        let Some(previous) = previous else {
+            let mut changes = vec!["created".to_owned()];
+            changes.extend(self.handle.iter().map(|handle| format!("handle {handle}")));
+            changes.extend(self.pds.iter().map(|pds| format!("pds {pds}")));
+            changes.extend(
+                self.labeler
+                    .iter()
+                    .map(|labeler| format!("labeler endpoint {labeler}")),
+            );
+            return changes;
+        };
+        let mut changes = Vec::new();
+        for (what, before, after) in [
+            ("handle", &previous.handle, &self.handle),
+            ("pds", &previous.pds, &self.pds),
+            ("labeler endpoint", &previous.labeler, &self.labeler),
+        ] {
+            match (before, after) {
+                (None, Some(after)) => changes.push(format!("{what} set to {after}")),
+                (Some(before), None) => changes.push(format!("{what} {before} removed")),
+                (Some(before), Some(after)) if before != after => {
+                    changes.push(format!("{what} changed from {before} to {after}"));
+                }
+                _ => {}
+            }
+        }
+        if changes.is_empty() {
+            changes.push("keys or other services changed".to_owned());
+        }
+        changes
+    }
+}
+
+impl InfoCmd {
+    pub async fn go(self) -> Result<()> {
+        let dns_timeout = lookup::timeout_from_secs(self.dns_timeout);
+        let resolver = lookup::Resolver::new(&self.plc_directory, dns_timeout)?;
+        println!("looking up did...");
+        let did = resolver.resolve_handle(&self.handle_or_did).await?;
+        let doc = resolver.fetch_did_doc(&did).await?;
+        let handle = lookup::handle_from_doc(&doc);
+        let pds = lookup::service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer");
+        let labeler = lookup::service_from_doc(&doc, "#atproto_labeler", "AtprotoLabeler");
+
+        println!();
+        println!(
+            "handle: {handle}",
+            handle = handle.unwrap_or("(no handle listed in did)")
+        );
+        println!("did:    {did}");
+        println!();
+        println!(
+            "pds:     {pds}",
+            pds = pds.unwrap_or("(no pds endpoint defined)")
+        );
+        println!(
+            "labeler: {labeler}",
+            labeler = labeler.unwrap_or("(no labeler endpoint defined)")
+        );
+
+        if !self.plc_log {
+            return Ok(());
+        }
+        let parsed: Did = did.parse()?;
+        if parsed.method() != "plc" {
+            bail!(
+                "{did} is a did:{method}; only did:plc identities have an operation log",
+                method = parsed.method()
+            );
+        }
+        let log = resolver.plc_log(&did).await?;
+        let Some(entries) = log.as_array() else {
+            bail!("the plc log from the plc directory isn't a list of operations");
+        };
+
+        println!();
+        println!("plc operation log:");
+        let mut state: Option<PlcState> = None;
+        for entry in entries {
+            let created_at = entry["createdAt"].as_str().unwrap_or("(unknown time)");
+            let op = &entry["operation"];
+            let op_type = op["type"].as_str().unwrap_or("(no type)");
+            if entry["nullified"].as_bool() == Some(true) {
+                // undone by an operation signed with a higher-priority rotation key
+                println!("   {created_at}  {op_type} (nullified by a later operation)");
+                continue;
+            }
+            if op_type == "plc_tombstone" {
+                println!("   {created_at}  deactivated");
+                state = None;
+                continue;
+            }
+            let Some(new_state) = PlcState::from_operation(op) else {
+                println!("   {created_at}  operation of unknown type {op_type:?}");
+                continue;
+            };
+            let changes = new_state.changes_from(state.as_ref());
+            println!("   {created_at}  {changes}", changes = changes.join("; "));
+            state = Some(new_state);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1577 @@
+#[cfg(feature = "tui")]
+use crate::tui;
+use crate::{
+    db::{self, now, DateTime, LabelRecord, QueriedLabels, SIGNATURE_LENGTH},
+    format_duration_ms, lookup, truncate, GetCommonArgs, LabelStore, Mode, SAY_PREFIX,
+};
+use eyre::{bail, eyre as err, Result};
+use futures_util::StreamExt;
+use itertools::Itertools;
+use pin_project_lite::pin_project;
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    future::Future,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::{
+        mpsc::{channel, Receiver},
+        Notify,
+    },
+    time::{sleep, Sleep},
+};
+use tokio_tungstenite::{
+    connect_async_with_config,
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        error::CapacityError,
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue},
+        protocol::WebSocketConfig,
+        Message,
+    },
+};
+use url::Url;
+
+/// How labels are read from the labeler, with what that way of reading keeps track of over a run
+pub enum Source {
+    Subscribe(Subscription),
+    Poll(Polling),
+}
+
+impl Source {
+    /// reads labels from the labeler into the store over one connection, or until polling runs out
+    /// of pages
+    pub async fn read(
+        &mut self,
+        store: &mut LabelStore,
+        config: &StreamConfig,
+        labeler_domain: &str,
+    ) -> Result<StreamResult> {
+        match self {
+            Source::Subscribe(subscription) => {
+                subscription
+                    .stream_from_service(store, config, labeler_domain)
+                    .await
+            }
+            Source::Poll(polling) => {
+                polling
+                    .poll_from_service(store, config, labeler_domain)
+                    .await
+            }
+        }
+    }
+
+    /// the greatest seq received from the subscription stream. queryLabels doesn't say what seq
+    /// labels had, so polling leaves it at zero
+    pub fn cursor(&self) -> i64 {
+        match self {
+            Source::Subscribe(subscription) => subscription.cursor,
+            Source::Poll(_) => 0,
+        }
+    }
+
+    /// the queryLabels cursor of the next page to poll, if polling has been given or got one
+    pub fn query_cursor(&self) -> Option<&str> {
+        match self {
+            Source::Subscribe(_) => None,
+            Source::Poll(polling) => polling.query_cursor.as_deref(),
+        }
+    }
+
+    pub fn subscription(&self) -> Option<&Subscription> {
+        match self {
+            Source::Subscribe(subscription) => Some(subscription),
+            Source::Poll(_) => None,
+        }
+    }
+
+    /// picks up reading where a run that was cut off left off, given what it saved and the #info
+    /// messages it got, and describes where that is
+    pub fn resume(
+        &mut self,
+        run: db::UnfinishedRun,
+        progress: &db::RunProgress,
+        info_counts: BTreeMap<String, usize>,
+    ) -> String {
+        match self {
+            Source::Subscribe(subscription) => {
+                subscription.info_counts = info_counts;
+                subscription.first_seq_received = progress.first_seq;
+                if run.start_cursor == 0 {
+                    // the interrupted run started from the beginning of the labeler's history
+                    subscription.first_available_seq = progress.first_seq;
+                }
+                subscription.cursor = subscription
+                    .cursor
+                    .max(run.start_cursor)
+                    .max(progress.last_seq.unwrap_or(0));
+                format!("cursor {cursor}", cursor = subscription.cursor)
+            }
+            Source::Poll(polling) => {
+                if run.query_cursor.is_some() {
+                    polling.query_cursor = run.query_cursor;
+                }
+                match &polling.query_cursor {
+                    Some(cursor) => format!("query cursor {cursor:?}"),
+                    None => "the beginning".to_owned(),
+                }
+            }
+        }
+    }
+}
+
+/// What reading the label subscription stream keeps track of over a run, apart from the labels
+pub struct Subscription {
+    /// cursor (largest known seq)
+    pub cursor: i64,
+    /// cursor the current connection to the labeler started from
+    connection_start_cursor: i64,
+    /// whether to fail on messages of unknown types and malformed signatures rather than count them
+    pub strict: bool,
+    /// most labels to accept in a single label update
+    pub max_batch: usize,
+    /// limit on how many messages are buffered, from --max-buffer-memory
+    pub buffer_budget: Option<BufferBudget>,
+    /// number of #info messages received, by name
+    pub info_counts: BTreeMap<String, usize>,
+    /// whether the labeler told us our cursor was outdated and we have yet to see where its history
+    /// picks up
+    pub awaiting_history_start: bool,
+    /// earliest seq the labeler had after telling us our cursor was outdated
+    pub history_start: Option<i64>,
+    /// seq of the first label record we received this run
+    pub first_seq_received: Option<i64>,
+    /// oldest seq the labeler still has, if we learned it this run
+    pub first_available_seq: Option<i64>,
+    /// number of messages skipped for each unrecognized event stream message type
+    pub unknown_message_types: BTreeMap<String, usize>,
+    /// number of text websocket messages received, which aren't part of the event stream
+    pub text_messages: usize,
+    /// the first few distinct text messages received, shortened
+    pub text_message_samples: Vec<String>,
+}
+
+impl Subscription {
+    /// starts reading the stream after the given cursor
+    pub fn new(cursor: i64) -> Self {
+        Self {
+            cursor,
+            connection_start_cursor: cursor,
+            strict: false,
+            max_batch: usize::MAX,
+            buffer_budget: None,
+            info_counts: BTreeMap::new(),
+            awaiting_history_start: false,
+            history_start: None,
+            first_seq_received: None,
+            first_available_seq: None,
+            unknown_message_types: BTreeMap::new(),
+            text_messages: 0,
+            text_message_samples: Vec::new(),
+        }
+    }
+
+    /// prints the messages the stream sent besides labels and info, and which seqs were captured
+    pub fn print_details(&self) {
+        if self.text_messages > 0 {
+            say!(
+                "received {count} text websocket message(s), such as:",
+                count = self.text_messages
+            );
+            for sample in &self.text_message_samples {
+                say!("   {sample:?}");
+            }
+        }
+        if !self.unknown_message_types.is_empty() {
+            say!("skipped messages of unknown type:");
+            for (ty, count) in &self.unknown_message_types {
+                say!("   {ty:?}: {count}");
+            }
+        }
+        match (self.first_seq_received, self.first_available_seq) {
+            (Some(first), Some(available)) => say!(
+                "captured seqs {first} through {cursor} of the {available} through {cursor} the \
+                labeler has available",
+                cursor = self.cursor,
+            ),
+            (Some(first), None) => say!(
+                "captured seqs {first} through {cursor}; the labeler's oldest available seq is \
+                unknown (stream from cursor 0 to learn it)",
+                cursor = self.cursor,
+            ),
+            (None, _) => {}
+        }
+    }
+}
+
+/// Where polling with queryLabels has got to
+pub struct Polling {
+    /// the queryLabels cursor of the next page to poll, if polling has been given or got one. It is
+    /// opaque, so it is kept apart from the subscription cursor
+    pub query_cursor: Option<String>,
+}
+
+enum StreamHeaderType {
+    Type(String),
+    Error,
+}
+
+/// Reads an event stream frame header type
+///
+/// https://atproto.com/specs/event-stream#streaming-wire-protocol-v0
+fn header_type(bin: &mut &[u8]) -> Result<StreamHeaderType> {
+    #[derive(Deserialize)]
+    struct Header {
+        op: i64,
+        t: Option<String>,
+    }
+    Ok(
+        match ciborium::from_reader(bin)
+            .map_err(|e| err!("error decoding event stream header: {e}"))?
+        {
+            Header { op: 1, t: Some(t) } => StreamHeaderType::Type(t),
+            Header { op: -1, t: None } => StreamHeaderType::Error,
+            malformed => bail!(
+                "received a malformed event stream header: op {op}",
+                op = malformed.op,
+            ),
+        },
+    )
+}
+
+pub enum StreamResult {
+    Ok,
+    /// the cursor advanced by the requested `--cursor-step` and we should reconnect
+    Stepped,
+    Closed,
+    WebsocketError,
+    AtprotoError {
+        error: String,
+        message: Option<String>,
+    },
+}
+
+/// Checks that a host looks like a labeler, by querying it for labels or failing that by opening a
+/// label subscription
+pub async fn preflight(
+    http_client: &reqwest::Client,
+    labeler_domain: &str,
+    connect_timeout: Option<Duration>,
+) -> Result<()> {
+    say!("checking that {labeler_domain} serves labels...");
+    let query_error = match lookup::query_labels(http_client, labeler_domain).await {
+        Ok(()) => {
+            say!("OK --> {labeler_domain} answers label queries");
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    let address = subscription_url(labeler_domain, None, &[])?;
+    let timeout = SleepHandle::new(connect_timeout);
+    let subscribe_error = select! {
+        () = timeout => format!("timed out connecting to {address}"),
+        connected = connect_async_with_config(address.as_str(), None, false) => match connected {
+            Ok(_) => {
+                say!("OK --> {labeler_domain} accepts label subscriptions");
+                return Ok(());
+            }
+            Err(e) => format!("{e} ({address})"),
+        },
+    };
+    bail!(
+        "{labeler_domain} doesn't look like a labeler; check the domain, or use `lookup` with the \
+        labeler's handle or did to find it.\n\
+        querying labels: {query_error}\n\
+        subscribing to labels: {subscribe_error}"
+    );
+}
+
+/// Builds the url to subscribe to a labeler's labels at, from its `host` or `host:port`, the
+/// cursor to start after if any, and the extra query parameters from --ws-param
+fn subscription_url(
+    labeler_domain: &str,
+    cursor: Option<i64>,
+    params: &[(String, String)],
+) -> Result<Url> {
+    let mut url = Url::parse("wss://localhost").expect("base url is valid");
+    let (host, port) = match labeler_domain.rsplit_once(':') {
+        // an ipv6 host is in brackets, so a colon after it is the last one
+        Some((host, port)) if !port.contains(']') => {
+            let port = port
+                .parse()
+                .map_err(|_| err!("labeler host {labeler_domain:?} has an invalid port"))?;
+            (host, Some(port))
+        }
+        _ => (labeler_domain, None),
+    };
+    url.set_host(Some(host))
+        .map_err(|e| err!("labeler host {labeler_domain:?} is not valid: {e}"))?;
+    url.set_port(port)
+        .map_err(|()| err!("labeler host {labeler_domain:?} cannot have a port"))?;
+    url.set_path("/xrpc/com.atproto.label.subscribeLabels");
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(cursor) = cursor {
+            query.append_pair("cursor", &cursor.to_string());
+        }
+        query.extend_pairs(params);
+    }
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    Ok(url)
+}
+
+/// How to connect to a labeler and read its labels, apart from what is done with them. The command
+/// line options are turned into one of these for each connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamConfig {
+    pub mode: Mode,
+    /// how long the stream can go without a message before it is taken to be caught up, or None to
+    /// wait forever
+    pub stream_timeout: Option<Duration>,
+    /// how long to wait for each connection attempt or queryLabels request, or None to wait
+    /// forever
+    pub connect_timeout: Option<Duration>,
+    pub max_connect_attempts: usize,
+    /// most messages to buffer between the websocket and processing them
+    pub buffer_size: usize,
+    pub max_message_size: usize,
+    /// extra query parameters for the subscription url
+    pub ws_params: Vec<(String, String)>,
+    pub ws_subprotocol: Option<String>,
+    /// how far the cursor may advance over one connection before reconnecting
+    pub cursor_step: Option<i64>,
+    /// whether to dump each message received to stderr
+    pub print_raw_cbor: bool,
+}
+
+/// the same defaults as the command line options
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Subscribe,
+            stream_timeout: Some(Duration::from_secs(5)),
+            connect_timeout: Some(Duration::from_secs(10)),
+            max_connect_attempts: 3,
+            buffer_size: 10_000,
+            max_message_size: 10 * 1024 * 1024,
+            ws_params: Vec::new(),
+            ws_subprotocol: None,
+            cursor_step: None,
+            print_raw_cbor: false,
+        }
+    }
+}
+
+impl StreamConfig {
+    fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    fn stream_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.stream_timeout = timeout;
+        self
+    }
+
+    fn connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    fn max_connect_attempts(mut self, attempts: NonZeroUsize) -> Self {
+        self.max_connect_attempts = attempts.get();
+        self
+    }
+
+    fn buffer_size(mut self, size: NonZeroUsize) -> Self {
+        self.buffer_size = size.get();
+        self
+    }
+
+    fn max_message_size(mut self, size: NonZeroUsize) -> Self {
+        self.max_message_size = size.get();
+        self
+    }
+
+    fn ws_params(mut self, params: Vec<(String, String)>) -> Self {
+        self.ws_params = params;
+        self
+    }
+
+    fn ws_subprotocol(mut self, subprotocol: Option<String>) -> Self {
+        self.ws_subprotocol = subprotocol;
+        self
+    }
+
+    fn cursor_step(mut self, step: Option<i64>) -> Self {
+        self.cursor_step = step;
+        self
+    }
+
+    fn print_raw_cbor(mut self, print: bool) -> Self {
+        self.print_raw_cbor = print;
+        self
+    }
+
+    /// the options that were set but do nothing in this mode, as they would be given on the
+    /// command line
+    pub fn unused_options(&self) -> Vec<&'static str> {
+        let mut unused = Vec::new();
+        if self.mode == Mode::Poll {
+            let default = Self::default();
+            // queryLabels has no stream to go quiet or websocket to configure
+            if self.stream_timeout != default.stream_timeout {
+                unused.push("--stream-timeout");
+            }
+            if self.max_connect_attempts != default.max_connect_attempts {
+                unused.push("--max-connect-attempts");
+            }
+            if self.buffer_size != default.buffer_size {
+                unused.push("--buffer-size");
+            }
+            if self.max_message_size != default.max_message_size {
+                unused.push("--max-message-size");
+            }
+            if !self.ws_params.is_empty() {
+                unused.push("--ws-param");
+            }
+            if self.ws_subprotocol.is_some() {
+                unused.push("--ws-subprotocol");
+            }
+            if self.cursor_step.is_some() {
+                unused.push("--cursor-step");
+            }
+            if self.print_raw_cbor {
+                unused.push("--print-raw-cbor");
+            }
+        }
+        unused
+    }
+}
+
+impl From<&GetCommonArgs> for StreamConfig {
+    fn from(args: &GetCommonArgs) -> Self {
+        Self::default()
+            .mode(args.mode)
+            .stream_timeout(lookup::timeout_from_secs(args.stream_timeout))
+            .connect_timeout(lookup::timeout_from_secs(args.connect_timeout))
+            .max_connect_attempts(args.max_connect_attempts)
+            .buffer_size(args.buffer_size)
+            .max_message_size(args.max_message_size)
+            .ws_params(args.ws_param.clone())
+            .ws_subprotocol(args.ws_subprotocol.clone())
+            .cursor_step(args.cursor_step)
+            .print_raw_cbor(args.print_raw_cbor)
+    }
+}
+
+/// What a binary event stream frame turned out to be, once it has been processed
+#[derive(Debug)]
+enum FrameOutcome {
+    /// a #labels message, whose records are left for the caller to add to the store
+    Labels { seq: i64, labels: Vec<LabelRecord> },
+    /// an #info message from the labeler, which has been counted
+    Info,
+    /// an event stream error, after which the labeler will close the connection
+    Error {
+        code: String,
+        message: Option<String>,
+    },
+    /// a message of a type we don't know, which was skipped
+    Unknown,
+}
+
+impl Subscription {
+    /// reads labels from the labeler's subscription stream into the store over one connection
+    async fn stream_from_service(
+        &mut self,
+        store: &mut LabelStore,
+        config: &StreamConfig,
+        labeler_domain: &str,
+    ) -> Result<StreamResult> {
+        let config = config.clone();
+        let start_cursor = self.cursor;
+        self.connection_start_cursor = start_cursor;
+        say!("streaming from cursor {start_cursor}");
+        let address = subscription_url(labeler_domain, Some(self.cursor), &config.ws_params)?;
+        let request = || -> Result<_> {
+            let mut request = address.as_str().into_client_request()?;
+            if let Some(subprotocol) = &config.ws_subprotocol {
+                request
+                    .headers_mut()
+                    .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_str(subprotocol)?);
+            }
+            Ok(request)
+        };
+        // Connect the websocket with timeout
+        let max_message_size = config.max_message_size;
+        let websocket_config = WebSocketConfig::default()
+            .max_message_size(Some(max_message_size))
+            .max_frame_size(Some(max_message_size));
+        let mut backoff = CONNECT_BACKOFF;
+        let mut attempt = 1;
+        let stream = loop {
+            let connect_timeout = SleepHandle::new(config.connect_timeout);
+            let failure = select! {
+                () = connect_timeout => format!("connecting to label service at {address} timed out"),
+                connected = connect_async_with_config(request()?, Some(websocket_config), false) => {
+                    match connected {
+                        Ok((connected_stream, _response)) => break connected_stream,
+                        Err(e) if !is_transient_connect_error(&e) => {
+                            say!("error connecting to label service at {address}: {e}");
+                            return Ok(StreamResult::WebsocketError);
+                        }
+                        Err(e) => format!("error connecting to label service at {address}: {e}"),
+                    }
+                }
+            };
+            if attempt >= config.max_connect_attempts {
+                say!("{failure}");
+                return Ok(StreamResult::WebsocketError);
+            }
+            say!("{failure}; trying again in {backoff:?}");
+            sleep(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        };
+
+        let (_write, mut read) = stream.split();
+        let (send, mut recv) = channel(config.buffer_size);
+        let budget = self
+            .buffer_budget
+            .as_ref()
+            .map(|budget| (budget.limit.clone(), budget.drained.clone()));
+
+        let reader = async move {
+            // read websocket messages from the connection until they slow down
+            let sleep_duration = config.stream_timeout;
+            loop {
+                let timeout = SleepHandle::new(sleep_duration);
+                let next_frame_read = read.next();
+                select! {
+                    () = timeout => {
+                        say!("label subscription stream slowed and crawled; terminating");
+                        break;
+                    }
+                    websocket_frame = next_frame_read => {
+                        let Some(msg) = websocket_frame else {
+                            say!("label subscription stream was closed");
+                            let _ = send.send(Err(tungstenite::Error::ConnectionClosed)).await;
+                            return;
+                        };
+                        if let Some((limit, drained)) = &budget {
+                            while send.max_capacity() - send.capacity()
+                                >= limit.load(Ordering::Relaxed)
+                            {
+                                drained.notified().await;
+                            }
+                        }
+                        let Ok(()) = send.send(msg).await else {
+                            return; // channel closed; shut down
+                        };
+                    }
+                }
+            }
+        };
+        // keep telling the labeler's messages apart when reading from several
+        match SAY_PREFIX.try_with(RefCell::clone) {
+            Ok(prefix) => tokio::spawn(SAY_PREFIX.scope(prefix, reader)),
+            Err(_) => tokio::spawn(reader),
+        };
+
+        let begin = now();
+        let bytes_before = store.bytes_received;
+        let processing_before = store.processing_time;
+        let stream_result = 'stream_result: {
+            while let Some(message) = next_message(store, self.cursor, &mut recv).await? {
+                if let Some(budget) = &mut self.buffer_budget {
+                    budget.taken(&message);
+                }
+                // a labeler that closes the connection after sending us something has most likely sent
+                // all the history it has, so that isn't worth retrying
+                let made_progress = self.cursor > start_cursor;
+                let bin = match message {
+                    Ok(Message::Text(text)) => {
+                        store.bytes_received += text.len() as u64;
+                        if config.print_raw_cbor {
+                            eprintln!("text frame: {text:?}", text = text.as_str());
+                        }
+                        if let Some((error, message)) = self.record_text_message(text.as_str()) {
+                            break 'stream_result Ok(StreamResult::AtprotoError { error, message });
+                        }
+                        continue;
+                    }
+                    Ok(Message::Binary(bin)) => bin,
+                    Ok(Message::Close(frame)) => {
+                        if let Some(frame) = frame {
+                            say!(
+                                "label subscription stream closed: {code:?} {reason:?}",
+                                code = frame.code,
+                                reason = frame.reason.as_str(),
+                            );
+                        } else {
+                            say!("label subscription stream closed");
+                        }
+                        if made_progress {
+                            break 'stream_result Ok(StreamResult::Ok);
+                        }
+                        break 'stream_result Ok(StreamResult::Closed);
+                    }
+                    Err(tungstenite::Error::ConnectionClosed) if made_progress => {
+                        say!("assuming the labeler closed the stream because we are caught up");
+                        break 'stream_result Ok(StreamResult::Ok);
+                    }
+                    Err(tungstenite::Error::ConnectionClosed) => {
+                        break 'stream_result Ok(StreamResult::WebsocketError);
+                    }
+                    Err(tungstenite::Error::Capacity(CapacityError::MessageTooLong {
+                        size,
+                        max_size,
+                    })) => {
+                        say!("frame too large: {size} bytes, limit {max_size}");
+                        break 'stream_result Ok(StreamResult::WebsocketError);
+                    }
+                    Err(e) => {
+                        say!("error reading websocket message: {e}");
+                        break 'stream_result Ok(StreamResult::WebsocketError);
+                    }
+                    _ => continue,
+                };
+                if config.print_raw_cbor {
+                    eprint!("{}", hex_dump(&bin));
+                }
+                store.bytes_received += bin.len() as u64;
+                let processing_start = Instant::now();
+                let received_at = now();
+                let mut outcome = self.process_frame(store, &bin, &received_at);
+                if let Ok(FrameOutcome::Labels { labels, .. }) = &mut outcome {
+                    // stored apart from decoding, since normalizing their targets can look up handles
+                    if let Err(e) = store
+                        .process_labels(std::mem::take(labels), &received_at)
+                        .await
+                    {
+                        outcome = Err(e);
+                    }
+                }
+                store.processing_time += processing_start.elapsed();
+                match outcome? {
+                    FrameOutcome::Labels { seq, .. } => {
+                        if config
+                            .cursor_step
+                            .is_some_and(|step| seq - start_cursor >= step)
+                        {
+                            break 'stream_result Ok(StreamResult::Stepped);
+                        }
+                    }
+                    FrameOutcome::Info | FrameOutcome::Unknown => {}
+                    FrameOutcome::Error { code, message } => {
+                        break 'stream_result Ok(StreamResult::AtprotoError {
+                            error: code,
+                            message,
+                        });
+                    }
+                }
+            }
+            Ok(StreamResult::Ok)
+        };
+        let end = now();
+        drop(recv);
+        let elapsed = (end - begin).to_std()?;
+        store.streaming_time += elapsed;
+        say!(
+            "elapsed: {elapsed} ({bytes} byte(s) received, {processing} processing them)",
+            elapsed = humantime::format_duration(elapsed),
+            bytes = store.bytes_received - bytes_before,
+            processing = format_duration_ms(store.processing_time - processing_before),
+        );
+        stream_result
+    }
+
+    /// decodes one binary frame from the label subscription stream, checks that its seq follows the
+    /// cursor, and counts what was odd about it. The labels it carries are returned for the caller to
+    /// add to the store, which may have to look up their targets; reading from the websocket and
+    /// deciding when to reconnect are left to the caller too
+    fn process_frame(
+        &mut self,
+        store: &mut LabelStore,
+        frame: &[u8],
+        received_at: &DateTime,
+    ) -> Result<FrameOutcome> {
+        let mut bin = frame;
+        // the schema for this endpoint is declared here:
+        // https://github.com/bluesky-social/atproto/blob/main/lexicons/com/atproto/label/subscribeLabels.json
+        let outcome = match header_type(&mut bin)? {
+            StreamHeaderType::Error => {
+                #[derive(Deserialize)]
+                struct ErrorPayload {
+                    error: String,
+                    message: Option<String>,
+                }
+                let ErrorPayload { error, message } = ciborium::from_reader(&mut bin)
+                    .map_err(|e| err!("malformed stream error: {e}"))?;
+                if !bin.is_empty() {
+                    let extra_bytes = bin.len();
+                    say!("EXTRA DATA: received {extra_bytes} at end of event stream error message");
+                };
+                return Ok(FrameOutcome::Error {
+                    code: error,
+                    message,
+                });
+            }
+            StreamHeaderType::Type(ty) if ty == "#labels" => {
+                let bad_signatures = store.anomalies.bad_signatures;
+                let header = &frame[..frame.len() - bin.len()];
+                let (seq, labels, extra) = LabelRecord::from_subscription_record(
+                    header,
+                    bin,
+                    &mut store.interner,
+                    &mut store.anomalies,
+                    self.max_batch,
+                    store.preserve_timestamps,
+                )?;
+                debug_log!(
+                    "#labels message of {bytes} bytes: seq {seq}, {count} label(s)",
+                    bytes = frame.len(),
+                    count = labels.len(),
+                );
+                let new_bad_signatures = store.anomalies.bad_signatures - bad_signatures;
+                if new_bad_signatures > 0 {
+                    if self.strict {
+                        bail!(
+                            "label record(s) at seq {seq} have signatures that aren't \
+                            {SIGNATURE_LENGTH} bytes long"
+                        );
+                    }
+                    say!(
+                        "warning: {new_bad_signatures} label record(s) at seq {seq} have signatures \
+                        that aren't {SIGNATURE_LENGTH} bytes long"
+                    );
+                }
+                bin = &frame[frame.len() - extra..];
+                if seq <= self.cursor {
+                    bail!(
+                        "seq did not increase (was {was}, is now {seq})",
+                        was = self.cursor
+                    );
+                }
+                self.cursor = seq;
+                if self.first_seq_received.is_none() {
+                    self.first_seq_received = Some(seq);
+                    // streaming from the very beginning, the labeler starts with the oldest history
+                    // it still has
+                    if self.connection_start_cursor == 0 {
+                        self.first_available_seq = Some(seq);
+                    }
+                }
+                if self.awaiting_history_start {
+                    self.awaiting_history_start = false;
+                    self.history_start = Some(seq);
+                    self.first_available_seq = Some(seq);
+                    say!(
+                        "the labeler no longer has history before seq {seq}; your snapshot may be \
+                        incomplete"
+                    );
+                }
+                FrameOutcome::Labels { seq, labels }
+            }
+            StreamHeaderType::Type(ty) if ty == "#info" => {
+                let info: atrium_api::com::atproto::label::subscribe_labels::Info =
+                    ciborium::from_reader(&mut bin)
+                        .map_err(|e| err!("error parsing #info message: {e}"))?;
+                debug_log!(
+                    "#info message: {name}: {message}",
+                    name = info.name,
+                    message = info.message.as_deref().unwrap_or("(no message)"),
+                );
+                self.process_info(store, &info.name, info.message.as_deref(), received_at)?;
+                FrameOutcome::Info
+            }
+            StreamHeaderType::Type(ty) => {
+                if self.strict {
+                    bail!("unknown event stream message type: {ty:?}");
+                }
+                // relays may multiplex other message types into the stream; skip them. their seqs
+                // aren't label seqs, so they are only logged and never move the cursor
+                #[derive(Deserialize)]
+                struct UnknownPayload {
+                    seq: Option<i64>,
+                }
+                let payload = ciborium::from_reader(&mut bin);
+                debug_log!(
+                    "skipped {ty:?} message of {bytes} bytes: seq {seq}",
+                    bytes = frame.len(),
+                    seq = match &payload {
+                        Ok(UnknownPayload { seq: Some(seq) }) => seq.to_string(),
+                        _ => "unknown".to_owned(),
+                    },
+                );
+                bin = &[];
+                let seen = self.unknown_message_types.entry(ty.clone()).or_default();
+                *seen += 1;
+                if *seen == 1 {
+                    say!(
+                        "warning: skipping a message of unknown type {ty:?} ({bytes} bytes); any more \
+                        are counted in the summary",
+                        bytes = frame.len(),
+                    );
+                }
+                FrameOutcome::Unknown
+            }
+        };
+        if !bin.is_empty() {
+            let extra_bytes = bin.len();
+            say!("EXTRA DATA: received {extra_bytes} at end of event stream message");
+        };
+        Ok(outcome)
+    }
+
+    /// counts a text websocket message, keeping a sample of it for the summary. returns the error
+    /// and message if it is a JSON error object like an event stream error
+    fn record_text_message(&mut self, text: &str) -> Option<(String, Option<String>)> {
+        self.text_messages += 1;
+        let sample = truncate(text, TEXT_SAMPLE_LENGTH).into_owned();
+        if self.text_message_samples.len() < TEXT_SAMPLE_COUNT
+            && !self.text_message_samples.contains(&sample)
+        {
+            self.text_message_samples.push(sample);
+        }
+        #[derive(Deserialize)]
+        struct ErrorPayload {
+            error: String,
+            message: Option<String>,
+        }
+        let ErrorPayload { error, message } = serde_json::from_str(text).ok()?;
+        Some((error, message))
+    }
+
+    /// reports an #info message from the labeler and keeps track of it
+    fn process_info(
+        &mut self,
+        store: &LabelStore,
+        name: &str,
+        message: Option<&str>,
+        now: &DateTime,
+    ) -> Result<()> {
+        match message {
+            Some(message) => say!("info from labeler: {name}: {message}"),
+            None => say!("info from labeler: {name}"),
+        }
+        *self.info_counts.entry(name.to_owned()).or_default() += 1;
+        if name == "OutdatedCursor" {
+            self.awaiting_history_start = true;
+        }
+        if let (Some(db), Some(run_id)) = (&store.store, store.run_id) {
+            db::insert_info(db, run_id, now, self.cursor, name, message)?;
+        }
+        Ok(())
+    }
+}
+
+/// How long to wait before trying to connect again after the first failed attempt, doubling after
+/// each one after that
+const CONNECT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// whether a failed attempt to connect might succeed if tried again, as with timeouts, dns and tls
+/// failures, and server errors, rather than the labeler definitely refusing the subscription
+fn is_transient_connect_error(e: &tungstenite::Error) -> bool {
+    match e {
+        tungstenite::Error::Http(response) => response.status().is_server_error(),
+        tungstenite::Error::Url(_) | tungstenite::Error::HttpFormat(_) => false,
+        _ => true,
+    }
+}
+
+/// Most labels queryLabels will return in one page
+const QUERY_PAGE_SIZE: usize = 250;
+
+impl Polling {
+    /// Fetches labels from the labeler with com.atproto.label.queryLabels, a page at a time, until
+    /// it has no more
+    async fn poll_from_service(
+        &mut self,
+        store: &mut LabelStore,
+        config: &StreamConfig,
+        labeler_domain: &str,
+    ) -> Result<StreamResult> {
+        match &self.query_cursor {
+            Some(cursor) => say!("polling labels from query cursor {cursor:?}"),
+            None => say!("polling labels from the beginning"),
+        }
+        let address = format!("https://{labeler_domain}/xrpc/com.atproto.label.queryLabels");
+        let mut http_client = reqwest::Client::builder();
+        if let Some(timeout) = config.connect_timeout {
+            http_client = http_client.timeout(timeout);
+        }
+        let http_client = http_client.build()?;
+        // only the labeler's own labels, if we know who it is
+        let source = match store.labeler_dids.len() {
+            1 => store.labeler_dids.iter().next().map(|did| did.to_string()),
+            _ => None,
+        };
+        let begin = now();
+        let bytes_before = store.bytes_received;
+        let processing_before = store.processing_time;
+        let poll_result = loop {
+            let mut params = vec![
+                ("uriPatterns", "*".to_owned()),
+                ("limit", QUERY_PAGE_SIZE.to_string()),
+            ];
+            params.extend(source.clone().map(|source| ("sources", source)));
+            params.extend(self.query_cursor.clone().map(|cursor| ("cursor", cursor)));
+            let response = match http_client.get(&address).query(&params).send().await {
+                Ok(response) => response,
+                Err(e) => {
+                    say!("error querying labels: {e}");
+                    break StreamResult::WebsocketError;
+                }
+            };
+            let status = response.status();
+            let body = match response.bytes().await {
+                Ok(body) => body,
+                Err(e) => {
+                    say!("error reading queried labels: {e}");
+                    break StreamResult::WebsocketError;
+                }
+            };
+            store.bytes_received += body.len() as u64;
+            if !status.is_success() {
+                #[derive(Deserialize)]
+                struct XrpcError {
+                    error: String,
+                    message: Option<String>,
+                }
+                match serde_json::from_slice::<XrpcError>(&body) {
+                    Ok(XrpcError { error, message }) => {
+                        break StreamResult::AtprotoError { error, message };
+                    }
+                    Err(_) => {
+                        say!("label query failed: {status}");
+                        break StreamResult::WebsocketError;
+                    }
+                }
+            }
+
+            let processing_start = Instant::now();
+            let page = QueriedLabels::parse(&body)?;
+            if page.is_empty() {
+                store.processing_time += processing_start.elapsed();
+                break StreamResult::Ok;
+            }
+            let next_cursor = page.cursor.clone();
+            let labels = page.into_records(
+                &mut store.interner,
+                &mut store.anomalies,
+                store.preserve_timestamps,
+            )?;
+            store.process_labels(labels, &now()).await?;
+            store.processing_time += processing_start.elapsed();
+            let Some(next_cursor) = next_cursor else {
+                break StreamResult::Ok;
+            };
+            if self.query_cursor.as_ref() == Some(&next_cursor) {
+                bail!("the labeler gave the same query cursor {next_cursor:?} for the next page");
+            }
+            if let Some(run_id) = store.run_id {
+                // the labels are saved before the cursor that skips past them
+                store.flush_inserts()?;
+                if let Some(db) = &store.store {
+                    db::set_run_query_cursor(db, run_id, &next_cursor)?;
+                }
+            }
+            self.query_cursor = Some(next_cursor);
+        };
+        let elapsed = (now() - begin).to_std()?;
+        store.streaming_time += elapsed;
+        say!(
+            "elapsed: {elapsed} ({bytes} byte(s) received, {processing} processing them)",
+            elapsed = humantime::format_duration(elapsed),
+            bytes = store.bytes_received - bytes_before,
+            processing = format_duration_ms(store.processing_time - processing_before),
+        );
+        Ok(poll_result)
+    }
+}
+
+/// Waits for the next message read from the labeler, keeping the dashboard up to date meanwhile if
+/// there is one. Returns None once the stream has ended, or when the dashboard was asked to quit
+async fn next_message(
+    #[cfg_attr(not(feature = "tui"), allow(unused_variables))] store: &mut LabelStore,
+    #[cfg_attr(not(feature = "tui"), allow(unused_variables))] cursor: i64,
+    recv: &mut Receiver<tungstenite::Result<Message>>,
+) -> Result<Option<tungstenite::Result<Message>>> {
+    #[cfg(feature = "tui")]
+    if let Some(mut dashboard) = store.dashboard.take() {
+        let message = loop {
+            let action = select! {
+                message = recv.recv() => {
+                    dashboard.mark_frame();
+                    break message;
+                }
+                action = dashboard.next_action() => action,
+            };
+            match action {
+                tui::Action::Redraw => dashboard.draw(&store.dashboard_status(cursor))?,
+                tui::Action::Quit => break None,
+            }
+        };
+        store.dashboard = Some(dashboard);
+        return Ok(message);
+    }
+    Ok(recv.recv().await)
+}
+
+/// Number of messages whose sizes are averaged to work out how many fit in --max-buffer-memory
+const BUFFER_BUDGET_SAMPLES: usize = 100;
+
+/// Limits how many messages are buffered between reading them from the websocket and processing
+/// them, so that they take up about a given amount of memory
+pub struct BufferBudget {
+    max_memory: usize,
+    /// most messages to buffer, shared with the task reading from the websocket
+    limit: Arc<AtomicUsize>,
+    /// notified each time a message is taken from the buffer, so the reading task can check the
+    /// limit again
+    drained: Arc<Notify>,
+    /// number of messages whose sizes have been added up so far
+    sampled: usize,
+    sampled_bytes: usize,
+}
+
+impl BufferBudget {
+    /// until the sizes of the messages are known, assumes each may be as large as allowed
+    pub fn new(max_memory: usize, max_message_size: usize) -> Self {
+        Self {
+            max_memory,
+            limit: Arc::new(AtomicUsize::new((max_memory / max_message_size).max(1))),
+            drained: Arc::new(Notify::new()),
+            sampled: 0,
+            sampled_bytes: 0,
+        }
+    }
+
+    /// notes that a message was taken from the buffer, updating the limit once enough message
+    /// sizes have been seen
+    fn taken(&mut self, message: &tungstenite::Result<Message>) {
+        self.drained.notify_one();
+        if self.sampled == BUFFER_BUDGET_SAMPLES {
+            return;
+        }
+        self.sampled_bytes += match message {
+            Ok(Message::Binary(bin)) => bin.len(),
+            Ok(Message::Text(text)) => text.len(),
+            _ => return,
+        };
+        self.sampled += 1;
+        if self.sampled == BUFFER_BUDGET_SAMPLES {
+            let typical_size = (self.sampled_bytes / self.sampled).max(1);
+            let limit = (self.max_memory / typical_size).max(1);
+            self.limit.store(limit, Ordering::Relaxed);
+            say!(
+                "messages average {typical_size} byte(s), so --max-buffer-memory has room to \
+                buffer {limit} of them"
+            );
+        }
+    }
+}
+
+/// how many distinct text websocket messages to keep for the summary, and how much of each
+pub const TEXT_SAMPLE_COUNT: usize = 5;
+const TEXT_SAMPLE_LENGTH: usize = 200;
+
+/// number of bytes at the start of a frame to include in its hex dump
+const HEX_DUMP_LIMIT: usize = 512;
+
+/// formats the start of a binary frame like `xxd`, with the offset, hex bytes in groups of 4, and
+/// printable ascii for each 16 byte line
+fn hex_dump(bin: &[u8]) -> String {
+    let mut dump = format!("binary frame of {len} byte(s):\n", len = bin.len());
+    for (i, line) in bin[..bin.len().min(HEX_DUMP_LIMIT)].chunks(16).enumerate() {
+        let hex = line
+            .chunks(4)
+            .map(|group| group.iter().map(|b| format!("{b:02x}")).join(""))
+            .join(" ");
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump += &format!("{offset:08x}: {hex:<35}  {ascii}\n", offset = i * 16);
+    }
+    if bin.len() > HEX_DUMP_LIMIT {
+        dump += &format!(
+            "({more} more byte(s) not shown)\n",
+            more = bin.len() - HEX_DUMP_LIMIT
+        );
+    }
+    dump
+}
+
+pin_project! {
+    /// A timer that fires after its duration, or never if it wasn't given one
+    struct SleepHandle {
+        #[pin]
+        sleep: Option<Sleep>,
+    }
+}
+
+impl SleepHandle {
+    fn new(duration: Option<Duration>) -> Self {
+        Self {
+            sleep: duration.map(sleep),
+        }
+    }
+}
+
+impl Future for SleepHandle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.project().sleep.as_pin_mut() {
+            Some(sleep) => sleep.poll(cx),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    fn cbor_text(text: &str) -> ciborium::Value {
+        ciborium::Value::Text(text.to_owned())
+    }
+
+    /// A subscription stream message with the given header op and type, and body
+    fn event_frame(op: i64, ty: Option<&str>, body: Vec<(&str, ciborium::Value)>) -> Vec<u8> {
+        use ciborium::Value;
+
+        let mut header = vec![(cbor_text("op"), op.into())];
+        header.extend(ty.map(|ty| (cbor_text("t"), cbor_text(ty))));
+        let body = body
+            .into_iter()
+            .map(|(key, value)| (cbor_text(key), value))
+            .collect();
+        let mut frame = Vec::new();
+        ciborium::into_writer(&Value::Map(header), &mut frame).unwrap();
+        ciborium::into_writer(&Value::Map(body), &mut frame).unwrap();
+        frame
+    }
+
+    /// The labels of a subscription stream message, each a target and value from `src`
+    fn labels_body(src: &str, labels: &[(&str, &str)]) -> ciborium::Value {
+        use ciborium::Value;
+
+        Value::Array(
+            labels
+                .iter()
+                .map(|(uri, val)| {
+                    Value::Map(vec![
+                        (cbor_text("ver"), 1.into()),
+                        (cbor_text("src"), cbor_text(src)),
+                        (cbor_text("uri"), cbor_text(uri)),
+                        (cbor_text("val"), cbor_text(val)),
+                        (cbor_text("cts"), cbor_text("2024-01-01T00:00:00.000Z")),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// A subscription stream message carrying the given labels, each a target and value from `src`
+    pub fn labels_frame(seq: i64, src: &str, labels: &[(&str, &str)]) -> Vec<u8> {
+        event_frame(
+            1,
+            Some("#labels"),
+            vec![("seq", seq.into()), ("labels", labels_body(src, labels))],
+        )
+    }
+
+    /// Splits a subscription stream frame into its header and body, as [`header_type`] does
+    pub fn split_frame(frame: &[u8]) -> (&[u8], &[u8]) {
+        let mut body = frame;
+        ciborium::from_reader::<ciborium::Value, _>(&mut body).unwrap();
+        (&frame[..frame.len() - body.len()], body)
+    }
+
+    /// What processing a frame should come to
+    #[derive(Debug)]
+    enum Expected {
+        Labels {
+            seq: i64,
+            count: usize,
+        },
+        Info,
+        Error {
+            code: &'static str,
+        },
+        Unknown,
+        /// an error mentioning this
+        Fails(&'static str),
+    }
+
+    #[test]
+    fn frames_are_decoded_and_checked() {
+        const SRC: &str = "did:plc:labeler";
+        let labels = [("did:plc:x", "spam"), ("did:plc:y", "rude")];
+        let mut trailing_garbage = labels_frame(11, SRC, &labels);
+        trailing_garbage.extend([0xde, 0xad, 0xbe, 0xef]);
+        // each frame arrives at a subscription whose cursor is at seq 10
+        let cases = [
+            (
+                "valid",
+                labels_frame(11, SRC, &labels),
+                Expected::Labels { seq: 11, count: 2 },
+                11,
+            ),
+            (
+                "trailing garbage",
+                trailing_garbage,
+                Expected::Labels { seq: 11, count: 2 },
+                11,
+            ),
+            (
+                "bad header op",
+                event_frame(2, Some("#labels"), vec![("seq", 11.into())]),
+                Expected::Fails("malformed event stream header"),
+                10,
+            ),
+            (
+                "header without a type",
+                event_frame(1, None, vec![("seq", 11.into())]),
+                Expected::Fails("malformed event stream header"),
+                10,
+            ),
+            (
+                "header that isn't cbor",
+                vec![0xff, 0x00, 0x01],
+                Expected::Fails("error decoding event stream header"),
+                10,
+            ),
+            (
+                "largest seq allowed",
+                labels_frame(i64::MAX - 1, SRC, &labels),
+                Expected::Labels {
+                    seq: i64::MAX - 1,
+                    count: 2,
+                },
+                i64::MAX - 1,
+            ),
+            (
+                "huge seq",
+                labels_frame(i64::MAX, SRC, &labels),
+                Expected::Fails("sequence number in label update: 9223372036854775807"),
+                10,
+            ),
+            (
+                "seq too large for an integer",
+                event_frame(
+                    1,
+                    Some("#labels"),
+                    vec![
+                        ("seq", u64::MAX.into()),
+                        ("labels", labels_body(SRC, &labels)),
+                    ],
+                ),
+                Expected::Fails("integer `18446744073709551615`, expected i64"),
+                10,
+            ),
+            (
+                "duplicate seq",
+                labels_frame(10, SRC, &labels),
+                Expected::Fails("seq did not increase (was 10, is now 10)"),
+                10,
+            ),
+            (
+                "earlier seq",
+                labels_frame(3, SRC, &labels),
+                Expected::Fails("seq did not increase (was 10, is now 3)"),
+                10,
+            ),
+            (
+                "info",
+                event_frame(
+                    1,
+                    Some("#info"),
+                    vec![("name", cbor_text("OutdatedCursor"))],
+                ),
+                Expected::Info,
+                10,
+            ),
+            (
+                "error",
+                event_frame(
+                    -1,
+                    None,
+                    vec![
+                        ("error", cbor_text("FutureCursor")),
+                        ("message", cbor_text("cursor in the future")),
+                    ],
+                ),
+                Expected::Error {
+                    code: "FutureCursor",
+                },
+                10,
+            ),
+            (
+                "unknown type with a seq of its own",
+                event_frame(1, Some("#identity"), vec![("seq", 99.into())]),
+                Expected::Unknown,
+                10,
+            ),
+        ];
+        for (name, frame, expected, cursor) in cases {
+            let mut store = LabelStore::new().unwrap();
+            let mut subscription = Subscription::new(10);
+            let outcome = subscription.process_frame(&mut store, &frame, &now());
+            match (&expected, outcome) {
+                (
+                    Expected::Labels { seq, count },
+                    Ok(FrameOutcome::Labels { seq: got, labels }),
+                ) => {
+                    assert_eq!((got, labels.len()), (*seq, *count), "{name}");
+                    assert_eq!(subscription.first_seq_received, Some(*seq), "{name}");
+                }
+                (Expected::Info, Ok(FrameOutcome::Info))
+                | (Expected::Unknown, Ok(FrameOutcome::Unknown)) => {}
+                (Expected::Error { code }, Ok(FrameOutcome::Error { code: got, .. })) => {
+                    assert_eq!(got, *code, "{name}");
+                }
+                (Expected::Fails(error), Err(e)) => {
+                    assert!(e.to_string().contains(error), "{name}: {e}");
+                    assert_eq!(subscription.first_seq_received, None, "{name}");
+                }
+                (expected, outcome) => panic!("{name}: expected {expected:?}, got {outcome:?}"),
+            }
+            assert_eq!(subscription.cursor, cursor, "{name}");
+            // nothing is added to the store until the caller does it
+            assert_eq!(store.total_labels, 0, "{name}");
+        }
+    }
+
+    #[test]
+    fn frames_are_counted_across_a_connection() {
+        let mut store = LabelStore::new().unwrap();
+        let mut subscription = Subscription::new(10);
+        let unknown = event_frame(1, Some("#identity"), vec![("seq", 99.into())]);
+        let outdated = event_frame(
+            1,
+            Some("#info"),
+            vec![
+                ("name", cbor_text("OutdatedCursor")),
+                ("message", cbor_text("cursor is too old")),
+            ],
+        );
+        for frame in [&unknown, &outdated, &unknown] {
+            subscription
+                .process_frame(&mut store, frame, &now())
+                .unwrap();
+        }
+        assert_eq!(
+            subscription.unknown_message_types.get("#identity"),
+            Some(&2)
+        );
+        assert_eq!(subscription.info_counts.get("OutdatedCursor"), Some(&1));
+        assert!(subscription.awaiting_history_start);
+
+        // the labeler picks up with the oldest history it still has
+        let frame = labels_frame(50, "did:plc:labeler", &[("did:plc:x", "spam")]);
+        subscription
+            .process_frame(&mut store, &frame, &now())
+            .unwrap();
+        assert!(!subscription.awaiting_history_start);
+        assert_eq!(subscription.history_start, Some(50));
+        assert_eq!(subscription.first_available_seq, Some(50));
+        let frame = labels_frame(51, "did:plc:labeler", &[("did:plc:y", "spam")]);
+        subscription
+            .process_frame(&mut store, &frame, &now())
+            .unwrap();
+        assert_eq!(subscription.first_seq_received, Some(50));
+        assert_eq!(subscription.history_start, Some(50));
+        assert_eq!(subscription.cursor, 51);
+
+        subscription.strict = true;
+        let e = subscription
+            .process_frame(&mut store, &unknown, &now())
+            .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            r##"unknown event stream message type: "#identity""##
+        );
+    }
+
+    /// decodes a frame as the stream does, and has the store save its labels
+    async fn feed(store: &mut LabelStore, subscription: &mut Subscription, frame: &[u8]) {
+        let received_at = now();
+        let outcome = subscription.process_frame(store, frame, &received_at);
+        if let FrameOutcome::Labels { labels, .. } = outcome.unwrap() {
+            store.process_labels(labels, &received_at).await.unwrap();
+        }
+    }
+
+    /// a store saving into the database at `path`, picking up the run that was cut off there if
+    /// there is one, as `get` does when it starts
+    fn start_store(path: &std::path::Path, source: &mut Source) -> LabelStore {
+        let db = db::connect(path, db::DEFAULT_LABEL_TABLE).unwrap();
+        let mut store = LabelStore::with_db_connection(db, db::DEFAULT_LABEL_TABLE).unwrap();
+        store.labeler_domain = "labeler.example".to_owned();
+        let db = store.store.as_ref().unwrap();
+        match db::find_unfinished_run(db, &store.table, &store.labeler_domain).unwrap() {
+            Some(run) => store.resume_run(run, source).unwrap(),
+            None => {
+                store.run_id = Some(
+                    db::insert_run(
+                        db,
+                        &store.table,
+                        &store.labeler_domain,
+                        &store.run_start,
+                        source.cursor(),
+                    )
+                    .unwrap(),
+                );
+            }
+        }
+        store
+    }
+
+    #[tokio::test]
+    async fn interrupted_runs_resume_without_counting_twice() {
+        const SRC: &str = "did:plc:labeler";
+        let path = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-resume.sqlite",
+            pid = std::process::id()
+        ));
+        crate::remove_db_files(&path).unwrap();
+        let outdated = event_frame(
+            1,
+            Some("#info"),
+            vec![("name", cbor_text("OutdatedCursor"))],
+        );
+
+        let mut source = Source::Subscribe(Subscription::new(0));
+        let mut store = start_store(&path, &mut source);
+        let run_id = store.run_id;
+        let Source::Subscribe(subscription) = &mut source else {
+            unreachable!()
+        };
+        feed(&mut store, subscription, &outdated).await;
+        let labels = [("did:plc:x", "spam"), ("did:plc:y", "spam")];
+        feed(&mut store, subscription, &labels_frame(1, SRC, &labels)).await;
+        let labels = [("did:plc:x", "rude")];
+        feed(&mut store, subscription, &labels_frame(2, SRC, &labels)).await;
+        // the process is killed: the run is never finished
+        drop(store);
+
+        let mut source = Source::Subscribe(Subscription::new(0));
+        let mut store = start_store(&path, &mut source);
+        assert_eq!(store.run_id, run_id);
+        assert_eq!(source.cursor(), 2);
+        assert_eq!((store.total_labels, store.inserted_records), (3, 3));
+        assert_eq!(store.frames_processed, 2);
+        let counts: Vec<_> = store
+            .value_counts
+            .iter()
+            .map(|((_, val), counts)| (val.as_ref(), counts.applied, counts.negated))
+            .collect();
+        assert_eq!(counts, [("rude", 1, 0), ("spam", 2, 0)]);
+        let Source::Subscribe(subscription) = &mut source else {
+            unreachable!()
+        };
+        assert_eq!(subscription.info_counts.get("OutdatedCursor"), Some(&1));
+
+        // reconnecting from the restored cursor, the labeler carries on after seq 2
+        let labels = [("did:plc:z", "spam")];
+        feed(&mut store, subscription, &labels_frame(3, SRC, &labels)).await;
+        let mut report = crate::RunReport::default();
+        store.finalize(&mut report, &source).await.unwrap();
+        assert_eq!((report.end_cursor, report.total_labels), (3, 4));
+        assert_eq!(report.saved_records, Some(4));
+        let summary = report.summary.unwrap();
+        assert_eq!(summary["total_labels"], 4);
+        assert_eq!(summary["saved_records"], 4);
+        let effective: u64 = summary["effective_labels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["count"].as_u64().unwrap())
+            .sum();
+        assert_eq!(effective, 4);
+
+        let db = db::open_read_only(&path).unwrap();
+        let (finished, end_cursor, received): (Option<String>, i64, usize) = db
+            .query_row(
+                "SELECT finished_at_timestamp, end_cursor, labels_received FROM run_stats;",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert!(finished.is_some());
+        assert_eq!((end_cursor, received), (3, 4));
+        drop(db);
+        crate::remove_db_files(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn runs_summarize_labels_saved_in_earlier_runs() {
+        const SRC: &str = "did:plc:labeler";
+        let path = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-replay.sqlite",
+            pid = std::process::id()
+        ));
+        crate::remove_db_files(&path).unwrap();
+        let labels = [("did:plc:x", "spam"), ("did:plc:y", "spam")];
+        let applied = labels_frame(1, SRC, &labels);
+        let ciborium::Value::Array(mut negations) = labels_body(SRC, &[("did:plc:y", "spam")])
+        else {
+            unreachable!()
+        };
+        let ciborium::Value::Map(negation) = &mut negations[0] else {
+            unreachable!()
+        };
+        negation.push((cbor_text("neg"), true.into()));
+        let negated = event_frame(
+            1,
+            Some("#labels"),
+            vec![
+                ("seq", 2.into()),
+                ("labels", ciborium::Value::Array(negations)),
+            ],
+        );
+
+        let mut total_effective = vec![];
+        for frames in [&[&applied, &negated][..], &[&applied]] {
+            let mut source = Source::Subscribe(Subscription::new(0));
+            let mut store = start_store(&path, &mut source);
+            let Source::Subscribe(subscription) = &mut source else {
+                unreachable!()
+            };
+            for frame in frames {
+                feed(&mut store, subscription, frame).await;
+            }
+            let mut report = crate::RunReport::default();
+            store.finalize(&mut report, &source).await.unwrap();
+            total_effective.push(report.summary.unwrap()["total_effective"].clone());
+        }
+        // the second run saves nothing new, but still received the label on did:plc:x, and the
+        // label on did:plc:y it received was negated in the first
+        assert_eq!(total_effective, [1, 1]);
+        crate::remove_db_files(&path).unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use clap::ValueEnum;
+use eyre::{eyre as err, Result};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How much detail to write to the log file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum LogLevel {
+    /// The same messages that are printed to the console
+    Info,
+    /// Also a line for each message received from the labeler
+    Debug,
+}
+
+/// The log file being written, if any
+static LOG: Mutex<Option<LogFile>> = Mutex::new(None);
+/// Set when the log file wants debug lines, so they aren't formatted for nothing otherwise
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// When to rotate the log file, and how many rotated files to keep
+pub struct Rotation {
+    pub max_size: u64,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+struct LogFile {
+    path: PathBuf,
+    /// only `None` while the file is being rotated
+    file: Option<File>,
+    level: LogLevel,
+    rotation: Rotation,
+    size: u64,
+    opened: Instant,
+}
+
+/// Starts copying messages to the log file at `path`, appending to it if it exists. Does nothing
+/// if a log file was already started, as it is when streaming from several labelers
+pub fn start(path: &Path, level: LogLevel, rotation: Rotation) -> Result<()> {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    if log.is_some() {
+        return Ok(());
+    }
+    let file = open(path)
+        .map_err(|e| err!("could not open log file {path}: {e}", path = path.display()))?;
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+    *log = Some(LogFile {
+        path: path.to_owned(),
+        file: Some(file),
+        level,
+        rotation,
+        size,
+        opened: Instant::now(),
+    });
+    DEBUG.store(level >= LogLevel::Debug, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether debug lines are being written to the log file
+pub fn debug_enabled() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+}
+
+/// Writes a message to the log file, if there is one and it wants messages of this level. Lines
+/// are written whole, and the file is only rotated between them
+pub fn write(level: LogLevel, text: &str) {
+    let mut log = LOG.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(log_file) = log.as_mut() else {
+        return;
+    };
+    if level > log_file.level {
+        return;
+    }
+    if let Err(e) = log_file.write(level, text) {
+        eprintln!(
+            "error writing log file {path}: {e}; no longer logging",
+            path = log_file.path.display()
+        );
+        *log = None;
+        DEBUG.store(false, Ordering::Relaxed);
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl LogFile {
+    fn write(&mut self, level: LogLevel, text: &str) -> io::Result<()> {
+        let time = crate::db::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let level = match level {
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+        let mut lines = String::new();
+        for line in text.split('\n') {
+            lines += &format!("{time} {level:5} {line}\n");
+        }
+        let file = self.file.as_mut().expect("log file is open between writes");
+        file.write_all(lines.as_bytes())?;
+        self.size += lines.len() as u64;
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max_age| self.opened.elapsed() >= max_age);
+        if self.size >= self.rotation.max_size || too_old {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    /// moves the current file aside as `<path>.1`, shifting older ones up and dropping the oldest,
+    /// and starts a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        drop(self.file.take());
+        let numbered = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        if self.rotation.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.rotation.keep).rev() {
+                match std::fs::rename(numbered(n), numbered(n + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            std::fs::rename(&self.path, numbered(1))?;
+        }
+        self.file = Some(open(&self.path)?);
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty directory for a test's log files
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "labelview-test-{pid}-logs-{name}",
+            pid = std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// writes lines numbered from 0 to a log file that keeps `keep` rotated files, returning the
+    /// directory it is in. Lines are 39 bytes, so with a 100 byte limit the file is rotated after
+    /// every third line
+    fn write_lines(name: &str, count: usize, keep: usize) -> PathBuf {
+        let dir = temp_dir(name);
+        let path = dir.join("labelview.log");
+        let mut log = LogFile {
+            path: path.clone(),
+            file: Some(open(&path).unwrap()),
+            level: LogLevel::Debug,
+            rotation: Rotation {
+                max_size: 100,
+                max_age: None,
+                keep,
+            },
+            size: 0,
+            opened: Instant::now(),
+        };
+        for n in 0..count {
+            log.write(LogLevel::Info, &format!("line-{n:02}")).unwrap();
+        }
+        dir
+    }
+
+    /// the names of the files in a directory, and the numbers of the lines written to each
+    fn log_files(dir: &Path) -> Vec<(String, Vec<usize>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let lines = std::fs::read_to_string(&path)
+                    .unwrap()
+                    .lines()
+                    .map(|line| {
+                        let [_time, level, message] = *line.split_whitespace().collect::<Vec<_>>()
+                        else {
+                            panic!("unexpected line {line:?}");
+                        };
+                        assert_eq!(level, "info");
+                        message.strip_prefix("line-").unwrap().parse().unwrap()
+                    })
+                    .collect();
+                let name = path.file_name().unwrap().to_str().unwrap().to_owned();
+                (name, lines)
+            })
+            .collect();
+        files.sort();
+        std::fs::remove_dir_all(dir).unwrap();
+        files
+    }
+
+    fn file(name: &str, lines: impl IntoIterator<Item = usize>) -> (String, Vec<usize>) {
+        (name.to_owned(), lines.into_iter().collect())
+    }
+
+    #[test]
+    fn rotated_files_keep_every_line() {
+        let files = log_files(&write_lines("keep-all", 20, 10));
+        assert_eq!(
+            files,
+            [
+                file("labelview.log", 18..20),
+                file("labelview.log.1", 15..18),
+                file("labelview.log.2", 12..15),
+                file("labelview.log.3", 9..12),
+                file("labelview.log.4", 6..9),
+                file("labelview.log.5", 3..6),
+                file("labelview.log.6", 0..3),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_drops_the_oldest_files() {
+        let files = log_files(&write_lines("keep-2", 20, 2));
+        assert_eq!(
+            files,
+            [
+                file("labelview.log", 18..20),
+                file("labelview.log.1", 15..18),
+                file("labelview.log.2", 12..15),
+            ]
+        );
+        // a rotation that leaves the current file empty
+        let files = log_files(&write_lines("keep-2-exact", 9, 2));
+        assert_eq!(
+            files,
+            [
+                file("labelview.log", []),
+                file("labelview.log.1", 6..9),
+                file("labelview.log.2", 3..6),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_without_keeping_files_starts_over() {
+        let files = log_files(&write_lines("keep-0", 20, 0));
+        assert_eq!(files, [file("labelview.log", 18..20)]);
+    }
+}
@@ -1,119 +1,607 @@
+use crate::atproto::{normalize_did, Did};
 use eyre::{bail, eyre as err, Result};
+use hickory_resolver::{
+    proto::{op::ResponseCode, ProtoError, ProtoErrorKind},
+    ResolveError, TokioResolver,
+};
+use itertools::Itertools;
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use url::Url;
 
 pub use atrium_api::did_doc::DidDocument;
 
-pub async fn did(handle_or_did: &str) -> Result<String> {
-    // most of the lookup logic here is learned from
-    // https://github.com/bluesky-social/atproto/tree/main/packages/identity
-    if handle_or_did.starts_with("did:") {
-        Ok(handle_or_did.to_owned())
-    } else {
-        if let Some(did) = find_did_in_dns(&format!("_atproto.{handle_or_did}")).await {
-            return Ok(did);
-        } else if let Some(did) = find_did_in_well_known(handle_or_did).await {
+/// Ways that resolving an account or reading its records can fail that a caller may want to tell
+/// apart. These are returned inside an `eyre::Report`; match on one with
+/// `report.downcast_ref::<LookupError>()`.
+#[derive(Debug, thiserror::Error)]
+pub enum LookupError {
+    /// neither the dns TXT record nor the https .well-known page named a did for the handle
+    #[error("could not resolve did from handle")]
+    HandleNotFound,
+    /// the https .well-known lookup of a handle timed out
+    #[error("could not resolve did from handle: HTTPS .well-known lookup timed out")]
+    HandleLookupTimedOut,
+    /// a did, as given or as found for a handle, breaks the did syntax or its method's rules
+    #[error("{reason} ({found})")]
+    InvalidDid { reason: String, found: String },
+    /// the document of a did was asked for by something that isn't a did
+    #[error("not a did")]
+    NotADid,
+    /// a did whose method labelview doesn't know how to fetch documents for
+    #[error("unsupported did type")]
+    UnsupportedDidMethod { method: String },
+    /// a fetched did document that isn't valid json or isn't shaped like a did document
+    #[error("error parsing did document from {from}: {reason}")]
+    MalformedDidDocument { from: String, reason: String },
+    /// a fetched did document that is for some other did than the one asked for
+    #[error("the fetched did document didn't match the request")]
+    DidDocumentMismatch,
+    /// a record fetched from a pds that doesn't parse
+    #[error("error parsing {collection} record from pds: {reason}")]
+    MalformedRecord { collection: String, reason: String },
+}
+
+/// the plc directory used unless another is asked for
+pub const DEFAULT_PLC_DIRECTORY: &str = "plc.directory";
+/// most entries each of a resolver's caches holds before it forgets the least recently used
+const CACHE_CAPACITY: usize = 10_000;
+/// most lookups a resolver has underway over the network at once
+const MAX_CONCURRENT_LOOKUPS: usize = 8;
+/// number of times to try a dns TXT lookup that fails for reasons that might be temporary
+const DNS_ATTEMPTS: usize = 3;
+
+/// Resolves handles and dids, sharing one HTTP client and dns resolver between every lookup and
+/// remembering what it has found. One resolver is shared by all the labelers of a run, so
+/// labelers streamed at once take turns when too many lookups are underway
+pub struct Resolver<T = Network> {
+    plc_directory: String,
+    /// timeout for each method of resolving a handle to a did, if any
+    timeout: Option<Duration>,
+    transport: T,
+    lookups: Semaphore,
+    cache: Mutex<ResolverCache>,
+}
+
+/// What a resolver has found. Failed lookups aren't remembered, except when looking up handles
+/// for display
+struct ResolverCache {
+    /// dids by the handle they were resolved from
+    dids: Lru<String, String>,
+    /// did documents by did
+    docs: Lru<String, DidDocument>,
+    /// handles by did, or `None` where there wasn't one
+    handles: Lru<String, Option<String>>,
+}
+
+/// The requests a resolver makes over the network, so that something else can stand in for them
+pub trait Transport: Send + Sync {
+    /// fetches the body of a url, failing on an error status
+    fn fetch(&self, url: &str) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// looks up the TXT records of a dns name, each as the strings it holds
+    fn txt_records(
+        &self,
+        name: &str,
+    ) -> impl Future<Output = Result<Vec<Vec<Box<[u8]>>>, ResolveError>> + Send;
+}
+
+/// The real network: an HTTP client and the system's dns resolver
+pub struct Network {
+    http_client: reqwest::Client,
+    dns_resolver: TokioResolver,
+}
+
+impl Transport for Network {
+    async fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self
+            .http_client
+            .get(url)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| err!("{e}"))?;
+        let content = response
+            .bytes()
+            .await
+            .map_err(|e| err!("could not read the response: {e}"))?;
+        Ok(content.to_vec())
+    }
+
+    async fn txt_records(&self, name: &str) -> Result<Vec<Vec<Box<[u8]>>>, ResolveError> {
+        let lookup = self.dns_resolver.txt_lookup(name).await?;
+        Ok(lookup
+            .iter()
+            .map(|record| record.txt_data().to_vec())
+            .collect())
+    }
+}
+
+impl Resolver {
+    pub fn new(plc_directory: &str, timeout: Option<Duration>) -> Result<Self> {
+        let dns_resolver = TokioResolver::builder_tokio()
+            .map_err(|e| err!("could not read the system's dns configuration: {e}"))?
+            .build();
+        let network = Network {
+            http_client: reqwest::Client::new(),
+            dns_resolver,
+        };
+        Ok(Self::with_transport(plc_directory, timeout, network))
+    }
+
+    /// the HTTP client the resolver uses, for other requests made alongside its lookups
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.transport.http_client
+    }
+}
+
+impl<T: Transport> Resolver<T> {
+    fn with_transport(plc_directory: &str, timeout: Option<Duration>, transport: T) -> Self {
+        Self {
+            plc_directory: plc_directory.to_owned(),
+            timeout,
+            transport,
+            lookups: Semaphore::new(MAX_CONCURRENT_LOOKUPS),
+            cache: Mutex::new(ResolverCache {
+                dids: Lru::new(CACHE_CAPACITY),
+                docs: Lru::new(CACHE_CAPACITY),
+                handles: Lru::new(CACHE_CAPACITY),
+            }),
+        }
+    }
+
+    /// waits until there is room for another lookup over the network
+    async fn lookup_permit(&self) -> SemaphorePermit<'_> {
+        self.lookups
+            .acquire()
+            .await
+            .expect("the lookup semaphore is never closed")
+    }
+
+    fn cache(&self) -> MutexGuard<'_, ResolverCache> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Resolves a handle to a did, or passes through a did unchanged. Each resolution method is
+    /// given its own timeout, if the resolver has one.
+    pub async fn resolve_handle(&self, handle_or_did: &str) -> Result<String> {
+        // most of the lookup logic here is learned from
+        // https://github.com/bluesky-social/atproto/tree/main/packages/identity
+        let normalized = normalize_did(handle_or_did);
+        if normalized.starts_with("did:") {
+            return checked_did(&normalized, "as given");
+        }
+        if let Some(did) = self.cache().dids.get(handle_or_did) {
             return Ok(did);
         }
-        bail!("could not resolve did from handle");
+        let dns_domain = format!("_atproto.{handle_or_did}");
+        let from_dns = match with_timeout(self.timeout, self.find_did_in_dns(&dns_domain)).await {
+            Some(Some(did)) => Some(checked_did(
+                &normalize_did(&did),
+                &format!("from the dns TXT record of {dns_domain}"),
+            )?),
+            Some(None) => None,
+            None => {
+                say!("dns TXT lookup timed out");
+                None
+            }
+        };
+        let did = match from_dns {
+            Some(did) => did,
+            None => {
+                match with_timeout(self.timeout, self.find_did_in_well_known(handle_or_did)).await {
+                    Some(Some(did)) => checked_did(
+                        &normalize_did(&did),
+                        &format!("from https://{handle_or_did}/.well-known/atproto-did"),
+                    )?,
+                    Some(None) => return Err(LookupError::HandleNotFound.into()),
+                    None => return Err(LookupError::HandleLookupTimedOut.into()),
+                }
+            }
+        };
+        self.cache()
+            .dids
+            .insert(handle_or_did.to_owned(), did.clone());
+        Ok(did)
     }
-}
 
-async fn find_did_in_dns(dns_domain: &str) -> Option<String> {
-    println!("looking up did via dns TXT...");
-    let dns_resolver = hickory_resolver::TokioResolver::builder_tokio()
-        .unwrap()
-        .build();
-    let lookup = dns_resolver.txt_lookup(dns_domain).await.ok()?;
-    for record in lookup.iter() {
-        let Some((first, rest)) = record.txt_data().split_first() else {
-            continue;
+    pub async fn find_did_in_dns(&self, dns_domain: &str) -> Option<String> {
+        say!("looking up did via dns TXT...");
+        let _permit = self.lookup_permit().await;
+        let mut backoff = Duration::from_millis(250);
+        let mut attempt = 1;
+        let records = loop {
+            match self.transport.txt_records(dns_domain).await {
+                Ok(records) => break records,
+                Err(e) if attempt < DNS_ATTEMPTS && is_transient(&e) => {
+                    say!("dns TXT lookup failed: {e}; retrying in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(_) => return None,
+            }
         };
-        let Some(after_prefix) = first.strip_prefix("did=".as_bytes()) else {
-            continue;
+        records
+            .iter()
+            .find_map(|record| did_from_txt_record(record))
+    }
+
+    pub async fn find_did_in_well_known(&self, https_domain: &str) -> Option<String> {
+        say!("looking up did via dns HTTPS .well-known...");
+        let _permit = self.lookup_permit().await;
+        let content = self
+            .transport
+            .fetch(&format!("https://{https_domain}/.well-known/atproto-did"))
+            .await
+            .ok()?;
+        did_from_well_known(&content)
+    }
+
+    /// Fetches the labeler endpoint a domain publishes at `/.well-known/atproto-labeler-endpoint`,
+    /// in the style of `/.well-known/atproto-did`. This is a non-standard extension that only some
+    /// self-hosted labelers follow, not part of atproto
+    pub async fn well_known_labeler(&self, domain: &str) -> Option<String> {
+        say!("looking up labeler endpoint via HTTPS .well-known...");
+        let fetch = async {
+            let _permit = self.lookup_permit().await;
+            let content = self
+                .transport
+                .fetch(&format!(
+                    "https://{domain}/.well-known/atproto-labeler-endpoint"
+                ))
+                .await
+                .ok()?;
+            let content = std::str::from_utf8(&content).ok()?;
+            // the endpoint is the first line, like the did in atproto-did
+            let endpoint = content.lines().next()?.trim_ascii();
+            Url::parse(endpoint).ok().map(|_| endpoint.to_owned())
         };
-        let mut full_text = Vec::new();
-        full_text.extend_from_slice(after_prefix);
-        full_text.extend(rest.iter().flatten());
-        return String::from_utf8(full_text).ok();
+        with_timeout(self.timeout, fetch).await.flatten()
     }
-    None
-}
 
-async fn find_did_in_well_known(https_domain: &str) -> Option<String> {
-    println!("looking up did via dns HTTPS .well-known...");
-    let http_client = reqwest::Client::new();
-    let response = http_client
-        .get(format!("https://{https_domain}/.well-known/atproto-did"))
-        .send()
-        .await
-        .and_then(reqwest::Response::error_for_status)
-        .ok()?;
-    let content = response.bytes().await.ok()?;
-    match std::str::from_utf8(&content) {
-        Ok(content) => {
-            // split first line and trim whitespace
-            let well_known_did = content
-                .split_once('\n')
-                .map(|(first, _)| first)
-                .unwrap_or(content)
-                .trim_ascii();
-            if well_known_did.starts_with("did:") {
-                Some(well_known_did.to_owned())
-            } else {
+    pub async fn fetch_did_doc(&self, did: &str) -> Result<DidDocument> {
+        self.did_doc(did, true).await
+    }
+
+    /// Finds the handle a did claims in its did document, if it has one. The handle isn't checked
+    /// to resolve back to the did, so it is only good for display. Failed lookups are reported
+    /// and remembered as having no handle
+    pub async fn resolve_did_to_handle(&self, did: &str) -> Option<String> {
+        if let Some(handle) = self.cache().handles.get(did) {
+            return handle;
+        }
+        let handle = match self.did_doc(did, false).await {
+            Ok(doc) => handle_from_doc(&doc).map(str::to_owned),
+            Err(e) => {
+                say!("could not look up the handle of {did}: {e}");
                 None
             }
+        };
+        self.cache().handles.insert(did.to_owned(), handle.clone());
+        handle
+    }
+
+    async fn did_doc(&self, did: &str, verbose: bool) -> Result<DidDocument> {
+        if let Some(doc) = self.cache().docs.get(did) {
+            return Ok(doc);
+        }
+        let location = did_doc_location(&self.plc_directory, did)?;
+        if verbose && location.source == "plc directory" {
+            say!("reading did document from plc directory...");
         }
-        _ => None,
+        let source = location.source;
+        let content = {
+            let _permit = self.lookup_permit().await;
+            self.transport
+                .fetch(&location.url)
+                .await
+                .map_err(|e| err!("error fetching did from {source}: {e}"))?
+        };
+        let doc = parse_did_doc(&content, did, source)?;
+        self.cache().docs.insert(did.to_owned(), doc.clone());
+        Ok(doc)
+    }
+
+    /// Fetches the audit log of a `did:plc` from the plc directory: every operation that has
+    /// changed the did's document, oldest first, each with the time the directory accepted it.
+    /// The plain `/log` endpoint has the same operations without their times
+    pub async fn plc_log(&self, did: &str) -> Result<serde_json::Value> {
+        let _permit = self.lookup_permit().await;
+        let content = self
+            .transport
+            .fetch(&format!(
+                "https://{plc_directory}/{did}/log/audit",
+                plc_directory = self.plc_directory
+            ))
+            .await
+            .map_err(|e| err!("error fetching plc log from plc directory: {e}"))?;
+        serde_json::from_slice(&content)
+            .map_err(|e| err!("error parsing plc log from plc directory: {e}"))
     }
 }
 
-pub async fn did_doc(plc_directory: &str, did: &str) -> Result<DidDocument> {
-    let doc: DidDocument = match did.strip_prefix("did:").and_then(|s| s.split_once(':')) {
-        Some(("plc", _)) => {
-            println!("reading did document from plc directory...");
-            let http_client = reqwest::Client::new();
-            let response = http_client
-                .get(format!("https://{plc_directory}/{did}"))
-                .send()
-                .await
-                .and_then(reqwest::Response::error_for_status)
-                .map_err(|e| err!("error fetching did from plc directory: {e}"))?;
-            // parse the json response
-            let content = response
-                .bytes()
-                .await
-                .map_err(|e| err!("error reading did from plc directory response: {e}"))?;
-            serde_json::from_slice(&content)
-                .map_err(|e| err!("error parsing did document from plc directory: {e}"))?
-        }
-        Some(("web", domain)) => {
-            let http_client = reqwest::Client::new();
-            let response = http_client
-                .get(format!("https://{domain}/.well-known/did.json"))
-                .send()
-                .await
-                .and_then(reqwest::Response::error_for_status)
-                .map_err(|e| err!("error fetching did from .well-known: {e}"))?;
-            // parse the json response
-            let content = response
-                .bytes()
-                .await
-                .map_err(|e| err!("error reading did from .well-known response: {e}"))?;
-            serde_json::from_slice(&content)
-                .map_err(|e| err!("error parsing did document from .well-known: {e}"))?
+/// A map that holds at most a fixed number of entries, forgetting the least recently used one to
+/// make room for another. Finding that entry takes a pass over the map, which is cheap next to
+/// the network lookup that adds an entry
+struct Lru<K, V> {
+    capacity: usize,
+    /// each entry with the tick it was last used at
+    entries: HashMap<K, (V, u64)>,
+    tick: u64,
+}
+
+impl<K: Hash + Eq, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        self.tick += 1;
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // ticks are never reused, so this only drops the oldest entry
+            if let Some(oldest) = self.entries.values().map(|(_, used)| *used).min() {
+                self.entries.retain(|_, (_, used)| *used != oldest);
+            }
         }
-        Some(_) => {
-            bail!("unsupported did type");
+        self.entries.insert(key, (value, self.tick));
+    }
+}
+
+/// checks that a did is valid for an atproto account, saying where it came from if it isn't
+pub fn checked_did(did: &str, source: &str) -> Result<String> {
+    did.parse::<Did>()
+        .and_then(|parsed| parsed.check_method_rules())
+        .map_err(|e| LookupError::InvalidDid {
+            reason: e.to_string(),
+            found: source.to_owned(),
+        })?;
+    Ok(did.to_owned())
+}
+
+/// Checks a labeler service given on the command line and returns it as `host` or `host:port`.
+/// Unless `as_url` is set it must be just that, so that a pasted url or a stray path or query
+/// string is refused instead of quietly connecting somewhere else. With `as_url` it must be an
+/// https or wss url whose path, if any, is one of the label endpoints
+pub fn labeler_host(service: &str, as_url: bool) -> Result<String> {
+    let service = service.trim();
+    let url = if as_url {
+        Url::parse(service).map_err(|e| err!("could not parse labeler url {service:?}: {e}"))?
+    } else {
+        if service.contains("://") {
+            bail!("labeler service {service:?} should be a domain name; pass --url to give a url");
         }
-        None => {
-            bail!("not a did");
+        Url::parse(&format!("wss://{service}"))
+            .map_err(|e| err!("labeler service {service:?} is not a valid host: {e}"))?
+    };
+    if !matches!(url.scheme(), "https" | "wss") {
+        bail!("labeler url {service:?} should use https or wss");
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        bail!("labeler service {service:?} should not include a user name or password");
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        bail!(
+            "labeler service {service:?} should not include a query string or fragment; use \
+            --cursor and --ws-param to add parameters"
+        );
+    }
+    let path_allowed = match url.path() {
+        "" | "/" => true,
+        "/xrpc/com.atproto.label.subscribeLabels" | "/xrpc/com.atproto.label.queryLabels" => as_url,
+        _ => false,
+    };
+    if !path_allowed {
+        if as_url {
+            bail!("labeler url {service:?} should not have a path other than a label endpoint");
         }
+        bail!("labeler service {service:?} should be a domain name; pass --url to give a url");
+    }
+    let Some(host) = url.host_str() else {
+        bail!("labeler service {service:?} does not specify a host");
+    };
+    Ok(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_owned(),
+    })
+}
+
+/// Reads a timeout given in seconds on the command line, where zero and negative values (which
+/// can't be made into a duration anyway) mean to wait forever
+pub fn timeout_from_secs(secs: f64) -> Option<Duration> {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+}
+
+/// runs the future to completion, giving up and returning None if a timeout is provided and it
+/// elapses first
+async fn with_timeout<T>(timeout: Option<Duration>, fut: impl Future<Output = T>) -> Option<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// Reads the did from the strings of an `_atproto` dns TXT record, which hold `did=` followed by
+/// the did, possibly split across several strings
+pub fn did_from_txt_record(txt_data: &[Box<[u8]>]) -> Option<String> {
+    let (first, rest) = txt_data.split_first()?;
+    let after_prefix = first.strip_prefix("did=".as_bytes())?;
+    let mut full_text = Vec::new();
+    full_text.extend_from_slice(after_prefix);
+    full_text.extend(rest.iter().flatten());
+    String::from_utf8(full_text).ok()
+}
+
+/// whether a failed dns lookup might succeed if tried again, as with timeouts and SERVFAIL, rather
+/// than the name definitely not having the record
+fn is_transient(e: &ResolveError) -> bool {
+    match e.proto().map(ProtoError::kind) {
+        Some(ProtoErrorKind::NoRecordsFound { response_code, .. }) => !matches!(
+            response_code,
+            ResponseCode::NXDomain | ResponseCode::NoError
+        ),
+        _ => true,
+    }
+}
+
+/// Reads the did from the body of a handle's `/.well-known/atproto-did`: its first line, if that
+/// looks like a did
+pub fn did_from_well_known(content: &[u8]) -> Option<String> {
+    let content = std::str::from_utf8(content).ok()?;
+    // split first line and trim whitespace
+    let well_known_did = content
+        .split_once('\n')
+        .map(|(first, _)| first)
+        .unwrap_or(content)
+        .trim_ascii();
+    normalize_did(well_known_did)
+        .starts_with("did:")
+        .then(|| well_known_did.to_owned())
+}
+
+/// Where a did's document can be fetched from
+pub struct DidDocLocation {
+    pub url: String,
+    /// what serves the document, for messages
+    pub source: &'static str,
+}
+
+/// Works out where to fetch the document of a did from: the plc directory for `did:plc`, and the
+/// host named by a `did:web`, at `/.well-known/did.json` or under the did's path
+///
+/// https://w3c-ccg.github.io/did-method-web/#read-resolve
+pub fn did_doc_location(plc_directory: &str, did: &str) -> Result<DidDocLocation> {
+    let Ok(parsed) = did.parse::<Did>() else {
+        return Err(LookupError::NotADid.into());
     };
+    match (parsed.method(), parsed.identifier()) {
+        ("plc", _) => Ok(DidDocLocation {
+            url: format!("https://{plc_directory}/{did}"),
+            source: "plc directory",
+        }),
+        ("web", identifier) => {
+            let mut segments = identifier.split(':');
+            // a port is percent-encoded so it isn't mistaken for a path separator
+            let host = segments.next().unwrap_or_default().replace("%3A", ":");
+            let path = segments.join("/");
+            let url = if path.is_empty() {
+                format!("https://{host}/.well-known/did.json")
+            } else {
+                format!("https://{host}/{path}/did.json")
+            };
+            Ok(DidDocLocation {
+                url,
+                source: ".well-known",
+            })
+        }
+        (method, _) => Err(LookupError::UnsupportedDidMethod {
+            method: method.to_owned(),
+        }
+        .into()),
+    }
+}
+
+/// Parses a fetched did document, checking that it is the document of the did that was asked for
+pub fn parse_did_doc(content: &[u8], did: &str, source: &str) -> Result<DidDocument> {
+    let doc: DidDocument =
+        serde_json::from_slice(content).map_err(|e| LookupError::MalformedDidDocument {
+            from: source.to_owned(),
+            reason: e.to_string(),
+        })?;
     if doc.id != did {
-        bail!("the fetched did document didn't match the request");
+        return Err(LookupError::DidDocumentMismatch.into());
     }
     Ok(doc)
 }
 
+/// Fetches a record from an account's repository on its pds, returning the record's value
+pub async fn get_record(
+    http_client: &reqwest::Client,
+    pds: &str,
+    did: &str,
+    collection: &str,
+    rkey: &str,
+) -> Result<serde_json::Value> {
+    #[derive(serde::Deserialize)]
+    struct GetRecordOutput {
+        value: serde_json::Value,
+    }
+
+    let url = url::Url::parse_with_params(
+        &format!("{pds}/xrpc/com.atproto.repo.getRecord"),
+        [("repo", did), ("collection", collection), ("rkey", rkey)],
+    )
+    .map_err(|e| err!("could not build a url for the pds {pds:?}: {e}"))?;
+    let response = http_client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| err!("error fetching {collection} record from pds: {e}"))?;
+    let content = response
+        .bytes()
+        .await
+        .map_err(|e| err!("error reading {collection} record from pds response: {e}"))?;
+    let output: GetRecordOutput =
+        serde_json::from_slice(&content).map_err(|e| LookupError::MalformedRecord {
+            collection: collection.to_owned(),
+            reason: e.to_string(),
+        })?;
+    Ok(output.value)
+}
+
+/// Asks a host for a single label via com.atproto.label.queryLabels, to see whether it serves
+/// labels at all
+pub async fn query_labels(http_client: &reqwest::Client, domain: &str) -> Result<()> {
+    let url = url::Url::parse_with_params(
+        &format!("https://{domain}/xrpc/com.atproto.label.queryLabels"),
+        [("uriPatterns", "*"), ("limit", "1")],
+    )
+    .map_err(|e| err!("could not build a url for the host {domain:?}: {e}"))?;
+    let response = http_client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| err!("error querying labels: {e}"))?;
+    let content = response
+        .bytes()
+        .await
+        .map_err(|e| err!("error reading queried labels: {e}"))?;
+    let output: serde_json::Value =
+        serde_json::from_slice(&content).map_err(|e| err!("error parsing queried labels: {e}"))?;
+    if !output
+        .get("labels")
+        .is_some_and(serde_json::Value::is_array)
+    {
+        bail!("response to label query has no list of labels");
+    }
+    Ok(())
+}
+
 pub fn handle_from_doc(doc: &DidDocument) -> Option<&str> {
     doc.also_known_as
         .iter()
@@ -134,3 +622,297 @@ pub fn service_from_doc<'a>(
         }
     })
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use hickory_resolver::proto::{
+        op::Query,
+        rr::{Name, RecordType},
+    };
+    use std::collections::VecDeque;
+
+    pub const DID: &str = "did:plc:ewvi7nxzyoun6zhxrhs64oiz";
+
+    /// the did document of a labeler that serves labels but has no pds
+    pub const LABELER_WITHOUT_PDS: &str = r##"{
+        "@context": ["https://www.w3.org/ns/did/v1"],
+        "id": "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+        "alsoKnownAs": ["at://labeler.test"],
+        "service": [
+            {
+                "id": "#atproto_labeler",
+                "type": "AtprotoLabeler",
+                "serviceEndpoint": "https://labeler.test"
+            }
+        ]
+    }"##;
+
+    /// the did document of an ordinary account, with a pds and no labeler
+    pub const PDS_WITHOUT_LABELER: &str = r##"{
+        "@context": ["https://www.w3.org/ns/did/v1"],
+        "id": "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+        "alsoKnownAs": ["at://alice.test"],
+        "service": [
+            {
+                "id": "#atproto_pds",
+                "type": "AtprotoPersonalDataServer",
+                "serviceEndpoint": "https://pds.test"
+            }
+        ]
+    }"##;
+
+    /// A stand-in network serving fixed responses and counting the requests made of it
+    #[derive(Default)]
+    pub struct FakeNetwork {
+        pages: HashMap<String, Vec<u8>>,
+        txt: HashMap<String, Vec<Vec<Box<[u8]>>>>,
+        /// response codes that lookups of a name fail with, in order, before it is answered
+        txt_failures: Mutex<HashMap<String, VecDeque<ResponseCode>>>,
+        requests: Mutex<Vec<String>>,
+    }
+
+    impl FakeNetwork {
+        pub fn page(mut self, url: &str, body: &str) -> Self {
+            self.pages.insert(url.to_owned(), body.as_bytes().to_vec());
+            self
+        }
+
+        fn txt(mut self, name: &str, text: &str) -> Self {
+            let record = vec![text.as_bytes().into()];
+            self.txt.insert(name.to_owned(), vec![record]);
+            self
+        }
+
+        /// makes the next lookups of `name` fail with these response codes, one per lookup
+        fn txt_failures(self, name: &str, codes: &[ResponseCode]) -> Self {
+            let codes = codes.iter().copied().collect();
+            self.txt_failures
+                .lock()
+                .unwrap()
+                .insert(name.to_owned(), codes);
+            self
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    impl Transport for FakeNetwork {
+        async fn fetch(&self, url: &str) -> Result<Vec<u8>> {
+            self.requests.lock().unwrap().push(url.to_owned());
+            self.pages
+                .get(url)
+                .cloned()
+                .ok_or_else(|| err!("HTTP status client error (404 Not Found)"))
+        }
+
+        async fn txt_records(&self, name: &str) -> Result<Vec<Vec<Box<[u8]>>>, ResolveError> {
+            self.requests.lock().unwrap().push(format!("txt {name}"));
+            let failure = self
+                .txt_failures
+                .lock()
+                .unwrap()
+                .get_mut(name)
+                .and_then(VecDeque::pop_front);
+            if let Some(response_code) = failure {
+                let query = Query::query(Name::from_ascii(name).unwrap(), RecordType::TXT);
+                let e = ProtoError::nx_error(
+                    Box::new(query),
+                    None,
+                    None,
+                    None,
+                    response_code,
+                    false,
+                    None,
+                );
+                return Err(e.into());
+            }
+            Ok(self.txt.get(name).cloned().unwrap_or_default())
+        }
+    }
+
+    pub fn resolver(network: FakeNetwork) -> Resolver<FakeNetwork> {
+        Resolver::with_transport(DEFAULT_PLC_DIRECTORY, None, network)
+    }
+
+    /// where the resolver fetches the document of [`DID`]
+    pub fn doc_url() -> String {
+        format!("https://plc.directory/{DID}")
+    }
+
+    fn did_doc(did: &str, handle: &str) -> String {
+        serde_json::json!({
+            "id": did,
+            "alsoKnownAs": [format!("at://{handle}")],
+        })
+        .to_string()
+    }
+
+    #[tokio::test]
+    async fn did_documents_are_fetched_once() {
+        let doc_url = doc_url();
+        let resolver = resolver(FakeNetwork::default().page(&doc_url, &did_doc(DID, "alice.test")));
+        let doc = resolver.fetch_did_doc(DID).await.unwrap();
+        assert_eq!(doc.id, DID);
+        resolver.fetch_did_doc(DID).await.unwrap();
+        // the handle comes from the same cached document
+        assert_eq!(
+            resolver.resolve_did_to_handle(DID).await.as_deref(),
+            Some("alice.test")
+        );
+        assert_eq!(resolver.transport.requests(), [doc_url]);
+    }
+
+    #[tokio::test]
+    async fn failed_did_document_fetches_are_tried_again() {
+        let resolver = resolver(FakeNetwork::default());
+        let e = resolver.fetch_did_doc(DID).await.unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("error fetching did from plc directory:"));
+        assert!(resolver.fetch_did_doc(DID).await.is_err());
+        assert_eq!(resolver.transport.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_handle_lookups_for_display_are_remembered() {
+        let resolver = resolver(FakeNetwork::default());
+        assert_eq!(resolver.resolve_did_to_handle(DID).await, None);
+        assert_eq!(resolver.resolve_did_to_handle(DID).await, None);
+        assert_eq!(resolver.transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn mismatched_did_documents_are_not_cached() {
+        let other = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa";
+        let doc_url = doc_url();
+        let resolver = resolver(FakeNetwork::default().page(&doc_url, &did_doc(other, "x.test")));
+        let e = resolver.fetch_did_doc(DID).await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the fetched did document didn't match the request"
+        );
+        assert!(resolver.fetch_did_doc(DID).await.is_err());
+        assert_eq!(resolver.transport.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn handles_resolve_from_dns_once() {
+        let resolver =
+            resolver(FakeNetwork::default().txt("_atproto.alice.test", &format!("did={DID}")));
+        assert_eq!(resolver.resolve_handle("alice.test").await.unwrap(), DID);
+        assert_eq!(resolver.resolve_handle("alice.test").await.unwrap(), DID);
+        assert_eq!(resolver.transport.requests(), ["txt _atproto.alice.test"]);
+    }
+
+    #[tokio::test]
+    async fn transient_dns_failures_are_retried() {
+        let name = "_atproto.alice.test";
+        // a resolver whose lookups of the name fail like this before it is found
+        let failing = |failures: &[ResponseCode]| {
+            resolver(
+                FakeNetwork::default()
+                    .txt(name, &format!("did={DID}"))
+                    .txt_failures(name, failures),
+            )
+        };
+        let servfail = ResponseCode::ServFail;
+
+        let resolver = failing(&[servfail, servfail]);
+        assert_eq!(resolver.find_did_in_dns(name).await.as_deref(), Some(DID));
+        assert_eq!(
+            resolver.transport.requests(),
+            ["txt _atproto.alice.test"; 3]
+        );
+
+        // given up on after the last attempt
+        let resolver = failing(&[servfail; DNS_ATTEMPTS]);
+        assert_eq!(resolver.find_did_in_dns(name).await, None);
+        assert_eq!(resolver.transport.requests().len(), DNS_ATTEMPTS);
+
+        // a name that doesn't exist won't start existing on a retry
+        let resolver = failing(&[ResponseCode::NXDomain]);
+        assert_eq!(resolver.find_did_in_dns(name).await, None);
+        assert_eq!(resolver.transport.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handles_fall_back_to_well_known() {
+        let resolver =
+            resolver(FakeNetwork::default().page("https://bob.test/.well-known/atproto-did", DID));
+        assert_eq!(resolver.resolve_handle("bob.test").await.unwrap(), DID);
+        assert_eq!(resolver.resolve_handle("bob.test").await.unwrap(), DID);
+        assert_eq!(
+            resolver.transport.requests(),
+            [
+                "txt _atproto.bob.test",
+                "https://bob.test/.well-known/atproto-did"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unresolvable_handles_are_tried_again() {
+        let resolver = resolver(FakeNetwork::default());
+        let e = resolver.resolve_handle("nobody.test").await.unwrap_err();
+        assert_eq!(e.to_string(), "could not resolve did from handle");
+        assert!(resolver.resolve_handle("nobody.test").await.is_err());
+        assert_eq!(resolver.transport.requests().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn lookup_failures_can_be_told_apart() {
+        let other = "did:plc:aaaaaaaaaaaaaaaaaaaaaaaa";
+        let resolver = resolver(
+            FakeNetwork::default()
+                .page(&doc_url(), &did_doc(other, "x.test"))
+                .page(&format!("https://plc.directory/{other}"), "{}"),
+        );
+        let kind = |e: eyre::Report| {
+            let kind = e.downcast_ref::<LookupError>().map(|e| format!("{e:?}"));
+            kind.unwrap_or_else(|| panic!("not a LookupError: {e}"))
+        };
+        let e = resolver.resolve_handle("nobody.test").await.unwrap_err();
+        assert_eq!(kind(e), "HandleNotFound");
+        let e = resolver.resolve_handle("did:plc:short").await.unwrap_err();
+        assert!(kind(e).starts_with("InvalidDid"));
+        let e = resolver.fetch_did_doc(DID).await.unwrap_err();
+        assert_eq!(kind(e), "DidDocumentMismatch");
+        let e = resolver.fetch_did_doc(other).await.unwrap_err();
+        assert!(kind(e).starts_with("MalformedDidDocument"));
+        let e = resolver.fetch_did_doc("did:key:zQ3sh").await.unwrap_err();
+        assert_eq!(kind(e), r#"UnsupportedDidMethod { method: "key" }"#);
+        let e = resolver.fetch_did_doc("alice.test").await.unwrap_err();
+        assert_eq!(kind(e), "NotADid");
+        // failures that aren't worth telling apart are only reported
+        let e = resolver
+            .fetch_did_doc("did:web:nowhere.test")
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<LookupError>().is_none());
+    }
+
+    #[tokio::test]
+    async fn dids_resolve_without_any_lookup() {
+        let resolver = resolver(FakeNetwork::default());
+        assert_eq!(resolver.resolve_handle(DID).await.unwrap(), DID);
+        assert!(resolver.transport.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn labeler_endpoints_are_read_without_a_pds() {
+        let resolver = resolver(FakeNetwork::default().page(&doc_url(), LABELER_WITHOUT_PDS));
+        let doc = resolver.fetch_did_doc(DID).await.unwrap();
+        assert_eq!(handle_from_doc(&doc), Some("labeler.test"));
+        assert_eq!(
+            service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer"),
+            None
+        );
+        assert_eq!(
+            service_from_doc(&doc, "#atproto_labeler", "AtprotoLabeler"),
+            Some("https://labeler.test")
+        );
+    }
+}
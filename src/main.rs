@@ -1,24 +1,132 @@
-use crate::db::{now, parse_datetime, Connection, DateTime, LabelKey, LabelRecord};
-use clap::{Args, Parser};
+use crate::{
+    atproto::{normalize_did, verify_cid_format, AtUri, Did, HandleTarget, Nsid},
+    db::{
+        now, parse_datetime, ConflictPolicy, Connection, DateTime, DecodeAnomalies, FirstOf,
+        Interner, LabelKey, LabelRecord, Receipt, RecordFilter, RunStats, SIGNATURE_LENGTH,
+    },
+    export::{EmitFormat, RecordEmitter},
+    ingest::{
+        preflight, BufferBudget, Polling, Source, StreamConfig, StreamResult, Subscription,
+        TEXT_SAMPLE_COUNT,
+    },
+    logfile::LogLevel,
+    lookup::Resolver,
+};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use comfy_table::{presets, CellAlignment, ColumnConstraint, ContentArrangement, Table, Width};
 use eyre::{bail, eyre as err, Result};
-use futures_util::StreamExt;
+use futures_util::future::join_all;
 use itertools::Itertools;
-use serde::Deserialize;
+use serde::Serialize;
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    num::NonZeroUsize,
-    path::PathBuf,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    io::Read,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
     rc::Rc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use tokio::{select, sync::mpsc::channel, time::sleep};
-use tokio_tungstenite::{connect_async, tungstenite, tungstenite::Message};
 use url::Url;
 
+/// Set when stdout carries label records from --emit, so that messages go to stderr instead
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints a progress or summary message like `println!`, to stdout unless --emit is writing label
+/// records there
+macro_rules! say {
+    () => {
+        crate::say_text(String::new())
+    };
+    ($($arg:tt)*) => {
+        crate::say_text(format!($($arg)*))
+    };
+}
+
+tokio::task_local! {
+    /// Which labeler messages are about, when streaming from several labelers at once
+    static SAY_PREFIX: RefCell<String>;
+}
+
+/// Writes a line of detail to the log file, if it was asked for at debug level
+macro_rules! debug_log {
+    ($($arg:tt)*) => {
+        if crate::logfile::debug_enabled() {
+            crate::logfile::write(
+                crate::logfile::LogLevel::Debug,
+                &crate::with_say_prefix(format!($($arg)*)),
+            )
+        }
+    };
+}
+
+/// Starts each line of a message with the labeler it's about, if there are several
+fn with_say_prefix(text: String) -> String {
+    SAY_PREFIX
+        .try_with(|prefix| {
+            let prefix = prefix.borrow();
+            if text.is_empty() {
+                return prefix.clone();
+            }
+            text.lines()
+                .map(|line| format!("{prefix} {line}"))
+                .join("\n")
+        })
+        .unwrap_or(text)
+}
+
+/// Prints a message for `say!`, copying it to the log file if there is one
+fn say_text(text: String) {
+    let text = with_say_prefix(text);
+    logfile::write(LogLevel::Info, &text);
+    if MESSAGES_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{text}");
+    } else {
+        println!("{text}");
+    }
+}
+
+mod atproto;
+mod compare;
 mod db;
+mod doctor;
+mod export;
+mod info;
+mod ingest;
+mod logfile;
 mod lookup;
+mod policies;
+#[cfg(feature = "tui")]
+mod tui;
 
 #[derive(Debug, Parser)]
+enum Cmd {
+    #[command(flatten)]
+    Get(Box<GetCmd>),
+    /// Show statistics about the label records saved in a database
+    Stats(StatsCmd),
+    /// Write out the label records saved in a database as JSON
+    Export(export::ExportCmd),
+    /// Show the label values a labeler declares, and compare them with the ones it has used
+    Policies(policies::PoliciesCmd),
+    /// Compare which targets two labelers currently label, and how often they agree
+    Compare(compare::CompareCmd),
+    /// Show an account's did, handle, and service endpoints, and optionally their history
+    Info(info::InfoCmd),
+    /// Check for common problems with the environment labelview runs in
+    Doctor(doctor::DoctorCmd),
+    /// Bring a database's schema up to date with this version of labelview
+    Migrate(MigrateCmd),
+    /// Tidy up the label records saved in a database
+    Maintenance(MaintenanceCmd),
+}
+
+#[derive(Debug, Subcommand)]
 enum GetCmd {
     /// Get labels looking up the labeler via handle or did
     Lookup(GetLookupCmd),
@@ -26,8 +134,21 @@ enum GetCmd {
     Direct(GetDirectCmd),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Stream labels over a websocket with com.atproto.label.subscribeLabels
+    Subscribe,
+    /// Fetch labels page by page over HTTPS with com.atproto.label.queryLabels, for labelers whose
+    /// subscription is unreliable. Labels fetched this way don't say which seq they had, so
+    /// they're saved without one
+    Poll,
+}
+
 #[derive(Debug, Clone, Args)]
 struct GetCommonArgs {
+    /// How to fetch labels from the labeler
+    #[arg(long, value_enum, default_value_t = Mode::Subscribe)]
+    mode: Mode,
     /// Timeout when the stream's updates start slowing down to assume that it is caught up, in
     /// seconds. Non-positive values wait forever
     #[arg(long, default_value = "5")]
@@ -36,439 +157,2394 @@ struct GetCommonArgs {
     /// forever
     #[arg(long, default_value = "10")]
     connect_timeout: f64,
+    /// Number of times to try connecting to the labeler before counting the connection as failed,
+    /// waiting a little longer after each failure. This is separate from the retries made when the
+    /// labeler stops sending labels without the cursor advancing
+    #[arg(long, default_value = "3")]
+    max_connect_attempts: NonZeroUsize,
     /// Save all records read from the labeler into the specified Sqlite file.
     ///
-    /// A table named "label_records" will be created and the data inserted into it, plus the time
-    /// that it is received from the labeling service.
+    /// A table named by --table will be created and the data inserted into it, plus the time that
+    /// it is received from the labeling service. Missing parent directories are created.
     #[arg(long)]
     save_to_db: Option<PathBuf>,
+    /// Name of the table in the database to save label records into. Several tables can share one
+    /// database file, for instance to keep captures from different labelers apart
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = parse_table_name)]
+    table: String,
+    /// Number of label records to collect before writing them to the database together
+    #[arg(long, default_value = "1000")]
+    insert_batch_size: NonZeroUsize,
+    /// What to do with a label record that has the same key and seq as one already in the database
+    /// but different contents. Conflicts are listed in the summary and the ingest_errors table
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::KeepOld)]
+    conflict_policy: ConflictPolicy,
     /// Maximum number of messages to buffer while processing. Increasing this can speed up
-    /// ingestion at the network level at the cost of more memory usage.
+    /// ingestion at the network level at the cost of more memory usage: each buffered message can
+    /// be as large as --max-message-size, so a full buffer can hold up to their product in bytes.
     #[arg(long, default_value = "10000")]
     buffer_size: NonZeroUsize,
+    /// Warn before streaming if a full buffer of the largest allowed messages could use more than
+    /// this many bytes
+    #[arg(long, default_value = "1073741824")]
+    buffer_memory_warning: u64,
+    /// Approximate limit on the memory used by buffered messages, in bytes. The number of messages
+    /// buffered is worked out from the average size of the first 100 messages received, assuming
+    /// the largest allowed size until then, and is never more than --buffer-size
+    #[arg(long)]
+    max_buffer_memory: Option<NonZeroUsize>,
+    /// Largest websocket message to accept from the labeler, in bytes. A larger message ends the
+    /// connection rather than being read into memory
+    #[arg(long, default_value = "10485760")]
+    max_message_size: NonZeroUsize,
+    /// Extra query parameter to add to the subscription url, as `key=value`, for labelers that
+    /// extend the endpoint. May be given more than once. Values are url-encoded
+    #[arg(long, value_parser = parse_ws_param)]
+    ws_param: Vec<(String, String)>,
+    /// Websocket subprotocol to ask the labeler for when subscribing. The connection fails if the
+    /// labeler doesn't agree to it
+    #[arg(long, value_parser = parse_ws_subprotocol)]
+    ws_subprotocol: Option<String>,
+    /// Most label records to accept in a single label update from the labeler. An update with more
+    /// is refused before its records are decoded
+    #[arg(long, default_value = "100000")]
+    max_batch: NonZeroUsize,
+    /// Sequence number to resume streaming from; only label records with a greater seq will be
+    /// received
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(i64).range(0..))]
+    cursor: i64,
+    /// Cursor to start polling from with --mode poll, as given by the labeler's queryLabels. It
+    /// means whatever the labeler wants it to, unlike --cursor, which is a seq in the subscription
+    /// stream
+    #[arg(long)]
+    query_cursor: Option<String>,
+    /// Resume streaming from the greatest seq already saved in the --save-to-db table, fetching
+    /// only newer labels. With --mode poll, resume polling where the last poll of this labeler
+    /// into the table left off instead. The table should only hold records from this labeler
+    #[arg(long, requires = "save_to_db", conflicts_with_all = ["cursor", "query_cursor"])]
+    continue_from_db: bool,
+    /// Stream and process labels as usual, but write nothing to the --save-to-db database. It is
+    /// still opened, read-only, to check its path and schema, and --continue-from-db still reads
+    /// its cursor from it
+    #[arg(long)]
+    dry_run: bool,
+    /// Write each label record to stdout as it is received, in this format, for pipelines that
+    /// don't need a database (though --save-to-db still works alongside it). Progress messages and
+    /// the summary go to stderr instead
+    #[arg(long, value_enum)]
+    emit: Option<EmitFormat>,
+    /// Reconnect each time the cursor advances by this many sequence numbers, resuming from the
+    /// latest cursor. Some labelers refuse to stream their full history over a single connection
+    #[arg(long, value_parser = clap::value_parser!(i64).range(1..))]
+    cursor_step: Option<i64>,
+    /// Least the cursor must advance over a connection for it to count as progress. After 3
+    /// connections in a row without progress the labeler is given up on, so raising this stops a
+    /// labeler that only sends a few records before rebuffing each connection
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(i64).range(1..))]
+    min_seq_progress: i64,
+    /// Approximate limit on the memory used to track effective labels when not saving to a
+    /// database, in bytes. Once exceeded, tracking moves into a temporary Sqlite file
+    #[arg(long)]
+    max_memory: Option<NonZeroUsize>,
+    /// Keep only the N most recently created effective labels in memory, dropping the oldest as
+    /// newer ones arrive, for following a labeler indefinitely. The summary then describes only
+    /// those labels
+    #[arg(long, value_name = "N", conflicts_with_all = ["save_to_db", "max_memory"])]
+    tail: Option<NonZeroUsize>,
+    /// Each time the labeler rejects us as too slow without any progress being made, double the
+    /// buffer size for the next attempt, up to this many messages
+    #[arg(long)]
+    adaptive_buffer_max: Option<NonZeroUsize>,
+    /// Look up the handles of the dids in the summary and show them alongside, at the cost of an
+    /// HTTPS request for each
+    #[arg(long)]
+    resolve_handles: bool,
+    /// Fail when the stream sends a message type other than labels, infos, and errors, or a label
+    /// record with a signature of the wrong length, instead of counting it and carrying on
+    #[arg(long)]
+    strict: bool,
+    /// Keep the create and expiry timestamps of label records exactly as the labeler wrote them,
+    /// instead of rewriting them as UTC to the second so they all compare correctly. Only the
+    /// original timestamps match the labeler's signatures
+    #[arg(long)]
+    preserve_timestamps: bool,
+    /// Fail when a label targets a specific record without including the cid of the version of the
+    /// record it applies to, instead of warning about it
+    #[arg(long)]
+    require_cid_for_records: bool,
+    /// Resolve labels' targets that name an account by handle rather than by did, like
+    /// at://alice.bsky.social/..., to the account's did so they count alongside its other labels.
+    /// The target as the labeler sent it is kept in the saved record's original_target_uri
+    #[arg(long)]
+    normalize_targets: bool,
+    /// Print a hex dump of the start of each binary frame received, and each text frame, to stderr
+    /// before parsing it, for debugging labelers that send unexpected data
+    #[arg(long, hide_short_help = true)]
+    print_raw_cbor: bool,
+    /// Also write the update summary to this file as JSON
+    #[arg(long)]
+    summary_json: Option<PathBuf>,
+    /// Write the update summary as JSON into this directory instead, named after the labeler's did
+    /// (or its domain, if the did isn't known ahead of time) as `<did>.summary.json`. Characters
+    /// that aren't safe in file names are replaced with underscores
+    #[arg(long, conflicts_with = "summary_json")]
+    output_dir: Option<PathBuf>,
+    /// Write a report of how the run went into this directory as `<did>.json`: whether it
+    /// succeeded, the error if not, the cursors it started and ended at, how many labels it
+    /// received and saved, how long it took, and the update summary. The report is written even
+    /// when the run fails
+    #[arg(long)]
+    report_dir: Option<PathBuf>,
+    /// Show a live dashboard while streaming instead of scrolling progress messages. Press q to
+    /// stop streaming and show the summary, p to pause the recent labels, and / to filter by label
+    /// value
+    #[cfg(feature = "tui")]
+    #[arg(long)]
+    tui: bool,
+    /// Indent JSON output so it is easier to read by eye
+    #[arg(long)]
+    json_pretty: bool,
+    /// Write the process id to this file while streaming, removing it when finished. Refuses to
+    /// start if the file names a process that is still running
+    #[arg(long)]
+    pid_file: Option<PathBuf>,
+    /// Also write messages to this file, for keeping the diagnostics of a long-running follow. It
+    /// is appended to if it exists, and rotated when it grows too large or old, keeping older
+    /// files beside it as `<file>.1`, `<file>.2`, and so on
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    /// How much to write to the log file, independently of what is printed
+    #[arg(long, value_enum, default_value_t = LogLevel::Info, requires = "log_file")]
+    log_level: LogLevel,
+    /// Rotate the log file once it grows to this many bytes
+    #[arg(long, default_value = "10000000", requires = "log_file")]
+    log_max_size: NonZeroU64,
+    /// Also rotate the log file once it has been written to for this many hours
+    #[arg(long, requires = "log_file")]
+    log_max_hours: Option<NonZeroU64>,
+    /// Number of rotated log files to keep
+    #[arg(long, default_value = "5", requires = "log_file")]
+    log_keep: usize,
+    /// Show times in the summary in the local timezone instead of UTC. Saved and JSON output stays
+    /// in UTC
+    #[arg(long)]
+    local_time: bool,
+    /// Print the update summary as a single line for each source did, like `src=<did> labels=<n>
+    /// effective=<m> max_seq=<s> latest=<ts>`, instead of in full. Easier to follow in logs and to
+    /// diff across runs; the JSON summary still has everything
+    #[arg(long)]
+    compact: bool,
+    /// Point out effective labels that will expire within this many days of the end of the run
+    #[arg(long, default_value = "7")]
+    warn_expiring_soon: u32,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 struct GetLookupCmd {
     #[clap(flatten)]
     common: GetCommonArgs,
     /// Handle or DID of the labeler to read from
-    handle_or_did: String,
+    #[arg(required_unless_present = "labelers")]
+    handle_or_did: Option<String>,
+    /// Handle or DID of another labeler to read from. May be given more than once; the labelers
+    /// are streamed from one after another, each with its own summary, followed by their totals
+    #[arg(long = "labeler", action = ArgAction::Append)]
+    labelers: Vec<String>,
+    /// Stream from all the labelers at once instead of one after another. A labeler that fails
+    /// doesn't stop the others
+    #[arg(long)]
+    parallel: bool,
     /// Directory service to use for plc lookups
-    #[arg(long, default_value = "plc.directory")]
+    #[arg(long, default_value = lookup::DEFAULT_PLC_DIRECTORY)]
     plc_directory: String,
+    /// Timeout for each attempt to resolve a handle to a did (via dns, then via HTTPS), in
+    /// seconds. Non-positive values wait forever
+    #[arg(long, default_value = "5")]
+    dns_timeout: f64,
+    /// Connect to the labeler found the last time it was looked up with this database, skipping
+    /// resolution. It is looked up again if connecting fails
+    #[arg(long, requires = "save_to_db")]
+    cached_identity: bool,
+}
+
+/// How to look up a labeler's did and service endpoint
+struct IdentityLookup<T = lookup::Network> {
+    handle_or_did: String,
+    resolver: Arc<Resolver<T>>,
+}
+
+impl<T: lookup::Transport> IdentityLookup<T> {
+    /// resolves the labeler's did and reads its labeler endpoint from its did document
+    async fn resolve(&self) -> Result<db::LabelerIdentity> {
+        say!("looking up did...");
+        // make sure we have a did
+        let did = self.resolver.resolve_handle(&self.handle_or_did).await?;
+        // get the document
+        let doc = self.resolver.fetch_did_doc(&did).await?;
+        // get all the bits from the did-doc and print some of them out
+        let handle = lookup::handle_from_doc(&doc);
+        let handle_text = handle.unwrap_or("(no handle listed in did)");
+        // read the handle, did, and pds & labeler endpoint urls from the response
+        let pds = lookup::service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer");
+        let labeler = lookup::service_from_doc(&doc, "#atproto_labeler", "AtprotoLabeler");
+
+        say!();
+        say!("handle: {handle_text}");
+        say!("did:    {did}");
+        say!();
+        // labelers don't need a pds to serve labels, only to publish their declaration
+        let pds_text = pds.unwrap_or("(no pds endpoint defined, so no labeler declaration)");
+        let labeler_text = labeler.unwrap_or("(no labeler endpoint defined)");
+        say!("pds:     {pds_text}");
+        say!("labeler: {labeler_text}");
+
+        let labeler = match labeler {
+            Some(labeler) => labeler.to_owned(),
+            None => {
+                // some self-hosted labelers publish their endpoint at their domain instead
+                let web_host = did
+                    .strip_prefix("did:web:")
+                    .filter(|host| !host.contains([':', '%']));
+                let domain = handle.or(web_host);
+                let well_known = match domain {
+                    Some(domain) => self.resolver.well_known_labeler(domain).await,
+                    None => None,
+                };
+                let Some(labeler) = well_known else {
+                    bail!(
+                        "that entity doesn't seem to be a labeler. if you know the domain of its \
+                        labeler service, try `labelview direct <domain> --labeler-did {did}`"
+                    );
+                };
+                say!(
+                    "labeler: {labeler} (from https://{domain}/.well-known/atproto-labeler-endpoint)",
+                    domain = domain.unwrap_or_default(),
+                );
+                labeler
+            }
+        };
+
+        let labeler_url = Url::parse(&labeler)
+            .map_err(|e| err!("could not parse labeler endpoint as url: {e}"))?;
+        let Some(labeler_domain) = labeler_url.domain() else {
+            bail!("labeler endpoint url does not seem to specify a domain");
+        };
+        Ok(db::LabelerIdentity {
+            did,
+            labeler_domain: labeler_domain.to_owned(),
+            verified_at: now(),
+        })
+    }
 }
 
 #[derive(Debug, Args)]
 struct GetDirectCmd {
     #[clap(flatten)]
     common: GetCommonArgs,
-    /// Domain name for the labeler service
+    /// Domain name for the labeler service, optionally with a port
     labeler_service: String,
+    /// Take the labeler service as an https or wss url, such as the labeler endpoint from a did
+    /// document, rather than a domain name
+    #[arg(long)]
+    url: bool,
+    /// Did the labeler's records are expected to come from, if known
+    #[arg(long)]
+    labeler_did: Option<String>,
+    /// Before streaming, check that the host answers label queries or accepts a label
+    /// subscription, to catch typos and hosts that aren't labelers with a clearer error
+    #[arg(long)]
+    preflight: bool,
 }
 
-enum StreamHeaderType {
-    Type(String),
-    Error,
+#[derive(Debug, Args)]
+struct StatsCmd {
+    /// Sqlite file previously written with --save-to-db
+    db: PathBuf,
+    /// Name of the table of label records to read
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = parse_table_name)]
+    table: String,
+    #[command(flatten)]
+    filter: RecordFilterArgs,
+    /// Rank the labelers by how many labels they created per day, over the span of create
+    /// timestamps in the database
+    #[arg(long)]
+    velocity: bool,
+    /// List how many records apply and negate each label value, and how many targets each is in
+    /// effect for
+    #[arg(long)]
+    values: bool,
+    /// Count labels as in effect at this time instead of now, considering only the records
+    /// created before it. Given as an RFC 3339 timestamp or a date like 2024-01-31
+    #[arg(long, value_parser = parse_time, requires = "values")]
+    as_of: Option<DateTime>,
 }
 
-impl GetCmd {
-    async fn go(self) -> Result<()> {
-        let mut store = LabelStore::new()?;
-
-        let common_args; // common arguments
+#[derive(Debug, Args)]
+struct MigrateCmd {
+    /// Sqlite file previously written with --save-to-db
+    #[arg(long)]
+    db: PathBuf,
+    /// Name of the table of label records to bring up to date
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = parse_table_name)]
+    table: String,
+}
 
-        println!("looking up did...");
-        let labeler_domain = match self {
-            GetCmd::Lookup(cmd) => {
-                common_args = cmd.common;
-                // make sure we have a did
-                let did = lookup::did(&cmd.handle_or_did).await?;
-                // because we are looking up the did document to find the service, we will know
-                // ahead of time what the src did should be for all the label records
-                store.set_known_did(did.clone().into())?;
-                // get the document
-                let doc = lookup::did_doc(&cmd.plc_directory, &did).await?;
-                // get all the bits from the did-doc and print some of them out
-                let handle = lookup::handle_from_doc(&doc);
-                let handle_text = handle.unwrap_or("(no handle listed in did)");
-                // read the handle, did, and pds & labeler endpoint urls from the response
-                let pds =
-                    lookup::service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer");
-                let labeler = lookup::service_from_doc(&doc, "#atproto_labeler", "AtprotoLabeler");
-
-                println!();
-                println!("handle: {handle_text}");
-                println!("did:    {did}");
-                println!();
-                let pds_text = pds.unwrap_or("(no pds endpoint defined)");
-                let labeler_text = labeler.unwrap_or("(no labeler endpoint defined)");
-                println!("pds:     {pds_text}");
-                println!("labeler: {labeler_text}");
-
-                let Some(labeler) = labeler else {
-                    bail!("that entity doesn't seem to be a labeler.");
-                };
+#[derive(Debug, Args)]
+struct MaintenanceCmd {
+    /// Sqlite file previously written with --save-to-db
+    #[arg(long)]
+    db: PathBuf,
+    /// Name of the table of label records to tidy
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = parse_table_name)]
+    table: String,
+    /// Rewrite create timestamps written in other formats, like with fractional seconds or a
+    /// timezone offset, as UTC to the second so they compare correctly. The rewritten records no
+    /// longer carry the exact timestamp the labeler signed
+    #[arg(long)]
+    normalize_timestamps: bool,
+    /// Remove label records that expired more than this many days ago, as far as that can be done
+    /// without changing which labels are in effect: records replaced by a later one for the same
+    /// label, and every record of a label whose latest record expired. The labeler's history is no
+    /// longer complete afterwards, so stats will report missing seqs
+    #[arg(long, value_name = "DAYS")]
+    prune_expired_before: Option<u32>,
+    /// Rebuild the database file afterwards, so that the space freed by removing records is given
+    /// back
+    #[arg(long)]
+    vacuum: bool,
+    /// Check the database for damage and for records that break what labelview expects of them,
+    /// like several records for one label at the same seq, before doing anything else. Nothing is
+    /// changed if problems are found
+    #[arg(long)]
+    check_integrity: bool,
+}
 
-                let labeler_url = Url::parse(labeler)
-                    .map_err(|e| err!("could not parse labeler endpoint as url: {e}"))?;
-                let Some(labeler_domain) = labeler_url.domain() else {
-                    bail!("labeler endpoint url does not seem to specify a domain");
-                };
-                labeler_domain.to_owned()
-            }
-            GetCmd::Direct(cmd) => {
-                common_args = cmd.common;
-                cmd.labeler_service
-            }
-        };
+/// Options choosing which saved label records to read, by when they were created and what they
+/// label
+#[derive(Debug, Args)]
+struct RecordFilterArgs {
+    /// Only include records created at or after this time, given as an RFC 3339 timestamp or a
+    /// date like 2024-01-31
+    #[arg(long, value_parser = parse_time)]
+    since: Option<DateTime>,
+    /// Only include records created before this time, given as an RFC 3339 timestamp or a date
+    #[arg(long, value_parser = parse_time)]
+    until: Option<DateTime>,
+    /// Only include records created within this many days before now
+    #[arg(long)]
+    since_days: Option<u32>,
+    /// Only include records created within this many hours before now
+    #[arg(long)]
+    since_hours: Option<u32>,
+    /// Only include records with this seq or greater
+    #[arg(long)]
+    first_seq: Option<i64>,
+    /// Only include records with this seq or less
+    #[arg(long)]
+    last_seq: Option<i64>,
+    /// Only include self-labels: records from a labeler labeling its own account
+    #[arg(long)]
+    self_labels: bool,
+    /// Only include records about this account: labels on the account itself and on any of its
+    /// records
+    #[arg(long, value_parser = parse_target_did)]
+    target_did: Option<String>,
+}
 
-        if let Some(db_path) = &common_args.save_to_db {
-            store.store = Some(db::connect(db_path)?);
+impl RecordFilterArgs {
+    /// builds the filter, using the latest of the cutoffs given for --since and its shorthands
+    fn to_filter(&self) -> RecordFilter {
+        let now = now();
+        let since = [
+            self.since,
+            self.since_days
+                .map(|days| now - chrono::Duration::days(days.into())),
+            self.since_hours
+                .map(|hours| now - chrono::Duration::hours(hours.into())),
+        ]
+        .into_iter()
+        .flatten()
+        .max();
+        RecordFilter {
+            since,
+            until: self.until,
+            first_seq: self.first_seq,
+            last_seq: self.last_seq,
+            self_labels: self.self_labels,
+            target_did: self.target_did.clone(),
+            src: None,
         }
+    }
+}
 
-        println!();
-        println!("streaming from labeler service");
-
-        // We retry the entire streaming process until we fail multiple times without making any
-        // forward progress. Some labeling services seem to behave strangely and poorly,
-        // deterministically rebuffing attempts to stream label history from cursor zero by saying
-        // that the consumer is "too slow" no matter how fast it is, requiring the consumer to
-        // repeatedly resume at marching intervals to get the whole story.
-        const MAX_RETRIES: usize = 3;
-        let mut retries = 0;
-        while retries < MAX_RETRIES {
-            let last_cursor = store.cursor;
-            match stream_from_service(&mut store, &common_args, &labeler_domain).await? {
-                StreamResult::Ok => break,
-                StreamResult::Closed | StreamResult::WebsocketError => {}
-                StreamResult::AtprotoError { error, message } => {
-                    println!(
-                        "label subscription stream returned an error: {error}: {message}",
-                        message = message.as_deref().unwrap_or("(no error message)"),
-                    );
-                }
-            }
-            retries = if store.cursor > last_cursor {
-                0
-            } else {
-                retries + 1
-            };
-        }
-        if retries == MAX_RETRIES {
-            println!("reached maximum retries without making progress; giving up");
-        }
+/// Parses a --target-did argument as a did, normalized the way saved records' dids are. It isn't
+/// held to the plc and web method rules, so that records with unusual targets can still be found
+fn parse_target_did(did: &str) -> Result<String> {
+    let did = normalize_did(did);
+    did.parse::<Did>()?;
+    Ok(did.into_owned())
+}
 
-        store.finalize()
+/// Parses a time given on the command line, as an RFC 3339 timestamp or a date in UTC
+fn parse_time(time: &str) -> Result<DateTime> {
+    if let Some(time) = parse_datetime(time) {
+        return Ok(time);
+    }
+    match chrono::NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_time(chrono::NaiveTime::MIN).and_utc()),
+        Err(_) => bail!("expected an RFC 3339 timestamp or a date like 2024-01-31"),
     }
 }
 
-/// Reads an event stream frame header type
-///
-/// https://atproto.com/specs/event-stream#streaming-wire-protocol-v0
-fn header_type(bin: &mut &[u8]) -> Result<StreamHeaderType> {
-    #[derive(Deserialize)]
-    struct Header {
-        op: i64,
-        t: Option<String>,
+/// Parses a --table argument, rejecting names that can't safely be used in queries
+fn parse_table_name(name: &str) -> Result<String> {
+    db::validate_table_name(name)?;
+    Ok(name.to_owned())
+}
+
+/// Parses a --ws-param argument as a key and value
+fn parse_ws_param(param: &str) -> Result<(String, String)> {
+    let Some((key, value)) = param.split_once('=') else {
+        bail!("expected a parameter like key=value");
+    };
+    if key.is_empty() {
+        bail!("the parameter's key is empty");
     }
-    Ok(
-        match ciborium::from_reader(bin)
-            .map_err(|e| err!("error decoding event stream header: {e}"))?
-        {
-            Header { op: 1, t: Some(t) } => StreamHeaderType::Type(t),
-            Header { op: -1, t: None } => StreamHeaderType::Error,
-            malformed => bail!(
-                "received a malformed event stream header: op {op}",
-                op = malformed.op,
-            ),
-        },
-    )
+    if key == "cursor" {
+        bail!("the cursor parameter is set by labelview; use --cursor instead");
+    }
+    Ok((key.to_owned(), value.to_owned()))
 }
 
-enum StreamResult {
-    Ok,
-    Closed,
-    WebsocketError,
-    AtprotoError {
-        error: String,
-        message: Option<String>,
-    },
+/// Parses a --ws-subprotocol argument, which must be a token as http defines it
+fn parse_ws_subprotocol(subprotocol: &str) -> Result<String> {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if subprotocol.is_empty() || !subprotocol.chars().all(is_token_char) {
+        bail!("a subprotocol name can only contain letters, digits, and !#$%&'*+-.^_`|~");
+    }
+    Ok(subprotocol.to_owned())
 }
 
-async fn stream_from_service(
-    store: &mut LabelStore,
-    common_args: &GetCommonArgs,
-    labeler_domain: &str,
-) -> Result<StreamResult> {
-    let common_args = common_args.clone();
-    println!("streaming from cursor {cursor}", cursor = store.cursor);
-    let address = Url::parse(&format!(
-        "wss://{labeler_domain}/xrpc/com.atproto.label.subscribeLabels?cursor={cursor}",
-        cursor = store.cursor,
-    ))?;
-    // Connect the websocket with timeout
-    let stream;
-    {
-        let connect_timeout = Duration::try_from_secs_f64(common_args.connect_timeout)
-            .ok()
-            .map(sleep);
-        select! {
-            Some(()) = conditional_sleep(connect_timeout) => {
-                println!("connecting to label service timed out");
-                return Ok(StreamResult::WebsocketError);
-            }
-            connected = connect_async(&address) => {
-                let Ok((connected_stream, _response)) = connected else {
-                    println!(
-                        "error connecting to label service: {err}",
-                        err = connected.err().unwrap()
-                    );
-                    return Ok(StreamResult::WebsocketError);
-                };
-                stream = connected_stream;
-            }
-        }
+impl GetLookupCmd {
+    /// the handles or dids of the labelers to read from, in the order given
+    fn targets(&self) -> impl Iterator<Item = &String> {
+        self.handle_or_did.iter().chain(&self.labelers)
+    }
+
+    /// a resolver for looking up the labelers, using the plc directory and timeout asked for
+    fn resolver(&self) -> Result<Resolver> {
+        Resolver::new(
+            &self.plc_directory,
+            lookup::timeout_from_secs(self.dns_timeout),
+        )
     }
 
-    let (_write, mut read) = stream.split();
-    let (send, mut recv) = channel(common_args.buffer_size.get());
+    /// streams from each of several labelers, then reports how they went all together. They share
+    /// one resolver, so a did or handle is only looked up once
+    async fn go_many(self, resolver: Arc<Resolver>) -> Result<()> {
+        let common = &self.common;
+        if common.save_to_db.is_some() {
+            bail!(
+                "a database table only holds one labeler's records, so --save-to-db can't be \
+                used with more than one labeler"
+            );
+        }
+        if common.summary_json.is_some() {
+            bail!(
+                "--summary-json only holds one labeler's summary; use --output-dir to write one \
+                for each labeler"
+            );
+        }
+        #[cfg(feature = "tui")]
+        if common.tui {
+            bail!("--tui can only show one labeler at a time");
+        }
+        if self.parallel && common.pid_file.is_some() {
+            bail!("--pid-file can't be used with --parallel, since each labeler would claim it");
+        }
 
-    tokio::spawn(async move {
-        // read websocket messages from the connection until they slow down
-        let sleep_duration = Duration::try_from_secs_f64(common_args.stream_timeout).ok();
-        loop {
-            let timeout = sleep_duration.map(sleep);
-            let next_frame_read = read.next();
-            select! {
-                Some(()) = conditional_sleep(timeout) => {
-                    println!("label subscription stream slowed and crawled; terminating");
+        let targets: Vec<String> = self.targets().cloned().collect();
+        let runs = targets.iter().map(|target| {
+            let cmd = GetCmd::Lookup(GetLookupCmd {
+                handle_or_did: Some(target.clone()),
+                labelers: vec![],
+                parallel: false,
+                ..self.clone()
+            });
+            let resolver = Arc::clone(&resolver);
+            async move {
+                let mut report = RunReport::default();
+                let prefix = RefCell::new(format!("[{target}]"));
+                let result = SAY_PREFIX
+                    .scope(prefix, cmd.go_one(&mut report, resolver))
+                    .await;
+                (target, report, result)
+            }
+        });
+        let finished = if self.parallel {
+            join_all(runs).await
+        } else {
+            let mut finished = vec![];
+            for run in runs {
+                let (target, report, result) = run.await;
+                let failed = result.is_err();
+                finished.push((target, report, result));
+                if failed {
                     break;
                 }
-                websocket_frame = next_frame_read => {
-                    let Some(msg) = websocket_frame else {
-                        println!("label subscription stream was closed");
-                        let _ = send.send(Err(tungstenite::Error::ConnectionClosed)).await;
-                        return;
-                    };
-                    let Ok(()) = send.send(msg).await else {
-                        return; // channel closed; shut down
-                    };
-                }
             }
+            finished
+        };
+
+        say!();
+        say!("all {count} labelers:", count = targets.len());
+        for (target, report, result) in &finished {
+            let name = report.did.as_deref().unwrap_or(target);
+            match result {
+                Ok(()) => say!(
+                    "   {name}: {total} label record(s) up to seq {cursor}",
+                    total = report.total_labels,
+                    cursor = report.end_cursor,
+                ),
+                Err(e) => say!(
+                    "   {name}: failed after {total} label record(s): {e}",
+                    total = report.total_labels,
+                ),
+            }
+        }
+        say!(
+            "received a total of {total} label record(s)",
+            total = finished
+                .iter()
+                .map(|(_, report, _)| report.total_labels)
+                .sum::<usize>()
+        );
+        let skipped = targets.len() - finished.len();
+        if skipped > 0 {
+            say!("XX --> stopped after a labeler failed; {skipped} labeler(s) were not read from");
+        }
+        let mut errors = finished
+            .into_iter()
+            .filter_map(|(_, _, result)| result.err());
+        match (errors.next(), errors.count()) {
+            (None, _) => {
+                say!("OK --> read from every labeler");
+                Ok(())
+            }
+            (Some(e), 0) => Err(e),
+            (Some(_), others) => bail!("{failed} labelers failed", failed = others + 1),
+        }
+    }
+}
+
+impl GetCmd {
+    async fn go(self) -> Result<()> {
+        let common_args = match &self {
+            GetCmd::Lookup(cmd) => &cmd.common,
+            GetCmd::Direct(cmd) => &cmd.common,
+        };
+        if let Some(path) = &common_args.log_file {
+            let rotation = logfile::Rotation {
+                max_size: common_args.log_max_size.get(),
+                max_age: common_args
+                    .log_max_hours
+                    .map(|hours| Duration::from_secs(hours.get() * 60 * 60)),
+                keep: common_args.log_keep,
+            };
+            logfile::start(path, common_args.log_level, rotation)?;
+            say!("writing log to {path}", path = path.display());
+        }
+        let resolver = Arc::new(match &self {
+            GetCmd::Lookup(cmd) => cmd.resolver()?,
+            // only used to look up handles for the summary
+            GetCmd::Direct(_) => Resolver::new(lookup::DEFAULT_PLC_DIRECTORY, None)?,
+        });
+        match self {
+            GetCmd::Lookup(cmd) if cmd.targets().count() > 1 => cmd.go_many(resolver).await,
+            cmd => cmd.go_one(&mut RunReport::default(), resolver).await,
         }
-    });
+    }
 
-    let begin = now();
-    let stream_result = 'stream_result: {
-        while let Some(message) = recv.recv().await {
-            let bin = match message.map_err(|e| err!("error reading websocket message: {e}")) {
-                Ok(Message::Text(text)) => {
-                    println!("text websocket message: {text:?}");
-                    continue;
+    /// streams from one labeler, writing a report of how it went if asked to
+    async fn go_one(self, report: &mut RunReport, resolver: Arc<Resolver>) -> Result<()> {
+        let (report_dir, target) = match &self {
+            GetCmd::Lookup(cmd) => (
+                cmd.common.report_dir.clone(),
+                cmd.targets().next().cloned().unwrap_or_default(),
+            ),
+            GetCmd::Direct(cmd) => (cmd.common.report_dir.clone(), cmd.labeler_service.clone()),
+        };
+        let started = Instant::now();
+        *report = RunReport {
+            started_at: now().to_rfc3339(),
+            ..RunReport::default()
+        };
+        let result = self.run(report, resolver).await;
+        let Some(report_dir) = report_dir else {
+            return result;
+        };
+
+        report.duration_seconds = started.elapsed().as_secs_f64();
+        match &result {
+            Ok(()) => report.status = "ok",
+            Err(e) => {
+                report.status = "failed";
+                report.error = Some(format!("{e}"));
+            }
+        }
+        let name = report
+            .did
+            .as_deref()
+            .or(report.labeler_domain.as_deref())
+            .unwrap_or(&target);
+        let path = report_dir.join(format!("{}.json", file_name_safe(name)));
+        let written = std::fs::create_dir_all(&report_dir)
+            .and_then(|()| std::fs::write(&path, serde_json::to_string_pretty(&report)? + "\n"))
+            .map_err(|e| err!("error writing report to {path}: {e}", path = path.display()));
+        match (result, written) {
+            (Ok(()), written) => written,
+            (Err(e), written) => {
+                // the run's own error matters more
+                if let Err(write_error) = written {
+                    say!("{write_error}");
                 }
-                Ok(Message::Binary(bin)) => bin,
-                Ok(Message::Close(frame)) => {
-                    if let Some(frame) = frame {
-                        println!(
-                            "label subscription stream closed: {code:?} {reason:?}",
-                            code = frame.code,
-                            reason = frame.reason.as_str(),
+                Err(e)
+            }
+        }
+    }
+
+    /// streams from the labeler, keeping `report` up to date with how far it has gotten
+    async fn run(self, report: &mut RunReport, resolver: Arc<Resolver>) -> Result<()> {
+        let mut common_args = match &self {
+            GetCmd::Lookup(cmd) => cmd.common.clone(),
+            GetCmd::Direct(cmd) => cmd.common.clone(),
+        };
+        if common_args.emit.is_some() {
+            MESSAGES_TO_STDERR.store(true, Ordering::Relaxed);
+        }
+        if common_args.max_buffer_memory.is_none() && matches!(common_args.mode, Mode::Subscribe) {
+            let buffer_size = common_args
+                .adaptive_buffer_max
+                .map_or(common_args.buffer_size, |max| {
+                    max.max(common_args.buffer_size)
+                });
+            let worst_case = (buffer_size.get() as u64)
+                .saturating_mul(common_args.max_message_size.get() as u64);
+            if worst_case > common_args.buffer_memory_warning {
+                say!(
+                    "warning: a full buffer of {buffer_size} message(s) of up to {max_size} bytes \
+                    each could use as much as {worst_case} bytes of memory; consider lowering \
+                    --buffer-size or --max-message-size, or setting --max-buffer-memory",
+                    max_size = common_args.max_message_size,
+                );
+            }
+        }
+        // the latest seq saved in the database, when continuing from it
+        let mut saved_cursor = None;
+        let mut store = match &common_args.save_to_db {
+            Some(db_path) if common_args.dry_run => {
+                say!("dry run: no records will be written to the database");
+                let db_path = prepare_db_path(db_path, true)?;
+                if db_path.is_file() {
+                    let db = db::open_read_only(&db_path)?;
+                    let pending = db::pending_migrations(&db, &common_args.table)?;
+                    if pending > 0 {
+                        say!(
+                            "{path} would first have {pending} schema migration(s) applied",
+                            path = db_path.display()
                         );
+                    }
+                    if common_args.continue_from_db {
+                        let mut store = LabelStore::with_db_connection(db, &common_args.table)?;
+                        store.say_continuing(common_args.mode);
+                        saved_cursor = Some(store.saved_cursor);
+                        // track effective labels in memory instead, so nothing is written
+                        store.store = None;
+                        store
                     } else {
-                        println!("label subscription stream closed");
+                        LabelStore::new()?
+                    }
+                } else {
+                    say!(
+                        "{path} does not exist yet; it would be created",
+                        path = db_path.display()
+                    );
+                    if common_args.continue_from_db {
+                        bail!("there is no database to continue from");
                     }
-                    break 'stream_result Ok(StreamResult::Closed);
+                    LabelStore::new()?
                 }
-                Err(..) => {
-                    break 'stream_result Ok(StreamResult::WebsocketError);
+            }
+            Some(db_path) => {
+                let db_path = prepare_db_path(db_path, false)?;
+                say!("saving label records to {path}", path = db_path.display());
+                let db = db::connect(&db_path, &common_args.table)?;
+                if common_args.continue_from_db {
+                    let store = LabelStore::with_db_connection(db, &common_args.table)?;
+                    store.say_continuing(common_args.mode);
+                    saved_cursor = Some(store.saved_cursor);
+                    store
+                } else {
+                    let mut store = LabelStore::new()?;
+                    store.store = Some(db);
+                    store
                 }
-                _ => continue,
-            };
-            let now = now();
-            let mut bin: &[u8] = &bin;
-            // the schema for this endpoint is declared here:
-            // https://github.com/bluesky-social/atproto/blob/main/lexicons/com/atproto/label/subscribeLabels.json
-            match header_type(&mut bin)? {
-                StreamHeaderType::Error => {
-                    #[derive(Deserialize)]
-                    struct ErrorPayload {
-                        error: String,
-                        message: Option<String>,
+            }
+            None => LabelStore::new()?,
+        };
+
+        store.resolver = Arc::clone(&resolver);
+        // set when we connect using a labeler identity remembered in the database, so we can look
+        // it up again if that doesn't work
+        let mut identity_lookup = None;
+        let mut labeler_domain = match self {
+            GetCmd::Lookup(cmd) => {
+                let handle_or_did = cmd.targets().next().cloned().unwrap_or_default();
+                let lookup = IdentityLookup {
+                    handle_or_did,
+                    resolver,
+                };
+                let cached = match &store.store {
+                    Some(db) if cmd.cached_identity => {
+                        db::get_labeler_identity(db, &lookup.handle_or_did)?
                     }
-                    let ErrorPayload { error, message } = ciborium::from_reader(&mut bin)
-                        .map_err(|e| err!("malformed stream error: {e}"))?;
-                    if !bin.is_empty() {
-                        let extra_bytes = bin.len();
-                        println!(
-                            "EXTRA DATA: received {extra_bytes} at end of event stream error \
-                            message"
+                    _ => None,
+                };
+                let identity = match cached {
+                    Some(identity) => {
+                        say!(
+                            "using the identity of {handle_or_did} saved in the database, last \
+                            verified at {verified_at}",
+                            handle_or_did = lookup.handle_or_did,
+                            verified_at = identity.verified_at.to_rfc3339(),
                         );
-                    };
-                    break 'stream_result Ok(StreamResult::AtprotoError { error, message });
-                }
-                StreamHeaderType::Type(ty) => {
-                    if ty == "#labels" {
-                        let (seq, labels) = LabelRecord::from_subscription_record(&mut bin)?;
-                        if seq <= store.cursor {
-                            bail!(
-                                "seq did not increase (was {was}, is now {seq})",
-                                was = store.cursor
-                            );
+                        say!();
+                        say!("did:     {did}", did = identity.did);
+                        say!("labeler: {domain}", domain = identity.labeler_domain);
+                        store.cached_identity_verified_at = Some(identity.verified_at);
+                        identity_lookup = Some(lookup);
+                        identity
+                    }
+                    None => {
+                        let identity = lookup.resolve().await?;
+                        if let Some(db) = &store.store {
+                            db::save_labeler_identity(db, &lookup.handle_or_did, &identity)?;
                         }
-                        store.process_labels(labels, &now)?;
-                        store.cursor = seq;
-                    } else if ty == "#info" {
-                        let info: atrium_api::com::atproto::label::subscribe_labels::Info =
-                            ciborium::from_reader(&mut bin)
-                                .map_err(|e| err!("error parsing #info message: {e}"))?;
-                        let name = &info.name;
-                        let message = &info.message;
-                        println!("info: {name:?}: {message:?}");
-                    } else {
-                        bail!("unknown event stream message type: {ty:?}");
+                        identity
                     }
-                    if !bin.is_empty() {
-                        let extra_bytes = bin.len();
-                        println!(
-                            "EXTRA DATA: received {extra_bytes} at end of event stream message"
-                        );
-                    };
+                };
+                // because we are looking up the did document to find the service, we will know
+                // ahead of time what the src did should be for all the label records
+                store.set_known_did(&identity.did)?;
+                // keep the labeler's definitions of its label values alongside its records,
+                // refreshed each run
+                if let Some(db) = &store.store {
+                    match policies::save_label_definitions(db, &store.resolver, &identity.did).await
+                    {
+                        Ok(defined) => say!(
+                            "saved the definitions of {defined} label value(s) to the database"
+                        ),
+                        Err(e) => {
+                            say!("warning: could not save the labeler's label definitions: {e}")
+                        }
+                    }
+                }
+                // when reading from several labelers, tell their messages apart by did from now on
+                let _ = SAY_PREFIX.try_with(|prefix| {
+                    *prefix.borrow_mut() = format!("[{did}]", did = identity.did);
+                });
+                identity.labeler_domain
+            }
+            GetCmd::Direct(cmd) => {
+                let labeler_domain = lookup::labeler_host(&cmd.labeler_service, cmd.url)?;
+                if let Some(did) = &cmd.labeler_did {
+                    let did = lookup::checked_did(&normalize_did(did), "given as --labeler-did")?;
+                    store.set_known_did(&did)?;
                 }
+                if cmd.preflight {
+                    preflight(
+                        resolver.http_client(),
+                        &labeler_domain,
+                        lookup::timeout_from_secs(common_args.connect_timeout),
+                    )
+                    .await?;
+                }
+                labeler_domain
+            }
+        };
+
+        store.table = common_args.table.clone();
+        store.labeler_domain = labeler_domain.clone();
+        store.summary_json = match &common_args.output_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir).map_err(|e| {
+                    err!(
+                        "could not create output directory {dir}: {e}",
+                        dir = dir.display()
+                    )
+                })?;
+                let name = store
+                    .labeler_dids
+                    .iter()
+                    .next()
+                    .map_or(labeler_domain.as_str(), Did::as_str);
+                Some(dir.join(format!("{}.summary.json", file_name_safe(name))))
+            }
+            None => common_args.summary_json.clone(),
+        };
+        store.json_pretty = common_args.json_pretty;
+        store.local_time = common_args.local_time;
+        store.compact = common_args.compact;
+        store.emitter = common_args.emit.map(RecordEmitter::new).transpose()?;
+        store.warn_expiring_soon_days = common_args.warn_expiring_soon;
+        store.preserve_timestamps = common_args.preserve_timestamps;
+        store.require_cid_for_records = common_args.require_cid_for_records;
+        store.normalize_targets = common_args.normalize_targets;
+        store.resolve_handles = common_args.resolve_handles;
+        match common_args.mode {
+            Mode::Subscribe if common_args.query_cursor.is_some() => {
+                bail!("--query-cursor only works with --mode poll");
             }
+            Mode::Poll if common_args.cursor != 0 => {
+                bail!(
+                    "--cursor is a seq in the subscription stream, which polling doesn't use; \
+                    give --query-cursor to start polling somewhere else"
+                );
+            }
+            _ => {}
         }
-        Ok(StreamResult::Ok)
-    };
-    let end = now();
-    drop(recv);
-    println!(
-        "elapsed: {}",
-        humantime::format_duration((end - begin).to_std()?)
-    );
-    stream_result
-}
+        let mut source = match common_args.mode {
+            Mode::Subscribe => {
+                let mut subscription =
+                    Subscription::new(saved_cursor.unwrap_or(common_args.cursor));
+                subscription.strict = common_args.strict;
+                subscription.max_batch = common_args.max_batch.get();
+                subscription.buffer_budget = common_args
+                    .max_buffer_memory
+                    .map(|max| BufferBudget::new(max.get(), common_args.max_message_size.get()));
+                Source::Subscribe(subscription)
+            }
+            Mode::Poll => Source::Poll(Polling {
+                query_cursor: common_args.query_cursor.clone(),
+            }),
+        };
+        store.max_memory = common_args.max_memory.map(NonZeroUsize::get);
+        store.tail = common_args.tail.map(NonZeroUsize::get);
+        store.insert_batch_size = common_args.insert_batch_size.get();
+        store.conflict_policy = common_args.conflict_policy;
 
-/// waits for the timer only if a one is provided
-async fn conditional_sleep(t: Option<tokio::time::Sleep>) -> Option<()> {
-    match t {
-        Some(timer) => {
-            timer.await;
-            Some(())
+        if let (Source::Poll(polling), true) = (&mut source, common_args.continue_from_db) {
+            let latest = match (&store.store, &common_args.save_to_db) {
+                (Some(db), _) => db::latest_query_cursor(db, &store.table, &labeler_domain)?,
+                // a dry run doesn't keep the database open
+                (None, Some(path)) => db::latest_query_cursor(
+                    &db::open_read_only(path)?,
+                    &store.table,
+                    &labeler_domain,
+                )?,
+                (None, None) => None,
+            };
+            match &latest {
+                Some(cursor) => say!("continuing from query cursor {cursor:?}, where the last poll left off"),
+                None => say!("nothing has been polled from {labeler_domain} into the table yet; polling from the beginning"),
+            }
+            polling.query_cursor = latest;
         }
-        None => None,
-    }
-}
 
-struct LabelStore {
-    /// database we are saving labels into
-    store: Option<Connection>,
-    /// set of all src dids we have seen from the labeler stream so far, paired with their prior seq
-    labeler_dids: HashSet<Rc<str>>,
-    /// total labels read
-    total_labels: usize,
-    /// tracked effective labels
-    effective: HashMap<LabelKey, LabelRecord>,
-    /// greatest create timestamp of a label we've seen this trip
-    latest_create_timestamp: Option<Rc<str>>,
-    /// cursor (largest known seq)
-    cursor: i64,
-}
+        if let Some(db) = &store.store {
+            match db::find_unfinished_run(db, &store.table, &labeler_domain)? {
+                Some(run) => store.resume_run(run, &mut source)?,
+                None => {
+                    store.run_id = Some(db::insert_run(
+                        db,
+                        &store.table,
+                        &labeler_domain,
+                        &store.run_start,
+                        source.cursor(),
+                    )?);
+                }
+            }
+        }
 
-impl LabelStore {
-    fn new() -> Result<Self> {
-        Ok(Self {
-            store: None,
-            total_labels: 0,
-            effective: HashMap::new(),
-            labeler_dids: HashSet::new(),
-            latest_create_timestamp: None,
-            cursor: 0,
-        })
-    }
+        report.start_cursor = source.cursor();
+        report.update(&store, &source);
 
-    /// record the foreknowledge of an expected src did
-    fn set_known_did(&mut self, did: Rc<str>) -> Result<()> {
-        if !self.labeler_dids.is_empty() {
-            bail!("label store already knows of a labeler did");
+        let _pid_file = common_args
+            .pid_file
+            .as_deref()
+            .map(PidFile::create)
+            .transpose()?;
+
+        #[cfg(feature = "tui")]
+        if common_args.tui {
+            if matches!(common_args.mode, Mode::Poll) {
+                bail!("--tui only works with --mode subscribe");
+            }
+            if common_args.emit.is_some() {
+                bail!("--tui can't share the terminal with records written by --emit");
+            }
+            store.dashboard = Some(tui::Dashboard::start()?);
         }
-        self.labeler_dids.insert(did);
-        Ok(())
-    }
 
-    fn process_labels(&mut self, labels: Vec<LabelRecord>, now: &DateTime) -> Result<()> {
+        let unused_options = StreamConfig::from(&common_args).unused_options();
+        if !unused_options.is_empty() {
+            say!(
+                "warning: ignoring {options}, which only work with --mode subscribe",
+                options = unused_options.join(", "),
+            );
+        }
+
+        say!();
+        say!("streaming from labeler service");
+
+        // We retry the entire streaming process until we fail multiple times without making any
+        // forward progress. Some labeling services seem to behave strangely and poorly,
+        // deterministically rebuffing attempts to stream label history from cursor zero by saying
+        // that the consumer is "too slow" no matter how fast it is, requiring the consumer to
+        // repeatedly resume at marching intervals to get the whole story. What counts as progress is
+        // the cursor advancing by at least --min-seq-progress, or when polling, getting another page.
+        const MAX_RETRIES: usize = 3;
+        let mut retries = 0;
+        // on ctrl-c, stop streaming but still save and summarize what was received. the listener
+        // is made once, so a ctrl-c between connections isn't missed
+        let mut interrupt = std::pin::pin!(tokio::signal::ctrl_c());
+        let mut interrupted = false;
+        while retries < MAX_RETRIES {
+            let last_cursor = source.cursor();
+            let last_query_cursor = source.query_cursor().map(str::to_owned);
+            let config = StreamConfig::from(&common_args);
+            let read = tokio::select! {
+                read = source.read(&mut store, &config, &labeler_domain) => Some(read),
+                _ = &mut interrupt => None,
+            };
+            let Some(read) = read else {
+                interrupted = true;
+                break;
+            };
+            let result = match read {
+                Ok(result) => result,
+                Err(e) => {
+                    // keep what we did receive before failing
+                    store.flush_inserts()?;
+                    report.update(&store, &source);
+                    return Err(e);
+                }
+            };
+            let too_slow = matches!(
+                &result,
+                StreamResult::AtprotoError { error, .. } if error == "ConsumerTooSlow"
+            );
+            let (connection_failed, progressed) = match &source {
+                Source::Subscribe(subscription) => (
+                    subscription.cursor <= last_cursor,
+                    subscription.cursor - last_cursor >= common_args.min_seq_progress,
+                ),
+                Source::Poll(polling) => {
+                    let progressed = polling.query_cursor != last_query_cursor;
+                    (!progressed, progressed)
+                }
+            };
+            let connection_failed =
+                connection_failed && matches!(result, StreamResult::WebsocketError);
+            report.update(&store, &source);
+            let stats = &mut store.run_stats;
+            stats.connections += 1;
+            if !progressed {
+                stats.retries += 1;
+            }
+            match result {
+                StreamResult::Ok => {
+                    stats.ended_ok += 1;
+                    break;
+                }
+                StreamResult::Stepped => {
+                    stats.ended_stepped += 1;
+                    say!("cursor advanced by the requested step; reconnecting");
+                }
+                StreamResult::Closed => stats.ended_closed += 1,
+                StreamResult::WebsocketError => stats.ended_websocket_error += 1,
+                StreamResult::AtprotoError { error, message } => {
+                    stats.ended_atproto_error += 1;
+                    say!(
+                        "the labeler returned an error: {error}: {message}",
+                        message = message.as_deref().unwrap_or("(no error message)"),
+                    );
+                    stats.last_atproto_error = Some(db::AtprotoError {
+                        error,
+                        message,
+                        cursor: source.cursor(),
+                    });
+                }
+            }
+            if connection_failed {
+                if let Some(lookup) = identity_lookup.take() {
+                    say!("could not stream using the saved labeler identity; looking it up again");
+                    let identity = lookup.resolve().await?;
+                    if !store.labeler_dids.contains(identity.did.as_str()) {
+                        bail!(
+                            "{handle_or_did} now resolves to {did}, not the did saved in the \
+                            database",
+                            handle_or_did = lookup.handle_or_did,
+                            did = identity.did,
+                        );
+                    }
+                    if let Some(db) = &store.store {
+                        db::save_labeler_identity(db, &lookup.handle_or_did, &identity)?;
+                        if let Some(run_id) = store.run_id {
+                            db::set_run_labeler_domain(db, run_id, &identity.labeler_domain)?;
+                        }
+                    }
+                    store.cached_identity_verified_at = None;
+                    labeler_domain = identity.labeler_domain;
+                    store.labeler_domain = labeler_domain.clone();
+                    continue;
+                }
+            }
+            retries = if progressed { 0 } else { retries + 1 };
+            if let Some(max) = common_args.adaptive_buffer_max {
+                if too_slow && retries > 0 && common_args.buffer_size < max {
+                    common_args.buffer_size = common_args
+                        .buffer_size
+                        .saturating_mul(NonZeroUsize::new(2).unwrap())
+                        .min(max);
+                    say!(
+                        "growing buffer size to {size} for the next attempt",
+                        size = common_args.buffer_size
+                    );
+                }
+            }
+        }
+        // put the terminal back before reporting how it went
+        #[cfg(feature = "tui")]
+        drop(store.dashboard.take());
+        // once ctrl-c is being listened for it no longer stops the process by itself, so from here
+        // it stops it right away, in case finishing up takes too long
+        tokio::spawn(async {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                std::process::exit(130);
+            }
+        });
+        if interrupted {
+            say!("interrupted; saving and summarizing the labels received so far");
+            store.finalize(report, &source).await?;
+            bail!("interrupted");
+        }
+        if retries == MAX_RETRIES {
+            if common_args.min_seq_progress > 1 {
+                say!(
+                    "reached maximum retries without the cursor advancing by at least {min} on \
+                    a connection; giving up",
+                    min = common_args.min_seq_progress,
+                );
+            } else {
+                say!("reached maximum retries without making progress; giving up");
+            }
+        }
+        if common_args.adaptive_buffer_max.is_some() {
+            say!(
+                "final buffer size used: {size}",
+                size = common_args.buffer_size
+            );
+        }
+
+        store.finalize(report, &source).await
+    }
+}
+
+impl StatsCmd {
+    fn go(self) -> Result<()> {
+        if !self.db.is_file() {
+            bail!("no database found at {path}", path = self.db.display());
+        }
+        let db = db::open_for_reading(&self.db, &self.table)?;
+        let filter = self.filter.to_filter();
+
+        say!(
+            "label records cover {targets} unique target(s) with {vals} distinct label value(s)",
+            targets = db::count_distinct(&db, &self.table, &filter, "target_uri")?,
+            vals = db::count_distinct(&db, &self.table, &filter, "val")?,
+        );
+        let src_dids = db::get_distinct_src_dids(&db, &self.table, &filter)?;
+        say!("label records from {n} source did(s):", n = src_dids.len());
+        let mut any_missing = false;
+        for did in src_dids {
+            // each src's seqs are checked for gaps among all of its records, whatever the filter,
+            // since the records a filter leaves out would look like missing seqs
+            let from_src = RecordFilter {
+                src: Some(did.clone()),
+                ..Default::default()
+            };
+            let seqs = match db::get_seq_range(&db, &self.table, &from_src)? {
+                Some((min_seq, max_seq)) => {
+                    let distinct = db::count_distinct_seqs(&db, &self.table, &from_src)?;
+                    let missing = (max_seq - min_seq + 1) as u64 - distinct;
+                    any_missing |= missing > 0;
+                    format!(
+                        "seq range {min_seq}..{max_seq}: {distinct} distinct seqs ({missing} \
+                        missing)"
+                    )
+                }
+                None => "no records with a seq".to_owned(),
+            };
+            say!("   {did} {seqs}");
+        }
+        if any_missing {
+            say!(
+                "   missing seqs were never received, or were removed by maintenance \
+                --prune-expired-before"
+            );
+        }
+
+        if self.values {
+            say!();
+            match self.as_of {
+                Some(as_of) => say!(
+                    "label values, by records applying and negating them, and in effect at \
+                    {as_of}:",
+                    as_of = as_of.to_rfc3339()
+                ),
+                None => say!("label values, by records applying and negating them:"),
+            }
+            let (filter, as_of) = match self.as_of {
+                Some(as_of) => (filter.clone().created_before(as_of), as_of),
+                None => (filter.clone(), now()),
+            };
+            for usage in db::get_value_usage(&db, &self.table, &filter, &as_of)? {
+                let counts = ValueCounts {
+                    applied: usage.applied,
+                    negated: usage.negated,
+                };
+                say!(
+                    "   {src} {val:?}: {applied} applied, {negated} negated ({percent:.0}%), \
+                    {effective} effective",
+                    src = usage.src,
+                    val = usage.val,
+                    applied = usage.applied,
+                    negated = usage.negated,
+                    percent = counts.negation_ratio() * 100.0,
+                    effective = usage.effective,
+                );
+            }
+        }
+
+        if self.velocity {
+            say!();
+            say!("labeler activity, by labels created per day:");
+            let velocities = db::get_velocities(&db, &self.table, &filter)?
+                .into_iter()
+                .sorted_by(|a, b| {
+                    b.per_day()
+                        .unwrap_or(0.0)
+                        .total_cmp(&a.per_day().unwrap_or(0.0))
+                });
+            for velocity in velocities {
+                let src = &velocity.src;
+                let count = velocity.count;
+                let rate = match velocity.per_day() {
+                    Some(per_day) => format!("{per_day:>10.2} labels/day"),
+                    None => format!("{:>21}", "(no time span)"),
+                };
+                let busiest = match &velocity.busiest_day {
+                    Some((day, n)) => format!("busiest day {day} with {n}"),
+                    None => "no parseable create timestamps".to_owned(),
+                };
+                say!("   {src} {rate} ({count} label record(s); {busiest})");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MigrateCmd {
+    fn go(self) -> Result<()> {
+        if !self.db.is_file() {
+            bail!("no database found at {path}", path = self.db.display());
+        }
+        let db = db::open(&self.db)?;
+        let applied = db::migrate(&db, &self.table)?;
+        if applied.is_empty() {
+            say!(
+                "database is already up to date at schema version {version}, table {table} at \
+                {table_version}",
+                version = db::DATABASE_SCHEMA_VERSION,
+                table = self.table,
+                table_version = db::LABEL_TABLE_SCHEMA_VERSION,
+            );
+            return Ok(());
+        }
+        for migration in applied {
+            let target = match &migration.table {
+                Some(table) => format!("table {table}"),
+                None => "database".to_owned(),
+            };
+            say!(
+                "applied {target} schema version {version}: {description}",
+                version = migration.version,
+                description = migration.description,
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Number of duplicated seqs and labels --check-integrity lists
+const DUPLICATE_SAMPLE_COUNT: usize = 10;
+
+impl MaintenanceCmd {
+    fn go(self) -> Result<()> {
+        if !self.db.is_file() {
+            bail!("no database found at {path}", path = self.db.display());
+        }
+        if !self.normalize_timestamps
+            && self.prune_expired_before.is_none()
+            && !self.vacuum
+            && !self.check_integrity
+        {
+            bail!("nothing to do; choose a maintenance task, like --normalize-timestamps");
+        }
+        if self.check_integrity && !self.check_integrity()? {
+            bail!("the database has integrity problems");
+        }
+        let mut db = db::connect(&self.db, &self.table)?;
+        if self.normalize_timestamps {
+            let changed = db::compact_timestamps(&db, &self.table)?;
+            say!("normalized the create timestamps of {changed} label record(s)");
+        }
+        if let Some(days) = self.prune_expired_before {
+            let cutoff = now() - chrono::Duration::days(days.into());
+            let pruned = db::prune_expired(&mut db, &self.table, &cutoff)?;
+            say!(
+                "removed {superseded} expired label record(s) that later records had replaced",
+                superseded = pruned.superseded,
+            );
+            say!(
+                "removed {labels} label(s) whose latest record expired before {cutoff}, along with \
+                all {records} of their record(s)",
+                labels = pruned.expired_labels,
+                records = pruned.expired_label_records,
+                cutoff = cutoff.to_rfc3339(),
+            );
+        }
+        if self.vacuum {
+            say!("vacuuming the database...");
+            db::vacuum(&db)?;
+        }
+        Ok(())
+    }
+
+    /// reports on the integrity of the database without changing it, returning whether it is fine
+    fn check_integrity(&self) -> Result<bool> {
+        let db = db::open_read_only(&self.db)?;
+        let mut ok = true;
+        let problems = db::integrity_check(&db)?;
+        if problems.is_empty() {
+            say!("OK --> sqlite found no damage in the database file");
+        } else {
+            ok = false;
+            say!("XX --> sqlite found damage in the database file:");
+            for problem in problems {
+                say!("   {problem}");
+            }
+        }
+        let duplicates = db::find_duplicate_seqs(&db, &self.table)?;
+        if duplicates.is_empty() {
+            say!("OK --> no src has more than one record at the same seq");
+        } else {
+            ok = false;
+            say!(
+                "XX --> {n} seq(s) have more than one record from the same src:",
+                n = duplicates.len()
+            );
+            for duplicate in duplicates.iter().take(DUPLICATE_SAMPLE_COUNT) {
+                say!(
+                    "   {count} x seq {seq} from {src}",
+                    count = duplicate.count,
+                    seq = duplicate.seq,
+                    src = duplicate.src,
+                );
+            }
+            if duplicates.len() > DUPLICATE_SAMPLE_COUNT {
+                say!("   ...");
+            }
+        }
+        let duplicates = db::find_duplicate_key_seqs(&db, &self.table)?;
+        if duplicates.is_empty() {
+            say!("OK --> no label has more than one record at the same seq");
+        } else {
+            ok = false;
+            say!(
+                "XX --> {n} label(s) have more than one record at the same seq:",
+                n = duplicates.len()
+            );
+            for duplicate in duplicates.iter().take(DUPLICATE_SAMPLE_COUNT) {
+                say!(
+                    "   {count} x seq {seq} from {src}: {val:?} on {target}",
+                    count = duplicate.count,
+                    seq = duplicate.seq,
+                    src = duplicate.src,
+                    val = duplicate.val,
+                    target = duplicate.target_uri,
+                );
+            }
+            if duplicates.len() > DUPLICATE_SAMPLE_COUNT {
+                say!("   ...");
+            }
+        }
+        let unreadable = db::count_unreadable_timestamps(&db, &self.table)?;
+        if unreadable == 0 {
+            say!("OK --> every record's timestamps can be read");
+        } else {
+            ok = false;
+            say!(
+                "XX --> {unreadable} record(s) have create or expiry timestamps that can't be read \
+                as times"
+            );
+        }
+        Ok(ok)
+    }
+}
+
+/// Labels are tracked in one of two ways. When a database is attached, every record is written to
+/// it and the effective labels are computed from the database at the end, so memory use does not
+/// grow with the size of the labeler. Otherwise the latest record for each label key is kept in
+/// memory, and if that grows past `max_memory` it is moved into a temporary database file that is
+/// then used the same way.
+struct LabelStore {
+    /// database we are saving labels into
+    store: Option<Connection>,
+    /// name of the table in `store` that holds the label records
+    table: String,
+    /// host of the labeler service we are streaming from
+    labeler_domain: String,
+    /// file to write the summary to as JSON, if any
+    summary_json: Option<PathBuf>,
+    /// whether to indent JSON output
+    json_pretty: bool,
+    /// whether to show times in the summary in the local timezone rather than UTC
+    local_time: bool,
+    /// whether to print the summary as one line per src did
+    compact: bool,
+    /// writes records to stdout as they are received, for --emit
+    emitter: Option<RecordEmitter>,
+    /// effective labels expiring within this many days are pointed out in the summary
+    warn_expiring_soon_days: u32,
+    /// temporary directory holding the database we spilled into, if any. it is removed along with
+    /// the database and its journal files when dropped, however the run ends
+    spill_dir: Option<tempfile::TempDir>,
+    /// labels waiting to be written to the database, with the time they were received and the
+    /// index of the label message they came in
+    pending_inserts: Vec<(LabelRecord, DateTime, i64)>,
+    /// number of label messages (or pages of queried labels) processed this run, which indexes the
+    /// next one
+    frames_processed: i64,
+    /// number of pending labels to collect before writing them
+    insert_batch_size: usize,
+    /// number of records newly written to the database
+    inserted_records: usize,
+    /// number of records that were already present in the database
+    ignored_records: usize,
+    /// what to do with records that conflict with ones already in the database
+    conflict_policy: ConflictPolicy,
+    /// number of records that conflicted with ones already in the database
+    conflict_count: usize,
+    /// the first few conflicts, described
+    conflict_samples: Vec<String>,
+    /// set of all src dids we have seen from the labeler stream so far, paired with their prior seq
+    labeler_dids: HashSet<Did>,
+    /// the latest seq already saved in the database the store was started from, where streaming
+    /// into it picks up
+    saved_cursor: i64,
+    /// number of distinct label values already saved in the database the store was started from
+    saved_value_count: usize,
+    /// the did the labeler's records are expected to come from, if it was looked up or given
+    expected_did: Option<Did>,
+    /// the greatest seq and latest create timestamp among the records from each src did this run
+    src_progress: HashMap<Did, SrcProgress>,
+    /// shared allocations for strings that repeat across labels
+    interner: Interner,
+    /// total labels read
+    total_labels: usize,
+    /// tracked effective labels, when there is no database to compute them from
+    effective: HashMap<LabelKey, LabelRecord>,
+    /// approximate bytes used by `effective`
+    effective_memory: usize,
+    /// approximate limit on `effective_memory` before spilling to a temporary database
+    max_memory: Option<usize>,
+    /// number of effective labels to keep, dropping the oldest created first, for --tail
+    tail: Option<usize>,
+    /// the keys of `effective` ordered by the create time of their record, when there is a tail
+    /// limit to enforce
+    tail_index: BTreeSet<(DateTime, LabelKey)>,
+    /// when this run started; records seen in the database since then are from this run
+    run_start: DateTime,
+    /// id of this run's row in the database's run_stats, if we are saving to a database
+    run_id: Option<i64>,
+    /// how the connections to the labeler went
+    run_stats: RunStats,
+    /// problems in label records that were worked around while decoding them
+    anomalies: DecodeAnomalies,
+    /// total size of the websocket messages received
+    bytes_received: u64,
+    /// total time spent decoding frames and storing their records
+    processing_time: Duration,
+    /// total time spent connected to the labeler
+    streaming_time: Duration,
+    /// when the labeler identity we connected with was last verified, if it was saved in the
+    /// database rather than looked up this run
+    cached_identity_verified_at: Option<DateTime>,
+    /// whether to look up the handles of the dids shown in the summary
+    resolve_handles: bool,
+    /// looks up the handles of dids for the summary
+    resolver: Arc<Resolver>,
+    /// handles found for the dids shown in the summary
+    resolved_handles: BTreeMap<String, String>,
+    /// whether to keep label records' timestamps as the labeler wrote them
+    preserve_timestamps: bool,
+    /// whether to fail on labels targeting records without a cid rather than count them
+    require_cid_for_records: bool,
+    /// number of labels that target specific records without pinning them to a cid
+    missing_cid_count: usize,
+    /// number of labels whose target cid isn't a dag-cbor sha2-256 CIDv1, as records' cids are
+    malformed_cid_count: usize,
+    /// number of labels whose src labels its own account
+    self_label_count: usize,
+    /// whether to resolve targets that name accounts by handle to their dids
+    normalize_targets: bool,
+    /// number of labels whose target names an account by handle rather than by did
+    handle_target_count: usize,
+    /// number of those whose target was rewritten with the account's did
+    normalized_target_count: usize,
+    /// handles that couldn't be resolved, so they are only looked up and warned about once
+    unresolvable_handles: HashSet<String>,
+    /// number of labels received with each length of signature
+    signature_lengths: BTreeMap<usize, usize>,
+    /// number of labels received without a signature
+    unsigned_count: usize,
+    /// number of records applying and negating each label value from each src
+    value_counts: BTreeMap<(Did, Rc<str>), ValueCounts>,
+    /// greatest create timestamp of a label we've seen this trip
+    latest_create_timestamp: Option<Rc<str>>,
+    /// live view of the stream, if one was asked for
+    #[cfg(feature = "tui")]
+    dashboard: Option<tui::Dashboard>,
+}
+
+impl LabelStore {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            store: None,
+            table: db::DEFAULT_LABEL_TABLE.to_owned(),
+            labeler_domain: String::new(),
+            summary_json: None,
+            json_pretty: false,
+            local_time: false,
+            compact: false,
+            emitter: None,
+            warn_expiring_soon_days: 7,
+            spill_dir: None,
+            pending_inserts: Vec::new(),
+            frames_processed: 0,
+            insert_batch_size: 1,
+            inserted_records: 0,
+            ignored_records: 0,
+            conflict_policy: ConflictPolicy::KeepOld,
+            conflict_count: 0,
+            conflict_samples: Vec::new(),
+            total_labels: 0,
+            effective: HashMap::new(),
+            tail: None,
+            tail_index: BTreeSet::new(),
+            effective_memory: 0,
+            max_memory: None,
+            run_start: now(),
+            run_id: None,
+            run_stats: RunStats::default(),
+            labeler_dids: HashSet::new(),
+            saved_cursor: 0,
+            saved_value_count: 0,
+            expected_did: None,
+            src_progress: HashMap::new(),
+            interner: Interner::default(),
+            anomalies: DecodeAnomalies::default(),
+            bytes_received: 0,
+            processing_time: Duration::ZERO,
+            streaming_time: Duration::ZERO,
+            cached_identity_verified_at: None,
+            resolve_handles: false,
+            resolver: Arc::new(Resolver::new(lookup::DEFAULT_PLC_DIRECTORY, None)?),
+            resolved_handles: BTreeMap::new(),
+            preserve_timestamps: false,
+            require_cid_for_records: false,
+            missing_cid_count: 0,
+            malformed_cid_count: 0,
+            signature_lengths: BTreeMap::new(),
+            unsigned_count: 0,
+            self_label_count: 0,
+            normalize_targets: false,
+            handle_target_count: 0,
+            normalized_target_count: 0,
+            unresolvable_handles: HashSet::new(),
+            value_counts: BTreeMap::new(),
+            latest_create_timestamp: None,
+            #[cfg(feature = "tui")]
+            dashboard: None,
+        })
+    }
+
+    /// gathers the numbers shown on the dashboard
+    #[cfg(feature = "tui")]
+    fn dashboard_status(&self, cursor: i64) -> tui::Status<'_> {
+        let mut value_counts = BTreeMap::<&str, (usize, usize)>::new();
+        for ((_, val), counts) in &self.value_counts {
+            let (applied, negated) = value_counts.entry(val).or_default();
+            *applied += counts.applied;
+            *negated += counts.negated;
+        }
+        tui::Status {
+            labeler_domain: &self.labeler_domain,
+            labeler_did: match self.labeler_dids.len() {
+                1 => self.labeler_dids.iter().next().map(Did::as_str),
+                _ => None,
+            },
+            cursor,
+            connections: self.run_stats.connections,
+            retries: self.run_stats.retries,
+            total_labels: self.total_labels,
+            value_counts: value_counts
+                .into_iter()
+                .map(|(val, (applied, negated))| (val, applied, negated))
+                .collect(),
+        }
+    }
+
+    /// starts a store saving into a database that already has label records, to pick up from the
+    /// latest seq saved there. the database stays the source of truth for effective labels, so
+    /// nothing is read into memory besides the srcs seen so far and a few counts
+    pub fn with_db_connection(db: Connection, table: &str) -> Result<Self> {
+        let mut store = Self::new()?;
+        let all = RecordFilter::default();
+        for did in db::get_distinct_src_dids(&db, table, &all)? {
+            let did = Did::try_from(store.interner.intern(&did))?;
+            store.labeler_dids.insert(did);
+        }
+        store.saved_cursor = db::get_seq_range(&db, table, &all)?.map_or(0, |(_, latest)| latest);
+        store.saved_value_count = db::count_distinct(&db, table, &all, "val")?;
+        store.table = table.to_owned();
+        store.store = Some(db);
+        Ok(store)
+    }
+
+    /// says where a store started from a database continues from
+    fn say_continuing(&self, mode: Mode) {
+        if mode == Mode::Subscribe {
+            say!(
+                "continuing from seq {cursor}, the latest saved in the database, which has \
+                {values} distinct label value(s) so far",
+                cursor = self.saved_cursor,
+                values = self.saved_value_count,
+            );
+        }
+    }
+
+    /// record the foreknowledge of an expected src did
+    fn set_known_did(&mut self, did: &str) -> Result<()> {
+        if !self.labeler_dids.is_empty() {
+            if self.labeler_dids.contains(did) {
+                return Ok(());
+            }
+            bail!(
+                "expected label records from {did}, but the label store already has them from \
+                {known}",
+                known = self.labeler_dids.iter().sorted().join(", ")
+            );
+        }
+        let did = Did::try_from(self.interner.intern(did))?;
+        self.labeler_dids.insert(did.clone());
+        self.expected_did = Some(did);
+        Ok(())
+    }
+
+    /// counts labels whose target names an account by handle, and rewrites them with the
+    /// account's did if we are normalizing targets
+    async fn check_handle_targets(&mut self, labels: &mut [LabelRecord]) {
+        for label in labels {
+            let Some(target) = HandleTarget::parse(&label.dbkey.key.target_uri) else {
+                continue;
+            };
+            if self.handle_target_count == 0 {
+                say!(
+                    "warning: label records are targeting accounts by handle rather than by did, \
+                    such as: {uri}; they will be counted in the summary",
+                    uri = label.dbkey.key.target_uri
+                );
+            }
+            self.handle_target_count += 1;
+            if !self.normalize_targets {
+                continue;
+            }
+            // handles are case-insensitive
+            let handle = target.handle.to_ascii_lowercase();
+            if self.unresolvable_handles.contains(&handle) {
+                continue;
+            }
+            match self.resolver.resolve_handle(&handle).await {
+                Ok(did) => {
+                    let normalized = target.with_did(&did);
+                    let original =
+                        std::mem::replace(&mut label.dbkey.key.target_uri, normalized.into());
+                    label.original_target_uri = Some(original.to_string());
+                    self.normalized_target_count += 1;
+                }
+                Err(e) => {
+                    say!(
+                        "warning: could not resolve the handle {handle} targeted by label \
+                        records, so its targets are left as they are: {e}"
+                    );
+                    self.unresolvable_handles.insert(handle);
+                }
+            }
+        }
+    }
+
+    async fn process_labels(&mut self, mut labels: Vec<LabelRecord>, now: &DateTime) -> Result<()> {
+        self.check_handle_targets(&mut labels).await;
+        let frame_index = self.frames_processed;
+        self.frames_processed += 1;
         self.total_labels += labels.len();
+        let missing_cids = labels
+            .iter()
+            .filter(|label| label.is_unpinned_record_label())
+            .count();
+        if missing_cids > 0 {
+            if self.require_cid_for_records {
+                bail!(
+                    "{missing_cids} label record(s) target specific records without pinning a cid"
+                );
+            }
+            if self.missing_cid_count == 0 {
+                say!(
+                    "warning: label records are targeting specific records without pinning a \
+                    cid; they will be counted in the summary"
+                );
+            }
+            self.missing_cid_count += missing_cids;
+        }
+        let mut malformed_cids = labels
+            .iter()
+            .filter_map(|label| verify_cid_format(label.target_cid.as_deref()?).err());
+        if let Some(first) = malformed_cids.next() {
+            if self.malformed_cid_count == 0 {
+                say!(
+                    "warning: label records pin target cids that aren't in the form of a record's \
+                    cid, such as: {first}; they will be counted in the summary"
+                );
+            }
+            self.malformed_cid_count += 1 + malformed_cids.count();
+        }
+        let receipt = Receipt {
+            seen_at: *now,
+            run_id: self.run_id,
+            frame_index: Some(frame_index),
+        };
         for mut label in labels {
+            match &label.sig {
+                Some(sig) => *self.signature_lengths.entry(sig.len()).or_default() += 1,
+                None => self.unsigned_count += 1,
+            }
+            if let Some(emitter) = &mut self.emitter {
+                emitter.emit(&label, &self.labeler_domain, &receipt)?;
+            }
+            if label.is_self_label() {
+                if self.self_label_count == 0 {
+                    say!(
+                        "warning: the labeler is labeling its own account; these self-labels will \
+                        be counted in the summary"
+                    );
+                }
+                self.self_label_count += 1;
+            }
             if !self.labeler_dids.contains(&label.dbkey.key.src) {
                 self.labeler_dids.insert(label.dbkey.key.src.clone());
             }
+            match self.src_progress.get_mut(&label.dbkey.key.src) {
+                Some(progress) => {
+                    progress.max_seq = progress.max_seq.max(label.dbkey.seq);
+                    if label.create_timestamp > progress.latest_create_timestamp {
+                        progress.latest_create_timestamp = label.create_timestamp.clone();
+                    }
+                }
+                None => {
+                    self.src_progress.insert(
+                        label.dbkey.key.src.clone(),
+                        SrcProgress {
+                            max_seq: label.dbkey.seq,
+                            latest_create_timestamp: label.create_timestamp.clone(),
+                        },
+                    );
+                }
+            }
+
+            let key = &label.dbkey.key;
+            let counts = self
+                .value_counts
+                .entry((key.src.clone(), key.val.clone()))
+                .or_default();
+            if label.neg {
+                counts.negated += 1;
+            } else {
+                counts.applied += 1;
+            }
+            #[cfg(feature = "tui")]
+            if let Some(dashboard) = &mut self.dashboard {
+                dashboard.record_label(&label);
+            }
 
             // keep track of the latest create timestamp
             if Some(label.create_timestamp.as_ref()) > self.latest_create_timestamp.as_deref() {
                 self.latest_create_timestamp = Some(label.create_timestamp.clone());
             }
 
-            if let Some(store) = &self.store {
-                label.insert(store, now)?;
+            if self.store.is_some() {
+                // the database is the source of truth for effective labels
+                self.pending_inserts.push((label, *now, frame_index));
+                if self.pending_inserts.len() >= self.insert_batch_size {
+                    self.flush_inserts()?;
+                }
+                continue;
             }
 
-            // discard the signature data after it's been stored in the db, we no longer need it by
-            // this point
+            // discard the signature data, we don't need it to track effective labels
             label.sig = None;
 
-            // TODO(widders): make sure the label we're effecting over has an older create timestamp
-            self.effective.insert(label.dbkey.key.clone(), label);
+            // only replace the label in effect if this one supercedes it
+            match self.effective.entry(label.dbkey.key.clone()) {
+                Entry::Occupied(mut existing) => {
+                    if label > *existing.get() {
+                        self.effective_memory += approximate_size(&label);
+                        let old = existing.insert(label);
+                        self.effective_memory -= approximate_size(&old);
+                        if self.tail.is_some() {
+                            // keep the index in step with the record now in effect for the key
+                            self.tail_index.remove(&(created_at(&old), old.dbkey.key));
+                            self.tail_index
+                                .insert((created_at(existing.get()), existing.key().clone()));
+                        }
+                    }
+                }
+                Entry::Vacant(vacant) => {
+                    self.effective_memory += approximate_size(&label);
+                    if self.tail.is_some() {
+                        self.tail_index
+                            .insert((created_at(&label), vacant.key().clone()));
+                    }
+                    vacant.insert(label);
+                }
+            }
+            if let Some(tail) = self.tail {
+                while self.effective.len() > tail {
+                    let Some((_, oldest)) = self.tail_index.pop_first() else {
+                        break;
+                    };
+                    if let Some(evicted) = self.effective.remove(&oldest) {
+                        self.effective_memory -= approximate_size(&evicted);
+                    }
+                }
+            }
+            if self
+                .max_memory
+                .is_some_and(|max| self.effective_memory > max)
+            {
+                self.spill(now)?;
+            }
+        }
+        if let Some(emitter) = &mut self.emitter {
+            emitter.flush_if_due()?;
         }
         Ok(())
     }
 
-    fn finalize(self) -> Result<()> {
-        let now = now();
+    /// picks up a run that was cut off before it finished, as if this were the same run: the
+    /// summary and the run's row cover everything it saved before it was interrupted
+    fn resume_run(&mut self, run: db::UnfinishedRun, source: &mut Source) -> Result<()> {
+        let Some(db) = &self.store else {
+            bail!("can only resume a run when saving to a database");
+        };
+        let progress = db::run_progress(
+            db,
+            &self.table,
+            &self.labeler_domain,
+            run.run_id,
+            &run.started_at,
+        )?;
+        let info_counts = db::count_infos(db, run.run_id)?;
+        self.run_id = Some(run.run_id);
+        self.run_start = run.started_at;
+        self.total_labels = progress.records;
+        self.inserted_records = progress.records;
+        self.latest_create_timestamp = progress.latest_create_timestamp.as_deref().map(Rc::from);
+        self.frames_processed = progress.last_frame_index.map_or(0, |index| index + 1);
+        for (did, max_seq, latest_create_timestamp) in &progress.srcs {
+            let did = Did::try_from(self.interner.intern(did))?;
+            self.labeler_dids.insert(did.clone());
+            self.src_progress.insert(
+                did,
+                SrcProgress {
+                    max_seq: *max_seq,
+                    latest_create_timestamp: Rc::from(latest_create_timestamp.as_str()),
+                },
+            );
+        }
+        for (src, val, applied, negated) in &progress.value_counts {
+            let key = (
+                Did::try_from(self.interner.intern(src))?,
+                self.interner.intern(val),
+            );
+            self.value_counts.insert(
+                key,
+                ValueCounts {
+                    applied: *applied,
+                    negated: *negated,
+                },
+            );
+        }
+        let (run_id, started_at) = (run.run_id, run.started_at);
+        let continuing_from = source.resume(run, &progress, info_counts);
+        say!(
+            "resuming unfinished run {run_id} started at {started_at}: {records} label record(s) \
+            already saved, continuing from {continuing_from}",
+            started_at = started_at.to_rfc3339(),
+            records = progress.records,
+        );
+        Ok(())
+    }
+
+    /// formats a did for the summary, followed by its handle if we are resolving them
+    async fn display_did(&mut self, did: &str) -> String {
+        if !self.resolve_handles {
+            return did.to_owned();
+        }
+        match self.resolver.resolve_did_to_handle(did).await {
+            Some(handle) => {
+                let shown = format!("{did} ({handle})");
+                self.resolved_handles.insert(did.to_owned(), handle);
+                shown
+            }
+            None => did.to_owned(),
+        }
+    }
+
+    /// writes any pending labels to the database
+    fn flush_inserts(&mut self) -> Result<()> {
+        if let Some(store) = &mut self.store {
+            let mut batch = db::insert_batch_tx(
+                store,
+                &self.table,
+                &self.labeler_domain,
+                self.run_id,
+                self.conflict_policy,
+            )?;
+            let mut conflicts = Vec::new();
+            for (label, seen_at, frame_index) in &self.pending_inserts {
+                match batch.insert(label, seen_at, Some(*frame_index)) {
+                    Ok(None) => {}
+                    Ok(Some(conflict)) => {
+                        if matches!(self.conflict_policy, ConflictPolicy::Error) {
+                            batch.rollback()?;
+                            db::insert_ingest_conflict(
+                                store,
+                                self.run_id,
+                                &self.table,
+                                &now(),
+                                &conflict,
+                                "stopped",
+                            )?;
+                            bail!("conflicting label record: {conflict}");
+                        }
+                        conflicts.push(conflict);
+                    }
+                    Err(e) => {
+                        // write none of the batch, so it can be retried as a whole
+                        batch.rollback()?;
+                        return Err(e);
+                    }
+                }
+            }
+            let inserted = batch.commit()?;
+            self.inserted_records += inserted;
+            self.ignored_records += self.pending_inserts.len() - inserted - conflicts.len();
+            let resolution = match self.conflict_policy {
+                ConflictPolicy::KeepNew => "replaced the saved record",
+                _ => "kept the saved record",
+            };
+            let now = now();
+            for conflict in conflicts {
+                if self.conflict_count == 0 {
+                    say!(
+                        "warning: received label records that conflict with ones already saved; \
+                        they will be listed in the summary"
+                    );
+                }
+                self.conflict_count += 1;
+                db::insert_ingest_conflict(
+                    store,
+                    self.run_id,
+                    &self.table,
+                    &now,
+                    &conflict,
+                    resolution,
+                )?;
+                if self.conflict_samples.len() < TEXT_SAMPLE_COUNT {
+                    self.conflict_samples.push(conflict.to_string());
+                }
+            }
+        }
+        self.pending_inserts.clear();
+        Ok(())
+    }
+
+    /// the number of records received from each src other than the expected did, if one was given
+    fn unexpected_srcs(&self) -> BTreeMap<&Did, usize> {
+        let mut unexpected = BTreeMap::new();
+        let Some(expected) = &self.expected_did else {
+            return unexpected;
+        };
+        for ((src, _), counts) in &self.value_counts {
+            if src != expected {
+                *unexpected.entry(src).or_default() += counts.applied + counts.negated;
+            }
+        }
+        unexpected
+    }
 
-        println!();
-        println!("--------------------");
-        println!("--> UPDATE SUMMARY");
-        println!("--------------------");
-        println!();
-        println!(
+    /// moves the tracked effective labels into a temporary database, which is used from then on
+    fn spill(&mut self, now: &DateTime) -> Result<()> {
+        let dir = tempfile::Builder::new()
+            .prefix("labelview-spill-")
+            .tempdir()
+            .map_err(|e| err!("could not create a temporary directory to spill into: {e}"))?;
+        let path = dir.path().join("labels.sqlite");
+        say!(
+            "effective labels exceeded the memory limit; spilling to {path}",
+            path = path.display()
+        );
+        let mut store = db::connect(&path, &self.table)?;
+        // sqlite's page cache, which also bounds how much it sorts in memory when the summary
+        // reads the latest records, is held to the memory limit too
+        if let Some(max_memory) = self.max_memory {
+            let kib = (max_memory / 1024).max(1) as i64;
+            store.pragma_update(None, "cache_size", -kib)?;
+        }
+        self.spill_dir = Some(dir);
+        let mut batch = db::insert_batch_tx(
+            &mut store,
+            &self.table,
+            &self.labeler_domain,
+            None,
+            ConflictPolicy::KeepOld,
+        )?;
+        // which label message each effective label came in isn't kept
+        for (_, label) in self.effective.drain() {
+            batch.insert(&label, now, None)?;
+        }
+        batch.commit()?;
+        self.effective.shrink_to_fit();
+        self.effective_memory = 0;
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// number of distinct targets among the labels received this run that are effective at `at`
+    pub fn unique_target_count(&self, at: &DateTime) -> Result<usize> {
+        let mut count = 0;
+        self.for_each_latest(at, |_, first| count += usize::from(first.target))?;
+        Ok(count)
+    }
+
+    /// number of distinct source dids among the labels received this run that are effective at `at`
+    pub fn unique_src_count(&self, at: &DateTime) -> Result<usize> {
+        let mut count = 0;
+        self.for_each_latest(at, |_, first| count += usize::from(first.src))?;
+        Ok(count)
+    }
+
+    /// number of labels received this run that are effective at `at` and target an account
+    pub fn account_label_count(&self, at: &DateTime) -> Result<usize> {
+        self.count_effective(at, |target| matches!(target, TargetKind::Account { .. }))
+    }
+
+    /// number of labels received this run that are effective at `at` and target a record
+    pub fn record_label_count(&self, at: &DateTime) -> Result<usize> {
+        self.count_effective(at, |target| matches!(target, TargetKind::Record { .. }))
+    }
+
+    /// number of labels received this run that are effective at `at` and target something that
+    /// isn't an account or a record
+    pub fn unknown_target_label_count(&self, at: &DateTime) -> Result<usize> {
+        self.count_effective(at, |target| matches!(target, TargetKind::Unknown))
+    }
+
+    fn count_effective(&self, at: &DateTime, kind: impl Fn(&TargetKind) -> bool) -> Result<usize> {
+        let mut count = 0;
+        self.for_each_latest(at, |label, _| {
+            if !label.neg
+                && !label.is_expired(at)
+                && kind(&TargetKind::from_target_uri(&label.dbkey.key.target_uri))
+            {
+                count += 1;
+            }
+        })?;
+        Ok(count)
+    }
+
+    /// the effective labels that haven't expired yet at `reference` but will within `within` of
+    /// it, soonest first
+    pub fn expiring_soon(
+        &self,
+        within: chrono::Duration,
+        reference: &DateTime,
+    ) -> Result<Vec<LabelRecord>> {
+        let until = *reference + within;
+        let mut expiring = Vec::new();
+        self.for_each_latest(reference, |label, _| {
+            if label.neg || label.is_expired(reference) {
+                return;
+            }
+            let Some(exp) = label.expiry_timestamp.as_deref().and_then(parse_datetime) else {
+                return;
+            };
+            if exp <= until {
+                expiring.push((exp, label.clone()));
+            }
+        })?;
+        expiring.sort_by_key(|(exp, _)| *exp);
+        Ok(expiring.into_iter().map(|(_, label)| label).collect())
+    }
+
+    /// calls `f` with the most recent record for every label key received this run, and whether
+    /// it is the first one effective at `at` for its target and for its src
+    fn for_each_latest(
+        &self,
+        at: &DateTime,
+        mut f: impl FnMut(&LabelRecord, FirstOf),
+    ) -> Result<()> {
+        match &self.store {
+            Some(store) => db::for_each_latest_label(
+                store,
+                &self.table,
+                self.run_id,
+                &self.run_start,
+                at,
+                |label, first| f(&label, first),
+            ),
+            None => {
+                let mut targets = HashSet::new();
+                let mut srcs = HashSet::new();
+                for label in self.effective.values() {
+                    let key = &label.dbkey.key;
+                    let effective = !label.neg && !label.is_expired(at);
+                    let first = FirstOf {
+                        target: effective && targets.insert(&key.target_uri),
+                        src: effective && srcs.insert(&key.src),
+                    };
+                    f(label, first);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// prints the first part of the full update summary: what was received and saved, how the
+    /// connections went, and anything odd about the records
+    fn print_run_details(&self, source: &Source) {
+        say!();
+        say!("--------------------");
+        say!("--> UPDATE SUMMARY");
+        say!("--------------------");
+        say!();
+        say!(
             "received a total of {total} label record(s)",
             total = self.total_labels
         );
-        println!(
-            "label records have sequence numbers up to {seq}",
-            seq = self.cursor
+        match source {
+            Source::Subscribe(subscription) => say!(
+                "label records have sequence numbers up to {seq}",
+                seq = subscription.cursor
+            ),
+            Source::Poll(polling) => {
+                if let Some(cursor) = &polling.query_cursor {
+                    say!("polling left off at query cursor {cursor:?}");
+                }
+            }
+        }
+        if self.store.is_some() && self.spill_dir.is_none() {
+            say!(
+                "saved {inserted} new record(s) to the database ({ignored} already present)",
+                inserted = self.inserted_records,
+                ignored = self.ignored_records,
+            );
+        }
+        if let Some(verified_at) = &self.cached_identity_verified_at {
+            say!(
+                "used the labeler identity saved in the database, last verified at {verified_at}",
+                verified_at = if self.local_time {
+                    verified_at.with_timezone(&chrono::Local).to_rfc3339()
+                } else {
+                    verified_at.to_rfc3339()
+                },
+            );
+        }
+        let stats = &self.run_stats;
+        say!(
+            "connected to the labeler {connections} time(s), {retries} of which made no progress",
+            connections = stats.connections,
+            retries = stats.retries,
+        );
+        say!(
+            "   connections ended: {ok} caught up, {stepped} stepped, {closed} closed, \
+            {websocket} websocket error(s), {atproto} atproto error(s)",
+            ok = stats.ended_ok,
+            stepped = stats.ended_stepped,
+            closed = stats.ended_closed,
+            websocket = stats.ended_websocket_error,
+            atproto = stats.ended_atproto_error,
         );
-        println!();
+        if let Some(e) = &stats.last_atproto_error {
+            say!(
+                "   the last error from the labeler was {error} at cursor {cursor}: {message}",
+                error = e.error,
+                cursor = e.cursor,
+                message = e.message.as_deref().unwrap_or("(no error message)"),
+            );
+        }
+        say!(
+            "received {bytes} byte(s) over {streaming} of streaming, {processing} of which was \
+            spent processing frames",
+            bytes = self.bytes_received,
+            streaming = format_duration_ms(self.streaming_time),
+            processing = format_duration_ms(self.processing_time),
+        );
+        if let Some(subscription) = source.subscription() {
+            if !subscription.info_counts.is_empty() {
+                say!("info messages from the labeler:");
+                for (name, count) in &subscription.info_counts {
+                    say!("   {name}: {count}");
+                }
+            }
+        }
+        if self.anomalies.invalid_cids > 0 {
+            say!(
+                "dropped {invalid} invalid target cid(s) from label records",
+                invalid = self.anomalies.invalid_cids
+            );
+        }
+        let signed: usize = self.signature_lengths.values().sum();
+        if signed + self.unsigned_count > 0 {
+            say!(
+                "{signed} label record(s) were signed and {unsigned} were not",
+                unsigned = self.unsigned_count
+            );
+            for (length, count) in &self.signature_lengths {
+                say!("   {count} signature(s) of {length} bytes");
+            }
+        }
+        if self.anomalies.bad_signatures > 0 {
+            say!(
+                "XX --> {bad} label record(s) have signatures that aren't {SIGNATURE_LENGTH} bytes \
+                long",
+                bad = self.anomalies.bad_signatures
+            );
+        }
+        if self.malformed_cid_count > 0 {
+            say!(
+                "XX --> {malformed} label(s) pin target cids that aren't dag-cbor sha2-256 \
+                CIDv1s, as record cids are",
+                malformed = self.malformed_cid_count
+            );
+        }
+        if self.missing_cid_count > 0 {
+            say!(
+                "XX --> {missing} label(s) target specific records without pinning a CID",
+                missing = self.missing_cid_count
+            );
+        }
+        if self.handle_target_count > 0 {
+            if self.normalize_targets {
+                say!(
+                    "XX --> {handles} label record(s) target accounts by handle rather than by \
+                    did; {normalized} of them were normalized to the account's did",
+                    handles = self.handle_target_count,
+                    normalized = self.normalized_target_count
+                );
+            } else {
+                say!(
+                    "XX --> {handles} label record(s) target accounts by handle rather than by \
+                    did; --normalize-targets resolves them to dids",
+                    handles = self.handle_target_count
+                );
+            }
+        }
+        if self.self_label_count > 0 {
+            say!(
+                "XX --> {self_labels} label record(s) are self-labels, from a labeler labeling its \
+                own account",
+                self_labels = self.self_label_count
+            );
+        }
+        if self.conflict_count > 0 {
+            say!(
+                "XX --> {conflicts} label record(s) conflicted with different records already \
+                saved with the same key and seq, such as:",
+                conflicts = self.conflict_count
+            );
+            for sample in &self.conflict_samples {
+                say!("   {sample}");
+            }
+        }
+        if self.anomalies.normalized_srcs > 0 {
+            say!(
+                "normalized the src did of {normalized} label record(s), such as by lowercasing \
+                its method",
+                normalized = self.anomalies.normalized_srcs
+            );
+        }
+        if self.anomalies.invalid_srcs > 0 {
+            say!(
+                "XX --> skipped {invalid} label record(s) whose src is not a valid did",
+                invalid = self.anomalies.invalid_srcs
+            );
+        }
+        if let Some(subscription) = source.subscription() {
+            subscription.print_details();
+        }
+    }
+
+    /// prints how recent the labels are, any signs that history was lost, and the src dids
+    async fn print_overview(
+        &mut self,
+        source: &Source,
+        now: DateTime,
+        previous_first_available: Option<i64>,
+    ) {
+        say!();
+
+        if let Some(latest_created_at) = &self.latest_create_timestamp {
+            match parse_datetime(latest_created_at) {
+                Some(cts) => {
+                    let ago = match (now - cts).to_std() {
+                        Ok(ago) => format!("{} ago", humantime::format_duration(ago)),
+                        Err(_) => format!(
+                            "{} in the future :(",
+                            humantime::format_duration((cts - now).to_std().unwrap_or_default())
+                        ),
+                    };
+                    let at: &str = if self.local_time {
+                        &cts.with_timezone(&chrono::Local).to_rfc3339()
+                    } else {
+                        latest_created_at
+                    };
+                    say!("== --> last label update received was at {at:?}, which is {ago}");
+                }
+                None => say!(
+                    "== --> last label update received was at {latest_created_at:?}, which could \
+                    not be parsed"
+                ),
+            }
+        } else {
+            say!("== --> received no labels this time.");
+        }
 
-        if let Some(latest_created_at) = &self.latest_create_timestamp {
-            let ago =
-                match parse_datetime(latest_created_at).and_then(|cts| (now - cts).to_std().ok()) {
-                    Some(ago) => &format!("{} ago", humantime::format_duration(ago)),
-                    None => "in the future :(",
-                };
-            println!(
-                "== --> last label update received was at {latest_created_at:?}, which is {ago}"
+        let subscription = source.subscription();
+        if let Some(seq) = subscription.and_then(|s| s.history_start) {
+            say!(
+                "XX --> the labeler no longer has history before seq {seq}; your snapshot may be \
+                incomplete"
             );
-        } else {
-            println!("== --> received no labels this time.");
+        } else if subscription.is_some_and(|s| s.awaiting_history_start) {
+            say!(
+                "XX --> the labeler said our cursor was outdated; your snapshot may be incomplete"
+            );
+        }
+
+        if let (Some(previous), Some(current)) = (
+            previous_first_available,
+            subscription.and_then(|s| s.first_available_seq),
+        ) {
+            if current > previous {
+                say!(
+                    "XX --> the labeler's oldest available seq advanced from {previous} to \
+                    {current} since the last run; any history in between that wasn't captured is \
+                    gone for good"
+                );
+            }
         }
 
         match self.labeler_dids.len() {
             0 => {}
-            1 => println!("OK --> got label records from exactly 1 labeler did (this is good)"),
-            2.. => println!(
+            1 => say!("OK --> got label records from exactly 1 labeler did (this is good)"),
+            2.. => say!(
                 "XX --> got label records from {} labeler dids from the same source (WEIRD!)",
                 self.labeler_dids.len(),
             ),
         }
+        if let Some(expected) = &self.expected_did {
+            let unexpected = self.unexpected_srcs();
+            if !unexpected.is_empty() {
+                say!(
+                    "XX --> label records came from {count} src did(s) other than the expected \
+                    {expected}:",
+                    count = unexpected.len(),
+                );
+                for (src, records) in unexpected {
+                    say!("    {records:>8} x {src}");
+                }
+            }
+        }
+
+        let width = output_width();
+        say!("(info) --> all source dids:");
+        let src_dids: Vec<Did> = self.labeler_dids.iter().sorted().cloned().collect();
+        for did in src_dids {
+            let did = self.display_did(did.as_str()).await;
+            say!("   {did}", did = truncate(&did, width - 3));
+        }
+        say!();
+
+        say!("--------------------");
+    }
 
-        println!("(info) --> all source dids:");
-        for did in self.labeler_dids.into_iter().sorted() {
-            println!("   {did}");
+    async fn finalize(mut self, report: &mut RunReport, source: &Source) -> Result<()> {
+        self.flush_inserts()?;
+        if let Some(emitter) = &mut self.emitter {
+            emitter.flush()?;
         }
-        println!();
+        report.update(&self, source);
+        let now = now();
+        let unsubscribed = Subscription::new(0);
+        let subscription = source.subscription().unwrap_or(&unsubscribed);
 
-        println!("--------------------");
+        if !self.compact {
+            self.print_run_details(source);
+        }
+        let mut previous_first_available = None;
+        if let (Some(db), Some(run_id)) = (&self.store, self.run_id) {
+            previous_first_available =
+                db::previous_first_available_seq(db, &self.labeler_domain, run_id)?;
+            db::finish_run(
+                db,
+                run_id,
+                &now,
+                source.cursor(),
+                self.total_labels,
+                subscription.first_available_seq,
+                &self.run_stats,
+            )?;
+        }
+        if !self.compact {
+            self.print_overview(source, now, previous_first_available)
+                .await;
+        }
 
+        let width = output_width();
         let global_labels: HashSet<_> = [
             "!hide",
             "!warn",
@@ -481,70 +2557,1308 @@ impl LabelStore {
         .collect();
 
         let mut effective_counts = BTreeMap::<_, usize>::new();
+        let mut effective_value_counts = HashMap::<_, usize>::new();
+        let mut account_counts = HashMap::<String, usize>::new();
         let mut total_effective = 0usize;
-        for (
-            LabelKey {
+        self.for_each_latest(&now, |label, _| {
+            let LabelKey {
                 src,
                 val,
                 target_uri,
-            },
-            label,
-        ) in self.effective
-        {
+            } = &label.dbkey.key;
             if !label.neg && !label.is_expired(&now) {
+                let target = TargetKind::from_target_uri(target_uri);
+                if let Some(did) = target.did() {
+                    *account_counts.entry(did.to_owned()).or_default() += 1;
+                }
                 *effective_counts
-                    .entry((
-                        src.clone(),
-                        val.clone(),
-                        TargetKind::from_target_uri(&target_uri),
-                    ))
+                    .entry((src.clone(), val.clone(), target.describe_kind()))
+                    .or_default() += 1;
+                *effective_value_counts
+                    .entry((src.clone(), val.clone()))
                     .or_default() += 1;
                 total_effective += 1;
             }
-        }
+        })?;
 
-        println!("labeler defined {total_effective} effective label(s)");
-        println!("--------------------");
+        let effective_by_target = TargetCounts {
+            account: self.account_label_count(&now)?,
+            record: self.record_label_count(&now)?,
+            unknown: self.unknown_target_label_count(&now)?,
+        };
+        let days = self.warn_expiring_soon_days;
+        let expiring_soon = self.expiring_soon(chrono::Duration::days(days.into()), &now)?;
+        if !self.compact {
+            if let Some(tail) = self.tail {
+                say!("tail mode: showing most recent {tail} effective labels only");
+            }
+            say!("labeler defined {total_effective} effective label(s)");
+            let unknown = match effective_by_target.unknown {
+                0 => String::new(),
+                unknown => format!(", {unknown} with a target that isn't an account or record"),
+            };
+            say!(
+                "   {account} account-level effective label(s), {record} record-level effective \
+                label(s){unknown}",
+                account = effective_by_target.account,
+                record = effective_by_target.record,
+            );
+            say!(
+                "effective labels apply to {targets} unique target(s) from {srcs} source DID(s)",
+                targets = self.unique_target_count(&now)?,
+                srcs = self.unique_src_count(&now)?,
+            );
+            if let Some(soonest) = expiring_soon.first() {
+                say!(
+                    "{count} effective label(s) expire within {days} days, the soonest at {exp}",
+                    count = expiring_soon.len(),
+                    exp = soonest.expiry_timestamp.as_deref().unwrap_or_default(),
+                );
+            }
+            say!("--------------------");
+        }
 
+        let mut table = Table::new();
+        table
+            .load_preset(presets::ASCII_BORDERS_ONLY_CONDENSED)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_width(width.try_into().unwrap_or(u16::MAX))
+            .set_header(["src", "label", "count", "target"]);
+        let mut summary_rows = Vec::new();
         for ((src, val, target_kind), count) in effective_counts {
-            let global_tag = if global_labels.contains(val.as_ref()) {
-                " (global)"
+            let global = global_labels.contains(val.as_ref());
+            let global_tag = if global { " (global)" } else { "" };
+            table.add_row([
+                truncate(src.as_str(), SRC_WIDTH).into_owned(),
+                truncate(&format!("{val:?}{global_tag}"), VAL_WIDTH).into_owned(),
+                count.to_string(),
+                target_kind.clone(),
+            ]);
+            summary_rows.push(SummaryRow {
+                src: src.to_string(),
+                val: val.to_string(),
+                global,
+                target: target_kind,
+                count,
+            });
+        }
+        for (column, max) in [(0, SRC_WIDTH), (1, VAL_WIDTH), (2, COUNT_WIDTH)] {
+            if let Some(column) = table.column_mut(column) {
+                column.set_constraint(ColumnConstraint::UpperBoundary(Width::Fixed(
+                    // leave room for the padding on either side of the cell
+                    max as u16 + 2,
+                )));
+            }
+        }
+        if let Some(count_column) = table.column_mut(2) {
+            count_column.set_cell_alignment(CellAlignment::Right);
+        }
+        if !self.compact {
+            say!("{table}");
+            say!("--------------------");
+            say!("label values, by records applying and negating them:");
+        }
+        let mut table = Table::new();
+        table
+            .load_preset(presets::ASCII_BORDERS_ONLY_CONDENSED)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_width(width.try_into().unwrap_or(u16::MAX))
+            .set_header(["src", "label", "applied", "negated", "effective"]);
+        let mut summary_values = Vec::new();
+        for ((src, val), counts) in &self.value_counts {
+            let effective = effective_value_counts
+                .get(&(src.clone(), val.clone()))
+                .copied()
+                .unwrap_or(0);
+            table.add_row([
+                truncate(src.as_str(), SRC_WIDTH).into_owned(),
+                truncate(&format!("{val:?}"), VAL_WIDTH).into_owned(),
+                counts.applied.to_string(),
+                format!(
+                    "{negated} ({percent:.0}%)",
+                    negated = counts.negated,
+                    percent = counts.negation_ratio() * 100.0,
+                ),
+                effective.to_string(),
+            ]);
+            summary_values.push(SummaryValue {
+                src: src.to_string(),
+                val: val.to_string(),
+                applied: counts.applied,
+                negated: counts.negated,
+                effective,
+            });
+        }
+        for (column, max) in [(0, SRC_WIDTH), (1, VAL_WIDTH)] {
+            if let Some(column) = table.column_mut(column) {
+                column.set_constraint(ColumnConstraint::UpperBoundary(Width::Fixed(
+                    max as u16 + 2,
+                )));
+            }
+        }
+        for column in 2..5 {
+            if let Some(column) = table.column_mut(column) {
+                column.set_cell_alignment(CellAlignment::Right);
+            }
+        }
+        if !self.compact {
+            say!("{table}");
+        }
+
+        let most_labeled_accounts: Vec<(String, usize)> = account_counts
+            .into_iter()
+            .sorted_by(|(a_did, a), (b_did, b)| b.cmp(a).then_with(|| a_did.cmp(b_did)))
+            .take(10)
+            .collect();
+        if !most_labeled_accounts.is_empty() && !self.compact {
+            say!("--------------------");
+            say!("most-labeled accounts (including their records):");
+            for (did, count) in &most_labeled_accounts {
+                let did = self.display_did(did).await;
+                say!("   {count:>8} x {did}", did = truncate(&did, width - 14));
+            }
+        }
+
+        if self.compact {
+            let mut labels_by_src = HashMap::<&Did, usize>::new();
+            for ((src, _), counts) in &self.value_counts {
+                *labels_by_src.entry(src).or_default() += counts.applied + counts.negated;
+            }
+            let mut effective_by_src = HashMap::<&Did, usize>::new();
+            for ((src, _), count) in &effective_value_counts {
+                *effective_by_src.entry(src).or_default() += count;
+            }
+            if self.labeler_dids.is_empty() {
+                say!(
+                    "src=- labels=0 effective=0 max_seq={cursor} latest=-",
+                    cursor = source.cursor()
+                );
+            }
+            for src in self.labeler_dids.iter().sorted() {
+                let progress = self.src_progress.get(src);
+                let latest = progress.map_or("-".to_owned(), |progress| {
+                    let cts = &progress.latest_create_timestamp;
+                    match parse_datetime(cts) {
+                        Some(cts) if self.local_time => {
+                            cts.with_timezone(&chrono::Local).to_rfc3339()
+                        }
+                        _ => cts.to_string(),
+                    }
+                });
+                say!(
+                    "src={src} labels={labels} effective={effective} max_seq={max_seq} \
+                    latest={latest}",
+                    labels = labels_by_src.get(src).copied().unwrap_or(0),
+                    effective = effective_by_src.get(src).copied().unwrap_or(0),
+                    max_seq = progress
+                        .and_then(|progress| progress.max_seq)
+                        .map_or("-".to_owned(), |max_seq| max_seq.to_string()),
+                );
+            }
+        }
+
+        let summary = Summary {
+            labeler_domain: &self.labeler_domain,
+            total_labels: self.total_labels,
+            cursor: source.cursor(),
+            saved_records: (self.store.is_some() && self.spill_dir.is_none())
+                .then_some(self.inserted_records),
+            already_present_records: (self.store.is_some() && self.spill_dir.is_none())
+                .then_some(self.ignored_records),
+            run_stats: &self.run_stats,
+            cached_identity_verified_at: self
+                .cached_identity_verified_at
+                .map(|verified_at| verified_at.to_rfc3339()),
+            bytes_received: self.bytes_received,
+            streaming_seconds: self.streaming_time.as_secs_f64(),
+            processing_seconds: self.processing_time.as_secs_f64(),
+            info_counts: &subscription.info_counts,
+            history_start: subscription.history_start,
+            first_seq_received: subscription.first_seq_received,
+            first_available_seq: subscription.first_available_seq,
+            unknown_message_types: &subscription.unknown_message_types,
+            text_messages: subscription.text_messages,
+            text_message_samples: &subscription.text_message_samples,
+            invalid_cids: self.anomalies.invalid_cids,
+            invalid_srcs: self.anomalies.invalid_srcs,
+            normalized_srcs: self.anomalies.normalized_srcs,
+            missing_cids: self.missing_cid_count,
+            malformed_cids: self.malformed_cid_count,
+            self_labels: self.self_label_count,
+            handle_targets: self.handle_target_count,
+            normalized_targets: self.normalized_target_count,
+            conflicts: self.conflict_count,
+            conflict_samples: &self.conflict_samples,
+            bad_signatures: self.anomalies.bad_signatures,
+            signed: self.signature_lengths.values().sum(),
+            unsigned: self.unsigned_count,
+            signature_lengths: &self.signature_lengths,
+            latest_create_timestamp: self.latest_create_timestamp.as_deref(),
+            src_dids: self.labeler_dids.iter().map(Did::as_str).sorted().collect(),
+            total_effective,
+            effective_by_target,
+            expiring_soon: expiring_soon.len(),
+            effective_labels: summary_rows,
+            label_values: summary_values,
+            most_labeled_accounts: most_labeled_accounts
+                .into_iter()
+                .map(|(did, count)| SummaryAccount { did, count })
+                .collect(),
+            handles: &self.resolved_handles,
+        };
+        if let Some(path) = &self.summary_json {
+            let json = if self.json_pretty {
+                serde_json::to_string_pretty(&summary)?
             } else {
-                ""
+                serde_json::to_string(&summary)?
             };
-            println!("{src} labels {count:>8} x: {val:?}{global_tag} -> {target_kind:?}");
+            std::fs::write(path, json + "\n").map_err(|e| {
+                err!(
+                    "error writing summary to {path}: {e}",
+                    path = path.display()
+                )
+            })?;
+        }
+        report.summary = Some(serde_json::to_value(&summary)?);
+
+        if let Some(dir) = self.spill_dir {
+            drop(self.store);
+            let path = dir.path().to_owned();
+            dir.close().map_err(|e| {
+                err!(
+                    "could not remove the spilled labels in {path}: {e}",
+                    path = path.display()
+                )
+            })?;
         }
 
         Ok(())
     }
 }
 
+/// How a run went, as written by --report-dir
+#[derive(Default, Serialize)]
+struct RunReport {
+    /// "ok" if the run finished, otherwise "failed"
+    status: &'static str,
+    error: Option<String>,
+    did: Option<String>,
+    labeler_domain: Option<String>,
+    started_at: String,
+    duration_seconds: f64,
+    start_cursor: i64,
+    end_cursor: i64,
+    total_labels: usize,
+    /// records newly saved to the database, if saving to one
+    saved_records: Option<usize>,
+    /// the update summary, if the run got far enough to make one
+    summary: Option<serde_json::Value>,
+}
+
+impl RunReport {
+    /// catches up with the progress of the run
+    fn update(&mut self, store: &LabelStore, source: &Source) {
+        if store.labeler_dids.len() == 1 {
+            self.did = store.labeler_dids.iter().next().map(|did| did.to_string());
+        }
+        self.labeler_domain = Some(store.labeler_domain.clone());
+        self.end_cursor = source.cursor();
+        self.total_labels = store.total_labels;
+        self.saved_records =
+            (store.store.is_some() && store.spill_dir.is_none()).then_some(store.inserted_records);
+    }
+}
+
+/// The update summary, as written by --summary-json
+#[derive(Serialize)]
+struct Summary<'a> {
+    labeler_domain: &'a str,
+    total_labels: usize,
+    cursor: i64,
+    /// records newly saved to the database, if saving to one
+    saved_records: Option<usize>,
+    already_present_records: Option<usize>,
+    run_stats: &'a RunStats,
+    cached_identity_verified_at: Option<String>,
+    bytes_received: u64,
+    streaming_seconds: f64,
+    processing_seconds: f64,
+    info_counts: &'a BTreeMap<String, usize>,
+    history_start: Option<i64>,
+    first_seq_received: Option<i64>,
+    first_available_seq: Option<i64>,
+    unknown_message_types: &'a BTreeMap<String, usize>,
+    text_messages: usize,
+    text_message_samples: &'a [String],
+    invalid_cids: usize,
+    invalid_srcs: usize,
+    normalized_srcs: usize,
+    missing_cids: usize,
+    /// records whose target cid isn't in the form of a record's cid
+    malformed_cids: usize,
+    /// records whose src labels its own account
+    self_labels: usize,
+    /// records whose target names an account by handle, and how many of those were rewritten with
+    /// the account's did
+    handle_targets: usize,
+    normalized_targets: usize,
+    /// records that conflicted with different records already saved with the same key and seq
+    conflicts: usize,
+    conflict_samples: &'a [String],
+    bad_signatures: usize,
+    /// records received with and without a signature
+    signed: usize,
+    unsigned: usize,
+    /// number of signatures received of each length in bytes
+    signature_lengths: &'a BTreeMap<usize, usize>,
+    latest_create_timestamp: Option<&'a str>,
+    src_dids: Vec<&'a str>,
+    total_effective: usize,
+    effective_by_target: TargetCounts,
+    /// effective labels that expire within --warn-expiring-soon days
+    expiring_soon: usize,
+    effective_labels: Vec<SummaryRow>,
+    label_values: Vec<SummaryValue>,
+    most_labeled_accounts: Vec<SummaryAccount>,
+    /// handles of the dids above, if --resolve-handles was given
+    handles: &'a BTreeMap<String, String>,
+}
+
+/// Numbers of effective labels on accounts, on records, and on targets that are neither
+#[derive(Default, Serialize)]
+struct TargetCounts {
+    account: usize,
+    record: usize,
+    unknown: usize,
+}
+
+/// How much a label value from one src was used, for the summary
+#[derive(Serialize)]
+struct SummaryValue {
+    src: String,
+    val: String,
+    applied: usize,
+    negated: usize,
+    effective: usize,
+}
+
+/// Replaces the characters of a did or domain that some filesystems don't allow in file names
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Numbers of records applying and negating a label value
+#[derive(Debug, Default)]
+struct ValueCounts {
+    applied: usize,
+    negated: usize,
+}
+
+impl ValueCounts {
+    /// the fraction of the records that are negations
+    fn negation_ratio(&self) -> f64 {
+        match self.applied + self.negated {
+            0 => 0.0,
+            total => self.negated as f64 / total as f64,
+        }
+    }
+}
+
+/// How far the records received from one src did this run go
+#[derive(Debug)]
+struct SrcProgress {
+    /// None when none of its records came with a seq, as when polling
+    max_seq: Option<i64>,
+    latest_create_timestamp: Rc<str>,
+}
+
+/// A row of the summary table of effective labels
+#[derive(Serialize)]
+struct SummaryRow {
+    src: String,
+    val: String,
+    global: bool,
+    target: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct SummaryAccount {
+    did: String,
+    count: usize,
+}
+
+/// widths of the columns in the summary table of effective labels, not counting padding
+const SRC_WIDTH: usize = 20;
+const VAL_WIDTH: usize = 30;
+const COUNT_WIDTH: usize = 10;
+
+/// the width to fit the summary into: the terminal's width, or a fixed width if we are not writing
+/// to a terminal
+fn output_width() -> usize {
+    match terminal_size::terminal_size() {
+        Some((terminal_size::Width(width), _)) => width.max(40).into(),
+        None => 120,
+    }
+}
+
+/// formats a duration for display, rounded to the millisecond
+fn format_duration_ms(duration: Duration) -> humantime::FormattedDuration {
+    humantime::format_duration(Duration::from_millis(duration.as_millis() as u64))
+}
+
+/// shortens text to at most `max` characters, marking where it was cut off
+fn truncate(text: &str, max: usize) -> Cow<'_, str> {
+    if text.chars().count() <= max {
+        return Cow::Borrowed(text);
+    }
+    let mut truncated: String = text.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    Cow::Owned(truncated)
+}
+
+/// rough estimate of the memory a tracked effective label occupies
+fn approximate_size(label: &LabelRecord) -> usize {
+    // src and val are interned, so they don't cost anything per label
+    size_of::<(LabelKey, LabelRecord)>()
+        + label.dbkey.key.target_uri.len()
+        + label.create_timestamp.len()
+        + label.expiry_timestamp.as_ref().map_or(0, String::len)
+        + label.target_cid.as_ref().map_or(0, String::len)
+}
+
+/// checks that a database can be saved at the given path, creating its parent directories if
+/// they are missing (unless this is a dry run), and returns the path made absolute
+fn prepare_db_path(path: &Path, dry_run: bool) -> Result<PathBuf> {
+    let path = std::path::absolute(path)
+        .map_err(|e| err!("could not resolve {path}: {e}", path = path.display()))?;
+    if path.is_dir() {
+        bail!(
+            "{path} is a directory; --save-to-db takes the path of a database file",
+            path = path.display()
+        );
+    }
+    if let Some(parent) = path.parent().filter(|_| !dry_run) {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            err!(
+                "could not create directory {parent} for the database: {e}",
+                parent = parent.display()
+            )
+        })?;
+    }
+    if path.is_file() {
+        // every sqlite database begins with this; sqlite also treats an empty file as a new one
+        const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+        let mut header = Vec::with_capacity(SQLITE_HEADER.len());
+        std::fs::File::open(&path)
+            .and_then(|file| {
+                file.take(SQLITE_HEADER.len() as u64)
+                    .read_to_end(&mut header)
+            })
+            .map_err(|e| err!("could not read {path}: {e}", path = path.display()))?;
+        if !header.is_empty() && header != SQLITE_HEADER {
+            say!(
+                "warning: {path} exists but does not look like a sqlite database",
+                path = path.display()
+            );
+        }
+    }
+    Ok(path)
+}
+
+/// when a label record was created, or the earliest possible time if that can't be read
+fn created_at(label: &LabelRecord) -> DateTime {
+    parse_datetime(&label.create_timestamp).unwrap_or(DateTime::MIN_UTC)
+}
+
+/// removes a sqlite database file along with its journal files, if they exist
+#[cfg(test)]
+fn remove_db_files(path: &Path) -> Result<()> {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        match std::fs::remove_file(&file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                bail!("could not remove {file:?}: {e}");
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// A file holding our process id, removed when this is dropped
+struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    fn create(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                if let Ok(pid) = contents.trim().parse::<u32>() {
+                    if process_is_running(pid) {
+                        bail!(
+                            "pid file {path} says labelview is already running as process {pid}",
+                            path = path.display()
+                        );
+                    }
+                }
+                say!("replacing stale pid file {path}", path = path.display());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => bail!("could not read pid file {path}: {e}", path = path.display()),
+        }
+        std::fs::write(path, format!("{pid}\n", pid = std::process::id())).map_err(|e| {
+            err!(
+                "could not write pid file {path}: {e}",
+                path = path.display()
+            )
+        })?;
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// whether another process with the given id exists
+#[cfg(unix)]
+fn process_is_running(pid: u32) -> bool {
+    // pid 0 and negative pids would signal whole process groups
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 || pid as u32 == std::process::id() {
+        return false;
+    }
+    // SAFETY: signal 0 sends nothing; kill only checks whether the process exists and whether we
+    // could signal it
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    // a process we aren't allowed to signal still exists
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// whether another process with the given id exists. where we can't tell, warns and assumes that it
+/// doesn't, so a pid file left behind by a crash doesn't block every later run
+#[cfg(not(unix))]
+fn process_is_running(pid: u32) -> bool {
+    say!("warning: can't tell whether process {pid} is still running here; assuming it isn't");
+    false
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum TargetKind {
-    Account,
-    Record { kind: String },
+    Account { did: String },
+    Record { kind: Nsid, at_uri: AtUri },
     Unknown,
 }
 
 impl TargetKind {
     fn from_target_uri(uri: &str) -> Self {
-        if let Some(rest) = uri.strip_prefix("at://") {
-            let mut split = rest.split('/');
-            if let (Some(_did), Some(middle)) = (split.next(), split.next()) {
-                Self::Record {
-                    kind: middle.to_owned(),
-                }
-            } else {
-                Self::Unknown
+        if uri.starts_with("at://") {
+            let Ok(at_uri) = AtUri::parse(uri) else {
+                return Self::Unknown;
+            };
+            match at_uri.collection.clone() {
+                Some(kind) => Self::Record { kind, at_uri },
+                // the uri of a whole repository names its account
+                None if at_uri.authority.parse::<Did>().is_ok() => Self::Account {
+                    did: at_uri.authority,
+                },
+                None => Self::Unknown,
+            }
+        } else if uri.parse::<Did>().is_ok() {
+            Self::Account {
+                did: uri.to_owned(),
             }
         } else {
-            // assume it's a did
-            Self::Account
+            Self::Unknown
+        }
+    }
+
+    /// the did of the account targeted, or of the account that owns the targeted record. Records
+    /// named with a handle rather than a did have none
+    fn did(&self) -> Option<&str> {
+        match self {
+            Self::Account { did } => Some(did),
+            Self::Record { at_uri, .. } => {
+                Some(at_uri.authority.as_str()).filter(|authority| authority.parse::<Did>().is_ok())
+            }
+            Self::Unknown => None,
+        }
+    }
+
+    /// describes the kind of target without identifying the specific target, for grouping
+    fn describe_kind(&self) -> String {
+        match self {
+            Self::Account { .. } => "Account".to_owned(),
+            Self::Record { kind, .. } => {
+                format!("Record {{ kind: {kind:?} }}", kind = kind.as_str())
+            }
+            Self::Unknown => "Unknown".to_owned(),
         }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    GetCmd::parse().go().await
+    match Cmd::parse() {
+        Cmd::Get(cmd) => (*cmd).go().await,
+        Cmd::Stats(cmd) => cmd.go(),
+        Cmd::Export(cmd) => cmd.go(),
+        Cmd::Policies(cmd) => cmd.go().await,
+        Cmd::Compare(cmd) => cmd.go(),
+        Cmd::Info(cmd) => cmd.go().await,
+        Cmd::Doctor(cmd) => cmd.go().await,
+        Cmd::Migrate(cmd) => cmd.go(),
+        Cmd::Maintenance(cmd) => cmd.go(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn running_processes_are_detected() {
+        assert!(process_is_running(std::os::unix::process::parent_id()));
+        // this process's own pid can only be in a pid file it didn't write
+        assert!(!process_is_running(std::process::id()));
+        assert!(!process_is_running(0));
+        assert!(!process_is_running(u32::MAX));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        child.wait().unwrap();
+        assert!(!process_is_running(pid));
+    }
+
+    /// environment variable that tells a test it is running in the child process
+    /// [`in_child_with_memory_limit`] started for it
+    #[cfg(target_os = "linux")]
+    const MEMORY_LIMITED_CHILD: &str = "LABELVIEW_TEST_MEMORY_LIMITED_CHILD";
+
+    /// Runs the test called `name` again in a child process whose address space can grow by at
+    /// most `limit` bytes past what it has when the test starts, returning how it went. An
+    /// allocation past the limit fails and aborts the child, rather than only being noticed after
+    /// the fact. Returns `None` in the child, which should then go on with the test
+    #[cfg(target_os = "linux")]
+    fn in_child_with_memory_limit(name: &str, limit: usize) -> Option<std::process::Output> {
+        if std::env::var_os(MEMORY_LIMITED_CHILD).is_none() {
+            let output = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([name, "--exact", "--include-ignored", "--test-threads=1"])
+                .env(MEMORY_LIMITED_CHILD, "1")
+                // one malloc arena, so the heap only grows by asking for more address space
+                .env("MALLOC_ARENA_MAX", "1")
+                .output()
+                .unwrap();
+            return Some(output);
+        }
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let kb: usize = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmSize:"))
+            .and_then(|rest| rest.trim().strip_suffix("kB"))
+            .and_then(|kb| kb.trim().parse().ok())
+            .unwrap();
+        let limit = (kb * 1024 + limit) as libc::rlim_t;
+        let rlimit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        // SAFETY: setrlimit only reads the limit it is given
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlimit) }, 0);
+        None
+    }
+
+    /// labels ingested by the memory limit tests, a thousand to a label message
+    #[cfg(target_os = "linux")]
+    const MEMORY_TEST_FRAMES: usize = 25;
+    /// labels ingested by the memory limit test at scale: a million, as many as a busy labeler has
+    #[cfg(target_os = "linux")]
+    const MEMORY_TEST_FRAMES_AT_SCALE: usize = 1000;
+    /// how far the memory limit tests' address space may grow. sqlite needs a few megabytes of
+    /// this, and tracking every label in memory needs several times more
+    #[cfg(target_os = "linux")]
+    const MEMORY_TEST_ADDRESS_SPACE: usize = 12 << 20;
+
+    /// Has a new store with the given memory limit process label messages of a thousand labels
+    /// each, all with different targets so every one is effective, and checks it ends up with all
+    /// of them
+    #[cfg(target_os = "linux")]
+    fn ingest_distinct_labels(max_memory: Option<usize>, frames: usize) {
+        const LABELS_PER_FRAME: usize = 1000;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut store = LabelStore::new().unwrap();
+        store.max_memory = max_memory;
+        let now = now();
+        for frame in 0..frames {
+            let targets: Vec<_> = (0..LABELS_PER_FRAME)
+                .map(|i| format!("did:plc:target{n:016}", n = frame * LABELS_PER_FRAME + i))
+                .collect();
+            let labels: Vec<_> = targets.iter().map(|uri| (uri.as_str(), "spam")).collect();
+            let frame = ingest::tests::labels_frame(frame as i64 + 1, "did:plc:labeler", &labels);
+            let (header, body) = ingest::tests::split_frame(&frame);
+            let (_, labels, _) = LabelRecord::from_subscription_record(
+                header,
+                body,
+                &mut store.interner,
+                &mut store.anomalies,
+                usize::MAX,
+                false,
+            )
+            .unwrap();
+            runtime
+                .block_on(store.process_labels(labels, &now))
+                .unwrap();
+        }
+        let mut effective = 0;
+        store.for_each_latest(&now, |_, _| effective += 1).unwrap();
+        assert_eq!(effective, frames * LABELS_PER_FRAME);
+        assert_eq!(store.spill_dir.is_some(), max_memory.is_some());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn labels_spill_under_the_memory_limit() {
+        let name = "tests::labels_spill_under_the_memory_limit";
+        match in_child_with_memory_limit(name, MEMORY_TEST_ADDRESS_SPACE) {
+            None => ingest_distinct_labels(Some(1 << 20), MEMORY_TEST_FRAMES),
+            Some(output) => assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            ),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn labels_tracked_in_memory_go_past_the_memory_limit() {
+        // the same labels as labels_spill_under_the_memory_limit, to show the limit is tight
+        let name = "tests::labels_tracked_in_memory_go_past_the_memory_limit";
+        match in_child_with_memory_limit(name, MEMORY_TEST_ADDRESS_SPACE) {
+            None => ingest_distinct_labels(None, MEMORY_TEST_FRAMES),
+            Some(output) => {
+                use std::os::unix::process::ExitStatusExt;
+
+                // running out of memory aborts, where a failed assertion would exit with an error
+                assert_eq!(
+                    output.status.signal(),
+                    Some(libc::SIGABRT),
+                    "{status}: {stderr}",
+                    status = output.status,
+                    stderr = String::from_utf8_lossy(&output.stderr),
+                );
+            }
+        }
+    }
+
+    /// A million labels, under the same memory limit as the other memory limit tests. This takes
+    /// over a minute even with optimizations, so it only runs when asked for:
+    ///
+    /// ```text
+    /// cargo test --release -- --ignored tests::labels_spill_under_the_memory_limit_at_scale
+    /// ```
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "slow; run with --ignored"]
+    fn labels_spill_under_the_memory_limit_at_scale() {
+        let name = "tests::labels_spill_under_the_memory_limit_at_scale";
+        match in_child_with_memory_limit(name, MEMORY_TEST_ADDRESS_SPACE) {
+            None => ingest_distinct_labels(Some(1 << 20), MEMORY_TEST_FRAMES_AT_SCALE),
+            Some(output) => assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            ),
+        }
+    }
+
+    #[test]
+    fn stores_pick_up_from_a_populated_database() {
+        let db = db::connect(Path::new(":memory:"), db::DEFAULT_LABEL_TABLE).unwrap();
+        for (src, uri, val, seq) in [
+            ("did:plc:b", "did:plc:x", "spam", 3),
+            ("did:plc:a", "did:plc:x", "spam", 7),
+            ("did:plc:a", "did:plc:y", "rude", 5),
+        ] {
+            db.execute(
+                r#"
+                INSERT INTO label_records(
+                    src, target_uri, val, seq, create_timestamp, neg, seen_at_timestamp
+                )
+                VALUES (?1, ?2, ?3, ?4, '2024-01-01T00:00:00Z', 0, '2024-01-01T00:00:00Z');
+                "#,
+                rusqlite::params![src, uri, val, seq],
+            )
+            .unwrap();
+        }
+        let store = LabelStore::with_db_connection(db, db::DEFAULT_LABEL_TABLE).unwrap();
+        assert_eq!(store.saved_cursor, 7);
+        assert_eq!(store.saved_value_count, 2);
+        let srcs: BTreeSet<_> = store.labeler_dids.iter().map(Did::as_str).collect();
+        assert_eq!(srcs, BTreeSet::from(["did:plc:a", "did:plc:b"]));
+        // the database holds the effective labels, not memory
+        assert!(store.effective.is_empty());
+        assert!(store.store.is_some());
+
+        let empty = db::connect(Path::new(":memory:"), db::DEFAULT_LABEL_TABLE).unwrap();
+        let store = LabelStore::with_db_connection(empty, db::DEFAULT_LABEL_TABLE).unwrap();
+        assert_eq!((store.saved_cursor, store.saved_value_count), (0, 0));
+        assert!(store.labeler_dids.is_empty());
+    }
+
+    /// decodes a message and has the store process its labels
+    async fn receive(store: &mut LabelStore, message: &[u8]) {
+        let (header, body) = ingest::tests::split_frame(message);
+        let (_, labels, _) = LabelRecord::from_subscription_record(
+            header,
+            body,
+            &mut store.interner,
+            &mut store.anomalies,
+            usize::MAX,
+            false,
+        )
+        .unwrap();
+        store.process_labels(labels, &now()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn spilled_labels_are_removed_when_the_store_is_dropped() {
+        let mut store = LabelStore::new().unwrap();
+        store.max_memory = Some(1);
+        let labels = [("did:plc:x", "spam")];
+        receive(
+            &mut store,
+            &ingest::tests::labels_frame(1, "did:plc:labeler", &labels),
+        )
+        .await;
+        let dir = store.spill_dir.as_ref().unwrap().path().to_owned();
+        assert!(dir.join("labels.sqlite").is_file());
+        // as when a run fails before it is finalized
+        drop(store);
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn records_from_unexpected_srcs_are_counted_by_src() {
+        let expected = "did:plc:expected";
+        let mut store = LabelStore::new().unwrap();
+        receive(
+            &mut store,
+            &ingest::tests::labels_frame(1, "did:plc:other", &[("did:plc:x", "spam")]),
+        )
+        .await;
+        // without an expected did any src is accepted
+        assert!(store.unexpected_srcs().is_empty());
+
+        store.expected_did = Some(expected.parse().unwrap());
+        let labels = [("did:plc:x", "spam"), ("did:plc:y", "rude")];
+        receive(
+            &mut store,
+            &ingest::tests::labels_frame(2, expected, &labels),
+        )
+        .await;
+        receive(
+            &mut store,
+            &ingest::tests::labels_frame(3, "did:plc:other", &labels),
+        )
+        .await;
+        receive(
+            &mut store,
+            &ingest::tests::labels_frame(4, "did:plc:third", &labels[..1]),
+        )
+        .await;
+        let unexpected: Vec<_> = store
+            .unexpected_srcs()
+            .into_iter()
+            .map(|(src, records)| (src.as_str(), records))
+            .collect();
+        assert_eq!(unexpected, [("did:plc:other", 3), ("did:plc:third", 1)]);
+    }
+
+    /// labels, each a src, target, value, whether it negates, and when it expires
+    type TestLabels<'a> = [(&'a str, &'a str, &'a str, bool, Option<&'a str>)];
+
+    /// a store keeping the latest records in memory and one saving them to a database, each having
+    /// processed the given labels in one label message
+    async fn stores_with(labels: &TestLabels<'_>) -> [LabelStore; 2] {
+        let db = db::connect(Path::new(":memory:"), db::DEFAULT_LABEL_TABLE).unwrap();
+        let stores = [
+            LabelStore::new().unwrap(),
+            LabelStore::with_db_connection(db, db::DEFAULT_LABEL_TABLE).unwrap(),
+        ];
+        let mut processed = Vec::new();
+        for mut store in stores {
+            let mut records = Vec::new();
+            for (seq, &(src, uri, val, neg, exp)) in (1..).zip(labels) {
+                let frame = ingest::tests::labels_frame(seq, src, &[(uri, val)]);
+                let (header, body) = ingest::tests::split_frame(&frame);
+                let (_, mut decoded, _) = LabelRecord::from_subscription_record(
+                    header,
+                    body,
+                    &mut store.interner,
+                    &mut store.anomalies,
+                    usize::MAX,
+                    false,
+                )
+                .unwrap();
+                for record in &mut decoded {
+                    record.neg = neg;
+                    record.expiry_timestamp = exp.map(str::to_owned);
+                }
+                records.extend(decoded);
+            }
+            store.process_labels(records, &now()).await.unwrap();
+            store.flush_inserts().unwrap();
+            processed.push(store);
+        }
+        processed.try_into().ok().unwrap()
+    }
+
+    #[tokio::test]
+    async fn unique_counts_only_count_effective_labels() {
+        let labels = [
+            ("did:plc:a", "did:plc:x", "spam", false, None),
+            ("did:plc:a", "did:plc:y", "spam", true, None),
+            ("did:plc:b", "did:plc:x", "rude", false, None),
+            (
+                "did:plc:b",
+                "did:plc:z",
+                "spam",
+                false,
+                Some("2024-06-01T00:00:00Z"),
+            ),
+            ("did:plc:c", "did:plc:w", "spam", true, None),
+        ];
+        for store in stores_with(&labels).await {
+            let saved = store.store.is_some();
+            let at = now();
+            assert_eq!(store.unique_target_count(&at).unwrap(), 1, "saved: {saved}");
+            assert_eq!(store.unique_src_count(&at).unwrap(), 2, "saved: {saved}");
+            // before the label on did:plc:z expires
+            let at = parse_datetime("2024-03-01T00:00:00Z").unwrap();
+            assert_eq!(store.unique_target_count(&at).unwrap(), 2, "saved: {saved}");
+            assert_eq!(store.unique_src_count(&at).unwrap(), 2, "saved: {saved}");
+        }
+    }
+
+    #[tokio::test]
+    async fn labels_expiring_soon_are_listed_soonest_first() {
+        let labels = [
+            (
+                "did:plc:a",
+                "did:plc:x",
+                "spam",
+                false,
+                Some("2024-01-05T00:00:00Z"),
+            ),
+            (
+                "did:plc:a",
+                "did:plc:y",
+                "spam",
+                false,
+                Some("2024-01-02T12:00:00Z"),
+            ),
+            // already expired, negated, too far off, or never expiring
+            (
+                "did:plc:a",
+                "did:plc:z",
+                "spam",
+                false,
+                Some("2024-01-01T00:00:00Z"),
+            ),
+            (
+                "did:plc:a",
+                "did:plc:w",
+                "spam",
+                true,
+                Some("2024-01-03T00:00:00Z"),
+            ),
+            (
+                "did:plc:a",
+                "did:plc:v",
+                "spam",
+                false,
+                Some("2024-02-01T00:00:00Z"),
+            ),
+            ("did:plc:a", "did:plc:u", "spam", false, None),
+            // exactly at the end of the window
+            (
+                "did:plc:b",
+                "did:plc:x",
+                "rude",
+                false,
+                Some("2024-01-08T00:00:00Z"),
+            ),
+        ];
+        let reference = parse_datetime("2024-01-01T00:00:00Z").unwrap();
+        for store in stores_with(&labels).await {
+            let expiring: Vec<_> = store
+                .expiring_soon(chrono::Duration::days(7), &reference)
+                .unwrap()
+                .into_iter()
+                .map(|label| label.dbkey.key.target_uri.to_string())
+                .collect();
+            assert_eq!(
+                expiring,
+                ["did:plc:y", "did:plc:x", "did:plc:x"],
+                "saved: {}",
+                store.store.is_some()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn effective_labels_are_counted_by_target_kind() {
+        let post = "at://did:plc:x/app.bsky.feed.post/3kabc";
+        let labels = [
+            ("did:plc:a", "did:plc:x", "spam", false, None),
+            ("did:plc:a", "did:plc:y", "spam", false, None),
+            ("did:plc:a", post, "spam", false, None),
+            ("did:plc:a", "at://alice.test", "spam", false, None),
+            // negated or expired labels aren't counted
+            ("did:plc:a", "did:plc:z", "spam", true, None),
+            (
+                "did:plc:a",
+                post,
+                "rude",
+                false,
+                Some("2024-06-01T00:00:00Z"),
+            ),
+            ("did:plc:a", "alice.test", "spam", true, None),
+        ];
+        for store in stores_with(&labels).await {
+            let saved = store.store.is_some();
+            let at = now();
+            assert_eq!(store.account_label_count(&at).unwrap(), 2, "saved: {saved}");
+            assert_eq!(store.record_label_count(&at).unwrap(), 1, "saved: {saved}");
+            assert_eq!(
+                store.unknown_target_label_count(&at).unwrap(),
+                1,
+                "saved: {saved}"
+            );
+        }
+    }
+
+    #[test]
+    fn targets_are_told_apart_by_kind() {
+        let record = |uri: &str| TargetKind::Record {
+            kind: Nsid::parse("app.bsky.feed.post").unwrap(),
+            at_uri: AtUri::parse(uri).unwrap(),
+        };
+        let account = |did: &str| TargetKind::Account {
+            did: did.to_owned(),
+        };
+        let post = "at://did:plc:ewvi7nxzyoun6zhxrhs64oiz/app.bsky.feed.post/3kabc";
+        let handle_post = "at://alice.test/app.bsky.feed.post/3kabc";
+        let cases = [
+            // (target, kind, did, describe_kind)
+            (
+                "did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                account("did:plc:ewvi7nxzyoun6zhxrhs64oiz"),
+                Some("did:plc:ewvi7nxzyoun6zhxrhs64oiz"),
+                "Account",
+            ),
+            (
+                "did:web:labeler.test",
+                account("did:web:labeler.test"),
+                Some("did:web:labeler.test"),
+                "Account",
+            ),
+            (
+                "at://did:plc:ewvi7nxzyoun6zhxrhs64oiz",
+                account("did:plc:ewvi7nxzyoun6zhxrhs64oiz"),
+                Some("did:plc:ewvi7nxzyoun6zhxrhs64oiz"),
+                "Account",
+            ),
+            (
+                post,
+                record(post),
+                Some("did:plc:ewvi7nxzyoun6zhxrhs64oiz"),
+                r#"Record { kind: "app.bsky.feed.post" }"#,
+            ),
+            // a record can be named by the handle of its account, but that isn't its did
+            (
+                handle_post,
+                record(handle_post),
+                None,
+                r#"Record { kind: "app.bsky.feed.post" }"#,
+            ),
+            ("at://alice.test", TargetKind::Unknown, None, "Unknown"),
+            ("alice.test", TargetKind::Unknown, None, "Unknown"),
+            ("", TargetKind::Unknown, None, "Unknown"),
+            ("not a target", TargetKind::Unknown, None, "Unknown"),
+            ("did:plc", TargetKind::Unknown, None, "Unknown"),
+            ("at://", TargetKind::Unknown, None, "Unknown"),
+            (
+                "at://did:plc:x/post/3kabc",
+                TargetKind::Unknown,
+                None,
+                "Unknown",
+            ),
+            (
+                "https://example.com/post",
+                TargetKind::Unknown,
+                None,
+                "Unknown",
+            ),
+        ];
+        for (uri, kind, did, description) in cases {
+            let target = TargetKind::from_target_uri(uri);
+            assert_eq!(target, kind, "{uri}");
+            assert_eq!(target.did(), did, "{uri}");
+            assert_eq!(target.describe_kind(), description, "{uri}");
+        }
+    }
+
+    /// the options of `labelview direct labeler.test` with some more arguments
+    fn direct_args(args: &[&str]) -> Result<GetCommonArgs, clap::Error> {
+        let command = ["labelview", "direct", "labeler.test"].iter().chain(args);
+        match Cmd::try_parse_from(command)? {
+            Cmd::Get(cmd) => match *cmd {
+                GetCmd::Direct(cmd) => Ok(cmd.common),
+                GetCmd::Lookup(_) => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn stream_config(args: &[&str]) -> StreamConfig {
+        StreamConfig::from(&direct_args(args).unwrap())
+    }
+
+    #[test]
+    fn stream_config_defaults_match_the_command_line() {
+        assert_eq!(stream_config(&[]), StreamConfig::default());
+    }
+
+    #[test]
+    fn non_positive_timeouts_wait_forever() {
+        let config = stream_config(&["--stream-timeout=0", "--connect-timeout=-1"]);
+        assert_eq!(config.stream_timeout, None);
+        assert_eq!(config.connect_timeout, None);
+        let config = stream_config(&["--stream-timeout=0.5", "--connect-timeout=2"]);
+        assert_eq!(config.stream_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.connect_timeout, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn subscribe_options_are_unused_when_polling() {
+        let subscribe_only = [
+            "--cursor-step=100",
+            "--ws-param=a=b",
+            "--stream-timeout=-1",
+            "--buffer-size=5",
+        ];
+        let config = stream_config(&subscribe_only);
+        assert_eq!(config.mode, Mode::Subscribe);
+        assert!(config.unused_options().is_empty());
+
+        let polling: Vec<_> = ["--mode=poll"].into_iter().chain(subscribe_only).collect();
+        assert_eq!(
+            stream_config(&polling).unused_options(),
+            [
+                "--stream-timeout",
+                "--buffer-size",
+                "--ws-param",
+                "--cursor-step"
+            ]
+        );
+        // the connection timeout bounds each queryLabels request
+        let polling = stream_config(&["--mode=poll", "--connect-timeout=1", "--save-to-db=x.db"]);
+        assert_eq!(polling.connect_timeout, Some(Duration::from_secs(1)));
+        assert!(polling.unused_options().is_empty());
+    }
+
+    #[test]
+    fn database_options_need_a_database() {
+        assert!(direct_args(&["--continue-from-db"]).is_err());
+        assert!(direct_args(&["--continue-from-db", "--save-to-db=x.db"]).is_ok());
+        assert!(direct_args(&["--continue-from-db", "--save-to-db=x.db", "--cursor=5"]).is_err());
+        assert!(direct_args(&["--tail=10", "--save-to-db=x.db"]).is_err());
+        assert!(direct_args(&["--tail=10", "--mode=poll"]).is_ok());
+    }
+
+    fn identity_lookup(
+        network: lookup::tests::FakeNetwork,
+    ) -> IdentityLookup<lookup::tests::FakeNetwork> {
+        IdentityLookup {
+            handle_or_did: lookup::tests::DID.to_owned(),
+            resolver: Arc::new(lookup::tests::resolver(network)),
+        }
+    }
+
+    #[tokio::test]
+    async fn labelers_without_a_pds_are_found() {
+        let network = lookup::tests::FakeNetwork::default().page(
+            &lookup::tests::doc_url(),
+            lookup::tests::LABELER_WITHOUT_PDS,
+        );
+        let identity = identity_lookup(network).resolve().await.unwrap();
+        assert_eq!(identity.did, lookup::tests::DID);
+        assert_eq!(identity.labeler_domain, "labeler.test");
+    }
+
+    #[tokio::test]
+    async fn labeler_endpoints_fall_back_to_well_known() {
+        let network = |endpoint: &str| {
+            lookup::tests::FakeNetwork::default()
+                .page(
+                    &lookup::tests::doc_url(),
+                    lookup::tests::PDS_WITHOUT_LABELER,
+                )
+                .page(
+                    "https://alice.test/.well-known/atproto-labeler-endpoint",
+                    endpoint,
+                )
+        };
+        let identity = identity_lookup(network("https://labels.alice.test\nanything else\n"))
+            .resolve()
+            .await
+            .unwrap();
+        assert_eq!(identity.did, lookup::tests::DID);
+        assert_eq!(identity.labeler_domain, "labels.alice.test");
+
+        let e = identity_lookup(network("not a url"))
+            .resolve()
+            .await
+            .unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("that entity doesn't seem to be a labeler"));
+    }
+
+    #[tokio::test]
+    async fn accounts_without_a_labeler_suggest_direct() {
+        let network = lookup::tests::FakeNetwork::default().page(
+            &lookup::tests::doc_url(),
+            lookup::tests::PDS_WITHOUT_LABELER,
+        );
+        let e = identity_lookup(network).resolve().await.unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "that entity doesn't seem to be a labeler. if you know the domain of its labeler \
+                service, try `labelview direct <domain> --labeler-did {did}`",
+                did = lookup::tests::DID,
+            )
+        );
+    }
 }
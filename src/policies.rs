@@ -0,0 +1,279 @@
+use crate::{
+    db::{self, now},
+    lookup::{self, Resolver},
+};
+use clap::Args;
+use comfy_table::{presets, ContentArrangement, Table};
+use eyre::{bail, eyre as err, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Debug, Args)]
+pub struct PoliciesCmd {
+    /// Handle or DID of the labeler
+    handle_or_did: String,
+    /// Sqlite file previously written with --save-to-db, to compare the declared label values with
+    /// the ones the labeler actually used
+    #[arg(long)]
+    db: Option<PathBuf>,
+    /// Name of the table of label records to read
+    #[arg(long, default_value = db::DEFAULT_LABEL_TABLE, value_parser = crate::parse_table_name)]
+    table: String,
+    /// Directory service to use for plc lookups
+    #[arg(long, default_value = lookup::DEFAULT_PLC_DIRECTORY)]
+    plc_directory: String,
+    /// Timeout for each attempt to resolve a handle to a did (via dns, then via HTTPS), in
+    /// seconds. Non-positive values wait forever
+    #[arg(long, default_value = "5")]
+    dns_timeout: f64,
+    /// Also write the policies to this file as JSON, including the labeler's service record as it
+    /// was fetched
+    #[arg(long)]
+    json: Option<PathBuf>,
+}
+
+/// The parts of an `app.bsky.labeler.service` record that declare the labeler's label values
+///
+/// https://github.com/bluesky-social/atproto/blob/main/lexicons/app/bsky/labeler/service.json
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServiceRecord {
+    policies: Policies,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Policies {
+    label_values: Vec<String>,
+    #[serde(default)]
+    label_value_definitions: Vec<LabelValueDefinition>,
+}
+
+/// How a labeler says one of its custom label values should be shown
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LabelValueDefinition {
+    identifier: String,
+    severity: String,
+    blurs: String,
+    default_setting: Option<String>,
+    #[serde(default)]
+    adult_only: bool,
+    #[serde(default)]
+    locales: Vec<LabelValueLocale>,
+}
+
+/// The name and description a labeler gives one of its label values in one language
+#[derive(Deserialize, Serialize)]
+struct LabelValueLocale {
+    lang: String,
+    name: String,
+    description: String,
+}
+
+impl Policies {
+    /// the label value definitions as rows of the label_definitions table, one for each locale
+    fn definition_rows(&self) -> Vec<db::LabelDefinition> {
+        let mut rows = Vec::new();
+        for definition in &self.label_value_definitions {
+            let row = |locale: Option<&LabelValueLocale>| db::LabelDefinition {
+                val: definition.identifier.clone(),
+                name: locale.map(|locale| locale.name.clone()),
+                description: locale.map(|locale| locale.description.clone()),
+                severity: definition.severity.clone(),
+                blurs: definition.blurs.clone(),
+                locale: locale.map(|locale| locale.lang.clone()),
+            };
+            if definition.locales.is_empty() {
+                rows.push(row(None));
+            } else {
+                rows.extend(definition.locales.iter().map(Some).map(row));
+            }
+        }
+        rows
+    }
+}
+
+/// Reads the labeler declaration of `did` from its pds, returning the record as it was fetched
+/// along with the policies it declares
+async fn read_declaration(
+    resolver: &Resolver,
+    pds: &str,
+    did: &str,
+) -> Result<(serde_json::Value, Policies)> {
+    let raw = lookup::get_record(
+        resolver.http_client(),
+        pds,
+        did,
+        "app.bsky.labeler.service",
+        "self",
+    )
+    .await?;
+    let record: ServiceRecord = serde_json::from_value(raw.clone())
+        .map_err(|e| err!("error parsing labeler declaration: {e}"))?;
+    Ok((raw, record.policies))
+}
+
+/// Fetches the label value definitions the labeler `did` declares and saves them to the database,
+/// replacing any saved before, so the database describes its label values without another lookup.
+/// Returns how many label values it defines
+pub async fn save_label_definitions(
+    db: &rusqlite::Connection,
+    resolver: &Resolver,
+    did: &str,
+) -> Result<usize> {
+    let doc = resolver.fetch_did_doc(did).await?;
+    let Some(pds) = lookup::service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer")
+    else {
+        bail!("{did} does not list a pds to publish a labeler declaration in");
+    };
+    let (_, policies) = read_declaration(resolver, pds, did).await?;
+    db::save_label_definitions(db, did, &policies.definition_rows(), &now())?;
+    Ok(policies.label_value_definitions.len())
+}
+
+/// The policies, as written by --json
+#[derive(Serialize)]
+struct PoliciesOutput<'a> {
+    did: &'a str,
+    record: &'a serde_json::Value,
+    declared: Vec<DeclaredValue<'a>>,
+    /// values used in the database's records that the labeler doesn't declare, if a database was
+    /// given
+    undeclared: Option<Vec<UsedValue<'a>>>,
+}
+
+#[derive(Serialize)]
+struct DeclaredValue<'a> {
+    value: &'a str,
+    definition: Option<&'a LabelValueDefinition>,
+    /// number of records in the database with this value, if a database was given
+    records: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct UsedValue<'a> {
+    value: &'a str,
+    records: usize,
+}
+
+impl PoliciesCmd {
+    pub async fn go(self) -> Result<()> {
+        let dns_timeout = lookup::timeout_from_secs(self.dns_timeout);
+        let resolver = lookup::Resolver::new(&self.plc_directory, dns_timeout)?;
+        println!("looking up did...");
+        let did = resolver.resolve_handle(&self.handle_or_did).await?;
+        let doc = resolver.fetch_did_doc(&did).await?;
+        let Some(pds) = lookup::service_from_doc(&doc, "#atproto_pds", "AtprotoPersonalDataServer")
+        else {
+            bail!(
+                "{did} does not list a pds, so it has no repository to publish a labeler \
+                declaration in"
+            );
+        };
+        println!("reading labeler declaration from {pds}...");
+        let (raw, policies) = read_declaration(&resolver, pds, &did).await?;
+
+        let used = match &self.db {
+            Some(path) => {
+                if !path.is_file() {
+                    bail!("no database found at {path}", path = path.display());
+                }
+                let db = db::connect(path, &self.table)?;
+                db::save_label_definitions(&db, &did, &policies.definition_rows(), &now())?;
+                Some(db::count_vals_from_src(&db, &self.table, &did)?)
+            }
+            None => None,
+        };
+        let definitions: BTreeMap<&str, &LabelValueDefinition> = policies
+            .label_value_definitions
+            .iter()
+            .map(|definition| (definition.identifier.as_str(), definition))
+            .collect();
+
+        println!();
+        println!(
+            "{did} declares {n} label value(s)",
+            n = policies.label_values.len()
+        );
+        let mut table = Table::new();
+        table
+            .load_preset(presets::ASCII_BORDERS_ONLY_CONDENSED)
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(["value", "severity", "blurs", "default", "adult", "records"]);
+        let mut declared = Vec::new();
+        for value in &policies.label_values {
+            let definition = definitions.get(value.as_str()).copied();
+            let records = used
+                .as_ref()
+                .map(|used| used.get(value).copied().unwrap_or(0));
+            let describe =
+                |field: fn(&LabelValueDefinition) -> &str| definition.map_or("", field).to_owned();
+            table.add_row([
+                format!("{value:?}"),
+                describe(|d| &d.severity),
+                describe(|d| &d.blurs),
+                describe(|d| d.default_setting.as_deref().unwrap_or("")),
+                describe(|d| if d.adult_only { "yes" } else { "no" }),
+                records.map_or(String::new(), |records| records.to_string()),
+            ]);
+            declared.push(DeclaredValue {
+                value,
+                definition,
+                records,
+            });
+        }
+        println!("{table}");
+
+        let undeclared = used.as_ref().map(|used| {
+            used.iter()
+                .filter(|(value, _)| !policies.label_values.contains(value))
+                .map(|(value, &records)| UsedValue { value, records })
+                .collect::<Vec<_>>()
+        });
+        if let Some(used) = &used {
+            let unused: Vec<_> = policies
+                .label_values
+                .iter()
+                .filter(|value| !used.contains_key(*value))
+                .collect();
+            if unused.is_empty() {
+                println!("every declared label value has been used");
+            } else {
+                println!("declared but never used:");
+                for value in unused {
+                    println!("   {value:?}");
+                }
+            }
+        }
+        match &undeclared {
+            Some(undeclared) if undeclared.is_empty() => {
+                println!("OK --> every label value used was declared");
+            }
+            Some(undeclared) => {
+                println!("XX --> label values used without being declared:");
+                for UsedValue { value, records } in undeclared {
+                    println!("   {value:?}: {records} record(s)");
+                }
+            }
+            None => {}
+        }
+
+        if let Some(path) = &self.json {
+            let output = PoliciesOutput {
+                did: &did,
+                record: &raw,
+                declared,
+                undeclared,
+            };
+            let json = serde_json::to_string_pretty(&output)?;
+            std::fs::write(path, json + "\n").map_err(|e| {
+                err!(
+                    "error writing policies to {path}: {e}",
+                    path = path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,356 @@
+use crate::db::LabelRecord;
+use eyre::Result;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, List, ListItem, Paragraph, Row, Table},
+    Terminal,
+};
+use std::{
+    collections::VecDeque,
+    io::{stdout, Stdout},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    select,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::{interval, Interval, MissedTickBehavior},
+};
+
+/// Number of the most recent labels kept for the scrolling pane
+const RECENT_LABELS: usize = 500;
+/// How often the dashboard is redrawn while waiting for the labeler
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+/// How far back the label rate is averaged over
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+/// How often the whole screen is repainted, to clean up after warnings printed while streaming
+const REPAINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the streaming loop should do after the dashboard has had its turn
+pub enum Action {
+    Redraw,
+    Quit,
+}
+
+/// The numbers shown on the dashboard, gathered from the label store each time it is drawn
+pub struct Status<'a> {
+    pub labeler_domain: &'a str,
+    pub labeler_did: Option<&'a str>,
+    pub cursor: i64,
+    /// connections to the labeler that have ended so far
+    pub connections: usize,
+    /// connections that ended without the cursor advancing
+    pub retries: usize,
+    pub total_labels: usize,
+    /// number of records applying and negating each label value, across all srcs
+    pub value_counts: Vec<(&'a str, usize, usize)>,
+}
+
+struct RecentLabel {
+    create_timestamp: String,
+    val: String,
+    target_uri: String,
+    neg: bool,
+}
+
+/// A full-screen view of the stream, updated as labels arrive
+///
+/// The terminal is put back the way it was when the dashboard is dropped.
+pub struct Dashboard {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    events: UnboundedReceiver<Event>,
+    stop_input: Arc<AtomicBool>,
+    redraw: Interval,
+    /// most recent labels first
+    recent: VecDeque<RecentLabel>,
+    /// number of labels received so far
+    received: usize,
+    /// number of labels that had been received when the recent labels pane was paused
+    paused_at: Option<usize>,
+    /// only show label values containing this
+    filter: String,
+    editing_filter: bool,
+    last_frame_at: Option<Instant>,
+    /// total labels received at each redraw within the rate window, oldest first
+    rate_samples: VecDeque<(Instant, usize)>,
+    /// connections ended as of the last redraw; messages printed around reconnecting scribble over
+    /// the screen, so it's repainted when this changes
+    drawn_connections: usize,
+    last_repaint: Instant,
+}
+
+impl Dashboard {
+    /// switches the terminal over to the dashboard and starts listening for key presses
+    pub fn start() -> Result<Self> {
+        enable_raw_mode()?;
+        execute!(stdout(), EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+
+        // crossterm's event reading blocks, so it gets a thread of its own
+        let (send, events) = unbounded_channel();
+        let stop_input = Arc::new(AtomicBool::new(false));
+        let stop = stop_input.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match event::poll(Duration::from_millis(100)) {
+                    Ok(true) => {
+                        let Ok(event) = event::read() else { break };
+                        if send.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut redraw = interval(REDRAW_INTERVAL);
+        redraw.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            terminal,
+            events,
+            stop_input,
+            redraw,
+            recent: VecDeque::with_capacity(RECENT_LABELS),
+            received: 0,
+            paused_at: None,
+            filter: String::new(),
+            editing_filter: false,
+            last_frame_at: None,
+            rate_samples: VecDeque::new(),
+            drawn_connections: 0,
+            last_repaint: Instant::now(),
+        })
+    }
+
+    /// waits until it's time to redraw or the user asks to quit
+    pub async fn next_action(&mut self) -> Action {
+        loop {
+            select! {
+                _ = self.redraw.tick() => return Action::Redraw,
+                event = self.events.recv() => {
+                    let Some(event) = event else {
+                        // no more input; carry on without it
+                        self.redraw.tick().await;
+                        return Action::Redraw;
+                    };
+                    if let Some(action) = self.handle_event(event) {
+                        return action;
+                    }
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) -> Option<Action> {
+        let Event::Key(KeyEvent {
+            code,
+            modifiers,
+            kind: KeyEventKind::Press,
+            ..
+        }) = event
+        else {
+            return matches!(event, Event::Resize(..)).then_some(Action::Redraw);
+        };
+        // raw mode swallows the interrupt, so treat it as quitting
+        if modifiers.contains(KeyModifiers::CONTROL) && code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+        if self.editing_filter {
+            match code {
+                KeyCode::Enter => self.editing_filter = false,
+                KeyCode::Esc => {
+                    self.editing_filter = false;
+                    self.filter.clear();
+                }
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => return None,
+            }
+            return Some(Action::Redraw);
+        }
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+            KeyCode::Char('p') | KeyCode::Char(' ') => {
+                self.paused_at = match self.paused_at {
+                    Some(_) => None,
+                    None => Some(self.received),
+                };
+                Some(Action::Redraw)
+            }
+            KeyCode::Char('/') => {
+                self.editing_filter = true;
+                self.filter.clear();
+                Some(Action::Redraw)
+            }
+            _ => None,
+        }
+    }
+
+    /// notes that a message was just received from the labeler
+    pub fn mark_frame(&mut self) {
+        self.last_frame_at = Some(Instant::now());
+    }
+
+    /// adds a label to the recent labels pane
+    pub fn record_label(&mut self, label: &LabelRecord) {
+        if self.recent.len() == RECENT_LABELS {
+            self.recent.pop_back();
+        }
+        self.recent.push_front(RecentLabel {
+            create_timestamp: label.create_timestamp.to_string(),
+            val: label.dbkey.key.val.to_string(),
+            target_uri: label.dbkey.key.target_uri.to_string(),
+            neg: label.neg,
+        });
+        self.received += 1;
+    }
+
+    pub fn draw(&mut self, status: &Status) -> Result<()> {
+        let now = Instant::now();
+        self.rate_samples.push_back((now, status.total_labels));
+        while self
+            .rate_samples
+            .front()
+            .is_some_and(|&(at, _)| now - at > RATE_WINDOW)
+        {
+            self.rate_samples.pop_front();
+        }
+        let rate = match self.rate_samples.front() {
+            Some(&(at, total)) if now > at => {
+                (status.total_labels - total) as f64 / (now - at).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        if status.connections != self.drawn_connections
+            || now - self.last_repaint > REPAINT_INTERVAL
+        {
+            self.drawn_connections = status.connections;
+            self.last_repaint = now;
+            self.terminal.clear()?;
+        }
+
+        let filter = self.filter.as_str();
+        let value_rows: Vec<_> = status
+            .value_counts
+            .iter()
+            .filter(|(val, ..)| val.contains(filter))
+            .map(|(val, applied, negated)| {
+                Row::new([format!("{val:?}"), applied.to_string(), negated.to_string()])
+            })
+            .collect();
+        let skipped = self
+            .paused_at
+            .map_or(0, |paused_at| self.received - paused_at);
+        let recent_items: Vec<_> = self
+            .recent
+            .iter()
+            .skip(skipped)
+            .filter(|label| label.val.contains(filter))
+            .map(|label| {
+                ListItem::new(format!(
+                    "{cts}  {sign}{val:?}  {target}",
+                    cts = label.create_timestamp,
+                    sign = if label.neg { "-" } else { "+" },
+                    val = label.val,
+                    target = label.target_uri,
+                ))
+            })
+            .collect();
+
+        let header = Paragraph::new(vec![
+            Line::from(format!(
+                "labeler: {domain}   did: {did}",
+                domain = status.labeler_domain,
+                did = status.labeler_did.unwrap_or("(not known yet)"),
+            )),
+            Line::from(format!(
+                "cursor: {cursor}   labels received: {total}",
+                cursor = status.cursor,
+                total = status.total_labels,
+            )),
+        ])
+        .block(Block::default().borders(Borders::ALL).title("labelview"));
+        let values = Table::new(
+            value_rows,
+            [
+                Constraint::Min(10),
+                Constraint::Length(9),
+                Constraint::Length(9),
+            ],
+        )
+        .header(
+            Row::new(["label", "applied", "negated"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL).title("label values"));
+        let mut recent_title = "recent labels".to_owned();
+        if self.paused_at.is_some() {
+            recent_title += " (paused)";
+        }
+        if !filter.is_empty() {
+            recent_title += &format!(" matching {filter:?}");
+        }
+        let recent = List::new(recent_items)
+            .block(Block::default().borders(Borders::ALL).title(recent_title));
+        let last_frame = match self.last_frame_at {
+            Some(at) => format!("last message {age:.1}s ago", age = (now - at).as_secs_f64()),
+            None => "no messages yet".to_owned(),
+        };
+        let status_line = Paragraph::new(format!(
+            "{rate:.1} labels/s   {reconnects} reconnect(s), {retries} without progress   \
+            {last_frame}",
+            reconnects = status.connections,
+            retries = status.retries,
+        ));
+        let help_line = Paragraph::new(if self.editing_filter {
+            format!("filter by label value: {filter}_   (enter to apply, esc to clear)")
+        } else {
+            "q quit   p pause recent labels   / filter by label value".to_owned()
+        })
+        .style(Style::default().add_modifier(Modifier::DIM));
+
+        self.terminal.draw(|frame| {
+            let [header_area, main_area, status_area, help_area] = Layout::vertical([
+                Constraint::Length(4),
+                Constraint::Min(5),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+            let [values_area, recent_area] =
+                Layout::horizontal([Constraint::Percentage(35), Constraint::Percentage(65)])
+                    .areas(main_area);
+            frame.render_widget(header, header_area);
+            frame.render_widget(values, values_area);
+            frame.render_widget(recent, recent_area);
+            frame.render_widget(status_line, status_area);
+            frame.render_widget(help_line, help_area);
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop_input.store(true, Ordering::Relaxed);
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}